@@ -11,6 +11,16 @@ pub enum CustomErrors {
 
     #[error("invalid state: {0}")]
     InvalidState(String),
+
+    /// Stored data that fails its checksum
+    #[error("corrupted: {0}")]
+    Corrupted(String),
+
+    #[error("resource exhausted: {0}")]
+    ResourceExhausted(String),
+
+    #[error("cancelled")]
+    Cancelled,
 }
 
 pub type Error = anyhow::Error;
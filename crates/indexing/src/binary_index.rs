@@ -149,11 +149,11 @@ pub enum IndexBinary {
 
 impl IndexBinary {
     pub fn new(vec_dims: u32, metric: DistanceMetric) -> Self {
-        if vec_dims % 64 == 0 {
+        if vec_dims.is_multiple_of(64) {
             IndexBinary::IndexBinaryStd64(IndexBinaryChunked::new(vec_dims / 8, metric))
-        } else if vec_dims % 32 == 0 {
+        } else if vec_dims.is_multiple_of(32) {
             IndexBinary::IndexBinaryStd32(IndexBinaryChunked::new(vec_dims / 8, metric))
-        } else if vec_dims % 16 == 0 {
+        } else if vec_dims.is_multiple_of(16) {
             IndexBinary::IndexBinaryStd16(IndexBinaryChunked::new(vec_dims / 8, metric))
         } else {
             IndexBinary::IndexBinaryStd8(IndexBinaryChunked::new(vec_dims / 8, metric))
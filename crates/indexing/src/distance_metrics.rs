@@ -42,6 +42,7 @@ fn hamming_distance_dyn<ChunkT: VecChunk>(a: &[ChunkT], b: &[ChunkT]) -> u32 {
 }
 
 /// Distance metrics for binary vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DistanceMetric {
     Hamming,
 }
@@ -18,7 +18,7 @@ pub struct SearchResult {
 
 impl PartialOrd for SearchResult {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.distance.cmp(&other.distance))
+        Some(self.cmp(other))
     }
 }
 
@@ -3,3 +3,5 @@ mod distance_metrics;
 mod index;
 
 pub use binary_index::*;
+pub use distance_metrics::{DistanceMetric, DistanceMetricFn};
+pub use index::{Index, SearchResult, Vector};
@@ -0,0 +1,363 @@
+use chrono::{TimeZone, Utc};
+use query_parser::Interval;
+
+use crate::DataValue;
+
+// Values of different types sort by their type, nulls first
+const NULL: u8 = 0x01;
+const BOOL: u8 = 0x02;
+const I64: u8 = 0x03;
+const F64: u8 = 0x04;
+const STRING: u8 = 0x05;
+const BYTES: u8 = 0x06;
+const DATETIME: u8 = 0x07;
+const INTERVAL: u8 = 0x08;
+const UUID: u8 = 0x09;
+const JSON: u8 = 0x0a;
+
+/// Ends strings and bytes, a zero inside them is written as `0x00 0xff`
+const TERMINATOR: [u8; 2] = [0x00, 0x01];
+const ESCAPED_ZERO: [u8; 2] = [0x00, 0xff];
+
+const SIGN: u64 = 1 << 63;
+const MICROS_PER_MONTH: i128 = 30 * 86_400_000_000;
+
+/// Encode a tuple of values so its bytes compare in the order of the values, element by
+/// element. Floats sort with -0 equal to 0 and every NaN equal and above infinity, intervals
+/// by their approximate length then months, and json by its text.
+pub fn encode_key(values: &[DataValue]) -> Vec<u8> {
+    let mut key = Vec::new();
+    for value in values {
+        encode_value(value, &mut key);
+    }
+    key
+}
+
+/// Append the encoding of a single value to `key`
+pub fn encode_value(value: &DataValue, key: &mut Vec<u8>) {
+    match value {
+        DataValue::Null => key.push(NULL),
+        DataValue::Bool(b) => key.extend([BOOL, *b as u8]),
+        DataValue::I64(i) => {
+            key.push(I64);
+            key.extend((*i as u64 ^ SIGN).to_be_bytes());
+        }
+        DataValue::F64(f) => {
+            key.push(F64);
+            key.extend(float_bits(*f).to_be_bytes());
+        }
+        DataValue::String(s) => {
+            key.push(STRING);
+            escape(s.as_bytes(), key);
+        }
+        DataValue::Bytes(bytes) => {
+            key.push(BYTES);
+            escape(bytes, key);
+        }
+        DataValue::DateTime(dt) => {
+            key.push(DATETIME);
+            key.extend((dt.timestamp() as u64 ^ SIGN).to_be_bytes());
+            key.extend(dt.timestamp_subsec_nanos().to_be_bytes());
+        }
+        DataValue::Interval(interval) => {
+            key.push(INTERVAL);
+            key.extend((interval.approximate_micros() as u128 ^ 1 << 127).to_be_bytes());
+            key.extend((interval.months as u32 ^ 1 << 31).to_be_bytes());
+        }
+        DataValue::UUID(uuid) => {
+            key.push(UUID);
+            key.extend(uuid.as_bytes());
+        }
+        DataValue::Json(json) => {
+            key.push(JSON);
+            escape(json.to_string().as_bytes(), key);
+        }
+    }
+}
+
+/// The values of an encoded tuple. Negative zero comes back as zero and NaNs as `f64::NAN`.
+pub fn decode_key(mut key: &[u8]) -> error::Result<Vec<DataValue>> {
+    let mut values = Vec::new();
+    while let Some((&tag, rest)) = key.split_first() {
+        key = rest;
+        let value = match tag {
+            NULL => DataValue::Null,
+            BOOL => match take::<1>(&mut key)? {
+                [0] => DataValue::Bool(false),
+                [1] => DataValue::Bool(true),
+                [b] => return Err(invalid(format!("bool encoded as {}", b))),
+            },
+            I64 => DataValue::I64((u64::from_be_bytes(take(&mut key)?) ^ SIGN) as i64),
+            F64 => {
+                let bits = u64::from_be_bytes(take(&mut key)?);
+                let bits = if bits & SIGN != 0 { bits ^ SIGN } else { !bits };
+                DataValue::F64(f64::from_bits(bits))
+            }
+            STRING => DataValue::String(
+                String::from_utf8(unescape(&mut key)?)
+                    .map_err(|e| invalid(format!("string key: {}", e)))?,
+            ),
+            BYTES => DataValue::Bytes(unescape(&mut key)?),
+            DATETIME => {
+                let secs = (u64::from_be_bytes(take(&mut key)?) ^ SIGN) as i64;
+                let nanos = u32::from_be_bytes(take(&mut key)?);
+                let dt = Utc
+                    .timestamp_opt(secs, nanos)
+                    .single()
+                    .ok_or_else(|| invalid(format!("datetime {}s {}ns", secs, nanos)))?;
+                DataValue::DateTime(dt)
+            }
+            INTERVAL => {
+                let approximate = (u128::from_be_bytes(take(&mut key)?) ^ 1 << 127) as i128;
+                let months = (u32::from_be_bytes(take(&mut key)?) ^ 1 << 31) as i32;
+                let micros = i64::try_from(approximate - months as i128 * MICROS_PER_MONTH)
+                    .map_err(|_| invalid(format!("interval of {} micros", approximate)))?;
+                DataValue::Interval(Interval { months, micros })
+            }
+            UUID => DataValue::UUID(uuid::Uuid::from_bytes(take(&mut key)?)),
+            JSON => DataValue::Json(
+                serde_json::from_slice(&unescape(&mut key)?)
+                    .map_err(|e| invalid(format!("json key: {}", e)))?,
+            ),
+            tag => return Err(invalid(format!("unknown type tag {:#04x}", tag))),
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Bits of `f` that order like the float, with its sign flipped when positive and every bit
+/// flipped when negative
+fn float_bits(f: f64) -> u64 {
+    let f = if f.is_nan() {
+        f64::NAN
+    } else if f == 0.0 {
+        0.0
+    } else {
+        f
+    };
+    let bits = f.to_bits();
+    if bits & SIGN != 0 {
+        !bits
+    } else {
+        bits ^ SIGN
+    }
+}
+
+fn escape(bytes: &[u8], key: &mut Vec<u8>) {
+    for &b in bytes {
+        match b {
+            0 => key.extend(ESCAPED_ZERO),
+            b => key.push(b),
+        }
+    }
+    key.extend(TERMINATOR);
+}
+
+fn unescape(key: &mut &[u8]) -> error::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        match take::<1>(key)? {
+            [0] => match take::<1>(key)? {
+                [0x01] => return Ok(bytes),
+                [0xff] => bytes.push(0),
+                [b] => return Err(invalid(format!("zero escaped with {:#04x}", b))),
+            },
+            [b] => bytes.push(b),
+        }
+    }
+}
+
+fn take<const N: usize>(key: &mut &[u8]) -> error::Result<[u8; N]> {
+    if key.len() < N {
+        return Err(invalid("key ends early".to_string()));
+    }
+    let (bytes, rest) = key.split_at(N);
+    *key = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn invalid(message: String) -> error::Error {
+    error::CustomErrors::InvalidArguments(message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+    use super::*;
+
+    /// A value of the kind picked by the first byte, drawn from a few values so ties and
+    /// shared prefixes are common
+    fn value(g: &mut Gen, kind: u8) -> DataValue {
+        let small = |g: &mut Gen| *g.choose(&[-2i64, -1, 0, 1, 2, i64::MIN, i64::MAX]).unwrap();
+        let text = |g: &mut Gen| -> String {
+            let len = usize::arbitrary(g) % 4;
+            (0..len)
+                .map(|_| *g.choose(&['\0', 'a', 'b', 'é']).unwrap())
+                .collect()
+        };
+        match kind % 10 {
+            0 => DataValue::Null,
+            1 => DataValue::Bool(bool::arbitrary(g)),
+            2 => DataValue::I64(if bool::arbitrary(g) {
+                small(g)
+            } else {
+                i64::arbitrary(g)
+            }),
+            3 => {
+                let any = f64::arbitrary(g);
+                DataValue::F64(
+                    *g.choose(&[
+                        f64::NAN,
+                        -f64::NAN,
+                        f64::INFINITY,
+                        f64::NEG_INFINITY,
+                        0.0,
+                        -0.0,
+                        f64::MIN_POSITIVE,
+                        -f64::MIN_POSITIVE,
+                        1.5,
+                        -1.5,
+                        any,
+                    ])
+                    .unwrap(),
+                )
+            }
+            4 => DataValue::String(text(g)),
+            5 => DataValue::Bytes(text(g).into_bytes()),
+            6 => DataValue::DateTime(
+                Utc.timestamp_opt(
+                    small(g).clamp(-2, 2) * 1_000_000,
+                    u32::arbitrary(g) % 1_000_000_000,
+                )
+                .unwrap(),
+            ),
+            7 => DataValue::Interval(Interval {
+                months: *g.choose(&[-1, 0, 1, 12]).unwrap(),
+                micros: *g.choose(&[-1, 0, 1, 30 * 86_400_000_000]).unwrap(),
+            }),
+            8 => DataValue::UUID(uuid::Uuid::from_u128(*g.choose(&[0, 1, 255, 256]).unwrap())),
+            _ => DataValue::Json(serde_json::json!({ "a": text(g) })),
+        }
+    }
+
+    fn rank(value: &DataValue) -> u8 {
+        encode_key(std::slice::from_ref(value))[0]
+    }
+
+    /// Logical order of two values, the same as query evaluation within a type
+    fn order(a: &DataValue, b: &DataValue) -> Ordering {
+        let float = |f: f64| if f.is_nan() { (1, 0.0) } else { (0, f) };
+        match (a, b) {
+            (DataValue::F64(a), DataValue::F64(b)) => {
+                let (a, b) = (float(*a), float(*b));
+                a.0.cmp(&b.0).then(a.1.partial_cmp(&b.1).unwrap())
+            }
+            (DataValue::Json(a), DataValue::Json(b)) => a.to_string().cmp(&b.to_string()),
+            (DataValue::Null, DataValue::Null) => Ordering::Equal,
+            (a, b) if rank(a) == rank(b) => same_type_order(a, b),
+            (a, b) => rank(a).cmp(&rank(b)),
+        }
+    }
+
+    fn same_type_order(a: &DataValue, b: &DataValue) -> Ordering {
+        match (a, b) {
+            (DataValue::I64(a), DataValue::I64(b)) => a.cmp(b),
+            (DataValue::String(a), DataValue::String(b)) => a.cmp(b),
+            (DataValue::Bool(a), DataValue::Bool(b)) => a.cmp(b),
+            (DataValue::DateTime(a), DataValue::DateTime(b)) => a.cmp(b),
+            (DataValue::UUID(a), DataValue::UUID(b)) => a.cmp(b),
+            (DataValue::Bytes(a), DataValue::Bytes(b)) => a.cmp(b),
+            (DataValue::Interval(a), DataValue::Interval(b)) => a
+                .approximate_micros()
+                .cmp(&b.approximate_micros())
+                .then(a.months.cmp(&b.months)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Tuples(Vec<DataValue>, Vec<DataValue>);
+
+    /// Two tuples that mostly share their types
+    impl Arbitrary for Tuples {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let kinds: Vec<u8> = (0..usize::arbitrary(g) % 4)
+                .map(|_| u8::arbitrary(g))
+                .collect();
+            let tuple = |g: &mut Gen| {
+                let mut kinds = kinds.clone();
+                if bool::arbitrary(g) {
+                    kinds.truncate(usize::arbitrary(g) % (kinds.len() + 1));
+                }
+                kinds.into_iter().map(|kind| value(g, kind)).collect()
+            };
+            Tuples(tuple(g), tuple(g))
+        }
+    }
+
+    fn tuple_order(a: &[DataValue], b: &[DataValue]) -> Ordering {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| order(a, b))
+            .find(|o| o.is_ne())
+            .unwrap_or(a.len().cmp(&b.len()))
+    }
+
+    #[test]
+    fn bytes_order_like_values() {
+        fn prop(tuples: Tuples) -> bool {
+            let Tuples(a, b) = tuples;
+            encode_key(&a).cmp(&encode_key(&b)) == tuple_order(&a, &b)
+        }
+        QuickCheck::new()
+            .tests(2000)
+            .quickcheck(prop as fn(Tuples) -> bool);
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        fn prop(tuples: Tuples) -> bool {
+            let Tuples(a, _) = tuples;
+            let decoded = decode_key(&encode_key(&a)).unwrap();
+            decoded.len() == a.len() && decoded.iter().zip(&a).all(|(d, v)| order(d, v).is_eq())
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(Tuples) -> bool);
+    }
+
+    #[test]
+    fn edge_values() {
+        let floats = [
+            f64::NEG_INFINITY,
+            -1.0,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            1e-300,
+            2.0,
+        ];
+        let keys: Vec<_> = floats
+            .iter()
+            .map(|f| encode_key(&[DataValue::F64(*f)]))
+            .collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(keys[3], keys[4]);
+        assert_eq!(
+            encode_key(&[DataValue::F64(f64::NAN)]),
+            encode_key(&[DataValue::F64(-f64::NAN)])
+        );
+
+        let a = encode_key(&[DataValue::String("a".into()), DataValue::I64(9)]);
+        let b = encode_key(&[DataValue::String("a\0".into()), DataValue::I64(0)]);
+        assert!(a < b);
+        assert_eq!(decode_key(&b).unwrap()[0], DataValue::String("a\0".into()));
+
+        assert!(decode_key(&[STRING, b'a', 0]).is_err());
+        assert!(decode_key(&[0xee]).is_err());
+    }
+}
@@ -1,5 +1,17 @@
+mod key;
+mod proto;
+mod statement;
+
+use std::mem;
+
 use chrono::{DateTime, Utc};
+use faiss::DistanceMetric;
+use query_parser::{FieldPath, Interval, PathSegment};
 
+pub use key::{decode_key, encode_key, encode_value};
+pub use statement::{distance_metric, Statement};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ColumnTypes {
     I64,
     F64,
@@ -8,9 +20,13 @@ pub enum ColumnTypes {
     DateTime,
     UUID,
     Bytes,
-    BinaryVector
+    /// Binary vector with the given number of bits
+    BinaryVector(u32),
+    Json,
+    Interval,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum DataValue {
     I64(i64),
     F64(f64),
@@ -18,40 +34,445 @@ pub enum DataValue {
     Bool(bool),
     DateTime(DateTime<Utc>),
     UUID(uuid::Uuid),
-    Bytes(Vec<u8>)
+    Bytes(Vec<u8>),
+    Json(serde_json::Value),
+    Interval(Interval),
+    Null,
+}
+
+impl DataValue {
+    /// Bytes the value takes up in memory, what it holds on the heap included
+    pub fn size_hint(&self) -> usize {
+        mem::size_of::<DataValue>()
+            + match self {
+                DataValue::String(s) => s.len(),
+                DataValue::Bytes(b) => b.len(),
+                DataValue::Json(json) => json_heap_bytes(json),
+                _ => 0,
+            }
+    }
+
+    /// The field at `path` inside a json value. Scalars come back as the matching value,
+    /// objects and arrays as json, and missing fields or values that aren't json as null.
+    pub fn field(&self, path: &[PathSegment]) -> DataValue {
+        let DataValue::Json(json) = self else {
+            return DataValue::Null;
+        };
+        let mut json = json;
+        for segment in path {
+            let field = match segment {
+                PathSegment::Key(key) => json.get(key),
+                PathSegment::Index(i) => usize::try_from(*i).ok().and_then(|i| json.get(i)),
+            };
+            match field {
+                Some(field) => json = field,
+                None => return DataValue::Null,
+            }
+        }
+        match json {
+            serde_json::Value::Null => DataValue::Null,
+            serde_json::Value::Bool(b) => DataValue::Bool(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => DataValue::I64(i),
+                None => n.as_f64().map_or(DataValue::Null, DataValue::F64),
+            },
+            serde_json::Value::String(s) => DataValue::String(s.clone()),
+            json => DataValue::Json(json.clone()),
+        }
+    }
+
+    /// Convert to a value of `data_type`, or null when it has no such value. Integers widen
+    /// to floats and strings are read as datetimes, uuids and intervals.
+    pub fn coerce(self, data_type: ColumnTypes) -> DataValue {
+        match (self, data_type) {
+            (DataValue::I64(i), ColumnTypes::I64) => DataValue::I64(i),
+            (DataValue::I64(i), ColumnTypes::F64) => DataValue::F64(i as f64),
+            (DataValue::F64(f), ColumnTypes::F64) => DataValue::F64(f),
+            (DataValue::String(s), ColumnTypes::String) => DataValue::String(s),
+            (DataValue::String(s), ColumnTypes::DateTime) => {
+                query_parser::parse_datetime(&s).map_or(DataValue::Null, DataValue::DateTime)
+            }
+            (DataValue::String(s), ColumnTypes::UUID) => {
+                uuid::Uuid::parse_str(&s).map_or(DataValue::Null, DataValue::UUID)
+            }
+            (DataValue::String(s), ColumnTypes::Interval) => {
+                s.parse().map_or(DataValue::Null, DataValue::Interval)
+            }
+            (DataValue::Interval(i), ColumnTypes::Interval) => DataValue::Interval(i),
+            (DataValue::Bool(b), ColumnTypes::Bool) => DataValue::Bool(b),
+            (DataValue::Json(j), ColumnTypes::Json) => DataValue::Json(j),
+            (DataValue::I64(i), ColumnTypes::Json) => DataValue::Json(i.into()),
+            (DataValue::F64(f), ColumnTypes::Json) => DataValue::Json(f.into()),
+            (DataValue::Bool(b), ColumnTypes::Json) => DataValue::Json(b.into()),
+            (DataValue::String(s), ColumnTypes::Json) => DataValue::Json(s.into()),
+            _ => DataValue::Null,
+        }
+    }
 }
 
+fn json_heap_bytes(json: &serde_json::Value) -> usize {
+    let value = mem::size_of::<serde_json::Value>();
+    match json {
+        serde_json::Value::String(s) => s.len(),
+        serde_json::Value::Array(values) => values.iter().map(|v| value + json_heap_bytes(v)).sum(),
+        serde_json::Value::Object(fields) => fields
+            .iter()
+            .map(|(k, v)| k.len() + value + json_heap_bytes(v))
+            .sum(),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Column {
     pub name: String,
     pub data_type: ColumnTypes,
+    /// Field of a json column the column's values are extracted from
+    pub generated: Option<FieldPath>,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, data_type: ColumnTypes) -> Self {
+        Column {
+            name: name.into(),
+            data_type,
+            generated: None,
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct PrimaryKey {
     pub name: String,
     pub data_type: ColumnTypes,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMethod {
+    /// Sorted index over scalar values
+    Sorted,
+    /// Exhaustive search over every vector
+    Flat,
+    /// Hierarchical navigable small world graph over vectors
+    Hnsw,
+}
+
+/// Settings `hnsw` indexes accept
+const HNSW_OPTIONS: [&str; 3] = ["m", "ef_construction", "ef_search"];
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Index {
     pub name: String,
-    pub data_type: ColumnTypes,
+    pub column: String,
     pub is_partition_key: bool,
+    /// Picked from the column type when not given
+    pub method: Option<IndexMethod>,
+    /// Distance metric of vector indexes, hamming when not given
+    pub metric: Option<DistanceMetric>,
+    /// Method specific settings, such as `m` for `hnsw`
+    pub options: Vec<(String, DataValue)>,
+}
+
+impl Index {
+    /// Plain index on a column, named after it
+    pub fn new(column: impl Into<String>) -> Self {
+        let column = column.into();
+        Index {
+            name: column.clone(),
+            column,
+            is_partition_key: false,
+            method: None,
+            metric: None,
+            options: Vec::new(),
+        }
+    }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct CreateTable {
     pub name: String,
     pub primary_key: Column,
+    /// Every column of the table, including the primary key
+    pub columns: Vec<Column>,
     pub indexes: Vec<Index>,
 }
+
+impl CreateTable {
+    pub fn new(name: impl Into<String>, primary_key: Column) -> Self {
+        CreateTable {
+            name: name.into(),
+            columns: vec![primary_key.clone()],
+            primary_key,
+            indexes: Vec::new(),
+        }
+    }
+
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn add_column(&mut self, column: Column) -> error::Result<()> {
+        if self.column(&column.name).is_some() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "table {} already has a column {}",
+                self.name, column.name
+            ))
+            .into());
+        }
+        if let Some(path) = &column.generated {
+            let source = self.column(&path.column).map(|c| c.data_type);
+            if source != Some(ColumnTypes::Json) {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "generated column {} has to read a json column defined before it, {} isn't one",
+                    column.name, path.column
+                ))
+                .into());
+            }
+        }
+        self.columns.push(column);
+        Ok(())
+    }
+
+    /// Fill in the generated columns of a row given in column order
+    pub fn generate(&self, row: &mut [DataValue]) -> error::Result<()> {
+        if row.len() != self.columns.len() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "table {} has {} columns, got {} values",
+                self.name,
+                self.columns.len(),
+                row.len()
+            ))
+            .into());
+        }
+        for (i, column) in self.columns.iter().enumerate() {
+            let Some(path) = &column.generated else {
+                continue;
+            };
+            let source = self.columns.iter().position(|c| c.name == path.column);
+            row[i] = source.map_or(DataValue::Null, |source| {
+                row[source].field(&path.segments).coerce(column.data_type)
+            });
+        }
+        Ok(())
+    }
+
+    /// Add an index, checking its method, metric and settings against the indexed column
+    pub fn add_index(&mut self, index: Index) -> error::Result<()> {
+        let invalid = |message: String| -> error::Result<()> {
+            Err(error::CustomErrors::InvalidArguments(message).into())
+        };
+        let Some(column) = self.column(&index.column) else {
+            return invalid(format!(
+                "table {} has no column {} to index",
+                self.name, index.column
+            ));
+        };
+        if column.data_type == ColumnTypes::Json {
+            return invalid(format!(
+                "json column {} can't be indexed, index a column generated from it instead",
+                column.name
+            ));
+        }
+        if self.indexes.iter().any(|i| i.name == index.name) {
+            return invalid(format!(
+                "table {} already has an index {}",
+                self.name, index.name
+            ));
+        }
+
+        let is_vector = matches!(column.data_type, ColumnTypes::BinaryVector(_));
+        match (index.method, is_vector) {
+            (None, _) | (Some(IndexMethod::Sorted), false) => {}
+            (Some(IndexMethod::Flat | IndexMethod::Hnsw), true) => {}
+            (Some(method), _) => {
+                return invalid(format!(
+                    "{:?} indexes can't index column {} of type {:?}",
+                    method, column.name, column.data_type
+                ))
+            }
+        }
+        if index.metric.is_some() && !is_vector {
+            return invalid(format!(
+                "distance metrics only apply to vector columns, {} is {:?}",
+                column.name, column.data_type
+            ));
+        }
+        for (key, value) in &index.options {
+            let known =
+                index.method == Some(IndexMethod::Hnsw) && HNSW_OPTIONS.contains(&key.as_str());
+            if !known {
+                return invalid(format!("unknown index setting {}", key));
+            }
+            if !matches!(value, DataValue::I64(v) if *v > 0) {
+                return invalid(format!(
+                    "index setting {} needs a positive integer, got {:?}",
+                    key, value
+                ));
+            }
+        }
+
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    /// Apply the changes of an `alter table` statement, either all of them or none
+    pub fn alter(&mut self, alter: &AlterTable) -> error::Result<()> {
+        let mut altered = self.clone();
+        for change in &alter.changes {
+            match change {
+                TableChange::AddColumn(column) => altered.add_column(column.clone())?,
+                TableChange::AddIndex(index) => altered.add_index(index.clone())?,
+            }
+        }
+        *self = altered;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DropTable {
     pub name: String,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableChange {
+    AddColumn(Column),
+    AddIndex(Index),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlterTable {
+    pub name: String,
+    pub changes: Vec<TableChange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub table: String,
+    pub index: Index,
+}
+
 type PartitionId = u64;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Query {
     pub table: String,
     pub partitions: Vec<PartitionId>,
-    pub query: query_parser::OpTree,
+    pub query: query_parser::QueryAst,
     pub parameters: Vec<DataValue>,
+    /// Columns returned for every matching row
     pub columns: Vec<Column>,
+    /// Columns and aggregates in the order aggregate queries return them. Without one they
+    /// return their groups, then their aggregates.
+    pub select: Vec<query_parser::SelectItem>,
+}
+
+/// Parse a `select` statement without resolving it against its table
+pub fn parse_select(statement: &str) -> error::Result<query_parser::SelectAst> {
+    Ok(query_parser::query::SelectParser::new()
+        .parse(statement)
+        .map_err(|e| error::CustomErrors::InvalidArguments(e.to_string()))?)
+}
+
+impl Query {
+    /// Parse a `select` statement into a query over every partition of its table, whose
+    /// columns are `table`
+    pub fn parse(
+        statement: &str,
+        parameters: Vec<DataValue>,
+        table: &[Column],
+    ) -> error::Result<Query> {
+        Query::from_select(parse_select(statement)?, parameters, table)
+    }
+
+    pub fn from_select(
+        select: query_parser::SelectAst,
+        parameters: Vec<DataValue>,
+        table: &[Column],
+    ) -> error::Result<Query> {
+        let query = &select.query;
+        if query.aggregates.is_empty() && !query.group_by.is_empty() {
+            return Err(error::CustomErrors::InvalidArguments(
+                "group by needs an aggregate in the select list".to_string(),
+            )
+            .into());
+        }
+        if !query.aggregates.is_empty() {
+            if let Some(column) = select.columns().find(|c| !query.group_by.contains(c)) {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "column {} must be grouped by to be selected with aggregates",
+                    column
+                ))
+                .into());
+            }
+        }
+        let columns = select
+            .columns()
+            .map(|name| {
+                table
+                    .iter()
+                    .find(|c| c.name == *name)
+                    .cloned()
+                    .ok_or_else(|| {
+                        error::CustomErrors::InvalidArguments(format!("unknown column {}", name))
+                            .into()
+                    })
+            })
+            .collect::<error::Result<_>>()?;
+
+        Ok(Query {
+            table: select.table,
+            partitions: Vec::new(),
+            query: select.query,
+            parameters,
+            columns,
+            select: select.items,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_queries() {
+        let table = [
+            Column::new("id", ColumnTypes::I64),
+            Column::new("source", ColumnTypes::String),
+            Column::new("vector", ColumnTypes::BinaryVector(8)),
+        ];
+        let parse = |statement| Query::parse(statement, vec![], &table);
+
+        let query = Query::parse(
+            "select id, vector from docs where vector topk 10",
+            vec![DataValue::Bytes(vec![1])],
+            &table,
+        )
+        .unwrap();
+        assert_eq!(query.table, "docs");
+        assert_eq!(query.columns, vec![table[0].clone(), table[2].clone()]);
+        assert_eq!(query.query.filter.to_string(), "vector topk 10");
+        assert_eq!(query.parameters, vec![DataValue::Bytes(vec![1])]);
+
+        let query = parse("select count(*), source from docs group by source").unwrap();
+        assert_eq!(query.columns, vec![table[1].clone()]);
+        assert_eq!(query.select.len(), 2);
+        assert!(parse("select id, count(*) from docs group by source").is_err());
+        assert!(parse("select id from docs group by id").is_err());
+        assert!(parse("select name from docs").is_err());
+        assert!(parse("id == 1").is_err());
+    }
+
+    #[test]
+    fn sizes_values() {
+        let base = DataValue::Null.size_hint();
+        assert_eq!(DataValue::I64(1).size_hint(), base);
+        assert_eq!(DataValue::String("abc".into()).size_hint(), base + 3);
+        assert_eq!(DataValue::Bytes(vec![0; 100]).size_hint(), base + 100);
+
+        let json = DataValue::Json(serde_json::json!({"ab": [1, "cd"]}));
+        let value = mem::size_of::<serde_json::Value>();
+        assert_eq!(json.size_hint(), base + 2 + value + 2 * value + 2);
+    }
 }
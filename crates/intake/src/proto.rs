@@ -0,0 +1,87 @@
+use chrono::{TimeZone, Utc};
+use sifter_proto::statements::{value::Value as ProtoValue, Value};
+
+use crate::DataValue;
+
+impl From<DataValue> for Value {
+    fn from(value: DataValue) -> Self {
+        let value = match value {
+            DataValue::I64(v) => Some(ProtoValue::I64(v)),
+            DataValue::F64(v) => Some(ProtoValue::F64(v)),
+            DataValue::String(v) => Some(ProtoValue::String(v)),
+            DataValue::Bool(v) => Some(ProtoValue::Bool(v)),
+            DataValue::DateTime(v) => Some(ProtoValue::DateTime(v.timestamp_micros())),
+            DataValue::UUID(v) => Some(ProtoValue::Uuid(v.as_bytes().to_vec())),
+            DataValue::Bytes(v) => Some(ProtoValue::Bytes(v)),
+            DataValue::Json(v) => Some(ProtoValue::Json(v.to_string())),
+            DataValue::Interval(v) => Some(ProtoValue::Interval(v.into())),
+            DataValue::Null => None,
+        };
+        Value { value }
+    }
+}
+
+impl TryFrom<Value> for DataValue {
+    type Error = error::Error;
+
+    fn try_from(value: Value) -> error::Result<Self> {
+        Ok(match value.value {
+            Some(ProtoValue::I64(v)) => DataValue::I64(v),
+            Some(ProtoValue::F64(v)) => DataValue::F64(v),
+            Some(ProtoValue::String(v)) => DataValue::String(v),
+            Some(ProtoValue::Bool(v)) => DataValue::Bool(v),
+            Some(ProtoValue::DateTime(v)) => {
+                DataValue::DateTime(Utc.timestamp_micros(v).single().ok_or_else(|| {
+                    error::CustomErrors::InvalidArguments(format!("datetime {} is out of range", v))
+                })?)
+            }
+            Some(ProtoValue::Uuid(v)) => {
+                DataValue::UUID(uuid::Uuid::from_slice(&v).map_err(|e| {
+                    error::CustomErrors::InvalidArguments(format!("invalid uuid: {}", e))
+                })?)
+            }
+            Some(ProtoValue::Bytes(v)) => DataValue::Bytes(v),
+            Some(ProtoValue::Json(v)) => {
+                DataValue::Json(serde_json::from_str(&v).map_err(|e| {
+                    error::CustomErrors::InvalidArguments(format!("invalid json: {}", e))
+                })?)
+            }
+            Some(ProtoValue::Interval(v)) => DataValue::Interval(v.into()),
+            None => DataValue::Null,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_values() {
+        for value in [
+            DataValue::I64(-3),
+            DataValue::F64(0.5),
+            DataValue::String("a".into()),
+            DataValue::Bool(true),
+            DataValue::DateTime(Utc.timestamp_micros(1_695_686_400_123_456).unwrap()),
+            DataValue::UUID(uuid::Uuid::from_u128(7)),
+            DataValue::Bytes(vec![1, 2]),
+            DataValue::Json(serde_json::json!({"tags": ["a", 1]})),
+            DataValue::Interval(query_parser::Interval {
+                months: -1,
+                micros: 3,
+            }),
+            DataValue::Null,
+        ] {
+            assert_eq!(
+                DataValue::try_from(Value::from(value.clone())).unwrap(),
+                value
+            );
+        }
+
+        let uuid = Value {
+            value: Some(ProtoValue::Uuid(vec![1, 2, 3])),
+        };
+        assert!(DataValue::try_from(uuid).is_err());
+    }
+}
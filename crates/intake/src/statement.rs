@@ -0,0 +1,344 @@
+use faiss::DistanceMetric;
+use query_parser::{AlterAst, ColumnAst, IndexAst, TypeAst, Value};
+
+use crate::{
+    AlterTable, Column, ColumnTypes, CreateIndex, CreateTable, DataValue, DropTable, Index,
+    IndexMethod, TableChange,
+};
+
+/// A parsed statement of the query language.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    /// A query, to resolve against its table's columns with [`crate::Query::from_select`]
+    Query {
+        select: Box<query_parser::SelectAst>,
+        parameters: Vec<DataValue>,
+    },
+    CreateTable(CreateTable),
+    DropTable(DropTable),
+    AlterTable(AlterTable),
+    CreateIndex(CreateIndex),
+}
+
+impl Statement {
+    /// Parse any statement. `parameters` bind the `$n` placeholders of queries.
+    pub fn parse(statement: &str, parameters: Vec<DataValue>) -> error::Result<Statement> {
+        let statement = query_parser::query::StatementParser::new()
+            .parse(statement)
+            .map_err(|e| invalid(e.to_string()))?;
+
+        Ok(match statement {
+            query_parser::Statement::Select(select) => Statement::Query {
+                select: Box::new(select),
+                parameters,
+            },
+            query_parser::Statement::CreateTable { name, columns } => {
+                Statement::CreateTable(create_table(name, columns)?)
+            }
+            query_parser::Statement::DropTable { name } => Statement::DropTable(DropTable { name }),
+            query_parser::Statement::AlterTable { table, change } => {
+                let changes = match change {
+                    AlterAst::AddColumn(column) => {
+                        let (column, index) = column_definition(column)?;
+                        let mut changes = vec![TableChange::AddColumn(column)];
+                        changes.extend(index.map(TableChange::AddIndex));
+                        changes
+                    }
+                    AlterAst::AddIndex(index) => {
+                        vec![TableChange::AddIndex(index_definition(index)?)]
+                    }
+                };
+                Statement::AlterTable(AlterTable {
+                    name: table,
+                    changes,
+                })
+            }
+            query_parser::Statement::CreateIndex { table, index } => {
+                Statement::CreateIndex(CreateIndex {
+                    table,
+                    index: index_definition(index)?,
+                })
+            }
+        })
+    }
+}
+
+fn invalid(message: String) -> error::Error {
+    error::CustomErrors::InvalidArguments(message).into()
+}
+
+fn create_table(name: String, columns: Vec<ColumnAst>) -> error::Result<CreateTable> {
+    let mut definitions = Vec::with_capacity(columns.len());
+    let mut primary_key = None;
+    for (i, column) in columns.into_iter().enumerate() {
+        let is_primary_key = column.primary_key;
+        let (column, index) = column_definition(column)?;
+        if is_primary_key {
+            if column.generated.is_some() {
+                return Err(invalid(format!(
+                    "primary key {} can't be a generated column",
+                    column.name
+                )));
+            }
+            if let Some(other) = primary_key {
+                let (Column { name: other, .. }, _) = &definitions[other];
+                return Err(invalid(format!(
+                    "table {} has two primary keys, {} and {}",
+                    name, other, column.name
+                )));
+            }
+            primary_key = Some(i);
+        }
+        definitions.push((column, index));
+    }
+    let primary_key =
+        primary_key.ok_or_else(|| invalid(format!("table {} has no primary key", name)))?;
+
+    let mut table = CreateTable::new(name, definitions[primary_key].0.clone());
+    let mut indexes = Vec::new();
+    for (i, (column, index)) in definitions.into_iter().enumerate() {
+        if i != primary_key {
+            table.add_column(column)?;
+        }
+        indexes.extend(index);
+    }
+    // Indexes are added once every column exists
+    for index in indexes {
+        table.add_index(index)?;
+    }
+    Ok(table)
+}
+
+/// A column and the index defined inline with it
+fn column_definition(column: ColumnAst) -> error::Result<(Column, Option<Index>)> {
+    let data_type = column_type(&column.data_type)?;
+    let index = column.index.map(index_definition).transpose()?;
+    Ok((
+        Column {
+            name: column.name,
+            data_type,
+            generated: column.generated,
+        },
+        index,
+    ))
+}
+
+fn column_type(data_type: &TypeAst) -> error::Result<ColumnTypes> {
+    let column_type = match data_type.name.as_str() {
+        "i64" => ColumnTypes::I64,
+        "f64" => ColumnTypes::F64,
+        "string" => ColumnTypes::String,
+        "bool" => ColumnTypes::Bool,
+        "datetime" => ColumnTypes::DateTime,
+        "uuid" => ColumnTypes::UUID,
+        "bytes" => ColumnTypes::Bytes,
+        "json" => ColumnTypes::Json,
+        "interval" => ColumnTypes::Interval,
+        "binary_vector" => {
+            return match data_type.size {
+                Some(bits) if bits > 0 && bits % 8 == 0 && bits <= u32::MAX as u64 => {
+                    Ok(ColumnTypes::BinaryVector(bits as u32))
+                }
+                Some(bits) => Err(invalid(format!(
+                    "binary vectors need a positive multiple of 8 bits, got {}",
+                    bits
+                ))),
+                None => Err(invalid("binary_vector needs a size in bits".to_string())),
+            }
+        }
+        name => return Err(invalid(format!("unknown column type {}", name))),
+    };
+    if data_type.size.is_some() {
+        return Err(invalid(format!("{} doesn't take a size", data_type.name)));
+    }
+    Ok(column_type)
+}
+
+fn index_definition(index: IndexAst) -> error::Result<Index> {
+    let method = index
+        .method
+        .map(|method| match method.as_str() {
+            "sorted" => Ok(IndexMethod::Sorted),
+            "flat" => Ok(IndexMethod::Flat),
+            "hnsw" => Ok(IndexMethod::Hnsw),
+            method => Err(invalid(format!("unknown index method {}", method))),
+        })
+        .transpose()?;
+    let metric = index.metric.as_deref().map(distance_metric).transpose()?;
+    let options = index
+        .options
+        .into_iter()
+        .map(|(key, value)| Ok((key, option_value(value)?)))
+        .collect::<error::Result<_>>()?;
+
+    Ok(Index {
+        name: index.name.unwrap_or_else(|| index.column.clone()),
+        method,
+        metric,
+        options,
+        ..Index::new(index.column)
+    })
+}
+
+/// The distance metric called `name` in the query language
+pub fn distance_metric(name: &str) -> error::Result<DistanceMetric> {
+    match name {
+        "hamming" => Ok(DistanceMetric::Hamming),
+        name => Err(invalid(format!("unknown distance metric {}", name))),
+    }
+}
+
+fn option_value(value: Value) -> error::Result<DataValue> {
+    Ok(match value {
+        Value::Double(d) => DataValue::F64(d),
+        Value::Integer(i) => DataValue::I64(i),
+        Value::String(s) => DataValue::String(s),
+        Value::Bool(b) => DataValue::Bool(b),
+        Value::DateTime(d) => DataValue::DateTime(d),
+        Value::UUID(u) => DataValue::UUID(u),
+        Value::Interval(i) => DataValue::Interval(i),
+        value @ (Value::ResourceTag(_) | Value::Literal(_) | Value::Field(_) | Value::Expr(_)) => {
+            return Err(invalid(format!(
+                "index settings need a constant, got {}",
+                value
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(statement: &str) -> error::Result<Statement> {
+        Statement::parse(statement, vec![])
+    }
+
+    #[test]
+    fn create_table() {
+        let Statement::CreateTable(table) = parse(
+            "create table t (id uuid primary key, ts datetime index, hash binary_vector(512) index hamming)",
+        )
+        .unwrap() else {
+            panic!("expected a table definition")
+        };
+        assert_eq!(table.name, "t");
+        assert_eq!(table.primary_key, Column::new("id", ColumnTypes::UUID));
+        assert_eq!(
+            table
+                .columns
+                .iter()
+                .map(|c| c.data_type)
+                .collect::<Vec<_>>(),
+            vec![
+                ColumnTypes::UUID,
+                ColumnTypes::DateTime,
+                ColumnTypes::BinaryVector(512)
+            ]
+        );
+        assert_eq!(table.indexes.len(), 2);
+        assert_eq!(table.indexes[1].metric, Some(DistanceMetric::Hamming));
+
+        for statement in [
+            "create table t (ts datetime)",
+            "create table t (id uuid primary key, other i64 primary key)",
+            "create table t (id uuid primary key, id uuid)",
+            "create table t (id uuid primary key, v binary_vector)",
+            "create table t (id uuid primary key, v binary_vector(7))",
+            "create table t (id uuid primary key, v i64(7))",
+            "create table t (id uuid primary key, v text)",
+            "create table t (id uuid primary key, v i64 index hamming)",
+            "create table t (id uuid primary key, v binary_vector(8) index cosine)",
+            "create table t (id uuid primary key, v i64 index using hnsw)",
+        ] {
+            assert!(parse(statement).is_err(), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn generated_columns() {
+        let Statement::CreateTable(table) = parse(
+            "create table t (id i64 primary key, meta json, source string as (meta.source) index, \
+             first f64 as (meta.scores[0]))",
+        )
+        .unwrap() else {
+            panic!("expected a table definition")
+        };
+        let meta = serde_json::json!({"source": "a", "scores": [2, 0.5]});
+        let mut row = vec![
+            DataValue::I64(1),
+            DataValue::Json(meta),
+            DataValue::Null,
+            DataValue::Null,
+        ];
+        table.generate(&mut row).unwrap();
+        assert_eq!(row[2], DataValue::String("a".into()));
+        assert_eq!(row[3], DataValue::F64(2.0));
+
+        row[1] = DataValue::Json(serde_json::json!({"source": 3}));
+        table.generate(&mut row).unwrap();
+        assert_eq!(row[2..], [DataValue::Null, DataValue::Null]);
+        assert!(table.generate(&mut row[..3]).is_err());
+
+        for statement in [
+            "create table t (id i64 primary key as (meta.id), meta json)",
+            "create table t (id i64 primary key, source string as (meta.source), meta json)",
+            "create table t (id i64 primary key, meta string, source string as (meta.source))",
+        ] {
+            assert!(parse(statement).is_err(), "{}", statement);
+        }
+    }
+
+    #[test]
+    fn alter_table() {
+        let mut table = CreateTable::new("t", Column::new("id", ColumnTypes::I64));
+        for statement in [
+            "alter table t add column hash binary_vector(64)",
+            "create index hnsw on t (hash hamming) using hnsw with (m = 16)",
+            "alter table t add column ts datetime index",
+        ] {
+            match parse(statement).unwrap() {
+                Statement::AlterTable(alter) => table.alter(&alter).unwrap(),
+                Statement::CreateIndex(create) => table.add_index(create.index).unwrap(),
+                statement => panic!("unexpected {:?}", statement),
+            }
+        }
+        assert_eq!(table.columns.len(), 3);
+        assert_eq!(
+            table.indexes[0],
+            Index {
+                name: "hnsw".into(),
+                method: Some(IndexMethod::Hnsw),
+                metric: Some(DistanceMetric::Hamming),
+                options: vec![("m".into(), DataValue::I64(16))],
+                ..Index::new("hash")
+            }
+        );
+        assert_eq!(table.indexes[1], Index::new("ts"));
+
+        for statement in [
+            "alter table t add column ts i64",
+            "alter table t add index (missing)",
+            "alter table t add index hnsw (hash)",
+            "alter table t add index (hash) using hnsw with (m = 0)",
+            "alter table t add index (hash) using hnsw with (levels = 3)",
+            "alter table t add index (hash) with (m = $1)",
+            "alter table t add index (ts) using flat",
+        ] {
+            let result = parse(statement).and_then(|statement| match statement {
+                Statement::AlterTable(alter) => table.alter(&alter),
+                statement => panic!("unexpected {:?}", statement),
+            });
+            assert!(result.is_err(), "{}", statement);
+        }
+
+        assert_eq!(
+            parse("drop table t").unwrap(),
+            Statement::DropTable(DropTable { name: "t".into() })
+        );
+        assert!(matches!(
+            parse("select id from t").unwrap(),
+            Statement::Query { .. }
+        ));
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    hash::{Hash, Hasher},
+};
+
+use intake::DataValue;
+use query_parser::{Aggregate, AggregateFn};
+
+use crate::eval::compare;
+
+/// Value usable as a hash key. Floats are compared by their bits, with every NaN and both
+/// zeros treated as equal.
+#[derive(Debug, Clone)]
+pub struct HashValue(pub DataValue);
+
+impl HashValue {
+    fn float_bits(f: f64) -> u64 {
+        if f.is_nan() {
+            f64::NAN.to_bits()
+        } else if f == 0.0 {
+            0
+        } else {
+            f.to_bits()
+        }
+    }
+}
+
+impl PartialEq for HashValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (DataValue::F64(a), DataValue::F64(b)) => {
+                HashValue::float_bits(*a) == HashValue::float_bits(*b)
+            }
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for HashValue {}
+
+impl Hash for HashValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(&self.0).hash(state);
+        match &self.0 {
+            DataValue::I64(i) => i.hash(state),
+            DataValue::F64(f) => HashValue::float_bits(*f).hash(state),
+            DataValue::String(s) => s.hash(state),
+            DataValue::Bool(b) => b.hash(state),
+            DataValue::DateTime(d) => d.hash(state),
+            DataValue::UUID(u) => u.hash(state),
+            DataValue::Bytes(b) => b.hash(state),
+            DataValue::Json(j) => j.to_string().hash(state),
+            DataValue::Interval(i) => i.hash(state),
+            DataValue::Null => {}
+        }
+    }
+}
+
+/// Running state of one aggregate within one group.
+#[derive(Debug)]
+pub enum Accumulator {
+    Count(u64),
+    CountDistinct(HashSet<HashValue>),
+    Min(Option<DataValue>),
+    Max(Option<DataValue>),
+    Sum(Option<DataValue>),
+    Avg { sum: f64, count: u64 },
+}
+
+impl Accumulator {
+    pub fn new(function: AggregateFn) -> Self {
+        match function {
+            AggregateFn::Count => Accumulator::Count(0),
+            AggregateFn::CountDistinct => Accumulator::CountDistinct(HashSet::new()),
+            AggregateFn::Min => Accumulator::Min(None),
+            AggregateFn::Max => Accumulator::Max(None),
+            AggregateFn::Sum => Accumulator::Sum(None),
+            AggregateFn::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+        }
+    }
+
+    /// Fold a row's value into the accumulator, `None` stands for `*`. Nulls are skipped by
+    /// everything but `count(*)`. Returns how many bytes the accumulator now holds on to
+    /// that it didn't before.
+    pub fn update(
+        &mut self,
+        aggregate: &Aggregate,
+        value: Option<&DataValue>,
+    ) -> error::Result<usize> {
+        let value = match value {
+            None => {
+                if let Accumulator::Count(count) = self {
+                    *count += 1;
+                }
+                return Ok(0);
+            }
+            Some(DataValue::Null) => return Ok(0),
+            Some(value) => value,
+        };
+
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::CountDistinct(seen) => {
+                let added = seen.insert(HashValue(value.clone()));
+                return Ok(if added { value.size_hint() } else { 0 });
+            }
+            Accumulator::Min(current) | Accumulator::Max(current) => {
+                let replace = match current.as_ref() {
+                    None => true,
+                    Some(current) => {
+                        let ordering = compare(value, current)
+                            .ok_or_else(|| incomparable(aggregate, value, current))?;
+                        match aggregate.function {
+                            AggregateFn::Min => ordering == Ordering::Less,
+                            _ => ordering == Ordering::Greater,
+                        }
+                    }
+                };
+                if replace {
+                    let held = current.as_ref().map_or(0, DataValue::size_hint);
+                    *current = Some(value.clone());
+                    return Ok(value.size_hint().saturating_sub(held));
+                }
+            }
+            Accumulator::Sum(current) => {
+                let sum = match (current.take(), value) {
+                    (None, DataValue::I64(v)) => DataValue::I64(*v),
+                    (None, DataValue::F64(v)) => DataValue::F64(*v),
+                    (Some(DataValue::I64(a)), DataValue::I64(b)) => {
+                        DataValue::I64(a.checked_add(*b).ok_or_else(|| {
+                            error::CustomErrors::InvalidState(format!("{} overflowed", aggregate))
+                        })?)
+                    }
+                    (Some(DataValue::I64(a)), DataValue::F64(b)) => DataValue::F64(a as f64 + b),
+                    (Some(DataValue::F64(a)), DataValue::I64(b)) => DataValue::F64(a + *b as f64),
+                    (Some(DataValue::F64(a)), DataValue::F64(b)) => DataValue::F64(a + b),
+                    (_, value) => return Err(not_numeric(aggregate, value)),
+                };
+                *current = Some(sum);
+            }
+            Accumulator::Avg { sum, count } => {
+                *sum += match value {
+                    DataValue::I64(v) => *v as f64,
+                    DataValue::F64(v) => *v,
+                    value => return Err(not_numeric(aggregate, value)),
+                };
+                *count += 1;
+            }
+        }
+        Ok(0)
+    }
+
+    /// The aggregate's value, null for `min`, `max`, `sum` and `avg` over no values
+    pub fn finish(self) -> DataValue {
+        match self {
+            Accumulator::Count(count) => DataValue::I64(count as i64),
+            Accumulator::CountDistinct(seen) => DataValue::I64(seen.len() as i64),
+            Accumulator::Min(value) | Accumulator::Max(value) | Accumulator::Sum(value) => {
+                value.unwrap_or(DataValue::Null)
+            }
+            Accumulator::Avg { count: 0, .. } => DataValue::Null,
+            Accumulator::Avg { sum, count } => DataValue::F64(sum / count as f64),
+        }
+    }
+}
+
+fn not_numeric(aggregate: &Aggregate, value: &DataValue) -> error::Error {
+    error::CustomErrors::InvalidArguments(format!(
+        "{} needs numeric values, got {:?}",
+        aggregate, value
+    ))
+    .into()
+}
+
+fn incomparable(aggregate: &Aggregate, a: &DataValue, b: &DataValue) -> error::Error {
+    error::CustomErrors::InvalidArguments(format!(
+        "{} can't compare {:?} with {:?}",
+        aggregate, a, b
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(function: AggregateFn) -> Aggregate {
+        Aggregate {
+            function,
+            column: Some("x".into()),
+        }
+    }
+
+    fn fold(function: AggregateFn, values: &[DataValue]) -> error::Result<DataValue> {
+        let aggregate = aggregate(function);
+        let mut accumulator = Accumulator::new(function);
+        for value in values {
+            accumulator.update(&aggregate, Some(value))?;
+        }
+        Ok(accumulator.finish())
+    }
+
+    #[test]
+    fn accumulators() {
+        let values = [
+            DataValue::I64(3),
+            DataValue::Null,
+            DataValue::F64(1.5),
+            DataValue::I64(3),
+        ];
+        assert_eq!(
+            fold(AggregateFn::Count, &values).unwrap(),
+            DataValue::I64(3)
+        );
+        assert_eq!(
+            fold(AggregateFn::CountDistinct, &values).unwrap(),
+            DataValue::I64(2)
+        );
+        assert_eq!(
+            fold(AggregateFn::Min, &values).unwrap(),
+            DataValue::F64(1.5)
+        );
+        assert_eq!(fold(AggregateFn::Max, &values).unwrap(), DataValue::I64(3));
+        assert_eq!(
+            fold(AggregateFn::Sum, &values).unwrap(),
+            DataValue::F64(7.5)
+        );
+        assert_eq!(
+            fold(AggregateFn::Avg, &values).unwrap(),
+            DataValue::F64(2.5)
+        );
+
+        // Only nulls
+        assert_eq!(
+            fold(AggregateFn::Sum, &[DataValue::Null]).unwrap(),
+            DataValue::Null
+        );
+        assert_eq!(fold(AggregateFn::Avg, &[]).unwrap(), DataValue::Null);
+
+        assert!(fold(
+            AggregateFn::Sum,
+            &[DataValue::I64(i64::MAX), DataValue::I64(1)]
+        )
+        .is_err());
+        assert!(fold(AggregateFn::Avg, &[DataValue::String("a".into())]).is_err());
+        assert!(fold(
+            AggregateFn::Min,
+            &[DataValue::I64(1), DataValue::Bool(true)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn hash_values() {
+        let set: HashSet<_> = [
+            HashValue(DataValue::F64(0.0)),
+            HashValue(DataValue::F64(-0.0)),
+            HashValue(DataValue::F64(f64::NAN)),
+            HashValue(DataValue::F64(-f64::NAN)),
+            HashValue(DataValue::I64(0)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 3);
+    }
+}
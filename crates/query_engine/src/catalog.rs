@@ -0,0 +1,112 @@
+use faiss::DistanceMetric;
+use intake::{Column, ColumnTypes, CreateTable};
+use query_parser::FieldPath;
+
+/// Kind of index backing a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Sorted index over scalar values, supports point and range lookups.
+    Scalar,
+    /// Vector index, supports `topk` and `within` searches.
+    Vector(DistanceMetric),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexInfo {
+    pub column: String,
+    pub kind: IndexKind,
+    /// Estimated number of distinct values in the column, used for selectivity estimates.
+    pub distinct_values: u64,
+}
+
+/// What the planner knows about a table: its columns, indexes and rough statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
+    pub name: String,
+    pub row_count: u64,
+    /// Column types, when known. Parameters compared with a column are checked against them.
+    pub columns: Vec<Column>,
+    pub indexes: Vec<IndexInfo>,
+}
+
+impl TableInfo {
+    pub fn new(name: impl Into<String>, row_count: u64) -> Self {
+        TableInfo {
+            name: name.into(),
+            row_count,
+            columns: Vec::new(),
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Build the table info for a table definition. Statistics start out as an empty table.
+    pub fn from_definition(table: &CreateTable) -> Self {
+        let primary_key = &table.primary_key;
+        let mut info = TableInfo::new(table.name.clone(), 0).with_index(
+            primary_key.name.clone(),
+            index_kind(&primary_key.data_type, None),
+            0,
+        );
+        info.columns = table.columns.clone();
+        for index in &table.indexes {
+            if index.column == primary_key.name {
+                continue;
+            }
+            let Some(column) = table.column(&index.column) else {
+                continue;
+            };
+            info = info.with_index(
+                index.column.clone(),
+                index_kind(&column.data_type, index.metric),
+                0,
+            );
+        }
+        info
+    }
+
+    pub fn with_index(
+        mut self,
+        column: impl Into<String>,
+        kind: IndexKind,
+        distinct_values: u64,
+    ) -> Self {
+        self.indexes.push(IndexInfo {
+            column: column.into(),
+            kind,
+            distinct_values,
+        });
+        self
+    }
+
+    pub fn with_column(mut self, name: impl Into<String>, data_type: ColumnTypes) -> Self {
+        self.columns.push(Column::new(name, data_type));
+        self
+    }
+
+    pub fn column_type(&self, column: &str) -> Option<ColumnTypes> {
+        self.columns
+            .iter()
+            .find(|c| c.name == column)
+            .map(|c| c.data_type)
+    }
+
+    /// The column generated from a json field, if any
+    pub fn generated_column(&self, path: &FieldPath) -> Option<&Column> {
+        self.columns
+            .iter()
+            .find(|c| c.generated.as_ref() == Some(path))
+    }
+
+    pub fn index(&self, column: &str) -> Option<&IndexInfo> {
+        self.indexes.iter().find(|index| index.column == column)
+    }
+}
+
+fn index_kind(data_type: &ColumnTypes, metric: Option<DistanceMetric>) -> IndexKind {
+    match data_type {
+        ColumnTypes::BinaryVector(_) => {
+            IndexKind::Vector(metric.unwrap_or(DistanceMetric::Hamming))
+        }
+        _ => IndexKind::Scalar,
+    }
+}
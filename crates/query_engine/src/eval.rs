@@ -0,0 +1,398 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use intake::DataValue;
+use query_parser::{Expr, OpTree, Value};
+
+use crate::functions;
+
+/// Column values of a single row, keyed by column name.
+pub type RowValues = HashMap<String, DataValue>;
+
+/// Resolve a constant or parameter to the value it stands for.
+pub fn constant(value: &Value, parameters: &[DataValue]) -> error::Result<DataValue> {
+    Ok(match value {
+        Value::Double(d) => DataValue::F64(*d),
+        Value::Integer(i) => DataValue::I64(*i),
+        Value::String(s) => DataValue::String(s.clone()),
+        Value::Bool(b) => DataValue::Bool(*b),
+        Value::DateTime(d) => DataValue::DateTime(*d),
+        Value::UUID(u) => DataValue::UUID(*u),
+        Value::Interval(i) => DataValue::Interval(*i),
+        Value::ResourceTag(tag) => (*tag as usize)
+            .checked_sub(1)
+            .and_then(|i| parameters.get(i))
+            .cloned()
+            .ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!(
+                    "parameter ${} is not bound, {} parameters were given",
+                    tag,
+                    parameters.len()
+                ))
+            })?,
+        Value::Literal(column) => {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "expected a constant, got column {}",
+                column
+            ))
+            .into())
+        }
+        Value::Field(path) => {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "expected a constant, got field {}",
+                path
+            ))
+            .into())
+        }
+        Value::Expr(expr) => return expression(expr, |v| constant(v, parameters)),
+    })
+}
+
+/// Evaluate an expression, resolving its operands with `operand`
+fn expression(
+    expr: &Expr,
+    mut operand: impl FnMut(&Value) -> error::Result<DataValue>,
+) -> error::Result<DataValue> {
+    match expr {
+        Expr::Arithmetic(op, a, b) => Ok(functions::arithmetic(*op, operand(a)?, operand(b)?)),
+        Expr::Call(function, arguments) => {
+            let arguments = arguments
+                .iter()
+                .map(operand)
+                .collect::<error::Result<_>>()?;
+            functions::call(*function, arguments)
+        }
+    }
+}
+
+/// Columns read by a predicate
+pub fn referenced_columns(tree: &OpTree, out: &mut Vec<String>) {
+    fn value_columns(v: &Value, out: &mut Vec<String>) {
+        let column = match v {
+            Value::Literal(column) => column,
+            Value::Field(path) => &path.column,
+            Value::Expr(expr) => {
+                expr.operands()
+                    .into_iter()
+                    .for_each(|v| value_columns(v, out));
+                return;
+            }
+            _ => return,
+        };
+        if !out.contains(column) {
+            out.push(column.clone());
+        }
+    }
+    let mut push = |v: &Value| value_columns(v, out);
+    match tree {
+        OpTree::Eq(a, b)
+        | OpTree::Neq(a, b)
+        | OpTree::Lt(a, b)
+        | OpTree::Lte(a, b)
+        | OpTree::Gt(a, b)
+        | OpTree::Gte(a, b)
+        | OpTree::Within(a, b)
+        | OpTree::TopK(a, b)
+        | OpTree::Like(a, b) => {
+            push(a);
+            push(b);
+        }
+        OpTree::WithinOf(a, b, target) | OpTree::TopKNear(a, b, target) => {
+            push(a);
+            push(b);
+            push(&target.vector);
+        }
+        OpTree::In(a, values) => {
+            push(a);
+            values.iter().for_each(push);
+        }
+        OpTree::Between(a, lower, upper) => {
+            push(a);
+            push(lower);
+            push(upper);
+        }
+        OpTree::IsNull(v) | OpTree::IsNotNull(v) | OpTree::Value(v) => push(v),
+        OpTree::And(a, b) | OpTree::Or(a, b) => {
+            referenced_columns(a, out);
+            referenced_columns(b, out);
+        }
+        OpTree::Not(t) => referenced_columns(t, out),
+        OpTree::Fuse(_, searches) => searches.iter().for_each(|t| referenced_columns(t, out)),
+    }
+}
+
+/// Order two values, numbers of different types compare by value. Values of unrelated types
+/// and nulls are unordered.
+pub fn compare(a: &DataValue, b: &DataValue) -> Option<Ordering> {
+    match (a, b) {
+        (DataValue::I64(a), DataValue::I64(b)) => Some(a.cmp(b)),
+        (DataValue::F64(a), DataValue::F64(b)) => a.partial_cmp(b),
+        (DataValue::I64(a), DataValue::F64(b)) => (*a as f64).partial_cmp(b),
+        (DataValue::F64(a), DataValue::I64(b)) => a.partial_cmp(&(*b as f64)),
+        (DataValue::String(a), DataValue::String(b)) => Some(a.cmp(b)),
+        (DataValue::Bool(a), DataValue::Bool(b)) => Some(a.cmp(b)),
+        (DataValue::DateTime(a), DataValue::DateTime(b)) => Some(a.cmp(b)),
+        (DataValue::UUID(a), DataValue::UUID(b)) => Some(a.cmp(b)),
+        (DataValue::Bytes(a), DataValue::Bytes(b)) => Some(a.cmp(b)),
+        // By length counting a month as 30 days, then by months so only equal intervals tie
+        (DataValue::Interval(a), DataValue::Interval(b)) => Some(
+            a.approximate_micros()
+                .cmp(&b.approximate_micros())
+                .then(a.months.cmp(&b.months)),
+        ),
+        _ => None,
+    }
+}
+
+enum PatternToken {
+    Char(char),
+    /// `_`
+    AnyOne,
+    /// `%`
+    AnyRun,
+}
+
+/// Split a `like` pattern into tokens, a backslash makes the next character match literally
+fn pattern_tokens(pattern: &str) -> Vec<PatternToken> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '\\' => PatternToken::Char(chars.next().unwrap_or('\\')),
+            '_' => PatternToken::AnyOne,
+            '%' => PatternToken::AnyRun,
+            c => PatternToken::Char(c),
+        });
+    }
+    tokens
+}
+
+/// Whether `value` matches a `like` pattern
+pub fn like(value: &str, pattern: &str) -> bool {
+    let tokens = pattern_tokens(pattern);
+    let chars: Vec<char> = value.chars().collect();
+
+    // Match greedily, on a mismatch let the last `%` swallow one more character and retry
+    let (mut t, mut c) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while c < chars.len() {
+        match tokens.get(t) {
+            Some(PatternToken::AnyRun) => {
+                backtrack = Some((t, c));
+                t += 1;
+            }
+            Some(PatternToken::AnyOne) => {
+                t += 1;
+                c += 1;
+            }
+            Some(PatternToken::Char(p)) if *p == chars[c] => {
+                t += 1;
+                c += 1;
+            }
+            _ => match backtrack {
+                Some((run, start)) => {
+                    backtrack = Some((run, start + 1));
+                    t = run + 1;
+                    c = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[t..]
+        .iter()
+        .all(|t| matches!(t, PatternToken::AnyRun))
+}
+
+/// The part of a `like` pattern before its first wildcard, which a sorted index can look up.
+#[derive(Debug, PartialEq)]
+pub enum LikePrefix {
+    /// The pattern has no wildcards
+    Exact(String),
+    /// The pattern is the prefix followed by nothing but `%`
+    Prefix(String),
+    /// Matches start with the prefix, but still need to be checked against the pattern
+    Partial(String),
+}
+
+pub fn like_prefix(pattern: &str) -> LikePrefix {
+    let tokens = pattern_tokens(pattern);
+    let prefix: String = tokens
+        .iter()
+        .map_while(|t| match t {
+            PatternToken::Char(c) => Some(*c),
+            _ => None,
+        })
+        .collect();
+    let rest = &tokens[prefix.chars().count()..];
+    if rest.is_empty() {
+        LikePrefix::Exact(prefix)
+    } else if rest.iter().all(|t| matches!(t, PatternToken::AnyRun)) {
+        LikePrefix::Prefix(prefix)
+    } else {
+        LikePrefix::Partial(prefix)
+    }
+}
+
+/// Evaluates predicates against single rows
+pub struct Evaluator<'a> {
+    parameters: &'a [DataValue],
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(parameters: &'a [DataValue]) -> Self {
+        Evaluator { parameters }
+    }
+
+    fn value(&self, value: &Value, row: &RowValues) -> error::Result<DataValue> {
+        match value {
+            Value::Literal(column) => row.get(column).cloned().ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!("unknown column {}", column)).into()
+            }),
+            Value::Field(path) => match row.get(&path.column) {
+                Some(value) => Ok(value.field(&path.segments)),
+                None => Err(error::CustomErrors::InvalidArguments(format!(
+                    "unknown column {}",
+                    path.column
+                ))
+                .into()),
+            },
+            Value::Expr(expr) => expression(expr, |v| self.value(v, row)),
+            value => constant(value, self.parameters),
+        }
+    }
+
+    fn compare(&self, a: &Value, b: &Value, row: &RowValues) -> error::Result<Option<Ordering>> {
+        Ok(compare(&self.value(a, row)?, &self.value(b, row)?))
+    }
+
+    /// Whether a row matches a predicate. Comparisons involving nulls or values of unrelated
+    /// types are unknown, and rows only match when the predicate is known to be true.
+    pub fn eval(&self, tree: &OpTree, row: &RowValues) -> error::Result<bool> {
+        Ok(self.truth(tree, row)? == Some(true))
+    }
+
+    /// Three valued truth of a predicate, `None` when it is unknown
+    pub fn truth(&self, tree: &OpTree, row: &RowValues) -> error::Result<Option<bool>> {
+        let ordering = |a, b| -> error::Result<Option<Ordering>> { self.compare(a, b, row) };
+        Ok(match tree {
+            OpTree::Eq(a, b) => ordering(a, b)?.map(Ordering::is_eq),
+            OpTree::Neq(a, b) => ordering(a, b)?.map(Ordering::is_ne),
+            OpTree::Lt(a, b) => ordering(a, b)?.map(Ordering::is_lt),
+            OpTree::Lte(a, b) => ordering(a, b)?.map(Ordering::is_le),
+            OpTree::Gt(a, b) => ordering(a, b)?.map(Ordering::is_gt),
+            OpTree::Gte(a, b) => ordering(a, b)?.map(Ordering::is_ge),
+            OpTree::Within(..)
+            | OpTree::TopK(..)
+            | OpTree::WithinOf(..)
+            | OpTree::TopKNear(..)
+            | OpTree::Fuse(..) => {
+                return Err(error::CustomErrors::InvalidState(format!(
+                    "vector predicate {} can't be evaluated row by row",
+                    tree
+                ))
+                .into())
+            }
+            OpTree::In(a, values) => {
+                let a = self.value(a, row)?;
+                let mut truth = Some(false);
+                for v in values {
+                    match compare(&a, &self.value(v, row)?) {
+                        Some(Ordering::Equal) => return Ok(Some(true)),
+                        Some(_) => {}
+                        None => truth = None,
+                    }
+                }
+                truth
+            }
+            OpTree::Between(a, lower, upper) => and(
+                ordering(a, lower)?.map(Ordering::is_ge),
+                ordering(a, upper)?.map(Ordering::is_le),
+            ),
+            OpTree::Like(a, pattern) => match (self.value(a, row)?, self.value(pattern, row)?) {
+                (DataValue::String(a), DataValue::String(pattern)) => Some(like(&a, &pattern)),
+                (_, DataValue::String(_)) => None,
+                (_, pattern) => {
+                    return Err(error::CustomErrors::InvalidArguments(format!(
+                        "like needs a string pattern, got {:?}",
+                        pattern
+                    ))
+                    .into())
+                }
+            },
+            OpTree::IsNull(v) => Some(self.value(v, row)? == DataValue::Null),
+            OpTree::IsNotNull(v) => Some(self.value(v, row)? != DataValue::Null),
+            OpTree::And(a, b) => match self.truth(a, row)? {
+                Some(false) => Some(false),
+                a => and(a, self.truth(b, row)?),
+            },
+            OpTree::Or(a, b) => match self.truth(a, row)? {
+                Some(true) => Some(true),
+                a => or(a, self.truth(b, row)?),
+            },
+            OpTree::Not(t) => self.truth(t, row)?.map(|t| !t),
+            OpTree::Value(v) => match self.value(v, row)? {
+                DataValue::Bool(b) => Some(b),
+                DataValue::Null => None,
+                v => {
+                    return Err(error::CustomErrors::InvalidArguments(format!(
+                        "expected a boolean, got {:?}",
+                        v
+                    ))
+                    .into())
+                }
+            },
+        })
+    }
+}
+
+fn and(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_patterns() {
+        for (value, pattern, expected) in [
+            ("foobar", "foo%", true),
+            ("foo", "foo%", true),
+            ("fo", "foo%", false),
+            ("foobar", "%bar", true),
+            ("foobar", "f_o%r", true),
+            ("foobar", "%o_a%", true),
+            ("foobar", "%x%", false),
+            ("", "%", true),
+            ("", "_", false),
+            ("50%", "50\\%", true),
+            ("500", "50\\%", false),
+            ("a_c", "a\\_c", true),
+            ("abc", "a\\_c", false),
+            ("mississippi", "%iss%ppi", true),
+        ] {
+            assert_eq!(like(value, pattern), expected, "{} like {}", value, pattern);
+        }
+    }
+
+    #[test]
+    fn like_prefixes() {
+        assert_eq!(like_prefix("foo"), LikePrefix::Exact("foo".into()));
+        assert_eq!(like_prefix("foo%%"), LikePrefix::Prefix("foo".into()));
+        assert_eq!(like_prefix("fo\\%o%"), LikePrefix::Prefix("fo%o".into()));
+        assert_eq!(like_prefix("foo_"), LikePrefix::Partial("foo".into()));
+        assert_eq!(like_prefix("%foo"), LikePrefix::Partial("".into()));
+    }
+}
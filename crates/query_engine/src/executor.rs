@@ -0,0 +1,1650 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, BinaryHeap, HashMap},
+    future::Future,
+    ops::Bound,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_stream::try_stream;
+use faiss::DistanceMetricFn;
+use futures_core::Stream;
+use intake::{DataValue, Query};
+use query_parser::{FusionMethod, Value};
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    aggregate::{Accumulator, HashValue},
+    eval::{compare, constant, referenced_columns, Evaluator, RowValues},
+    plan::{
+        Aggregation, GroupOutput, Operator, OrderExpr, OrderKey, Plan, PlanNode, ScanRange,
+        VectorSearch,
+    },
+    source::{Neighbour, RowId, RowIdStream, SearchLimit, TableSource},
+};
+
+/// 256 MB
+const DEFAULT_MEMORY_LIMIT: usize = 256 * 1_000_000;
+/// Bytes charged against the memory limit for every row held in an intermediate result, on
+/// top of the size of any values held with it
+const ROW_ENTRY_BYTES: usize = 32;
+
+/// Column holding the distance from the target vector when a query has vector predicates.
+pub const DISTANCE_COLUMN: &str = "_distance";
+/// Column holding the fused score of rows found by `fuse`, in place of their distance.
+pub const SCORE_COLUMN: &str = "_score";
+
+#[derive(Debug, Clone)]
+pub struct ExecutionOptions {
+    /// Upper bound on the memory used by intermediate results
+    pub memory_limit: usize,
+    /// Stops the query at the next row or operator once cancelled
+    pub cancel: CancellationToken,
+}
+
+impl Default for ExecutionOptions {
+    fn default() -> Self {
+        ExecutionOptions {
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+pub type RowStream<'a> = Pin<Box<dyn Stream<Item = error::Result<Vec<DataValue>>> + Send + 'a>>;
+
+pub struct QueryResults<'a> {
+    /// Names of the values in every row
+    pub columns: Vec<String>,
+    pub rows: RowStream<'a>,
+}
+
+/// Run a plan, streaming back the query's columns for every matching row. Rows found by a
+/// vector search come back nearest first and fused rows highest score first, ties and all
+/// other rows are ordered by row id.
+///
+/// Aggregate queries return the grouped columns followed by each aggregate instead, one row
+/// per group.
+///
+/// Nothing is read until the stream is polled, and dropping the stream stops the query.
+pub fn execute<'a>(
+    source: &'a dyn TableSource,
+    query: &'a Query,
+    plan: &'a Plan,
+    options: ExecutionOptions,
+) -> QueryResults<'a> {
+    execute_with(source, query, &query.parameters, plan, options)
+}
+
+/// Run a plan with `parameters` bound in place of the query's own.
+pub(crate) fn execute_with<'a>(
+    source: &'a dyn TableSource,
+    query: &'a Query,
+    parameters: &'a [DataValue],
+    plan: &'a Plan,
+    options: ExecutionOptions,
+) -> QueryResults<'a> {
+    if let Some(aggregation) = &plan.aggregation {
+        return execute_aggregation(source, parameters, plan, aggregation, options);
+    }
+
+    let projection: Vec<String> = query.columns.iter().map(|c| c.name.clone()).collect();
+    let scored = matches!(plan.root.op, Operator::Fuse { .. });
+    let with_distance = has_vector_search(&plan.root)
+        || plan
+            .order_by
+            .iter()
+            .any(|key| matches!(key.expr, OrderExpr::Distance { .. }));
+
+    let mut columns = projection.clone();
+    if scored {
+        columns.push(SCORE_COLUMN.to_string());
+    } else if with_distance {
+        columns.push(DISTANCE_COLUMN.to_string());
+    }
+
+    let rows = try_stream! {
+        let memory = MemoryBudget::new(options.memory_limit);
+        let execution = Execution {
+            source,
+            parameters,
+            memory: &memory,
+            cancel: &options.cancel,
+        };
+
+        // The result keeps its rows reserved while they're sorted and fetched
+        let mut result = execution.run(&plan.root).await?;
+        let mut rows: Vec<_> = std::mem::take(&mut result.rows).into_iter().collect();
+        let rows = if plan.order_by.is_empty() {
+            if scored {
+                sort_by_score(&mut rows);
+            } else if with_distance {
+                sort_by_distance(&mut rows);
+            }
+            let limit = plan.limit.map_or(usize::MAX, |limit| limit as usize);
+            rows.into_iter().skip(plan.offset as usize).take(limit).collect()
+        } else {
+            execution.top_n(rows, plan).await?
+        };
+
+        for (row, distance) in rows {
+            execution.check_cancelled()?;
+            let mut values = source.fetch(row, &projection).await?;
+            if with_distance {
+                values.push(distance.map(DataValue::F64).unwrap_or(DataValue::Null));
+            }
+            yield values;
+        }
+        drop(result);
+    };
+
+    QueryResults {
+        columns,
+        rows: Box::pin(rows),
+    }
+}
+
+fn execute_aggregation<'a>(
+    source: &'a dyn TableSource,
+    parameters: &'a [DataValue],
+    plan: &'a Plan,
+    aggregation: &'a Aggregation,
+    options: ExecutionOptions,
+) -> QueryResults<'a> {
+    let columns = aggregation
+        .output
+        .iter()
+        .map(|output| match *output {
+            GroupOutput::Group(i) => aggregation.group_by[i].clone(),
+            GroupOutput::Aggregate(i) => aggregation.aggregates[i].to_string(),
+        })
+        .collect();
+
+    let rows = try_stream! {
+        let memory = MemoryBudget::new(options.memory_limit);
+        let execution = Execution {
+            source,
+            parameters,
+            memory: &memory,
+            cancel: &options.cancel,
+        };
+
+        let mut result = execution.run(&plan.root).await?;
+        let rows: Vec<RowId> = std::mem::take(&mut result.rows).into_keys().collect();
+        let groups = execution.aggregate(rows, aggregation, plan).await?;
+        drop(result);
+
+        // Groups come back with their grouped columns then their aggregates
+        let group_width = aggregation.group_by.len();
+        for values in groups {
+            execution.check_cancelled()?;
+            yield aggregation
+                .output
+                .iter()
+                .map(|output| match *output {
+                    GroupOutput::Group(i) => values[i].clone(),
+                    GroupOutput::Aggregate(i) => values[group_width + i].clone(),
+                })
+                .collect();
+        }
+    };
+
+    QueryResults {
+        columns,
+        rows: Box::pin(rows),
+    }
+}
+
+fn has_vector_search(node: &PlanNode) -> bool {
+    matches!(node.op, Operator::VectorSearch { .. })
+        || node.children().into_iter().any(has_vector_search)
+}
+
+/// Nearest first, rows without a distance last, ties broken by row id
+fn sort_by_distance(rows: &mut [(RowId, Option<f64>)]) {
+    rows.sort_by(|(a_row, a), (b_row, b)| {
+        let by_distance = match (a, b) {
+            (Some(a), Some(b)) => a.total_cmp(b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        by_distance.then(a_row.cmp(b_row))
+    });
+}
+
+/// Highest score first, ties broken by row id
+fn sort_by_score(rows: &mut [(RowId, Option<f64>)]) {
+    rows.sort_by(|(a_row, a), (b_row, b)| {
+        let (a, b) = (
+            a.unwrap_or(f64::NEG_INFINITY),
+            b.unwrap_or(f64::NEG_INFINITY),
+        );
+        b.total_cmp(&a).then(a_row.cmp(b_row))
+    });
+}
+
+/// Combine rankings, each ordered nearest first, into a score per row
+fn fuse(method: &FusionMethod, rankings: &[Vec<(RowId, Option<f64>)>]) -> BTreeMap<RowId, f64> {
+    let mut scores = BTreeMap::new();
+    for (i, ranking) in rankings.iter().enumerate() {
+        match method {
+            FusionMethod::Rrf(k) => {
+                for (rank, (row, _)) in ranking.iter().enumerate() {
+                    *scores.entry(*row).or_insert(0.0) += 1.0 / (*k as f64 + rank as f64 + 1.0);
+                }
+            }
+            FusionMethod::Weighted(weights) => {
+                // The nearest row of a search has similarity 1 and the furthest 0
+                let (min, max) = ranking
+                    .iter()
+                    .filter_map(|(_, distance)| *distance)
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), d| {
+                        (min.min(d), max.max(d))
+                    });
+                for (row, distance) in ranking {
+                    let similarity = match distance {
+                        Some(d) if max > min => (max - d) / (max - min),
+                        _ => 1.0,
+                    };
+                    *scores.entry(*row).or_insert(0.0) += weights[i] * similarity;
+                }
+            }
+        }
+    }
+    scores
+}
+
+/// Where a row's value for a sort key comes from
+enum SortSource {
+    /// Index into the fetched columns
+    Column(usize),
+    /// Distance between the vector in the fetched column and a target
+    Distance(usize, Vec<u8>, DistanceMetricFn<u8>),
+}
+
+/// Row in a top-N heap, ordered by its sort key values and then by row id
+struct Ranked<'k> {
+    values: Vec<DataValue>,
+    row: RowId,
+    distance: Option<f64>,
+    order: &'k [OrderKey],
+}
+
+/// Total order for sorting, nulls sort after everything else
+fn sort_order(a: &DataValue, b: &DataValue) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    match (a, b) {
+        (DataValue::Null, DataValue::Null) => Ordering::Equal,
+        (DataValue::Null, _) => Ordering::Greater,
+        (_, DataValue::Null) => Ordering::Less,
+        (a, b) => compare(a, b).unwrap_or(Ordering::Equal),
+    }
+}
+
+impl<'k> Ord for Ranked<'k> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        for ((a, b), key) in self.values.iter().zip(&other.values).zip(self.order) {
+            let ordering = sort_order(a, b);
+            let ordering = if key.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        self.row.cmp(&other.row)
+    }
+}
+
+impl<'k> PartialOrd for Ranked<'k> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'k> PartialEq for Ranked<'k> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<'k> Eq for Ranked<'k> {}
+
+fn new_accumulators(aggregation: &Aggregation) -> Vec<Accumulator> {
+    aggregation
+        .aggregates
+        .iter()
+        .map(|aggregate| Accumulator::new(aggregate.function))
+        .collect()
+}
+
+/// Bytes the values take up, charged against the memory limit while they're held
+fn values_bytes<'v>(values: impl IntoIterator<Item = &'v DataValue>) -> usize {
+    values.into_iter().map(DataValue::size_hint).sum()
+}
+
+struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn reserve(&self, bytes: usize) -> error::Result<()> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.limit {
+            self.release(bytes);
+            return Err(error::CustomErrors::ResourceExhausted(format!(
+                "query needs more than its {} byte memory limit",
+                self.limit
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Intermediate result: the matching rows and, for rows found by a vector search, their
+/// distance from the target. Rows are charged against the memory limit as they're added, and
+/// stay charged until the set is dropped even once they're taken out of it.
+struct RowSet<'m> {
+    rows: BTreeMap<RowId, Option<f64>>,
+    reserved: usize,
+    memory: &'m MemoryBudget,
+}
+
+impl<'m> RowSet<'m> {
+    fn new(memory: &'m MemoryBudget) -> Self {
+        RowSet {
+            rows: BTreeMap::new(),
+            reserved: 0,
+            memory,
+        }
+    }
+
+    /// Add a row, or replace the distance of a row already in the set
+    fn insert(&mut self, row: RowId, distance: Option<f64>) -> error::Result<()> {
+        if let Some(current) = self.rows.get_mut(&row) {
+            *current = distance;
+            return Ok(());
+        }
+        self.memory.reserve(ROW_ENTRY_BYTES)?;
+        self.reserved += ROW_ENTRY_BYTES;
+        self.rows.insert(row, distance);
+        Ok(())
+    }
+
+    /// Move the row ids out of the set, which keeps them charged
+    fn take_ids(&mut self) -> BTreeSet<RowId> {
+        std::mem::take(&mut self.rows).into_keys().collect()
+    }
+}
+
+impl<'m> Drop for RowSet<'m> {
+    fn drop(&mut self) {
+        self.memory.release(self.reserved);
+    }
+}
+
+struct Execution<'a> {
+    source: &'a dyn TableSource,
+    parameters: &'a [DataValue],
+    memory: &'a MemoryBudget,
+    cancel: &'a CancellationToken,
+}
+
+type RunFuture<'s> = Pin<Box<dyn Future<Output = error::Result<RowSet<'s>>> + Send + 's>>;
+
+impl<'a> Execution<'a> {
+    fn check_cancelled(&self) -> error::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(error::CustomErrors::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    fn rows(
+        &self,
+        rows: impl IntoIterator<Item = (RowId, Option<f64>)>,
+    ) -> error::Result<RowSet<'a>> {
+        let mut set = RowSet::new(self.memory);
+        for (row, distance) in rows {
+            set.insert(row, distance)?;
+        }
+        Ok(set)
+    }
+
+    /// Row set of the streamed ids that aren't in `except`, charging them as they arrive
+    async fn scanned(
+        &self,
+        mut ids: RowIdStream<'_>,
+        except: Option<&RowSet<'_>>,
+    ) -> error::Result<RowSet<'a>> {
+        let mut set = RowSet::new(self.memory);
+        while let Some(batch) = ids.next().await {
+            self.check_cancelled()?;
+            for row in batch? {
+                if except.is_none_or(|except| !except.rows.contains_key(&row)) {
+                    set.insert(row, None)?;
+                }
+            }
+        }
+        Ok(set)
+    }
+
+    fn bound(&self, bound: &Bound<Value>) -> error::Result<Bound<DataValue>> {
+        Ok(match bound {
+            Bound::Included(v) => Bound::Included(constant(v, self.parameters)?),
+            Bound::Excluded(v) => Bound::Excluded(constant(v, self.parameters)?),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    }
+
+    fn target(&self, target: &Value) -> error::Result<Vec<u8>> {
+        match constant(target, self.parameters)? {
+            DataValue::Bytes(target) => Ok(target),
+            v => Err(error::CustomErrors::InvalidArguments(format!(
+                "vector searches need a binary target, got {:?}",
+                v
+            ))
+            .into()),
+        }
+    }
+
+    /// Order rows by the plan's sort keys, only keeping the rows within its limit and offset.
+    async fn top_n(
+        &self,
+        rows: Vec<(RowId, Option<f64>)>,
+        plan: &Plan,
+    ) -> error::Result<Vec<(RowId, Option<f64>)>> {
+        let offset = plan.offset as usize;
+        let keep = plan
+            .limit
+            .map_or(rows.len(), |limit| offset.saturating_add(limit as usize))
+            .min(rows.len());
+
+        let mut columns: Vec<String> = Vec::new();
+        let mut column = |name: &String| match columns.iter().position(|c| c == name) {
+            Some(i) => i,
+            None => {
+                columns.push(name.clone());
+                columns.len() - 1
+            }
+        };
+        let mut sources = Vec::with_capacity(plan.order_by.len());
+        for key in &plan.order_by {
+            sources.push(match &key.expr {
+                OrderExpr::Column(name) => SortSource::Column(column(name)),
+                OrderExpr::Distance {
+                    column: name,
+                    target,
+                    metric,
+                } => {
+                    let target = self.target(target)?;
+                    let distance = metric.into_fn::<u8>(target.len());
+                    SortSource::Distance(column(name), target, distance)
+                }
+            });
+        }
+
+        let charge = |ranked: &Ranked| ROW_ENTRY_BYTES + values_bytes(&ranked.values);
+        let mut reserved = 0;
+        // Max heap holding the best `keep` rows seen so far, the worst of them on top
+        let mut heap = BinaryHeap::with_capacity(keep + 1);
+        let result = async {
+            for (row, distance) in rows {
+                self.check_cancelled()?;
+                let fetched = self.source.fetch(row, &columns).await?;
+                let values: Vec<DataValue> = sources
+                    .iter()
+                    .map(|source| match source {
+                        SortSource::Column(i) => fetched[*i].clone(),
+                        SortSource::Distance(i, target, distance) => match &fetched[*i] {
+                            DataValue::Bytes(v) if v.len() == target.len() => {
+                                DataValue::F64(distance(v, target) as f64)
+                            }
+                            _ => DataValue::Null,
+                        },
+                    })
+                    .collect();
+                let distance = distance.or_else(|| {
+                    sources
+                        .iter()
+                        .zip(&values)
+                        .find_map(|(source, value)| match (source, value) {
+                            (SortSource::Distance(..), DataValue::F64(d)) => Some(*d),
+                            _ => None,
+                        })
+                });
+
+                let ranked = Ranked {
+                    values,
+                    row,
+                    distance,
+                    order: &plan.order_by,
+                };
+                let bytes = charge(&ranked);
+                self.memory.reserve(bytes)?;
+                reserved += bytes;
+                heap.push(ranked);
+                if heap.len() > keep {
+                    let dropped = charge(&heap.pop().unwrap());
+                    self.memory.release(dropped);
+                    reserved -= dropped;
+                }
+            }
+            Ok::<_, error::Error>(())
+        }
+        .await;
+        self.memory.release(reserved);
+        result?;
+
+        Ok(heap
+            .into_sorted_vec()
+            .into_iter()
+            .skip(offset)
+            .map(|ranked| (ranked.row, ranked.distance))
+            .collect())
+    }
+
+    /// Group rows by the aggregation's columns and compute its aggregates for every group.
+    /// Groups come back in the plan's order, ties broken by the grouped values.
+    async fn aggregate(
+        &self,
+        rows: Vec<RowId>,
+        aggregation: &Aggregation,
+        plan: &Plan,
+    ) -> error::Result<Vec<Vec<DataValue>>> {
+        let mut columns = aggregation.group_by.clone();
+        let mut inputs = Vec::with_capacity(aggregation.aggregates.len());
+        for aggregate in &aggregation.aggregates {
+            inputs.push(aggregate.column.as_ref().map(|column| {
+                columns.iter().position(|c| c == column).unwrap_or_else(|| {
+                    columns.push(column.clone());
+                    columns.len() - 1
+                })
+            }));
+        }
+        let group_width = aggregation.group_by.len();
+        let accumulator_bytes = (inputs.len() + 1) * ROW_ENTRY_BYTES;
+
+        let mut reserved = 0;
+        let mut groups: HashMap<Vec<HashValue>, Vec<Accumulator>> = HashMap::new();
+        if aggregation.group_by.is_empty() {
+            // Aggregates without groups always return a row, even when nothing matched
+            groups.insert(Vec::new(), new_accumulators(aggregation));
+        }
+        let result = async {
+            for row in rows {
+                self.check_cancelled()?;
+                let values = self.source.fetch(row, &columns).await?;
+                // Aggregates can read grouped columns, so the group key is a copy
+                let group: Vec<HashValue> = values[..group_width]
+                    .iter()
+                    .cloned()
+                    .map(HashValue)
+                    .collect();
+                let accumulators = match groups.entry(group) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let group_bytes = accumulator_bytes + values_bytes(&values[..group_width]);
+                        self.memory.reserve(group_bytes)?;
+                        reserved += group_bytes;
+                        entry.insert(new_accumulators(aggregation))
+                    }
+                };
+                for ((accumulator, aggregate), input) in accumulators
+                    .iter_mut()
+                    .zip(&aggregation.aggregates)
+                    .zip(&inputs)
+                {
+                    let value = input.map(|i| &values[i]);
+                    let added = accumulator.update(aggregate, value)?;
+                    self.memory.reserve(added)?;
+                    reserved += added;
+                }
+            }
+            Ok::<_, error::Error>(())
+        }
+        .await;
+        let mut groups: Vec<Vec<DataValue>> = groups
+            .into_iter()
+            .map(|(group, accumulators)| {
+                group
+                    .into_iter()
+                    .map(|v| v.0)
+                    .chain(accumulators.into_iter().map(Accumulator::finish))
+                    .collect()
+            })
+            .collect();
+        self.memory.release(reserved);
+        result?;
+
+        let keys: Vec<(usize, bool)> = plan
+            .order_by
+            .iter()
+            .filter_map(|key| match &key.expr {
+                OrderExpr::Column(column) => aggregation
+                    .group_by
+                    .iter()
+                    .position(|c| c == column)
+                    .map(|i| (i, key.descending)),
+                OrderExpr::Distance { .. } => None,
+            })
+            .chain((0..group_width).map(|i| (i, false)))
+            .collect();
+        groups.sort_by(|a, b| {
+            keys.iter()
+                .map(|&(i, descending)| {
+                    let ordering = sort_order(&a[i], &b[i]);
+                    if descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let limit = plan.limit.map_or(usize::MAX, |limit| limit as usize);
+        Ok(groups
+            .into_iter()
+            .skip(plan.offset as usize)
+            .take(limit)
+            .collect())
+    }
+
+    fn limit(&self, search: &VectorSearch) -> error::Result<SearchLimit> {
+        let (value, is_topk) = match search {
+            VectorSearch::TopK(k) => (constant(k, self.parameters)?, true),
+            VectorSearch::Within(radius) => (constant(radius, self.parameters)?, false),
+        };
+        match (value, is_topk) {
+            (DataValue::I64(k), true) if k >= 0 => Ok(SearchLimit::TopK(k as usize)),
+            (DataValue::I64(r), false) => Ok(SearchLimit::Within(r as f64)),
+            (DataValue::F64(r), false) => Ok(SearchLimit::Within(r)),
+            (v, true) => Err(error::CustomErrors::InvalidArguments(format!(
+                "topk needs a non-negative integer, got {:?}",
+                v
+            ))
+            .into()),
+            (v, false) => Err(error::CustomErrors::InvalidArguments(format!(
+                "within needs a number, got {:?}",
+                v
+            ))
+            .into()),
+        }
+    }
+
+    fn run<'s>(&'s self, node: &'s PlanNode) -> RunFuture<'s>
+    where
+        'a: 's,
+    {
+        Box::pin(async move {
+            self.check_cancelled()?;
+            match &node.op {
+                Operator::Empty => Ok(RowSet::new(self.memory)),
+                Operator::FullScan => self.scanned(self.source.row_ids(), None).await,
+                Operator::IndexScan { column, range } => {
+                    let range = match range {
+                        ScanRange::Eq(v) => {
+                            let v = constant(v, self.parameters)?;
+                            (Bound::Included(v.clone()), Bound::Included(v))
+                        }
+                        ScanRange::Range { lower, upper } => {
+                            (self.bound(lower)?, self.bound(upper)?)
+                        }
+                    };
+                    self.scanned(self.source.index_scan(column, &range), None)
+                        .await
+                }
+                Operator::VectorSearch {
+                    column,
+                    search,
+                    target,
+                    metric,
+                    prefilter,
+                } => {
+                    let target = self.target(target)?;
+                    let limit = self.limit(search)?;
+                    // The prefiltered set stays alive to keep its ids charged
+                    let mut prefiltered = match prefilter {
+                        Some(prefilter) => Some(self.run(prefilter).await?),
+                        None => None,
+                    };
+                    let candidates = prefiltered.as_mut().map(RowSet::take_ids);
+
+                    let mut hits = self
+                        .source
+                        .vector_search(column, &target, *metric, limit, candidates.as_ref())
+                        .await?;
+                    // Don't rely on the source for a deterministic order among equal distances
+                    hits.sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.row.cmp(&b.row)));
+                    if let SearchLimit::TopK(k) = limit {
+                        hits.truncate(k);
+                    }
+                    drop((candidates, prefiltered));
+                    self.rows(
+                        hits.into_iter()
+                            .map(|Neighbour { row, distance }| (row, Some(distance))),
+                    )
+                }
+                Operator::PostFilter {
+                    input,
+                    filter,
+                    limit,
+                } => {
+                    let limit = match constant(limit, self.parameters)? {
+                        DataValue::I64(k) if k >= 0 => k as usize,
+                        v => {
+                            return Err(error::CustomErrors::InvalidArguments(format!(
+                                "topk needs a non-negative integer, got {:?}",
+                                v
+                            ))
+                            .into())
+                        }
+                    };
+                    let mut input = self.run(input).await?;
+                    let filter = self.run(filter).await?;
+
+                    let mut rows: Vec<_> = std::mem::take(&mut input.rows)
+                        .into_iter()
+                        .filter(|(row, _)| filter.rows.contains_key(row))
+                        .collect();
+                    sort_by_distance(&mut rows);
+                    rows.truncate(limit);
+                    drop(filter);
+                    let rows = self.rows(rows);
+                    drop(input);
+                    rows
+                }
+                Operator::Filter { input, predicate } => {
+                    let mut input = self.run(input).await?;
+                    let mut columns = Vec::new();
+                    referenced_columns(predicate, &mut columns);
+                    let evaluator = Evaluator::new(self.parameters);
+
+                    let mut rows = RowSet::new(self.memory);
+                    for (row, distance) in std::mem::take(&mut input.rows) {
+                        self.check_cancelled()?;
+                        let values = self.source.fetch(row, &columns).await?;
+                        let values: RowValues = columns.iter().cloned().zip(values).collect();
+                        if evaluator.eval(predicate, &values)? {
+                            rows.insert(row, distance)?;
+                        }
+                    }
+                    Ok(rows)
+                }
+                Operator::Intersect(nodes) => {
+                    let mut sets = Vec::with_capacity(nodes.len());
+                    for node in nodes {
+                        sets.push(self.run(node).await?);
+                    }
+                    // Walk the smallest set, probing the others
+                    sets.sort_by_key(|s| s.rows.len());
+                    let mut rows = RowSet::new(self.memory);
+                    for (row, distance) in &sets[0].rows {
+                        let mut distance = *distance;
+                        let mut in_all = true;
+                        for other in &sets[1..] {
+                            match other.rows.get(row) {
+                                Some(d) => distance = distance.or(*d),
+                                None => {
+                                    in_all = false;
+                                    break;
+                                }
+                            }
+                        }
+                        if in_all {
+                            rows.insert(*row, distance)?;
+                        }
+                    }
+                    Ok(rows)
+                }
+                Operator::Union(nodes) => {
+                    let mut rows = RowSet::new(self.memory);
+                    for node in nodes {
+                        let mut set = self.run(node).await?;
+                        for (row, distance) in std::mem::take(&mut set.rows) {
+                            let distance = match (rows.rows.get(&row), distance) {
+                                (None, distance) => distance,
+                                (Some(Some(current)), Some(distance)) => {
+                                    Some(current.min(distance))
+                                }
+                                (Some(current), distance) => current.or(distance),
+                            };
+                            rows.insert(row, distance)?;
+                        }
+                    }
+                    Ok(rows)
+                }
+                Operator::Fuse { method, inputs } => {
+                    let mut sets = Vec::with_capacity(inputs.len());
+                    let mut rankings = Vec::with_capacity(inputs.len());
+                    for input in inputs {
+                        let mut set = self.run(input).await?;
+                        let mut ranking: Vec<_> =
+                            std::mem::take(&mut set.rows).into_iter().collect();
+                        sort_by_distance(&mut ranking);
+                        rankings.push(ranking);
+                        // Keep the rankings' memory reserved until they are fused
+                        sets.push(set);
+                    }
+                    let scores = fuse(method, &rankings);
+                    drop(rankings);
+                    let rows = self.rows(scores.into_iter().map(|(row, score)| (row, Some(score))));
+                    drop(sets);
+                    rows
+                }
+                Operator::Complement(input) => {
+                    let input = self.run(input).await?;
+                    self.scanned(self.source.row_ids(), Some(&input)).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use faiss::DistanceMetric;
+    use intake::{Column, ColumnTypes};
+    use query_parser::query::QueryParser;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        catalog::{IndexKind, TableInfo},
+        planner::plan,
+        source::{KeyRange, MemoryTable},
+    };
+
+    fn table() -> (MemoryTable, TableInfo) {
+        let mut table = MemoryTable::new(vec!["id".into(), "source".into(), "vector".into()]);
+        for (id, source, vector) in [
+            (0, "a", 0b0000_0000u8),
+            (1, "b", 0b0000_0001),
+            (2, "a", 0b0000_0011),
+            (3, "b", 0b0000_0010),
+            (4, "a", 0b0000_0111),
+            (5, "c", 0b1111_1111),
+        ] {
+            table
+                .insert(vec![
+                    DataValue::I64(id),
+                    DataValue::String(source.into()),
+                    DataValue::Bytes(vec![vector]),
+                ])
+                .unwrap();
+        }
+        let info = TableInfo::new("docs", 6)
+            .with_column("id", ColumnTypes::I64)
+            .with_column("source", ColumnTypes::String)
+            .with_column("vector", ColumnTypes::BinaryVector(8))
+            .with_index("id", IndexKind::Scalar, 6)
+            .with_index("source", IndexKind::Scalar, 3)
+            .with_index("vector", IndexKind::Vector(DistanceMetric::Hamming), 6);
+        (table, info)
+    }
+
+    fn query(scope: &str, columns: &[&str], parameters: Vec<DataValue>) -> Query {
+        Query {
+            table: "docs".into(),
+            partitions: vec![],
+            query: QueryParser::new().parse(scope).unwrap(),
+            parameters,
+            columns: columns
+                .iter()
+                .map(|name| Column::new(*name, ColumnTypes::I64))
+                .collect(),
+            select: vec![],
+        }
+    }
+
+    async fn run(
+        scope: &str,
+        parameters: Vec<DataValue>,
+        options: ExecutionOptions,
+    ) -> (Vec<String>, error::Result<Vec<Vec<DataValue>>>) {
+        let (table, info) = table();
+        let query = query(scope, &["id"], parameters);
+        let plan = plan(&query, &info).unwrap();
+        let results = execute(&table, &query, &plan, options);
+        let rows = results.rows.collect::<error::Result<Vec<_>>>().await;
+        (results.columns, rows)
+    }
+
+    #[tokio::test]
+    async fn fusion() {
+        let parameters = vec![
+            DataValue::Bytes(vec![0b0000_0001]),
+            DataValue::Bytes(vec![0b0000_0111]),
+        ];
+        let ids = |rows: error::Result<Vec<Vec<DataValue>>>| -> Vec<DataValue> {
+            rows.unwrap()
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect()
+        };
+
+        // Row 1 ranks first and third, row 2 third and second
+        let (columns, rows) = run(
+            "fuse(rrf, vector topk 3 near $1, vector topk 3 near $2) limit 2",
+            parameters.clone(),
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(columns, vec!["id", SCORE_COLUMN]);
+        let rows = rows.unwrap();
+        assert_eq!(
+            rows[0],
+            vec![DataValue::I64(1), DataValue::F64(1.0 / 61.0 + 1.0 / 63.0)]
+        );
+        assert_eq!(ids(Ok(rows)), vec![DataValue::I64(1), DataValue::I64(2)]);
+
+        let (_, rows) = run(
+            "fuse(weighted(1, 0.5), vector topk 3 near $1, vector topk 3 near $2)",
+            parameters.clone(),
+            ExecutionOptions::default(),
+        )
+        .await;
+        let scores: Vec<_> = rows
+            .unwrap()
+            .into_iter()
+            .map(|row| row[1].clone())
+            .collect();
+        assert_eq!(scores, [1.0, 0.5, 0.25, 0.0].map(DataValue::F64).to_vec(),);
+
+        // Each search only considers the rows the filter lets through
+        let (_, rows) = run(
+            "(fuse(rrf, vector topk 3 near $1, vector topk 3 near $2)) && (source == \"a\")",
+            parameters,
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            ids(rows),
+            vec![DataValue::I64(0), DataValue::I64(4), DataValue::I64(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn scalar_predicates() {
+        let (columns, rows) = run(
+            "(source == \"a\") && (id > 0)",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(columns, vec!["id"]);
+        assert_eq!(
+            rows.unwrap(),
+            vec![vec![DataValue::I64(2)], vec![DataValue::I64(4)]]
+        );
+
+        let (_, rows) = run(
+            "!((source == \"a\") || (other == 1))",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        // `other` isn't a column, the filter fails as soon as it reads a row
+        assert!(rows.is_err());
+
+        let (_, rows) = run(
+            "source != $1",
+            vec![DataValue::String("a".into())],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(1)],
+                vec![DataValue::I64(3)],
+                vec![DataValue::I64(5)]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn topk_is_ordered_by_distance() {
+        let target = DataValue::Bytes(vec![0b0000_0001]);
+        let (columns, rows) = run("vector topk 3", vec![target], ExecutionOptions::default()).await;
+        assert_eq!(columns, vec!["id", DISTANCE_COLUMN]);
+        // Rows 0 and 2 are both one bit away, the lower id comes first
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(1), DataValue::F64(0.0)],
+                vec![DataValue::I64(0), DataValue::F64(1.0)],
+                vec![DataValue::I64(2), DataValue::F64(1.0)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn vector_targets() {
+        let parameters = vec![DataValue::I64(2), DataValue::Bytes(vec![0b1111_1111])];
+        let (_, rows) = run(
+            "vector topk $1 near $2 using hamming",
+            parameters.clone(),
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(5), DataValue::F64(0.0)],
+                vec![DataValue::I64(4), DataValue::F64(5.0)],
+            ]
+        );
+
+        let (_, rows) = run(
+            "vector within 6 of $2",
+            parameters,
+            ExecutionOptions::default(),
+        )
+        .await;
+        let ids: Vec<_> = rows
+            .unwrap()
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect();
+        assert_eq!(
+            ids,
+            vec![DataValue::I64(5), DataValue::I64(4), DataValue::I64(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn filtered_vector_search() {
+        let target = DataValue::Bytes(vec![0b0000_0001]);
+
+        // `source == "a"` is too unselective to prefilter, so this runs as a post filter
+        let (_, rows) = run(
+            "(vector topk 2) && (source == \"a\")",
+            vec![target.clone()],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(0), DataValue::F64(1.0)],
+                vec![DataValue::I64(2), DataValue::F64(1.0)],
+            ]
+        );
+
+        // Post filtering over-fetches enough neighbours to find the single matching row
+        let (_, rows) = run(
+            "(vector topk 2) && (id == 5)",
+            vec![target.clone()],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![vec![DataValue::I64(5), DataValue::F64(7.0)]]
+        );
+
+        let (_, rows) = run(
+            "(vector within 1) || (id == 5)",
+            vec![target],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(1), DataValue::F64(0.0)],
+                vec![DataValue::I64(0), DataValue::F64(1.0)],
+                vec![DataValue::I64(2), DataValue::F64(1.0)],
+                vec![DataValue::I64(5), DataValue::Null],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancellation() {
+        let options = ExecutionOptions::default();
+        options.cancel.cancel();
+        let (_, rows) = run("id > 0", vec![], options).await;
+        let err = rows.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::Cancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let options = ExecutionOptions {
+            memory_limit: 3 * ROW_ENTRY_BYTES,
+            ..Default::default()
+        };
+        let (_, rows) = run("id < 3", vec![], options.clone()).await;
+        assert_eq!(rows.unwrap().len(), 3);
+
+        let (_, rows) = run("id < 4", vec![], options).await;
+        let err = rows.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::ResourceExhausted(_))
+        ));
+    }
+
+    /// Counts the batches of ids scans pull from the table
+    struct CountingSource {
+        table: MemoryTable,
+        batches: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl TableSource for CountingSource {
+        fn row_ids(&self) -> RowIdStream<'_> {
+            Box::pin(self.table.row_ids().map(|batch| {
+                self.batches.fetch_add(1, Ordering::Relaxed);
+                batch
+            }))
+        }
+
+        fn index_scan<'a>(&'a self, column: &'a str, range: &'a KeyRange) -> RowIdStream<'a> {
+            self.table.index_scan(column, range)
+        }
+
+        async fn vector_search(
+            &self,
+            column: &str,
+            target: &[u8],
+            metric: DistanceMetric,
+            limit: SearchLimit,
+            candidates: Option<&BTreeSet<RowId>>,
+        ) -> error::Result<Vec<Neighbour>> {
+            self.table
+                .vector_search(column, target, metric, limit, candidates)
+                .await
+        }
+
+        async fn fetch(&self, row: RowId, columns: &[String]) -> error::Result<Vec<DataValue>> {
+            self.table.fetch(row, columns).await
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_limit_stops_scans_early() {
+        let mut table = MemoryTable::new(vec!["id".into()]);
+        for id in 0..10_000 {
+            table.insert(vec![DataValue::I64(id)]).unwrap();
+        }
+        let source = CountingSource {
+            table,
+            batches: AtomicUsize::new(0),
+        };
+        let query = query("id != 5", &["id"], vec![]);
+        let plan = plan(&query, &TableInfo::new("docs", 10_000)).unwrap();
+        assert!(plan.root.is_full_scan());
+        let options = ExecutionOptions {
+            memory_limit: 100 * ROW_ENTRY_BYTES,
+            ..Default::default()
+        };
+        let results = execute(&source, &query, &plan, options);
+        let err = results
+            .rows
+            .collect::<error::Result<Vec<_>>>()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::ResourceExhausted(_))
+        ));
+        assert_eq!(source.batches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn unbound_parameters() {
+        let (_, rows) = run(
+            "id == $2",
+            vec![DataValue::I64(1)],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(rows.is_err());
+    }
+
+    #[tokio::test]
+    async fn order_limit_offset() {
+        let ids = |rows: Vec<Vec<DataValue>>| -> Vec<DataValue> {
+            rows.into_iter().map(|row| row[0].clone()).collect()
+        };
+
+        let (_, rows) = run(
+            "id >= 0 order by id desc limit 2 offset 1",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            ids(rows.unwrap()),
+            vec![DataValue::I64(4), DataValue::I64(3)]
+        );
+
+        let (_, rows) = run(
+            "id >= 0 order by source desc, id limit 3",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            ids(rows.unwrap()),
+            vec![DataValue::I64(5), DataValue::I64(1), DataValue::I64(3)]
+        );
+
+        // Paging without an order keeps the default row order
+        let (_, rows) = run(
+            "id >= 0 limit 2 offset 3",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            ids(rows.unwrap()),
+            vec![DataValue::I64(3), DataValue::I64(4)]
+        );
+    }
+
+    #[tokio::test]
+    async fn order_by_distance() {
+        let target = DataValue::Bytes(vec![0b0000_0011]);
+        let (columns, rows) = run(
+            "source == \"a\" order by distance(vector, $1) desc limit 2",
+            vec![target],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(columns, vec!["id", DISTANCE_COLUMN]);
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![DataValue::I64(0), DataValue::F64(2.0)],
+                vec![DataValue::I64(4), DataValue::F64(1.0)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aggregates() {
+        let string = |s: &str| DataValue::String(s.into());
+        let target = DataValue::Bytes(vec![0b0000_0001]);
+        let (columns, rows) = run(
+            "count(*) group by source where vector within 1",
+            vec![target],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(columns, vec!["source", "count(*)"]);
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![string("a"), DataValue::I64(2)],
+                vec![string("b"), DataValue::I64(1)],
+            ]
+        );
+
+        let (columns, rows) = run(
+            "count(*), count(distinct source), min(id), max(id), sum(id), avg(id)",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(columns.len(), 6);
+        assert_eq!(
+            rows.unwrap(),
+            vec![vec![
+                DataValue::I64(6),
+                DataValue::I64(3),
+                DataValue::I64(0),
+                DataValue::I64(5),
+                DataValue::I64(15),
+                DataValue::F64(2.5),
+            ]]
+        );
+
+        // Without groups there is always a row
+        let (_, rows) = run(
+            "count(*), sum(id) where id > 10",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![vec![DataValue::I64(0), DataValue::Null]]
+        );
+
+        let (_, rows) = run(
+            "count(id), max(id) group by source order by source desc limit 2",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.unwrap(),
+            vec![
+                vec![string("c"), DataValue::I64(1), DataValue::I64(5)],
+                vec![string("b"), DataValue::I64(2), DataValue::I64(3)],
+            ]
+        );
+
+        let (_, rows) = run(
+            "sum(source) group by id",
+            vec![],
+            ExecutionOptions::default(),
+        )
+        .await;
+        assert!(rows.is_err());
+    }
+
+    #[tokio::test]
+    async fn aggregate_memory_limit() {
+        // Enough for the scan, but not for a group per row
+        let options = ExecutionOptions {
+            memory_limit: 200,
+            ..Default::default()
+        };
+        let (_, rows) = run("count(*) group by id", vec![], options).await;
+        let err = rows.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::ResourceExhausted(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn memory_limit_counts_value_sizes() {
+        let options = ExecutionOptions {
+            memory_limit: 1_000,
+            ..Default::default()
+        };
+        for (length, fits) in [(4, true), (1_000, false)] {
+            let mut table = MemoryTable::new(vec!["id".into(), "name".into()]);
+            for id in 0..4 {
+                let name = DataValue::String(id.to_string().repeat(length));
+                table.insert(vec![DataValue::I64(id), name]).unwrap();
+            }
+            let info = TableInfo::new("docs", 4);
+            for scope in ["id >= 0 order by name", "count(*) group by name"] {
+                let query = query(scope, &["id"], vec![]);
+                let plan = plan(&query, &info).unwrap();
+                let results = execute(&table, &query, &plan, options.clone());
+                let rows = results.rows.collect::<error::Result<Vec<_>>>().await;
+                match rows {
+                    Ok(rows) => assert!(fits && rows.len() == 4, "{}", scope),
+                    Err(err) => assert!(
+                        !fits
+                            && matches!(
+                                err.downcast_ref::<error::CustomErrors>(),
+                                Some(error::CustomErrors::ResourceExhausted(_))
+                            ),
+                        "{}: {}",
+                        scope,
+                        err
+                    ),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn predicates() {
+        let mut table = MemoryTable::new(vec!["id".into(), "name".into()]);
+        for (id, name) in [
+            (0, Some("apple")),
+            (1, Some("apricot")),
+            (2, None),
+            (3, Some("banana")),
+            (4, Some("ap")),
+        ] {
+            let name = name.map_or(DataValue::Null, |n| DataValue::String(n.into()));
+            table.insert(vec![DataValue::I64(id), name]).unwrap();
+        }
+        let info = TableInfo::new("docs", 5)
+            .with_index("id", IndexKind::Scalar, 5)
+            .with_index("name", IndexKind::Scalar, 4);
+
+        for (scope, expected) in [
+            ("id in (1, 3, 7)", vec![1, 3]),
+            ("id between 1 and 3", vec![1, 2, 3]),
+            ("name like \"ap%\"", vec![0, 1, 4]),
+            ("name like \"ap_%\"", vec![0, 1]),
+            ("name like \"%an%\"", vec![3]),
+            ("name like \"ap\"", vec![4]),
+            ("name is null", vec![2]),
+            ("name is not null", vec![0, 1, 3, 4]),
+            // Nulls are neither in the list nor outside of it
+            ("!(name in (\"ap\", \"banana\"))", vec![0, 1]),
+            ("name != \"ap\"", vec![0, 1, 3]),
+            ("!(name like \"ap%\")", vec![3]),
+            ("!((name is null) || (id < 3))", vec![3, 4]),
+            ("(id > 3) && (id < 2)", vec![]),
+            ("id * 2 >= id + 3", vec![3, 4]),
+            ("id / 2 == 1", vec![2, 3]),
+            ("id - 1 in (0, 2)", vec![1, 3]),
+            ("len(name) < 3", vec![4]),
+            ("lower(name) == \"apple\"", vec![0]),
+        ] {
+            let query = query(scope, &["id"], vec![]);
+            let plan = plan(&query, &info).unwrap();
+            let rows = execute(&table, &query, &plan, ExecutionOptions::default())
+                .rows
+                .collect::<error::Result<Vec<_>>>()
+                .await
+                .unwrap();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|id| vec![DataValue::I64(id)])
+                .collect();
+            assert_eq!(rows, expected, "{}", scope);
+        }
+    }
+
+    #[tokio::test]
+    async fn expressions() {
+        let (_, rows) = run(
+            "hamming(vector, $1) <= 1",
+            vec![DataValue::Bytes(vec![0b0000_0001])],
+            ExecutionOptions::default(),
+        )
+        .await;
+        let ids: Vec<_> = [0, 1, 2].map(|id| vec![DataValue::I64(id)]).to_vec();
+        assert_eq!(rows.unwrap(), ids);
+
+        // Constant expressions are computed once, as the bounds of an index scan
+        let (table, info) = table();
+        let query = query("id < 1 + 2", &["id"], vec![]);
+        let plan = plan(&query, &info).unwrap();
+        assert!(!plan.root.is_full_scan());
+        let rows = execute(&table, &query, &plan, ExecutionOptions::default())
+            .rows
+            .collect::<error::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        assert_eq!(rows, ids);
+    }
+
+    #[tokio::test]
+    async fn relative_times() {
+        let mut table = MemoryTable::new(vec!["id".into(), "ts".into()]);
+        let now = chrono::Utc::now();
+        for (id, hours) in [(0, 2), (1, 30), (2, 24 * 40)] {
+            let ts = now - chrono::Duration::hours(hours);
+            table
+                .insert(vec![DataValue::I64(id), DataValue::DateTime(ts)])
+                .unwrap();
+        }
+        let info = TableInfo::new("docs", 3)
+            .with_column("id", intake::ColumnTypes::I64)
+            .with_column("ts", intake::ColumnTypes::DateTime);
+        for (scope, expected) in [
+            ("ts > now() - interval '1 day'", vec![0]),
+            ("ts + 'PT12H' > now() - 'P1D'", vec![0, 1]),
+            ("now() - ts > '1 month'", vec![2]),
+            ("ts < now() - $1", vec![1, 2]),
+        ] {
+            let day = DataValue::Interval("1 day".parse().unwrap());
+            let query = query(scope, &["id"], vec![day]);
+            let plan = plan(&query, &info).unwrap();
+            let rows = execute(&table, &query, &plan, ExecutionOptions::default())
+                .rows
+                .collect::<error::Result<Vec<_>>>()
+                .await
+                .unwrap();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|id| vec![DataValue::I64(id)])
+                .collect();
+            assert_eq!(rows, expected, "{}", scope);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_fields() {
+        let statement = intake::Statement::parse(
+            "create table docs (id i64 primary key, meta json, source string as (meta.source) index)",
+            vec![],
+        )
+        .unwrap();
+        let intake::Statement::CreateTable(definition) = statement else {
+            panic!("expected a table definition")
+        };
+        let mut table =
+            MemoryTable::new(definition.columns.iter().map(|c| c.name.clone()).collect());
+        for (id, meta) in [
+            (0, serde_json::json!({"source": "a", "tags": ["x", "y"]})),
+            (1, serde_json::json!({"source": "b", "tags": ["y"]})),
+            (2, serde_json::json!({"tags": []})),
+            // Not a string, so the generated column is null
+            (3, serde_json::json!({"source": 5, "tags": []})),
+        ] {
+            let mut row = vec![DataValue::I64(id), DataValue::Json(meta), DataValue::Null];
+            definition.generate(&mut row).unwrap();
+            table.insert(row).unwrap();
+        }
+        let mut info = TableInfo::from_definition(&definition);
+        info.row_count = 4;
+
+        for (statement, index_scan, expected) in [
+            (
+                "select id from docs where meta.tags[0] == \"y\"",
+                false,
+                vec![1],
+            ),
+            (
+                "select id from docs where meta.source == \"a\"",
+                true,
+                vec![0],
+            ),
+            (
+                "select id from docs where meta[\"source\"] is null",
+                true,
+                vec![2],
+            ),
+            (
+                "select id from docs where meta.tags[1] == meta.tags[0]",
+                false,
+                vec![],
+            ),
+            // Only string comparisons can read the string column
+            ("select id from docs where meta.source == 5", false, vec![3]),
+            (
+                "select id from docs where meta.source is not null",
+                false,
+                vec![0, 1, 3],
+            ),
+        ] {
+            let query = Query::parse(statement, vec![], &info.columns).unwrap();
+            let plan = plan(&query, &info).unwrap();
+            assert_eq!(!plan.root.is_full_scan(), index_scan, "{}", statement);
+            let rows = execute(&table, &query, &plan, ExecutionOptions::default())
+                .rows
+                .collect::<error::Result<Vec<_>>>()
+                .await
+                .unwrap();
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|id| vec![DataValue::I64(id)])
+                .collect();
+            assert_eq!(rows, expected, "{}", statement);
+        }
+    }
+
+    #[tokio::test]
+    async fn select_statements() {
+        let (table, info) = table();
+        let query = Query::parse(
+            "select source, id from docs where source in (\"b\", \"c\") order by id desc limit 2",
+            vec![],
+            &info.columns,
+        )
+        .unwrap();
+        let plan = plan(&query, &info).unwrap();
+        let results = execute(&table, &query, &plan, ExecutionOptions::default());
+        assert_eq!(results.columns, vec!["source", "id"]);
+        let rows = results
+            .rows
+            .collect::<error::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![DataValue::String("c".into()), DataValue::I64(5)],
+                vec![DataValue::String("b".into()), DataValue::I64(3)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aggregates_in_select_order() {
+        let (table, info) = table();
+        let query = Query::parse(
+            "select count(*), source, max(id) from docs group by id, source order by source",
+            vec![],
+            &info.columns,
+        )
+        .unwrap();
+        let plan = plan(&query, &info).unwrap();
+        let results = execute(&table, &query, &plan, ExecutionOptions::default());
+        // Only the selected group columns come back, where the select list has them
+        assert_eq!(results.columns, vec!["count(*)", "source", "max(id)"]);
+        let rows = results
+            .rows
+            .collect::<error::Result<Vec<_>>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            rows[0],
+            vec![
+                DataValue::I64(1),
+                DataValue::String("a".into()),
+                DataValue::I64(0)
+            ]
+        );
+        assert_eq!(rows.len(), 6);
+    }
+}
@@ -0,0 +1,469 @@
+use chrono::{Datelike, Days, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use faiss::DistanceMetric;
+use intake::{ColumnTypes, DataValue};
+use query_parser::{ArithmeticOp, Expr, Function, Interval, Value};
+
+use crate::catalog::TableInfo;
+
+/// Units `date_trunc` can truncate to
+const DATE_TRUNC_UNITS: [&str; 7] = ["second", "minute", "hour", "day", "week", "month", "year"];
+
+fn invalid(message: String) -> error::Error {
+    error::CustomErrors::InvalidArguments(message).into()
+}
+
+fn type_name(data_type: ColumnTypes) -> String {
+    match data_type {
+        ColumnTypes::BinaryVector(bits) => format!("binary_vector({})", bits),
+        data_type => format!("{:?}", data_type).to_lowercase(),
+    }
+}
+
+/// Apply an arithmetic operator. Integers stay integers unless mixed with floats, times move
+/// by intervals and intervals scale by integers. Overflows, division by zero and operands
+/// without a result give null.
+pub fn arithmetic(op: ArithmeticOp, a: DataValue, b: DataValue) -> DataValue {
+    let float = |a: f64, b: f64| {
+        let result = match op {
+            ArithmeticOp::Add => a + b,
+            ArithmeticOp::Sub => a - b,
+            ArithmeticOp::Mul => a * b,
+            ArithmeticOp::Div if b == 0.0 => return DataValue::Null,
+            ArithmeticOp::Div => a / b,
+        };
+        DataValue::F64(result)
+    };
+    match (a, b) {
+        (DataValue::I64(a), DataValue::I64(b)) => {
+            let result = match op {
+                ArithmeticOp::Add => a.checked_add(b),
+                ArithmeticOp::Sub => a.checked_sub(b),
+                ArithmeticOp::Mul => a.checked_mul(b),
+                ArithmeticOp::Div => a.checked_div(b),
+            };
+            result.map_or(DataValue::Null, DataValue::I64)
+        }
+        (DataValue::I64(a), DataValue::F64(b)) => float(a as f64, b),
+        (DataValue::F64(a), DataValue::I64(b)) => float(a, b as f64),
+        (DataValue::F64(a), DataValue::F64(b)) => float(a, b),
+        (a, b) => time_arithmetic(op, a, b).unwrap_or(DataValue::Null),
+    }
+}
+
+fn time_arithmetic(op: ArithmeticOp, a: DataValue, b: DataValue) -> Option<DataValue> {
+    use ArithmeticOp::*;
+    Some(match (op, a, b) {
+        (Add, DataValue::DateTime(t), DataValue::Interval(i))
+        | (Add, DataValue::Interval(i), DataValue::DateTime(t)) => {
+            DataValue::DateTime(i.add_to(t)?)
+        }
+        (Sub, DataValue::DateTime(t), DataValue::Interval(i)) => {
+            DataValue::DateTime(i.checked_neg()?.add_to(t)?)
+        }
+        (Sub, DataValue::DateTime(a), DataValue::DateTime(b)) => {
+            DataValue::Interval(Interval::from_micros((a - b).num_microseconds()?))
+        }
+        (Add, DataValue::Interval(a), DataValue::Interval(b)) => {
+            DataValue::Interval(a.checked_add(b)?)
+        }
+        (Sub, DataValue::Interval(a), DataValue::Interval(b)) => {
+            DataValue::Interval(a.checked_add(b.checked_neg()?)?)
+        }
+        (Mul, DataValue::Interval(i), DataValue::I64(n))
+        | (Mul, DataValue::I64(n), DataValue::Interval(i)) => {
+            DataValue::Interval(i.checked_mul(n)?)
+        }
+        (Div, DataValue::Interval(i), DataValue::I64(n)) => DataValue::Interval(i.checked_div(n)?),
+        _ => return None,
+    })
+}
+
+/// Type of an arithmetic result, `None` inside when an operand's type is unknown and
+/// `None` outside when the operator doesn't apply to operands of these types
+fn arithmetic_type(
+    op: ArithmeticOp,
+    a: Option<ColumnTypes>,
+    b: Option<ColumnTypes>,
+) -> Option<Option<ColumnTypes>> {
+    use ArithmeticOp::*;
+    use ColumnTypes::*;
+    let (Some(a), Some(b)) = (a, b) else {
+        return match a.or(b) {
+            Some(F64) => Some(Some(F64)),
+            None | Some(I64 | DateTime | Interval) => Some(None),
+            Some(_) => None,
+        };
+    };
+    Some(Some(match (op, a, b) {
+        (_, I64, I64) => I64,
+        (_, I64 | F64, I64 | F64) => F64,
+        (Add, DateTime, Interval) | (Add, Interval, DateTime) | (Sub, DateTime, Interval) => {
+            DateTime
+        }
+        (Sub, DateTime, DateTime) | (Add | Sub, Interval, Interval) => Interval,
+        (Mul, Interval, I64) | (Mul, I64, Interval) | (Div, Interval, I64) => Interval,
+        _ => return None,
+    }))
+}
+
+/// Call a built in function. Arguments of the wrong type give null, like comparisons between
+/// unrelated types are unknown.
+pub fn call(function: Function, arguments: Vec<DataValue>) -> error::Result<DataValue> {
+    if arguments.len() != function.arity() {
+        return Err(invalid(format!(
+            "{} takes {} arguments, got {}",
+            function.name(),
+            function.arity(),
+            arguments.len()
+        )));
+    }
+    let mut arguments = arguments.into_iter();
+    let mut next = || arguments.next().unwrap_or(DataValue::Null);
+    Ok(match function {
+        Function::Now => DataValue::DateTime(Utc::now()),
+        Function::DateTrunc => match (next(), next()) {
+            (DataValue::String(unit), DataValue::DateTime(ts)) => {
+                let truncated = date_trunc(&unit, ts.naive_utc())?;
+                DataValue::DateTime(Utc.from_utc_datetime(&truncated))
+            }
+            _ => DataValue::Null,
+        },
+        Function::Lower => match next() {
+            DataValue::String(s) => DataValue::String(s.to_lowercase()),
+            _ => DataValue::Null,
+        },
+        Function::Len => {
+            let len = match next() {
+                DataValue::String(s) => s.chars().count(),
+                DataValue::Bytes(b) => b.len(),
+                DataValue::Json(serde_json::Value::Array(a)) => a.len(),
+                DataValue::Json(serde_json::Value::Object(o)) => o.len(),
+                DataValue::Json(serde_json::Value::String(s)) => s.chars().count(),
+                _ => return Ok(DataValue::Null),
+            };
+            DataValue::I64(len as i64)
+        }
+        Function::Hamming => match (next(), next()) {
+            (DataValue::Bytes(a), DataValue::Bytes(b)) if a.len() == b.len() => {
+                let distance = DistanceMetric::Hamming.into_fn::<u8>(a.len());
+                DataValue::I64(distance(&a, &b) as i64)
+            }
+            _ => DataValue::Null,
+        },
+        Function::Cosine => match (next(), next()) {
+            (DataValue::Bytes(a), DataValue::Bytes(b)) if a.len() == b.len() => {
+                cosine_distance(&a, &b).map_or(DataValue::Null, DataValue::F64)
+            }
+            _ => DataValue::Null,
+        },
+    })
+}
+
+fn date_trunc(unit: &str, ts: NaiveDateTime) -> error::Result<NaiveDateTime> {
+    let date = ts.date();
+    let (hour, minute, second) = (ts.hour(), ts.minute(), ts.second());
+    let truncated = match unit {
+        "second" => date.and_hms_opt(hour, minute, second),
+        "minute" => date.and_hms_opt(hour, minute, 0),
+        "hour" => date.and_hms_opt(hour, 0, 0),
+        "day" => date.and_hms_opt(0, 0, 0),
+        "week" => {
+            let monday = Days::new(date.weekday().num_days_from_monday() as u64);
+            date.checked_sub_days(monday)
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        }
+        "month" => NaiveDate::from_ymd_opt(date.year(), date.month(), 1)
+            .and_then(|d| d.and_hms_opt(0, 0, 0)),
+        "year" => NaiveDate::from_ymd_opt(date.year(), 1, 1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        unit => {
+            return Err(invalid(format!(
+                "date_trunc can't truncate to {}, it takes one of {}",
+                unit,
+                DATE_TRUNC_UNITS.join(", ")
+            )))
+        }
+    };
+    truncated.ok_or_else(|| invalid(format!("can't truncate {} to a {}", ts, unit)))
+}
+
+/// One minus the cosine similarity of two binary vectors, `None` when either has no bits set
+fn cosine_distance(a: &[u8], b: &[u8]) -> Option<f64> {
+    let ones = |v: &[u8]| v.iter().map(|b| b.count_ones()).sum::<u32>() as f64;
+    let common = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (a & b).count_ones())
+        .sum::<u32>() as f64;
+    let norms = (ones(a) * ones(b)).sqrt();
+    (norms > 0.0).then(|| 1.0 - common / norms)
+}
+
+/// The type of a value when it is known before reading a row, checking expressions are given
+/// arguments they accept.
+pub fn value_type(value: &Value, table: &TableInfo) -> error::Result<Option<ColumnTypes>> {
+    Ok(match value {
+        Value::Double(_) => Some(ColumnTypes::F64),
+        Value::Integer(_) => Some(ColumnTypes::I64),
+        Value::String(_) => Some(ColumnTypes::String),
+        Value::Bool(_) => Some(ColumnTypes::Bool),
+        Value::DateTime(_) => Some(ColumnTypes::DateTime),
+        Value::UUID(_) => Some(ColumnTypes::UUID),
+        Value::Interval(_) => Some(ColumnTypes::Interval),
+        Value::ResourceTag(_) => None,
+        Value::Literal(column) => table.column_type(column),
+        Value::Field(path) => table.generated_column(path).map(|c| c.data_type),
+        Value::Expr(expr) => expr_type(expr, table)?,
+    })
+}
+
+fn expr_type(expr: &Expr, table: &TableInfo) -> error::Result<Option<ColumnTypes>> {
+    let types = expr
+        .operands()
+        .into_iter()
+        .map(|v| value_type(v, table))
+        .collect::<error::Result<Vec<_>>>()?;
+    match expr {
+        Expr::Arithmetic(op, ..) => arithmetic_type(*op, types[0], types[1]).ok_or_else(|| {
+            let name = |t: Option<ColumnTypes>| t.map_or("unknown".to_string(), type_name);
+            invalid(format!(
+                "can't {} a {} and a {} in {}",
+                op,
+                name(types[0]),
+                name(types[1]),
+                expr
+            ))
+        }),
+        Expr::Call(function, arguments) => {
+            for (i, t) in types.iter().enumerate() {
+                let Some(t) = *t else {
+                    continue;
+                };
+                let accepted = match function {
+                    Function::Now => true,
+                    Function::DateTrunc if i == 0 => t == ColumnTypes::String,
+                    Function::DateTrunc => t == ColumnTypes::DateTime,
+                    Function::Lower => t == ColumnTypes::String,
+                    Function::Len => matches!(
+                        t,
+                        ColumnTypes::String
+                            | ColumnTypes::Bytes
+                            | ColumnTypes::Json
+                            | ColumnTypes::BinaryVector(_)
+                    ),
+                    Function::Hamming | Function::Cosine => {
+                        matches!(t, ColumnTypes::Bytes | ColumnTypes::BinaryVector(_))
+                    }
+                };
+                if !accepted {
+                    return Err(invalid(format!(
+                        "{} can't take a {} in {}",
+                        function.name(),
+                        type_name(t),
+                        expr
+                    )));
+                }
+            }
+            if let (Function::DateTrunc, [Value::String(unit), _]) =
+                (function, arguments.as_slice())
+            {
+                if !DATE_TRUNC_UNITS.contains(&unit.as_str()) {
+                    return Err(invalid(format!(
+                        "date_trunc can't truncate to {}, it takes one of {}",
+                        unit,
+                        DATE_TRUNC_UNITS.join(", ")
+                    )));
+                }
+            }
+            if let (
+                Function::Hamming | Function::Cosine,
+                [Some(ColumnTypes::BinaryVector(a)), Some(ColumnTypes::BinaryVector(b))],
+            ) = (function, types.as_slice())
+            {
+                if a != b {
+                    return Err(invalid(format!(
+                        "{} compares vectors of {} and {} bits in {}",
+                        function.name(),
+                        a,
+                        b,
+                        expr
+                    )));
+                }
+            }
+            Ok(Some(match function {
+                Function::Now | Function::DateTrunc => ColumnTypes::DateTime,
+                Function::Lower => ColumnTypes::String,
+                Function::Len | Function::Hamming => ColumnTypes::I64,
+                Function::Cosine => ColumnTypes::F64,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(s: &str) -> DataValue {
+        DataValue::DateTime(chrono::DateTime::parse_from_rfc3339(s).unwrap().into())
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        use ArithmeticOp::*;
+        use DataValue::{F64, I64};
+
+        assert_eq!(arithmetic(Add, I64(1), I64(2)), I64(3));
+        assert_eq!(arithmetic(Sub, I64(1), F64(0.5)), F64(0.5));
+        assert_eq!(arithmetic(Div, I64(7), I64(2)), I64(3));
+        assert_eq!(arithmetic(Mul, F64(1.5), I64(2)), F64(3.0));
+        for (op, a, b) in [
+            (Div, I64(1), I64(0)),
+            (Div, F64(1.0), F64(0.0)),
+            (Add, I64(i64::MAX), I64(1)),
+            (Add, I64(1), DataValue::Null),
+            (Add, I64(1), DataValue::String("1".into())),
+        ] {
+            assert_eq!(arithmetic(op, a, b), DataValue::Null);
+        }
+    }
+
+    #[test]
+    fn calls_functions() {
+        let ts = datetime("2023-09-27T13:45:12.5Z");
+        for (unit, expected) in [
+            ("second", "2023-09-27T13:45:12Z"),
+            ("hour", "2023-09-27T13:00:00Z"),
+            ("day", "2023-09-27T00:00:00Z"),
+            ("week", "2023-09-25T00:00:00Z"),
+            ("month", "2023-09-01T00:00:00Z"),
+            ("year", "2023-01-01T00:00:00Z"),
+        ] {
+            let unit = DataValue::String(unit.into());
+            let truncated = call(Function::DateTrunc, vec![unit, ts.clone()]).unwrap();
+            assert_eq!(truncated, datetime(expected));
+        }
+        let unit = DataValue::String("fortnight".into());
+        assert!(call(Function::DateTrunc, vec![unit, ts]).is_err());
+
+        let string = |s: &str| DataValue::String(s.into());
+        assert_eq!(
+            call(Function::Lower, vec![string("AbC")]).unwrap(),
+            string("abc")
+        );
+        assert_eq!(
+            call(Function::Len, vec![string("héllo")]).unwrap(),
+            DataValue::I64(5)
+        );
+        assert_eq!(
+            call(Function::Len, vec![DataValue::I64(1)]).unwrap(),
+            DataValue::Null
+        );
+
+        let a = DataValue::Bytes(vec![0b1100, 0]);
+        let b = DataValue::Bytes(vec![0b0110, 0]);
+        assert_eq!(
+            call(Function::Hamming, vec![a.clone(), b.clone()]).unwrap(),
+            DataValue::I64(2)
+        );
+        assert_eq!(
+            call(Function::Cosine, vec![a.clone(), b]).unwrap(),
+            DataValue::F64(0.5)
+        );
+        let zero = DataValue::Bytes(vec![0, 0]);
+        assert_eq!(
+            call(Function::Cosine, vec![a, zero]).unwrap(),
+            DataValue::Null
+        );
+        assert!(call(Function::Lower, vec![]).is_err());
+    }
+
+    #[test]
+    fn computes_with_times() {
+        let time = |s| DataValue::DateTime(query_parser::parse_datetime(s).unwrap());
+        let interval = |s: &str| DataValue::Interval(s.parse().unwrap());
+        for (op, a, b, expected) in [
+            (
+                ArithmeticOp::Add,
+                time("2020-01-31"),
+                interval("1 month"),
+                time("2020-02-29"),
+            ),
+            (
+                ArithmeticOp::Sub,
+                time("2020-01-01"),
+                interval("PT1H"),
+                time("2019-12-31 23:00:00"),
+            ),
+            (
+                ArithmeticOp::Sub,
+                time("2020-01-02"),
+                time("2020-01-01"),
+                interval("1 day"),
+            ),
+            (
+                ArithmeticOp::Mul,
+                interval("1 day 1 hour"),
+                DataValue::I64(2),
+                interval("2 days 2 hours"),
+            ),
+            (
+                ArithmeticOp::Div,
+                interval("1 hour"),
+                DataValue::I64(0),
+                DataValue::Null,
+            ),
+            (
+                ArithmeticOp::Add,
+                time("2020-01-01"),
+                time("2020-01-01"),
+                DataValue::Null,
+            ),
+        ] {
+            assert_eq!(arithmetic(op, a, b), expected);
+        }
+    }
+
+    #[test]
+    fn checks_types() {
+        let table = TableInfo::new("docs", 0)
+            .with_column("id", ColumnTypes::I64)
+            .with_column("name", ColumnTypes::String)
+            .with_column("ts", ColumnTypes::DateTime)
+            .with_column("vec", ColumnTypes::BinaryVector(64))
+            .with_column("other", ColumnTypes::BinaryVector(128));
+        let value_type = |s: &str| {
+            let value = query_parser::query::ValueParser::new().parse(s).unwrap();
+            value_type(&value, &table)
+        };
+
+        for (value, expected) in [
+            ("id + 1", Some(ColumnTypes::I64)),
+            ("id * 0.5", Some(ColumnTypes::F64)),
+            ("id + $1", None),
+            ("len(lower(name)) - 1", Some(ColumnTypes::I64)),
+            ("date_trunc(\"day\", ts)", Some(ColumnTypes::DateTime)),
+            ("cosine(vec, $1)", Some(ColumnTypes::F64)),
+            ("ts - '1 day'", Some(ColumnTypes::DateTime)),
+            ("'1 hour' + ts", Some(ColumnTypes::DateTime)),
+            ("now() - ts", Some(ColumnTypes::Interval)),
+            ("'PT1H' * 2 - '1 minute'", Some(ColumnTypes::Interval)),
+            ("ts + $1", None),
+        ] {
+            assert_eq!(value_type(value).unwrap(), expected, "{}", value);
+        }
+        for value in [
+            "name + 1",
+            "lower(id)",
+            "date_trunc(\"fortnight\", ts)",
+            "date_trunc(ts, \"day\")",
+            "hamming(vec, other)",
+            "len(now())",
+            "ts + ts",
+            "'1 day' - ts",
+            "'1 day' * 0.5",
+            "ts * 2",
+        ] {
+            assert!(value_type(value).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,22 @@
+mod aggregate;
+mod catalog;
+mod eval;
+mod executor;
+mod functions;
+mod optimizer;
+mod plan;
+mod planner;
+mod prepared;
+mod service;
+mod source;
+
+pub use catalog::*;
+pub use executor::{
+    execute, ExecutionOptions, QueryResults, RowStream, DISTANCE_COLUMN, SCORE_COLUMN,
+};
+pub use optimizer::optimize;
+pub use plan::*;
+pub use planner::plan;
+pub use prepared::{ParameterType, PreparedStatement, StatementCache, StatementId};
+pub use service::{StatementService, Tables};
+pub use source::*;
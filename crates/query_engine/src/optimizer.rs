@@ -0,0 +1,457 @@
+use std::cmp::Ordering;
+
+use intake::DataValue;
+use query_parser::{OpTree, Value};
+
+use crate::eval::{compare, constant, Evaluator, RowValues};
+
+/// Rewrite a predicate into an equivalent, simpler one before planning: `Not` is pushed down
+/// to the leaves, predicates over constants are folded, `And`/`Or` chains are flattened and
+/// conjunctions that can't match any row become `false`.
+///
+/// Predicates follow three valued logic, so negating a comparison yields the opposite
+/// comparison: both are unknown for nulls. A contradiction may be unknown rather than false
+/// for some rows, which doesn't change what matches as no `Not` is left above it.
+pub fn optimize(tree: &OpTree) -> OpTree {
+    simplify(push_not(tree, false))
+}
+
+/// Move negations down to the leaves, `negate` is whether `tree` sits under a `Not`
+fn push_not(tree: &OpTree, negate: bool) -> OpTree {
+    match tree {
+        OpTree::Not(t) => push_not(t, !negate),
+        OpTree::And(a, b) | OpTree::Or(a, b) => {
+            let (a, b) = (Box::new(push_not(a, negate)), Box::new(push_not(b, negate)));
+            match (tree, negate) {
+                (OpTree::And(..), false) | (OpTree::Or(..), true) => OpTree::And(a, b),
+                _ => OpTree::Or(a, b),
+            }
+        }
+        t if !negate => t.clone(),
+        OpTree::Eq(a, b) => OpTree::Neq(a.clone(), b.clone()),
+        OpTree::Neq(a, b) => OpTree::Eq(a.clone(), b.clone()),
+        OpTree::Lt(a, b) => OpTree::Gte(a.clone(), b.clone()),
+        OpTree::Lte(a, b) => OpTree::Gt(a.clone(), b.clone()),
+        OpTree::Gt(a, b) => OpTree::Lte(a.clone(), b.clone()),
+        OpTree::Gte(a, b) => OpTree::Lt(a.clone(), b.clone()),
+        OpTree::IsNull(v) => OpTree::IsNotNull(v.clone()),
+        OpTree::IsNotNull(v) => OpTree::IsNull(v.clone()),
+        OpTree::Value(Value::Bool(b)) => OpTree::Value(Value::Bool(!b)),
+        t => OpTree::Not(Box::new(t.clone())),
+    }
+}
+
+fn simplify(tree: OpTree) -> OpTree {
+    match tree {
+        OpTree::And(..) => {
+            let mut operands = Vec::new();
+            flatten(tree, true, &mut operands);
+            rebuild(operands, true)
+        }
+        OpTree::Or(..) => {
+            let mut operands = Vec::new();
+            flatten(tree, false, &mut operands);
+            rebuild(operands, false)
+        }
+        OpTree::Not(t) => match simplify(*t) {
+            OpTree::Value(Value::Bool(b)) => OpTree::Value(Value::Bool(!b)),
+            t => OpTree::Not(Box::new(t)),
+        },
+        leaf => fold(leaf),
+    }
+}
+
+/// Collect the simplified operands of a chain of `And`s (`conjunction`) or `Or`s
+fn flatten(tree: OpTree, conjunction: bool, out: &mut Vec<OpTree>) {
+    match tree {
+        OpTree::And(a, b) if conjunction => {
+            flatten(*a, conjunction, out);
+            flatten(*b, conjunction, out);
+        }
+        OpTree::Or(a, b) if !conjunction => {
+            flatten(*a, conjunction, out);
+            flatten(*b, conjunction, out);
+        }
+        // Operands of a chain the simplified operand turned into are already simplified
+        t => split(simplify(t), conjunction, out),
+    }
+}
+
+fn split(tree: OpTree, conjunction: bool, out: &mut Vec<OpTree>) {
+    match tree {
+        OpTree::And(a, b) if conjunction => {
+            split(*a, conjunction, out);
+            split(*b, conjunction, out);
+        }
+        OpTree::Or(a, b) if !conjunction => {
+            split(*a, conjunction, out);
+            split(*b, conjunction, out);
+        }
+        t => out.push(t),
+    }
+}
+
+/// Rebuild a flattened chain, dropping duplicates and operands that don't change the result
+/// and short-circuiting on operands that decide it
+fn rebuild(operands: Vec<OpTree>, conjunction: bool) -> OpTree {
+    let identity = OpTree::Value(Value::Bool(conjunction));
+    let absorbing = OpTree::Value(Value::Bool(!conjunction));
+
+    let mut kept: Vec<OpTree> = Vec::with_capacity(operands.len());
+    for operand in operands {
+        if operand == absorbing {
+            return absorbing;
+        }
+        if operand != identity && !kept.contains(&operand) {
+            kept.push(operand);
+        }
+    }
+    if conjunction && is_contradiction(&kept) {
+        return absorbing;
+    }
+
+    kept.into_iter()
+        .reduce(|a, b| match conjunction {
+            true => OpTree::And(Box::new(a), Box::new(b)),
+            false => OpTree::Or(Box::new(a), Box::new(b)),
+        })
+        .unwrap_or(identity)
+}
+
+/// Values a leaf predicate reads
+pub(crate) fn leaf_values(tree: &OpTree) -> Vec<&Value> {
+    match tree {
+        OpTree::Eq(a, b)
+        | OpTree::Neq(a, b)
+        | OpTree::Lt(a, b)
+        | OpTree::Lte(a, b)
+        | OpTree::Gt(a, b)
+        | OpTree::Gte(a, b)
+        | OpTree::Within(a, b)
+        | OpTree::TopK(a, b)
+        | OpTree::Like(a, b) => vec![a, b],
+        OpTree::WithinOf(a, b, target) | OpTree::TopKNear(a, b, target) => {
+            vec![a, b, &target.vector]
+        }
+        OpTree::In(a, values) => std::iter::once(a).chain(values).collect(),
+        OpTree::Between(a, lower, upper) => vec![a, lower, upper],
+        OpTree::IsNull(v) | OpTree::IsNotNull(v) | OpTree::Value(v) => vec![v],
+        OpTree::And(..) | OpTree::Or(..) | OpTree::Not(..) | OpTree::Fuse(..) => vec![],
+    }
+}
+
+/// The value of a constant, `None` for columns, parameters and expressions, which may read the
+/// clock
+fn literal(value: &Value) -> Option<DataValue> {
+    match value {
+        Value::Literal(_) | Value::Field(_) | Value::ResourceTag(_) | Value::Expr(_) => None,
+        value => constant(value, &[]).ok(),
+    }
+}
+
+/// Replace a leaf that only reads constants with its truth value, when that is known
+fn fold(leaf: OpTree) -> OpTree {
+    if matches!(
+        leaf,
+        OpTree::Within(..)
+            | OpTree::TopK(..)
+            | OpTree::WithinOf(..)
+            | OpTree::TopKNear(..)
+            | OpTree::Fuse(..)
+    ) || leaf_values(&leaf).into_iter().any(|v| literal(v).is_none())
+    {
+        return leaf;
+    }
+    match Evaluator::new(&[]).truth(&leaf, &RowValues::new()) {
+        Ok(Some(truth)) => OpTree::Value(Value::Bool(truth)),
+        _ => leaf,
+    }
+}
+
+/// What a conjunction requires of a single column
+#[derive(Default)]
+struct ColumnBounds {
+    lower: Option<(DataValue, bool)>,
+    upper: Option<(DataValue, bool)>,
+    is_null: bool,
+    /// Some conjunct only holds for non-null values
+    not_null: bool,
+    /// Bounds of unrelated types, nothing can be concluded
+    incomparable: bool,
+}
+
+impl ColumnBounds {
+    /// Narrow to values above `value`, `inclusive` of it or not
+    fn tighten_lower(&mut self, value: DataValue, inclusive: bool) {
+        self.not_null = true;
+        let inclusive = match &self.lower {
+            Some((current, current_inclusive)) => match compare(&value, current) {
+                Some(Ordering::Less) => return,
+                Some(Ordering::Equal) => inclusive && *current_inclusive,
+                Some(Ordering::Greater) => inclusive,
+                None => return self.incomparable = true,
+            },
+            None => inclusive,
+        };
+        self.lower = Some((value, inclusive));
+    }
+
+    /// Narrow to values below `value`, `inclusive` of it or not
+    fn tighten_upper(&mut self, value: DataValue, inclusive: bool) {
+        self.not_null = true;
+        let inclusive = match &self.upper {
+            Some((current, current_inclusive)) => match compare(&value, current) {
+                Some(Ordering::Greater) => return,
+                Some(Ordering::Equal) => inclusive && *current_inclusive,
+                Some(Ordering::Less) => inclusive,
+                None => return self.incomparable = true,
+            },
+            None => inclusive,
+        };
+        self.upper = Some((value, inclusive));
+    }
+
+    fn is_empty(&self) -> bool {
+        if self.is_null && self.not_null {
+            return true;
+        }
+        match (&self.lower, &self.upper) {
+            _ if self.incomparable => false,
+            (Some((lower, lower_inclusive)), Some((upper, upper_inclusive))) => {
+                match compare(lower, upper) {
+                    Some(Ordering::Greater) => true,
+                    Some(Ordering::Equal) => !(*lower_inclusive && *upper_inclusive),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The bounds collected for a column, `None` when `column` isn't one
+fn column_bounds<'c>(
+    columns: &'c mut Vec<(String, ColumnBounds)>,
+    column: &Value,
+) -> Option<&'c mut ColumnBounds> {
+    let Value::Literal(column) = column else {
+        return None;
+    };
+    let i = match columns.iter().position(|(c, _)| c == column) {
+        Some(i) => i,
+        None => {
+            columns.push((column.clone(), ColumnBounds::default()));
+            columns.len() - 1
+        }
+    };
+    Some(&mut columns[i].1)
+}
+
+/// Whether the conjunction of `operands` can't be true for any row, judged from the bounds
+/// it puts on each column
+fn is_contradiction(operands: &[OpTree]) -> bool {
+    let mut columns = Vec::new();
+    for operand in operands {
+        match operand {
+            OpTree::Eq(a, b)
+            | OpTree::Neq(a, b)
+            | OpTree::Lt(a, b)
+            | OpTree::Lte(a, b)
+            | OpTree::Gt(a, b)
+            | OpTree::Gte(a, b) => {
+                let (column, value, flipped) = match (literal(a), literal(b)) {
+                    (None, Some(value)) => (a, value, false),
+                    (Some(value), None) => (b, value, true),
+                    _ => continue,
+                };
+                let Some(bounds) = column_bounds(&mut columns, column) else {
+                    continue;
+                };
+                match (operand, flipped) {
+                    (OpTree::Eq(..), _) => {
+                        bounds.tighten_lower(value.clone(), true);
+                        bounds.tighten_upper(value, true);
+                    }
+                    (OpTree::Neq(..), _) => bounds.not_null = true,
+                    (OpTree::Lt(..), false) | (OpTree::Gt(..), true) => {
+                        bounds.tighten_upper(value, false)
+                    }
+                    (OpTree::Lte(..), false) | (OpTree::Gte(..), true) => {
+                        bounds.tighten_upper(value, true)
+                    }
+                    (OpTree::Gt(..), false) | (OpTree::Lt(..), true) => {
+                        bounds.tighten_lower(value, false)
+                    }
+                    _ => bounds.tighten_lower(value, true),
+                }
+            }
+            OpTree::Between(column, lower, upper) => {
+                let (Some(lower), Some(upper)) = (literal(lower), literal(upper)) else {
+                    continue;
+                };
+                if let Some(bounds) = column_bounds(&mut columns, column) {
+                    bounds.tighten_lower(lower, true);
+                    bounds.tighten_upper(upper, true);
+                }
+            }
+            OpTree::In(column, _) | OpTree::Like(column, _) | OpTree::IsNotNull(column) => {
+                if let Some(bounds) = column_bounds(&mut columns, column) {
+                    bounds.not_null = true;
+                }
+            }
+            OpTree::IsNull(column) => {
+                if let Some(bounds) = column_bounds(&mut columns, column) {
+                    bounds.is_null = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    columns.iter().any(|(_, bounds)| bounds.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use query_parser::query::ScopeParser;
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+    use super::*;
+
+    fn optimized(input: &str) -> String {
+        optimize(&ScopeParser::new().parse(input).unwrap()).to_string()
+    }
+
+    #[test]
+    fn rewrites() {
+        for (input, expected) in [
+            ("1 == 1", "true"),
+            ("\"a\" < \"b\"", "true"),
+            ("!(!a)", "a"),
+            ("!(a == 1)", "a != 1"),
+            ("!((a < 1) || (b is null))", "(a >= 1) && (b is not null)"),
+            (
+                "!((a == 1) && (b like \"x%\"))",
+                "(a != 1) || (!(b like \"x%\"))",
+            ),
+            (
+                "((a == 1) && (b == 2)) && ((c == 3) && (a == 1))",
+                "((a == 1) && (b == 2)) && (c == 3)",
+            ),
+            ("(a == 1) && (2 > 1)", "a == 1"),
+            ("(a == 1) || (2 < 1)", "a == 1"),
+            ("(a == 1) || (1 in (3, 1))", "true"),
+            ("(a > 5) && (a < 3)", "false"),
+            ("(a >= 3) && (3 >= a)", "(a >= 3) && (3 >= a)"),
+            ("(a > 3) && (3 >= a)", "false"),
+            ("((a == 2) && (b == 1)) && (a between 3 and 4)", "false"),
+            ("(a is null) && (a > 1)", "false"),
+            ("(a == $1) && (a == 2)", "(a == $1) && (a == 2)"),
+            ("(a == 1) && (a == \"x\")", "(a == 1) && (a == \"x\")"),
+            ("((a > 5) && (a < 3)) || (b == 1)", "b == 1"),
+            ("(v topk 10) && (1 == 1)", "v topk 10"),
+            ("1 == \"a\"", "1 == \"a\""),
+        ] {
+            assert_eq!(optimized(input), expected, "{}", input);
+        }
+    }
+
+    /// Predicates over the integer columns `a` and `b` and the string column `s`
+    #[derive(Debug, Clone)]
+    struct Predicate(OpTree);
+
+    fn operand(g: &mut Gen) -> Value {
+        match u8::arbitrary(g) % 6 {
+            0 => Value::Literal("a".into()),
+            1 => Value::Literal("b".into()),
+            2 => Value::Literal("s".into()),
+            3 => Value::String(g.choose(&["", "a", "ab", "b"]).unwrap().to_string()),
+            _ => Value::Integer(i64::arbitrary(g) % 4),
+        }
+    }
+
+    fn predicate(g: &mut Gen, depth: usize) -> OpTree {
+        let choices = if depth == 0 { 12 } else { 15 };
+        let (a, b) = (operand(g), operand(g));
+        match u8::arbitrary(g) % choices {
+            0 => OpTree::Eq(a, b),
+            1 => OpTree::Neq(a, b),
+            2 => OpTree::Lt(a, b),
+            3 => OpTree::Lte(a, b),
+            4 => OpTree::Gt(a, b),
+            5 => OpTree::Gte(a, b),
+            6 => OpTree::In(a, vec![b, operand(g)]),
+            7 => OpTree::Between(a, b, operand(g)),
+            8 => {
+                let pattern = g.choose(&["a%", "_", "%b"]).unwrap().to_string();
+                OpTree::Like(a, Value::String(pattern))
+            }
+            9 => OpTree::IsNull(a),
+            10 => OpTree::IsNotNull(a),
+            11 => OpTree::Value(Value::Bool(bool::arbitrary(g))),
+            12 => OpTree::Not(Box::new(predicate(g, depth - 1))),
+            13 => OpTree::And(
+                Box::new(predicate(g, depth - 1)),
+                Box::new(predicate(g, depth - 1)),
+            ),
+            _ => OpTree::Or(
+                Box::new(predicate(g, depth - 1)),
+                Box::new(predicate(g, depth - 1)),
+            ),
+        }
+    }
+
+    impl Arbitrary for Predicate {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Predicate(predicate(g, 4))
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Row(RowValues);
+
+    impl Arbitrary for Row {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let int = |g: &mut Gen| match u8::arbitrary(g) % 5 {
+                0 => DataValue::Null,
+                _ => DataValue::I64(i64::arbitrary(g) % 4),
+            };
+            let string = match g.choose(&[None, Some(""), Some("a"), Some("ab"), Some("b")]) {
+                Some(Some(s)) => DataValue::String(s.to_string()),
+                _ => DataValue::Null,
+            };
+            Row([
+                ("a".to_string(), int(g)),
+                ("b".to_string(), int(g)),
+                ("s".to_string(), string),
+            ]
+            .into_iter()
+            .collect())
+        }
+    }
+
+    #[test]
+    fn preserves_semantics() {
+        fn prop(predicate: Predicate, rows: Vec<Row>) -> bool {
+            let evaluator = Evaluator::new(&[]);
+            let optimized = optimize(&predicate.0);
+            rows.iter().all(|Row(row)| {
+                evaluator.eval(&predicate.0, row).unwrap()
+                    == evaluator.eval(&optimized, row).unwrap()
+            })
+        }
+        QuickCheck::new()
+            .tests(2_000)
+            .quickcheck(prop as fn(Predicate, Vec<Row>) -> bool);
+    }
+
+    #[test]
+    fn is_idempotent() {
+        fn prop(predicate: Predicate) -> bool {
+            let optimized = optimize(&predicate.0);
+            optimize(&optimized) == optimized
+        }
+        QuickCheck::new()
+            .tests(2_000)
+            .quickcheck(prop as fn(Predicate) -> bool);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{fmt, ops::Bound};
+
+use faiss::DistanceMetric;
+use query_parser::{Aggregate, FusionMethod, OpTree, Value};
+
+/// Range of index keys an index scan visits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanRange {
+    Eq(Value),
+    Range {
+        lower: Bound<Value>,
+        upper: Bound<Value>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VectorSearch {
+    /// The `k` nearest vectors
+    TopK(Value),
+    /// Every vector within a distance
+    Within(Value),
+}
+
+/// Physical operators. Every operator produces a set of row ids, vector searches also produce
+/// the distance of each row from the target vector.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operator {
+    /// No rows, for predicates that can't match
+    Empty,
+    /// Every row in the table
+    FullScan,
+    /// Rows whose indexed column falls in `range`
+    IndexScan {
+        column: String,
+        range: ScanRange,
+    },
+    /// Search a vector index, only considering the rows produced by `prefilter` when it is set
+    VectorSearch {
+        column: String,
+        search: VectorSearch,
+        target: Value,
+        metric: DistanceMetric,
+        prefilter: Option<Box<PlanNode>>,
+    },
+    /// Keep the rows of the (over-fetched) vector search `input` that are also in `filter`,
+    /// truncated to the `limit` closest.
+    PostFilter {
+        input: Box<PlanNode>,
+        filter: Box<PlanNode>,
+        limit: Value,
+    },
+    /// Evaluate `predicate` against each row of `input`
+    Filter {
+        input: Box<PlanNode>,
+        predicate: OpTree,
+    },
+    Intersect(Vec<PlanNode>),
+    Union(Vec<PlanNode>),
+    /// Rank the rows of several vector searches by combining their rankings, producing a
+    /// score per row instead of a distance
+    Fuse {
+        method: FusionMethod,
+        inputs: Vec<PlanNode>,
+    },
+    /// Every row in the table that isn't produced by the input
+    Complement(Box<PlanNode>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanNode {
+    pub op: Operator,
+    pub estimated_rows: u64,
+}
+
+impl PlanNode {
+    pub fn new(op: Operator, estimated_rows: u64) -> Self {
+        PlanNode { op, estimated_rows }
+    }
+
+    /// Whether the node has to look at every row of the table to produce its output
+    pub fn is_full_scan(&self) -> bool {
+        match &self.op {
+            Operator::FullScan => true,
+            Operator::Filter { input, .. } => input.is_full_scan(),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn children(&self) -> Vec<&PlanNode> {
+        match &self.op {
+            Operator::Empty | Operator::FullScan | Operator::IndexScan { .. } => vec![],
+            Operator::VectorSearch { prefilter, .. } => {
+                prefilter.iter().map(|p| p.as_ref()).collect()
+            }
+            Operator::PostFilter { input, filter, .. } => vec![input, filter],
+            Operator::Filter { input, .. } => vec![input],
+            Operator::Intersect(nodes)
+            | Operator::Union(nodes)
+            | Operator::Fuse { inputs: nodes, .. } => nodes.iter().collect(),
+            Operator::Complement(input) => vec![input],
+        }
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}", "", indent = depth * 2)?;
+        match &self.op {
+            Operator::Empty => write!(f, "Empty")?,
+            Operator::FullScan => write!(f, "FullScan")?,
+            Operator::IndexScan { column, range } => {
+                write!(f, "IndexScan ")?;
+                match range {
+                    ScanRange::Eq(v) => write!(f, "{} == {}", column, v)?,
+                    ScanRange::Range { lower, upper } => {
+                        match lower {
+                            Bound::Included(v) => write!(f, "{} <= ", v)?,
+                            Bound::Excluded(v) => write!(f, "{} < ", v)?,
+                            Bound::Unbounded => {}
+                        }
+                        write!(f, "{}", column)?;
+                        match upper {
+                            Bound::Included(v) => write!(f, " <= {}", v)?,
+                            Bound::Excluded(v) => write!(f, " < {}", v)?,
+                            Bound::Unbounded => {}
+                        }
+                    }
+                }
+            }
+            Operator::VectorSearch {
+                column,
+                search,
+                target,
+                metric,
+                prefilter,
+            } => {
+                match search {
+                    VectorSearch::TopK(k) => write!(f, "VectorSearch {} topk {}", column, k)?,
+                    VectorSearch::Within(d) => write!(f, "VectorSearch {} within {}", column, d)?,
+                }
+                write!(f, " near {} using {:?}", target, metric)?;
+                if prefilter.is_some() {
+                    write!(f, " prefiltered")?;
+                }
+            }
+            Operator::PostFilter { limit, .. } => write!(f, "PostFilter limit {}", limit)?,
+            Operator::Filter { predicate, .. } => write!(f, "Filter {}", predicate)?,
+            Operator::Intersect(_) => write!(f, "Intersect")?,
+            Operator::Union(_) => write!(f, "Union")?,
+            Operator::Fuse { method, .. } => write!(f, "Fuse {}", method)?,
+            Operator::Complement(_) => write!(f, "Complement")?,
+        }
+        writeln!(f, " (rows={})", self.estimated_rows)?;
+
+        for child in self.children() {
+            child.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderExpr {
+    Column(String),
+    /// Distance between the vectors in `column` and `target`
+    Distance {
+        column: String,
+        target: Value,
+        metric: DistanceMetric,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderKey {
+    pub expr: OrderExpr,
+    pub descending: bool,
+}
+
+impl fmt::Display for OrderKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.expr {
+            OrderExpr::Column(column) => write!(f, "{}", column)?,
+            OrderExpr::Distance {
+                column,
+                target,
+                metric,
+            } => write!(f, "distance({}, {}) using {:?}", column, target, metric)?,
+        }
+        if self.descending {
+            write!(f, " desc")?;
+        }
+        Ok(())
+    }
+}
+
+/// Column of an aggregate query's output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupOutput {
+    /// The grouped column at this position in `group_by`
+    Group(usize),
+    /// The aggregate at this position in `aggregates`
+    Aggregate(usize),
+}
+
+/// Hash aggregation of the rows produced by a plan, one output row per group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregation {
+    pub group_by: Vec<String>,
+    pub aggregates: Vec<Aggregate>,
+    /// Columns returned for each group, in the order of the select list
+    pub output: Vec<GroupOutput>,
+    pub estimated_groups: u64,
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HashAggregate")?;
+        for (i, aggregate) in self.aggregates.iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            write!(f, "{}{}", sep, aggregate)?;
+        }
+        for (i, column) in self.group_by.iter().enumerate() {
+            let sep = if i == 0 { " group by " } else { ", " };
+            write!(f, "{}{}", sep, column)?;
+        }
+        write!(f, " (rows={})", self.estimated_groups)
+    }
+}
+
+/// Physical plan for a query against a single table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub table: String,
+    pub root: PlanNode,
+    /// Groups the rows produced by `root`, ordering and paging then apply to the groups
+    pub aggregation: Option<Aggregation>,
+    /// Order of the rows produced by `root`. Without one, rows found by a vector search come
+    /// first, nearest first, and everything else follows in row id order.
+    pub order_by: Vec<OrderKey>,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+impl Plan {
+    fn is_paged(&self) -> bool {
+        !self.order_by.is_empty() || self.limit.is_some() || self.offset > 0
+    }
+
+    /// Estimated number of rows the query returns
+    pub fn estimated_rows(&self) -> u64 {
+        let rows = match &self.aggregation {
+            Some(aggregation) => aggregation.estimated_groups,
+            None => self.root.estimated_rows,
+        };
+        let rows = rows.saturating_sub(self.offset);
+        self.limit.map_or(rows, |limit| rows.min(limit))
+    }
+
+    fn fmt_top_n(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "  TopN")?;
+        for (i, key) in self.order_by.iter().enumerate() {
+            let sep = if i == 0 { " order by " } else { ", " };
+            write!(f, "{}{}", sep, key)?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " limit {}", limit)?;
+        }
+        if self.offset > 0 {
+            write!(f, " offset {}", self.offset)?;
+        }
+        writeln!(f, " (rows={})", self.estimated_rows())
+    }
+}
+
+impl Plan {
+    /// Human readable rendering of the plan, one operator per line
+    pub fn explain(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Plan for {}", self.table)?;
+        let mut depth = 1;
+        if self.is_paged() {
+            self.fmt_top_n(f)?;
+            depth += 1;
+        }
+        if let Some(aggregation) = &self.aggregation {
+            writeln!(f, "{:indent$}{}", "", aggregation, indent = depth * 2)?;
+            depth += 1;
+        }
+        self.root.fmt_tree(f, depth)
+    }
+}
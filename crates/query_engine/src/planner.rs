@@ -0,0 +1,1190 @@
+use std::ops::Bound;
+
+use faiss::DistanceMetric;
+use intake::{ColumnTypes, Query};
+use query_parser::{
+    Direction, FusionMethod, OpTree, QueryAst, SelectItem, SortExpr, SortKey, Value,
+};
+
+use crate::{
+    catalog::{IndexInfo, IndexKind, TableInfo},
+    eval::{like_prefix, referenced_columns, LikePrefix},
+    functions::value_type,
+    optimizer::{leaf_values, optimize},
+    plan::{
+        Aggregation, GroupOutput, Operator, OrderExpr, OrderKey, Plan, PlanNode, ScanRange,
+        VectorSearch,
+    },
+};
+
+/// Fraction of rows a predicate without statistics is assumed to match
+const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+/// Fraction of rows a `within` search is assumed to match
+const WITHIN_SELECTIVITY: f64 = 0.1;
+/// Fraction of an indexed column assumed to be null
+const NULL_SELECTIVITY: f64 = 0.1;
+/// Filters estimated to match at most this fraction of the table are applied before a `topk`
+/// search, less selective filters are applied to an over-fetched search result instead.
+const PREFILTER_THRESHOLD: f64 = 0.1;
+
+/// Vector predicates that don't name the vector to compare against search around the first
+/// parameter.
+pub(crate) const DEFAULT_TARGET: Value = Value::ResourceTag(1);
+
+/// Plan a query against a table.
+pub fn plan(query: &Query, table: &TableInfo) -> error::Result<Plan> {
+    if query.table != table.name {
+        return Err(error::CustomErrors::InvalidArguments(format!(
+            "query targets table {} but was planned against {}",
+            query.table, table.name
+        ))
+        .into());
+    }
+
+    let planner = Planner { table };
+    let ast = &query.query;
+    let order_by: Vec<OrderKey> = ast
+        .order_by
+        .iter()
+        .map(|key| planner.order_key(key))
+        .collect::<error::Result<_>>()?;
+    let filter = resolve_fields(&ast.filter, table);
+    check_types(&filter, table)?;
+    let root = planner.plan(&optimize(&filter))?;
+    let aggregation = planner.aggregation(ast, &query.select, &order_by, &root)?;
+
+    Ok(Plan {
+        table: table.name.clone(),
+        root,
+        aggregation,
+        order_by,
+        limit: ast.limit,
+        offset: ast.offset.unwrap_or(0),
+    })
+}
+
+struct Planner<'a> {
+    table: &'a TableInfo,
+}
+
+impl<'a> Planner<'a> {
+    fn rows(&self) -> u64 {
+        self.table.row_count
+    }
+
+    fn estimate(&self, selectivity: f64) -> u64 {
+        (self.rows() as f64 * selectivity).ceil() as u64
+    }
+
+    fn selectivity(&self, node: &PlanNode) -> f64 {
+        if self.rows() == 0 {
+            return 1.0;
+        }
+        node.estimated_rows as f64 / self.rows() as f64
+    }
+
+    fn vector_metric(&self, column: &str) -> error::Result<DistanceMetric> {
+        match self.table.index(column).map(|index| index.kind) {
+            Some(IndexKind::Vector(metric)) => Ok(metric),
+            _ => Err(error::CustomErrors::InvalidArguments(format!(
+                "column {} has no vector index",
+                column
+            ))
+            .into()),
+        }
+    }
+
+    /// The metric of a column's vector index, which has to be the `requested` one if set
+    fn checked_metric(
+        &self,
+        column: &str,
+        requested: Option<&str>,
+    ) -> error::Result<DistanceMetric> {
+        let metric = self.vector_metric(column)?;
+        match requested.map(intake::distance_metric).transpose()? {
+            Some(requested) if requested != metric => {
+                Err(error::CustomErrors::InvalidArguments(format!(
+                    "column {} is indexed for {:?} distance, not {:?}",
+                    column, metric, requested
+                ))
+                .into())
+            }
+            _ => Ok(metric),
+        }
+    }
+
+    fn order_key(&self, key: &SortKey) -> error::Result<OrderKey> {
+        let expr = match &key.expr {
+            SortExpr::Column(column) => OrderExpr::Column(column.clone()),
+            SortExpr::Distance(column, target) => {
+                value_type(target, self.table)?;
+                OrderExpr::Distance {
+                    column: column.clone(),
+                    target: target.clone(),
+                    metric: self.vector_metric(column)?,
+                }
+            }
+        };
+        Ok(OrderKey {
+            expr,
+            descending: key.direction == Direction::Desc,
+        })
+    }
+
+    /// Hash aggregation for queries with aggregates. Groups can only be ordered by the columns
+    /// they are grouped by, and only grouped columns can be selected.
+    fn aggregation(
+        &self,
+        ast: &QueryAst,
+        select: &[SelectItem],
+        order_by: &[OrderKey],
+        root: &PlanNode,
+    ) -> error::Result<Option<Aggregation>> {
+        if ast.aggregates.is_empty() {
+            return Ok(None);
+        }
+        for key in order_by {
+            match &key.expr {
+                OrderExpr::Column(column) if ast.group_by.contains(column) => {}
+                _ => {
+                    return Err(error::CustomErrors::InvalidArguments(format!(
+                        "aggregate queries can only be ordered by grouped columns, got {}",
+                        key
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        // Indexed columns know how many distinct values they hold, other columns are
+        // assumed to split the rows like an unselective predicate
+        let groups = ast.group_by.iter().fold(1.0, |groups, column| {
+            let distinct = match self.table.index(column) {
+                Some(index) if index.distinct_values > 0 => index.distinct_values as f64,
+                _ => root.estimated_rows as f64 * DEFAULT_SELECTIVITY,
+            };
+            groups * distinct
+        });
+        let estimated_groups = if ast.group_by.is_empty() {
+            1
+        } else {
+            (groups.ceil() as u64).clamp(1, root.estimated_rows.max(1))
+        };
+
+        let output = if select.is_empty() {
+            (0..ast.group_by.len())
+                .map(GroupOutput::Group)
+                .chain((0..ast.aggregates.len()).map(GroupOutput::Aggregate))
+                .collect()
+        } else {
+            let mut aggregates = 0..ast.aggregates.len();
+            select
+                .iter()
+                .map(|item| match item {
+                    SelectItem::Column(column) => ast
+                        .group_by
+                        .iter()
+                        .position(|c| c == column)
+                        .map(GroupOutput::Group)
+                        .ok_or_else(|| {
+                            error::CustomErrors::InvalidArguments(format!(
+                                "column {} must be grouped by to be selected with aggregates",
+                                column
+                            ))
+                            .into()
+                        }),
+                    SelectItem::Aggregate(aggregate) => aggregates
+                        .next()
+                        .filter(|&i| ast.aggregates[i] == *aggregate)
+                        .map(GroupOutput::Aggregate)
+                        .ok_or_else(|| {
+                            error::CustomErrors::InvalidArguments(format!(
+                                "{} isn't one of the query's aggregates",
+                                aggregate
+                            ))
+                            .into()
+                        }),
+                })
+                .collect::<error::Result<_>>()?
+        };
+
+        Ok(Some(Aggregation {
+            group_by: ast.group_by.clone(),
+            aggregates: ast.aggregates.clone(),
+            output,
+            estimated_groups,
+        }))
+    }
+
+    fn plan(&self, tree: &OpTree) -> error::Result<PlanNode> {
+        match tree {
+            OpTree::Eq(a, b)
+            | OpTree::Neq(a, b)
+            | OpTree::Lt(a, b)
+            | OpTree::Lte(a, b)
+            | OpTree::Gt(a, b)
+            | OpTree::Gte(a, b) => Ok(self
+                .plan_comparison(tree, a, b)
+                .unwrap_or_else(|| self.full_scan(tree.clone()))),
+            OpTree::In(column, values) => Ok(self
+                .plan_in(column, values)
+                .unwrap_or_else(|| self.full_scan(tree.clone()))),
+            OpTree::Between(column, lower, upper) => Ok(self
+                .plan_between(column, lower, upper)
+                .unwrap_or_else(|| self.full_scan(tree.clone()))),
+            OpTree::Like(column, pattern) => Ok(self
+                .plan_like(tree, column, pattern)
+                .unwrap_or_else(|| self.full_scan(tree.clone()))),
+            OpTree::IsNull(column) | OpTree::IsNotNull(column) => Ok(self
+                .plan_null_check(tree, column)
+                .unwrap_or_else(|| self.full_scan(tree.clone()))),
+            OpTree::Within(..) | OpTree::TopK(..) | OpTree::WithinOf(..) | OpTree::TopKNear(..) => {
+                let vector = vector_predicate(tree).expect("vector predicate");
+                self.plan_vector_search(vector, None)
+            }
+            OpTree::Fuse(method, searches) => self.plan_fusion(method, searches, &[]),
+            OpTree::And(_, _) => {
+                let mut conjuncts = Vec::new();
+                flatten_and(tree, &mut conjuncts);
+                self.plan_and(&conjuncts)
+            }
+            OpTree::Or(..) | OpTree::Not(..) if has_fusion(tree) => Err(misplaced_fusion(tree)),
+            OpTree::Or(a, b) => {
+                let (a, b) = (self.plan(a)?, self.plan(b)?);
+                // Vector predicates can't be evaluated row by row, so they stay index lookups
+                if (a.is_full_scan() || b.is_full_scan()) && !has_vector_predicate(tree) {
+                    return Ok(self.full_scan(tree.clone()));
+                }
+                let rows = (a.estimated_rows + b.estimated_rows).min(self.rows());
+                let mut nodes = Vec::new();
+                for node in [a, b] {
+                    match node.op {
+                        Operator::Union(children) => nodes.extend(children),
+                        op => nodes.push(PlanNode::new(op, node.estimated_rows)),
+                    }
+                }
+                Ok(PlanNode::new(Operator::Union(nodes), rows))
+            }
+            OpTree::Not(t) => {
+                let node = self.plan(t)?;
+                if node.is_full_scan() {
+                    return Ok(self.full_scan(tree.clone()));
+                }
+                // Rows where the predicate is unknown match neither it nor its negation, so
+                // the complement is limited to the non-null values of the column it reads
+                let mut columns = Vec::new();
+                referenced_columns(t, &mut columns);
+                let index = match columns.as_slice() {
+                    [column] => self.scalar_index(&Value::Literal(column.clone())),
+                    _ => None,
+                };
+                match index {
+                    Some(index) => {
+                        let scan = self.non_null_scan(index);
+                        let complement = self.complement(node);
+                        let rows = complement.estimated_rows.min(scan.estimated_rows);
+                        Ok(PlanNode::new(
+                            Operator::Intersect(vec![scan, complement]),
+                            rows,
+                        ))
+                    }
+                    None if has_vector_predicate(t) => Ok(self.complement(node)),
+                    None => Ok(self.full_scan(tree.clone())),
+                }
+            }
+            OpTree::Value(Value::Bool(true)) => Ok(PlanNode::new(Operator::FullScan, self.rows())),
+            OpTree::Value(Value::Bool(false)) => Ok(PlanNode::new(Operator::Empty, 0)),
+            OpTree::Value(_) => Ok(self.full_scan(tree.clone())),
+        }
+    }
+
+    /// Filter every row of the table with a predicate
+    fn full_scan(&self, predicate: OpTree) -> PlanNode {
+        let input = PlanNode::new(Operator::FullScan, self.rows());
+        PlanNode::new(
+            Operator::Filter {
+                input: Box::new(input),
+                predicate,
+            },
+            self.estimate(DEFAULT_SELECTIVITY),
+        )
+    }
+
+    fn complement(&self, node: PlanNode) -> PlanNode {
+        let rows = self.rows().saturating_sub(node.estimated_rows);
+        PlanNode::new(Operator::Complement(Box::new(node)), rows)
+    }
+
+    /// Turn a comparison between an indexed column and a constant into an index scan
+    fn plan_comparison(&self, tree: &OpTree, a: &Value, b: &Value) -> Option<PlanNode> {
+        let (column, value, flipped) = match (a, b) {
+            (Value::Literal(column), value) if value.is_constant() => (column, value, false),
+            (value, Value::Literal(column)) if value.is_constant() => (column, value, true),
+            _ => return None,
+        };
+        let index = self.table.index(column)?;
+        if index.kind != IndexKind::Scalar {
+            return None;
+        }
+
+        let value = value.clone();
+        let eq_rows = self.rows() / index.distinct_values.max(1);
+        let range = |lower, upper| ScanRange::Range { lower, upper };
+        let range = match (tree, flipped) {
+            (OpTree::Eq(..), _) => {
+                return Some(self.index_scan(index, ScanRange::Eq(value), eq_rows))
+            }
+            // Every indexed value on either side, nulls aren't unequal to anything
+            (OpTree::Neq(..), _) => {
+                let rows = self.rows().saturating_sub(eq_rows);
+                let scans = vec![
+                    self.index_scan(
+                        index,
+                        range(Bound::Unbounded, Bound::Excluded(value.clone())),
+                        rows / 2,
+                    ),
+                    self.index_scan(
+                        index,
+                        range(Bound::Excluded(value), Bound::Unbounded),
+                        rows - rows / 2,
+                    ),
+                ];
+                return Some(PlanNode::new(Operator::Union(scans), rows));
+            }
+            (OpTree::Lt(..), false) | (OpTree::Gt(..), true) => {
+                range(Bound::Unbounded, Bound::Excluded(value))
+            }
+            (OpTree::Lte(..), false) | (OpTree::Gte(..), true) => {
+                range(Bound::Unbounded, Bound::Included(value))
+            }
+            (OpTree::Gt(..), false) | (OpTree::Lt(..), true) => {
+                range(Bound::Excluded(value), Bound::Unbounded)
+            }
+            (OpTree::Gte(..), false) | (OpTree::Lte(..), true) => {
+                range(Bound::Included(value), Bound::Unbounded)
+            }
+            _ => return None,
+        };
+        Some(self.index_scan(index, range, self.estimate(DEFAULT_SELECTIVITY)))
+    }
+
+    /// The scalar index on a column
+    fn scalar_index(&self, column: &Value) -> Option<&'a IndexInfo> {
+        let Value::Literal(column) = column else {
+            return None;
+        };
+        self.table
+            .index(column)
+            .filter(|index| index.kind == IndexKind::Scalar)
+    }
+
+    fn index_scan(&self, index: &IndexInfo, range: ScanRange, rows: u64) -> PlanNode {
+        PlanNode::new(
+            Operator::IndexScan {
+                column: index.column.clone(),
+                range,
+            },
+            rows,
+        )
+    }
+
+    /// One index lookup per listed value
+    fn plan_in(&self, column: &Value, values: &[Value]) -> Option<PlanNode> {
+        let index = self.scalar_index(column)?;
+        if !values.iter().all(Value::is_constant) {
+            return None;
+        }
+
+        let rows = self.rows() / index.distinct_values.max(1);
+        let mut scans: Vec<PlanNode> = values
+            .iter()
+            .map(|v| self.index_scan(index, ScanRange::Eq(v.clone()), rows))
+            .collect();
+        if scans.len() == 1 {
+            return scans.pop();
+        }
+        let rows = (rows * scans.len() as u64).min(self.rows());
+        Some(PlanNode::new(Operator::Union(scans), rows))
+    }
+
+    fn plan_between(&self, column: &Value, lower: &Value, upper: &Value) -> Option<PlanNode> {
+        let index = self.scalar_index(column)?;
+        if !lower.is_constant() || !upper.is_constant() {
+            return None;
+        }
+        let range = ScanRange::Range {
+            lower: Bound::Included(lower.clone()),
+            upper: Bound::Included(upper.clone()),
+        };
+        Some(self.index_scan(index, range, self.estimate(DEFAULT_SELECTIVITY)))
+    }
+
+    /// Patterns starting with a literal prefix scan the index range of strings with that
+    /// prefix, filtering the scanned rows when the rest of the pattern isn't just `%`.
+    fn plan_like(&self, tree: &OpTree, column: &Value, pattern: &Value) -> Option<PlanNode> {
+        let index = self.scalar_index(column)?;
+        let Value::String(pattern) = pattern else {
+            return None;
+        };
+
+        let (prefix, exact) = match like_prefix(pattern) {
+            LikePrefix::Exact(value) => {
+                let rows = self.rows() / index.distinct_values.max(1);
+                return Some(self.index_scan(index, ScanRange::Eq(Value::String(value)), rows));
+            }
+            LikePrefix::Prefix(prefix) => (prefix, true),
+            LikePrefix::Partial(prefix) => (prefix, false),
+        };
+        if prefix.is_empty() {
+            return None;
+        }
+
+        let upper = match prefix_successor(&prefix) {
+            Some(upper) => Bound::Excluded(Value::String(upper)),
+            None => Bound::Unbounded,
+        };
+        let range = ScanRange::Range {
+            lower: Bound::Included(Value::String(prefix)),
+            upper,
+        };
+        let scan = self.index_scan(index, range, self.estimate(DEFAULT_SELECTIVITY));
+        if exact {
+            return Some(scan);
+        }
+        let rows = (scan.estimated_rows as f64 * DEFAULT_SELECTIVITY).ceil() as u64;
+        Some(PlanNode::new(
+            Operator::Filter {
+                input: Box::new(scan),
+                predicate: tree.clone(),
+            },
+            rows,
+        ))
+    }
+
+    /// Every row with a non-null value in an indexed column
+    fn non_null_scan(&self, index: &IndexInfo) -> PlanNode {
+        let range = ScanRange::Range {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        };
+        let rows = self.rows() - self.estimate(NULL_SELECTIVITY).min(self.rows());
+        self.index_scan(index, range, rows)
+    }
+
+    /// Indexes only hold non-null values, so null checks are answered by the full index range
+    /// or its complement.
+    fn plan_null_check(&self, tree: &OpTree, column: &Value) -> Option<PlanNode> {
+        let scan = self.non_null_scan(self.scalar_index(column)?);
+        Some(match tree {
+            OpTree::IsNull(_) => self.complement(scan),
+            _ => scan,
+        })
+    }
+
+    fn plan_vector_search(
+        &self,
+        vector: VectorPredicate,
+        prefilter: Option<PlanNode>,
+    ) -> error::Result<PlanNode> {
+        let VectorPredicate {
+            column,
+            search,
+            target,
+            metric,
+        } = vector;
+        let Value::Literal(column) = column else {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "vector predicates need a column on the left hand side, got {}",
+                column
+            ))
+            .into());
+        };
+        let metric = self.checked_metric(column, metric)?;
+
+        let candidates = prefilter
+            .as_ref()
+            .map(|p| p.estimated_rows)
+            .unwrap_or(self.rows());
+        let rows = match &search {
+            VectorSearch::TopK(Value::Integer(k)) => u64::try_from(*k)
+                .map_err(|_| {
+                    error::CustomErrors::InvalidArguments(format!(
+                        "topk needs a count of zero or more, got {}",
+                        k
+                    ))
+                })?
+                .min(candidates),
+            VectorSearch::TopK(_) => (candidates as f64 * DEFAULT_SELECTIVITY).ceil() as u64,
+            VectorSearch::Within(_) => (candidates as f64 * WITHIN_SELECTIVITY).ceil() as u64,
+        };
+
+        Ok(PlanNode::new(
+            Operator::VectorSearch {
+                column: column.clone(),
+                search,
+                target,
+                metric,
+                prefilter: prefilter.map(Box::new),
+            },
+            rows,
+        ))
+    }
+
+    fn plan_and(&self, conjuncts: &[&OpTree]) -> error::Result<PlanNode> {
+        // The first topk search or fusion drives the plan, everything else filters the rows it
+        // can return
+        let driver = conjuncts
+            .iter()
+            .position(|t| is_topk(t) || matches!(t, OpTree::Fuse(..)));
+        let filters = || {
+            conjuncts
+                .iter()
+                .enumerate()
+                .filter(move |(i, _)| Some(*i) != driver)
+                .map(|(_, t)| *t)
+        };
+        if let Some(t) = filters().find(|t| has_fusion(t)) {
+            return Err(misplaced_fusion(t));
+        }
+        if let Some(OpTree::Fuse(method, searches)) = driver.map(|i| conjuncts[i]) {
+            return self.plan_fusion(method, searches, &filters().collect::<Vec<_>>());
+        }
+
+        let mut indexed = Vec::new();
+        let mut residual = Vec::new();
+        for conjunct in filters() {
+            let node = self.plan(conjunct)?;
+            if node.is_full_scan() {
+                residual.push(conjunct.clone());
+            } else {
+                indexed.push(node);
+            }
+        }
+
+        let filter = self.intersect(indexed, residual);
+        let Some(driver) = driver else {
+            // Conjunctions always have at least two terms, so without a driver there is a filter
+            return Ok(filter.expect("conjunction without terms"));
+        };
+        let vector = vector_predicate(conjuncts[driver]).expect("vector predicate");
+        let Some(filter) = filter else {
+            return self.plan_vector_search(vector, None);
+        };
+
+        let selectivity = self.selectivity(&filter);
+        match vector.search {
+            VectorSearch::TopK(Value::Integer(k)) if selectivity > PREFILTER_THRESHOLD => {
+                // Over-fetch enough results that roughly k of them should survive the filter
+                let fetch = (k as f64 / selectivity).ceil() as i64;
+                let search = self.plan_vector_search(
+                    VectorPredicate {
+                        search: VectorSearch::TopK(Value::Integer(fetch)),
+                        ..vector
+                    },
+                    None,
+                )?;
+                let rows = (k as u64).min(filter.estimated_rows);
+                Ok(PlanNode::new(
+                    Operator::PostFilter {
+                        input: Box::new(search),
+                        filter: Box::new(filter),
+                        limit: Value::Integer(k),
+                    },
+                    rows,
+                ))
+            }
+            _ => self.plan_vector_search(vector, Some(filter)),
+        }
+    }
+
+    /// Run each search, narrowed down by the filters, and rank the rows they find together
+    fn plan_fusion(
+        &self,
+        method: &FusionMethod,
+        searches: &[OpTree],
+        filters: &[&OpTree],
+    ) -> error::Result<PlanNode> {
+        if let FusionMethod::Weighted(weights) = method {
+            if weights.len() != searches.len() {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "fuse has {} searches but {} weights",
+                    searches.len(),
+                    weights.len()
+                ))
+                .into());
+            }
+        }
+
+        let mut inputs = Vec::with_capacity(searches.len());
+        for search in searches {
+            if !is_topk(search) {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "fuse combines topk searches, got {}",
+                    search
+                ))
+                .into());
+            }
+            let input = if filters.is_empty() {
+                self.plan(search)?
+            } else {
+                let mut conjuncts = vec![search];
+                conjuncts.extend(filters);
+                self.plan_and(&conjuncts)?
+            };
+            inputs.push(input);
+        }
+
+        let rows = inputs.iter().map(|node| node.estimated_rows).sum::<u64>();
+        Ok(PlanNode::new(
+            Operator::Fuse {
+                method: method.clone(),
+                inputs,
+            },
+            rows.min(self.rows()),
+        ))
+    }
+
+    /// Intersect index backed nodes and filter the result with the residual predicates
+    fn intersect(&self, mut indexed: Vec<PlanNode>, residual: Vec<OpTree>) -> Option<PlanNode> {
+        let input = match indexed.len() {
+            0 => None,
+            1 => indexed.pop(),
+            _ => {
+                let selectivity: f64 = indexed.iter().map(|n| self.selectivity(n)).product();
+                let rows = self.estimate(selectivity);
+                let mut nodes = Vec::new();
+                for node in indexed {
+                    match node.op {
+                        Operator::Intersect(children) => nodes.extend(children),
+                        op => nodes.push(PlanNode::new(op, node.estimated_rows)),
+                    }
+                }
+                Some(PlanNode::new(Operator::Intersect(nodes), rows))
+            }
+        };
+
+        let predicate = residual
+            .into_iter()
+            .reduce(|a, b| OpTree::And(Box::new(a), Box::new(b)));
+        match (input, predicate) {
+            (input, None) => input,
+            (None, Some(predicate)) => Some(self.full_scan(predicate)),
+            (Some(input), Some(predicate)) => {
+                let rows = (input.estimated_rows as f64 * DEFAULT_SELECTIVITY).ceil() as u64;
+                Some(PlanNode::new(
+                    Operator::Filter {
+                        input: Box::new(input),
+                        predicate,
+                    },
+                    rows,
+                ))
+            }
+        }
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`, `None` when there is
+/// no such string.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// A vector predicate taken apart
+struct VectorPredicate<'t> {
+    column: &'t Value,
+    search: VectorSearch,
+    target: Value,
+    metric: Option<&'t str>,
+}
+
+/// The parts of a vector predicate, `None` for other predicates
+fn vector_predicate(tree: &OpTree) -> Option<VectorPredicate<'_>> {
+    let (column, search, target) = match tree {
+        OpTree::Within(column, radius) => (column, VectorSearch::Within(radius.clone()), None),
+        OpTree::TopK(column, k) => (column, VectorSearch::TopK(k.clone()), None),
+        OpTree::WithinOf(column, radius, target) => {
+            (column, VectorSearch::Within(radius.clone()), Some(target))
+        }
+        OpTree::TopKNear(column, k, target) => {
+            (column, VectorSearch::TopK(k.clone()), Some(target))
+        }
+        _ => return None,
+    };
+    Some(VectorPredicate {
+        column,
+        search,
+        target: target.map_or(DEFAULT_TARGET, |t| t.vector.clone()),
+        metric: target.and_then(|t| t.metric.as_deref()),
+    })
+}
+
+fn is_topk(tree: &OpTree) -> bool {
+    matches!(
+        vector_predicate(tree),
+        Some(VectorPredicate {
+            search: VectorSearch::TopK(_),
+            ..
+        })
+    )
+}
+
+fn has_fusion(tree: &OpTree) -> bool {
+    match tree {
+        OpTree::Fuse(..) => true,
+        OpTree::And(a, b) | OpTree::Or(a, b) => has_fusion(a) || has_fusion(b),
+        OpTree::Not(t) => has_fusion(t),
+        _ => false,
+    }
+}
+
+/// Fusion ranks the rows it finds, which only filters can narrow down
+fn misplaced_fusion(tree: &OpTree) -> error::Error {
+    error::CustomErrors::InvalidArguments(format!(
+        "fuse can only be combined with filters using &&, got {}",
+        tree
+    ))
+    .into()
+}
+
+fn has_vector_predicate(tree: &OpTree) -> bool {
+    match tree {
+        OpTree::Within(..)
+        | OpTree::TopK(..)
+        | OpTree::WithinOf(..)
+        | OpTree::TopKNear(..)
+        | OpTree::Fuse(..) => true,
+        OpTree::And(a, b) | OpTree::Or(a, b) => has_vector_predicate(a) || has_vector_predicate(b),
+        OpTree::Not(t) => has_vector_predicate(t),
+        _ => false,
+    }
+}
+
+/// Check the expressions a predicate computes are given arguments they accept
+fn check_types(tree: &OpTree, table: &TableInfo) -> error::Result<()> {
+    match tree {
+        OpTree::And(a, b) | OpTree::Or(a, b) => {
+            check_types(a, table)?;
+            check_types(b, table)
+        }
+        OpTree::Not(t) => check_types(t, table),
+        OpTree::Fuse(_, searches) => searches.iter().try_for_each(|t| check_types(t, table)),
+        leaf => leaf_values(leaf)
+            .into_iter()
+            .try_for_each(|v| value_type(v, table).map(drop)),
+    }
+}
+
+fn flatten_and<'t>(tree: &'t OpTree, out: &mut Vec<&'t OpTree>) {
+    match tree {
+        OpTree::And(a, b) => {
+            flatten_and(a, out);
+            flatten_and(b, out);
+        }
+        t => out.push(t),
+    }
+}
+
+/// Replace the json fields a generated column holds with the column, so that they can use its
+/// index. The column holds the field converted to its type or null, so a comparison only reads
+/// the column when the field's other values couldn't match either.
+fn resolve_fields(tree: &OpTree, table: &TableInfo) -> OpTree {
+    // The column to compare with `others` in place of `field`
+    let column = |field: &Value, others: &[&Value]| {
+        let Value::Field(path) = field else {
+            return None;
+        };
+        let column = table.generated_column(path)?;
+        let alike = others.iter().all(|v| {
+            // Parameters are checked against the column's type before they are bound
+            let data_type = match v {
+                Value::ResourceTag(_) => Some(column.data_type),
+                v => value_type(v, table).ok().flatten(),
+            };
+            !has_field(v) && compares_alike(column.data_type, data_type)
+        });
+        alike.then(|| Value::Literal(column.name.clone()))
+    };
+    let compare = |a: &Value, b: &Value| match (column(a, &[b]), column(b, &[a])) {
+        (Some(a), _) => (a, b.clone()),
+        (_, Some(b)) => (a.clone(), b),
+        _ => (a.clone(), b.clone()),
+    };
+    let operand = |t: &OpTree| Box::new(resolve_fields(t, table));
+    match tree {
+        OpTree::Eq(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Eq(a, b)
+        }
+        OpTree::Neq(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Neq(a, b)
+        }
+        OpTree::Lt(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Lt(a, b)
+        }
+        OpTree::Lte(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Lte(a, b)
+        }
+        OpTree::Gt(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Gt(a, b)
+        }
+        OpTree::Gte(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Gte(a, b)
+        }
+        OpTree::Like(a, b) => {
+            let (a, b) = compare(a, b);
+            OpTree::Like(a, b)
+        }
+        OpTree::In(a, values) => OpTree::In(
+            column(a, &values.iter().collect::<Vec<_>>()).unwrap_or_else(|| a.clone()),
+            values.clone(),
+        ),
+        OpTree::Between(a, lower, upper) => OpTree::Between(
+            column(a, &[lower, upper]).unwrap_or_else(|| a.clone()),
+            lower.clone(),
+            upper.clone(),
+        ),
+        // A field without a value always converts to null, but the column is also null for
+        // values that don't convert, so the field is checked as well
+        OpTree::IsNull(Value::Field(path)) => match table.generated_column(path) {
+            Some(column) => OpTree::And(
+                Box::new(OpTree::IsNull(Value::Literal(column.name.clone()))),
+                Box::new(tree.clone()),
+            ),
+            None => tree.clone(),
+        },
+        OpTree::Value(v @ Value::Field(path)) => match table.generated_column(path) {
+            Some(column) if column.data_type == ColumnTypes::Bool => {
+                OpTree::Value(Value::Literal(column.name.clone()))
+            }
+            _ => OpTree::Value(v.clone()),
+        },
+        OpTree::IsNull(_) | OpTree::IsNotNull(_) | OpTree::Value(_) => tree.clone(),
+        OpTree::And(a, b) => OpTree::And(operand(a), operand(b)),
+        OpTree::Or(a, b) => OpTree::Or(operand(a), operand(b)),
+        OpTree::Not(t) => OpTree::Not(operand(t)),
+        OpTree::Fuse(method, searches) => OpTree::Fuse(
+            method.clone(),
+            searches.iter().map(|t| resolve_fields(t, table)).collect(),
+        ),
+        // Vector predicates read vector columns, which are never generated
+        OpTree::Within(..) | OpTree::TopK(..) | OpTree::WithinOf(..) | OpTree::TopKNear(..) => {
+            tree.clone()
+        }
+    }
+}
+
+fn has_field(value: &Value) -> bool {
+    match value {
+        Value::Field(_) => true,
+        Value::Expr(expr) => expr.operands().into_iter().any(has_field),
+        _ => false,
+    }
+}
+
+/// Whether comparing a field with a value of type `other` gives the same result as comparing
+/// its conversion to a `column`. Values that don't convert compare with neither, but times,
+/// uuids and intervals convert from strings, and floats don't convert to integers.
+fn compares_alike(column: ColumnTypes, other: Option<ColumnTypes>) -> bool {
+    matches!(
+        (column, other),
+        (ColumnTypes::String, Some(ColumnTypes::String))
+            | (ColumnTypes::Bool, Some(ColumnTypes::Bool))
+            | (ColumnTypes::F64, Some(ColumnTypes::I64 | ColumnTypes::F64))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use query_parser::query::QueryParser;
+
+    use super::*;
+
+    fn table() -> TableInfo {
+        TableInfo::new("docs", 10_000)
+            .with_index("id", IndexKind::Scalar, 10_000)
+            .with_index("source", IndexKind::Scalar, 4)
+            .with_index("vector", IndexKind::Vector(DistanceMetric::Hamming), 10_000)
+    }
+
+    fn plan_str(query: &str) -> error::Result<Plan> {
+        let query = Query {
+            table: "docs".into(),
+            partitions: vec![],
+            query: QueryParser::new().parse(query).unwrap(),
+            parameters: vec![],
+            columns: vec![],
+            select: vec![],
+        };
+        plan(&query, &table())
+    }
+
+    #[test]
+    fn index_scans() {
+        let plan = plan_str("id == 1").unwrap();
+        assert_eq!(
+            plan.root,
+            PlanNode::new(
+                Operator::IndexScan {
+                    column: "id".into(),
+                    range: ScanRange::Eq(Value::Integer(1))
+                },
+                1
+            )
+        );
+
+        // Constants on the left flip the comparison
+        let plan = plan_str("5 < id").unwrap();
+        assert_eq!(
+            plan.root.op,
+            Operator::IndexScan {
+                column: "id".into(),
+                range: ScanRange::Range {
+                    lower: Bound::Excluded(Value::Integer(5)),
+                    upper: Bound::Unbounded
+                }
+            }
+        );
+
+        // Both sides of the value, nulls aren't unequal to anything
+        let plan = plan_str("source != 1").unwrap();
+        assert!(matches!(&plan.root.op, Operator::Union(nodes) if nodes.len() == 2));
+        assert_eq!(plan.root.estimated_rows, 7_500);
+    }
+
+    #[test]
+    fn normalised_predicates() {
+        let plan = plan_str("(id > 5) && ((source == 1) && (id < 3))").unwrap();
+        assert_eq!(plan.root, PlanNode::new(Operator::Empty, 0));
+
+        let plan = plan_str("!((id == 1) || (source <= 2))").unwrap();
+        assert!(matches!(&plan.root.op, Operator::Intersect(nodes) if nodes.len() == 2));
+
+        let plan = plan_str("(1 == 1) && (!(source in (1, 2)))").unwrap();
+        let Operator::Intersect(nodes) = &plan.root.op else {
+            panic!(
+                "expected the non-null values outside the list, got {}",
+                plan
+            );
+        };
+        assert!(matches!(nodes[1].op, Operator::Complement(_)));
+    }
+
+    #[test]
+    fn bitmap_operators() {
+        let plan = plan_str("(id == 1) || (source == 2)").unwrap();
+        assert!(matches!(&plan.root.op, Operator::Union(nodes) if nodes.len() == 2));
+
+        let plan = plan_str("((id > 1) && (source == 2)) && (id < 5)").unwrap();
+        assert!(matches!(&plan.root.op, Operator::Intersect(nodes) if nodes.len() == 3));
+
+        // Unindexed columns are filtered after the index lookups
+        let plan = plan_str("(id > 1) && (other == 2)").unwrap();
+        let Operator::Filter { input, predicate } = plan.root.op else {
+            panic!("expected a filter")
+        };
+        assert!(matches!(input.op, Operator::IndexScan { .. }));
+        assert_eq!(predicate.to_string(), "other == 2");
+
+        // Anything unindexed in a disjunction needs a full scan
+        let plan = plan_str("(id == 1) || (other == 2)").unwrap();
+        assert!(plan.root.is_full_scan());
+    }
+
+    #[test]
+    fn vector_filtering() {
+        // A selective filter restricts the search space up front
+        let plan = plan_str("(vector topk 10) && (id == 1)").unwrap();
+        let Operator::VectorSearch { prefilter, .. } = &plan.root.op else {
+            panic!("expected a vector search")
+        };
+        assert!(prefilter.is_some());
+
+        // An unselective filter is applied to an over-fetched result
+        let plan = plan_str("(vector topk 10) && (source == 1)").unwrap();
+        let Operator::PostFilter { input, limit, .. } = &plan.root.op else {
+            panic!("expected a post filter")
+        };
+        assert_eq!(limit, &Value::Integer(10));
+        assert!(matches!(
+            &input.op,
+            Operator::VectorSearch {
+                search: VectorSearch::TopK(Value::Integer(40)),
+                prefilter: None,
+                ..
+            }
+        ));
+
+        assert!(plan_str("id topk 10").is_err());
+        assert!(plan_str("vector topk -1").is_err());
+    }
+
+    #[test]
+    fn vector_targets() {
+        let plan = plan_str("(vector topk 10 near $2 using hamming) && (id == 1)").unwrap();
+        let Operator::VectorSearch {
+            target, prefilter, ..
+        } = &plan.root.op
+        else {
+            panic!("expected a vector search")
+        };
+        assert_eq!(target, &Value::ResourceTag(2));
+        assert!(prefilter.is_some());
+
+        let plan = plan_str("vector within 4 of $3").unwrap();
+        assert_eq!(
+            plan.root.op,
+            Operator::VectorSearch {
+                column: "vector".into(),
+                search: VectorSearch::Within(Value::Integer(4)),
+                target: Value::ResourceTag(3),
+                metric: DistanceMetric::Hamming,
+                prefilter: None,
+            }
+        );
+
+        // The metric has to be the one the column is indexed for
+        assert!(plan_str("vector topk 10 near $1 using cosine").is_err());
+        assert!(plan_str("id within 4 of $1 using hamming").is_err());
+    }
+
+    #[test]
+    fn fusion() {
+        let plan =
+            plan_str("(fuse(rrf, vector topk 10 near $1, vector topk 20 near $2)) && (id == 1)")
+                .unwrap();
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 Fuse rrf(60) (rows=2)\n\
+            \x20   VectorSearch vector topk 10 near $1 using Hamming prefiltered (rows=1)\n\
+            \x20     IndexScan id == 1 (rows=1)\n\
+            \x20   VectorSearch vector topk 20 near $2 using Hamming prefiltered (rows=1)\n\
+            \x20     IndexScan id == 1 (rows=1)\n"
+        );
+
+        let plan = plan_str("fuse(weighted(0.7, 0.3), vector topk 10, vector topk 10 near $2)");
+        assert!(matches!(plan.unwrap().root.op, Operator::Fuse { .. }));
+
+        for query in [
+            "fuse(weighted(1), vector topk 10, vector topk 10 near $2)",
+            "fuse(rrf, vector within 4, vector topk 10 near $2)",
+            "fuse(rrf, (vector topk 10) && (id == 1))",
+            "fuse(rrf, id topk 10)",
+            "(fuse(rrf, vector topk 10)) || (id == 1)",
+            "!(fuse(rrf, vector topk 10))",
+            "(id == 1) && ((id == 2) || (fuse(rrf, vector topk 10)))",
+        ] {
+            assert!(plan_str(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn explain() {
+        let plan = plan_str("(vector topk 10) && ((source == 1) && (other > 2))").unwrap();
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 VectorSearch vector topk 10 near $1 using Hamming prefiltered (rows=10)\n\
+            \x20   Filter other > 2 (rows=834)\n\
+            \x20     IndexScan source == 1 (rows=2500)\n"
+        );
+
+        let plan = plan_str("id > 5 order by source desc, distance(vector, $2) limit 10 offset 5")
+            .unwrap();
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 TopN order by source desc, distance(vector, $2) using Hamming limit 10 offset 5 (rows=10)\n\
+            \x20   IndexScan 5 < id (rows=3334)\n"
+        );
+
+        assert!(plan_str("id > 5 order by distance(id, $1)").is_err());
+    }
+
+    #[test]
+    fn aggregation() {
+        let plan = plan_str("count(*) group by source where vector within 4").unwrap();
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 HashAggregate count(*) group by source (rows=4)\n\
+            \x20   VectorSearch vector within 4 near $1 using Hamming (rows=1000)\n"
+        );
+
+        let plan = plan_str("count(*), avg(id) order by id limit 1").unwrap_err();
+        assert!(plan.to_string().contains("grouped columns"));
+
+        let plan = plan_str("max(id) group by source order by source desc limit 2").unwrap();
+        assert_eq!(plan.estimated_rows(), 2);
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 TopN order by source desc limit 2 (rows=2)\n\
+            \x20   HashAggregate max(id) group by source (rows=4)\n\
+            \x20     FullScan (rows=10000)\n"
+        );
+    }
+
+    #[test]
+    fn predicates() {
+        let plan = plan_str("source in (1, 2, $1)").unwrap();
+        assert!(matches!(&plan.root.op, Operator::Union(nodes) if nodes.len() == 3));
+        assert_eq!(plan.root.estimated_rows, 7_500);
+        assert!(plan_str("other in (1, 2)").unwrap().root.is_full_scan());
+
+        let plan = plan_str("id between 1 and $1").unwrap();
+        assert_eq!(
+            plan.root.op,
+            Operator::IndexScan {
+                column: "id".into(),
+                range: ScanRange::Range {
+                    lower: Bound::Included(Value::Integer(1)),
+                    upper: Bound::Included(Value::ResourceTag(1)),
+                }
+            }
+        );
+
+        let plan = plan_str("(source like \"ab%\") && (id > 2)").unwrap();
+        assert_eq!(
+            plan.explain(),
+            "Plan for docs\n\
+            \x20 Intersect (rows=1112)\n\
+            \x20   IndexScan \"ab\" <= source < \"ac\" (rows=3334)\n\
+            \x20   IndexScan 2 < id (rows=3334)\n"
+        );
+
+        // The rest of the pattern is checked against the rows with the prefix
+        let plan = plan_str("source like \"ab_d\"").unwrap();
+        let Operator::Filter { input, .. } = &plan.root.op else {
+            panic!("expected a filter")
+        };
+        assert!(matches!(input.op, Operator::IndexScan { .. }));
+        assert!(plan_str("source like \"%b\"").unwrap().root.is_full_scan());
+
+        let plan = plan_str("source is null").unwrap();
+        assert!(matches!(plan.root.op, Operator::Complement(_)));
+        assert_eq!(plan.root.estimated_rows, 1_000);
+        let plan = plan_str("source is not null").unwrap();
+        assert!(matches!(plan.root.op, Operator::IndexScan { .. }));
+    }
+
+    #[test]
+    fn prefix_successors() {
+        assert_eq!(prefix_successor("ab").as_deref(), Some("ac"));
+        assert_eq!(prefix_successor("a\u{10FFFF}").as_deref(), Some("b"));
+        assert_eq!(prefix_successor("\u{D7FF}").as_deref(), Some("\u{E000}"));
+        assert_eq!(prefix_successor("\u{10FFFF}"), None);
+    }
+}
@@ -0,0 +1,596 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use intake::{ColumnTypes, DataValue, Query};
+use query_parser::{ArithmeticOp, Expr, Function, OpTree, SortExpr, Value};
+
+use crate::{
+    catalog::TableInfo,
+    executor::{execute_with, ExecutionOptions, QueryResults},
+    functions::value_type,
+    plan::Plan,
+    planner::{plan, DEFAULT_TARGET},
+    source::TableSource,
+};
+
+/// Identifies a statement prepared in a [`StatementCache`].
+pub type StatementId = u64;
+
+/// What a statement's parameter has to be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterType {
+    /// Anything, the statement only compares it with other constants
+    Any,
+    /// A value of the column's type or null, integers and floats stand in for each other
+    Column(ColumnTypes),
+    /// A non-negative integer, such as the `k` of `topk`
+    Count,
+    /// A number, such as the radius of `within`
+    Number,
+    /// The target of a vector search, with the bit width of the searched column when known
+    Vector(Option<u32>),
+}
+
+impl ParameterType {
+    pub fn accepts(&self, value: &DataValue) -> bool {
+        use ColumnTypes as T;
+        use DataValue as V;
+        match (self, value) {
+            (ParameterType::Any, _) => true,
+            (ParameterType::Count, V::I64(k)) => *k >= 0,
+            (ParameterType::Number, V::I64(_) | V::F64(_)) => true,
+            (ParameterType::Vector(bits), V::Bytes(vector)) => {
+                bits.is_none_or(|bits| vector_bytes(bits) == vector.len())
+            }
+            (ParameterType::Column(_), V::Null) => true,
+            (ParameterType::Column(column), value) => match (column, value) {
+                (T::I64 | T::F64, V::I64(_) | V::F64(_)) => true,
+                (T::BinaryVector(bits), V::Bytes(vector)) => vector_bytes(*bits) == vector.len(),
+                (T::String, V::String(_))
+                | (T::Bool, V::Bool(_))
+                | (T::DateTime, V::DateTime(_))
+                | (T::Interval, V::Interval(_))
+                | (T::UUID, V::UUID(_))
+                | (T::Bytes, V::Bytes(_))
+                | (T::Json, V::Json(_)) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ParameterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParameterType::Any => write!(f, "any value"),
+            ParameterType::Column(ColumnTypes::BinaryVector(bits)) => {
+                write!(f, "a binary_vector({}) or null", bits)
+            }
+            ParameterType::Column(column) => {
+                write!(f, "a {} or null", format!("{:?}", column).to_lowercase())
+            }
+            ParameterType::Count => write!(f, "a non-negative integer"),
+            ParameterType::Number => write!(f, "a number"),
+            ParameterType::Vector(Some(bits)) => write!(f, "a {} bit vector", bits),
+            ParameterType::Vector(None) => write!(f, "a binary vector"),
+        }
+    }
+}
+
+fn vector_bytes(bits: u32) -> usize {
+    (bits as usize).div_ceil(8)
+}
+
+/// Works out the parameters a query takes from where it uses them.
+struct Parameters<'t> {
+    table: &'t TableInfo,
+    types: Vec<ParameterType>,
+}
+
+impl<'t> Parameters<'t> {
+    fn expect(&mut self, value: &Value, expected: ParameterType) -> error::Result<()> {
+        if let Value::Expr(expr) = value {
+            return self.expression(expr);
+        }
+        let Value::ResourceTag(tag) = value else {
+            return Ok(());
+        };
+        let Some(i) = (*tag as usize).checked_sub(1) else {
+            return Err(error::CustomErrors::InvalidArguments(
+                "parameters are numbered from $1".to_string(),
+            )
+            .into());
+        };
+        if self.types.len() <= i {
+            self.types.resize(i + 1, ParameterType::Any);
+        }
+        match (self.types[i], expected) {
+            (_, ParameterType::Any) => {}
+            (ParameterType::Any, expected) => self.types[i] = expected,
+            (known, expected) if known == expected => {}
+            (known, expected) => {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "parameter ${} is used as both {} and {}",
+                    tag, known, expected
+                ))
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    fn column(&self, value: &Value) -> ParameterType {
+        match value {
+            Value::Literal(column) => self
+                .table
+                .column_type(column)
+                .map_or(ParameterType::Any, ParameterType::Column),
+            // Fields can hold anything, unless a generated column pins down their type
+            Value::Field(path) => self
+                .table
+                .generated_column(path)
+                .map_or(ParameterType::Any, |c| ParameterType::Column(c.data_type)),
+            Value::Expr(_) => match value_type(value, self.table) {
+                Ok(Some(data_type)) => ParameterType::Column(data_type),
+                _ => ParameterType::Any,
+            },
+            _ => ParameterType::Any,
+        }
+    }
+
+    /// Parameters an expression computes with have to be what its operator or function takes
+    fn expression(&mut self, expr: &Expr) -> error::Result<()> {
+        let string = ParameterType::Column(ColumnTypes::String);
+        match expr {
+            Expr::Arithmetic(op, a, b) => {
+                // Times only move by intervals, what else meets times and intervals depends
+                // on the side and operator
+                let operand = |other| match (op, other) {
+                    (_, ParameterType::Any) => ParameterType::Number,
+                    (ArithmeticOp::Add, ParameterType::Column(ColumnTypes::DateTime)) => {
+                        ParameterType::Column(ColumnTypes::Interval)
+                    }
+                    (_, ParameterType::Column(ColumnTypes::DateTime | ColumnTypes::Interval)) => {
+                        ParameterType::Any
+                    }
+                    (_, t) => t,
+                };
+                let (a_type, b_type) = (self.column(a), self.column(b));
+                self.expect(a, operand(b_type))?;
+                self.expect(b, operand(a_type))
+            }
+            Expr::Call(function, arguments) => match (function, arguments.as_slice()) {
+                (Function::DateTrunc, [unit, ts]) => {
+                    self.expect(unit, string)?;
+                    self.expect(ts, ParameterType::Column(ColumnTypes::DateTime))
+                }
+                (Function::Lower, [s]) => self.expect(s, string),
+                (Function::Hamming | Function::Cosine, [a, b]) => {
+                    self.expect(a, self.vector(b))?;
+                    self.expect(b, self.vector(a))
+                }
+                (_, arguments) => arguments
+                    .iter()
+                    .try_for_each(|v| self.expect(v, ParameterType::Any)),
+            },
+        }
+    }
+
+    fn vector(&self, column: &Value) -> ParameterType {
+        match self.column(column) {
+            ParameterType::Column(ColumnTypes::BinaryVector(bits)) => {
+                ParameterType::Vector(Some(bits))
+            }
+            _ => ParameterType::Vector(None),
+        }
+    }
+
+    /// Either side of a comparison has to be of the type of the other
+    fn compare(&mut self, a: &Value, b: &Value) -> error::Result<()> {
+        let (a_type, b_type) = (self.column(a), self.column(b));
+        self.expect(a, b_type)?;
+        self.expect(b, a_type)
+    }
+
+    fn tree(&mut self, tree: &OpTree) -> error::Result<()> {
+        match tree {
+            OpTree::Eq(a, b)
+            | OpTree::Neq(a, b)
+            | OpTree::Lt(a, b)
+            | OpTree::Lte(a, b)
+            | OpTree::Gt(a, b)
+            | OpTree::Gte(a, b)
+            | OpTree::Like(a, b) => self.compare(a, b),
+            OpTree::Within(column, radius) => {
+                self.expect(radius, ParameterType::Number)?;
+                self.expect(&DEFAULT_TARGET, self.vector(column))
+            }
+            OpTree::TopK(column, k) => {
+                self.expect(k, ParameterType::Count)?;
+                self.expect(&DEFAULT_TARGET, self.vector(column))
+            }
+            OpTree::WithinOf(column, radius, target) => {
+                self.expect(radius, ParameterType::Number)?;
+                self.expect(&target.vector, self.vector(column))
+            }
+            OpTree::TopKNear(column, k, target) => {
+                self.expect(k, ParameterType::Count)?;
+                self.expect(&target.vector, self.vector(column))
+            }
+            OpTree::Fuse(_, searches) => searches.iter().try_for_each(|s| self.tree(s)),
+            OpTree::In(a, values) => values.iter().try_for_each(|v| self.compare(a, v)),
+            OpTree::Between(a, lower, upper) => {
+                self.compare(a, lower)?;
+                self.compare(a, upper)
+            }
+            OpTree::IsNull(v) | OpTree::IsNotNull(v) => self.expect(v, ParameterType::Any),
+            OpTree::And(a, b) | OpTree::Or(a, b) => {
+                self.tree(a)?;
+                self.tree(b)
+            }
+            OpTree::Not(t) => self.tree(t),
+            OpTree::Value(v) => self.expect(v, ParameterType::Column(ColumnTypes::Bool)),
+        }
+    }
+}
+
+/// A query parsed and planned once, to be executed many times with different parameters.
+#[derive(Debug)]
+pub struct PreparedStatement {
+    query: Query,
+    plan: Plan,
+    parameters: Vec<ParameterType>,
+}
+
+impl PreparedStatement {
+    /// Plan `query` against `table` and work out the parameters it takes. Parameters already
+    /// bound to the query are ignored, they are given to every execution instead.
+    pub fn new(query: Query, table: &TableInfo) -> error::Result<Self> {
+        let plan = plan(&query, table)?;
+        let mut parameters = Parameters {
+            table,
+            types: Vec::new(),
+        };
+        parameters.tree(&query.query.filter)?;
+        for key in &query.query.order_by {
+            if let SortExpr::Distance(column, target) = &key.expr {
+                let vector = parameters.vector(&Value::Literal(column.clone()));
+                parameters.expect(target, vector)?;
+            }
+        }
+        Ok(PreparedStatement {
+            query,
+            plan,
+            parameters: parameters.types,
+        })
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn plan(&self) -> &Plan {
+        &self.plan
+    }
+
+    /// What each parameter, `$1` first, has to be bound to
+    pub fn parameters(&self) -> &[ParameterType] {
+        &self.parameters
+    }
+
+    /// Check `parameters` has a value of the right type for every parameter of the statement
+    pub fn check(&self, parameters: &[DataValue]) -> error::Result<()> {
+        if parameters.len() != self.parameters.len() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "statement takes {} parameters, got {}",
+                self.parameters.len(),
+                parameters.len()
+            ))
+            .into());
+        }
+        for (i, (expected, value)) in self.parameters.iter().zip(parameters).enumerate() {
+            if !expected.accepts(value) {
+                return Err(error::CustomErrors::InvalidArguments(format!(
+                    "parameter ${} needs {}, got {:?}",
+                    i + 1,
+                    expected,
+                    value
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the statement with `parameters` bound, see [`crate::execute`]
+    pub fn execute<'a>(
+        &'a self,
+        source: &'a dyn TableSource,
+        parameters: &'a [DataValue],
+        options: ExecutionOptions,
+    ) -> error::Result<QueryResults<'a>> {
+        self.check(parameters)?;
+        Ok(execute_with(
+            source,
+            &self.query,
+            parameters,
+            &self.plan,
+            options,
+        ))
+    }
+}
+
+/// Prepared statements by id, shared by everything executing them.
+#[derive(Debug)]
+pub struct StatementCache {
+    /// Most statements prepared at once
+    capacity: usize,
+    next_id: AtomicU64,
+    statements: RwLock<HashMap<StatementId, Arc<PreparedStatement>>>,
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> Self {
+        StatementCache {
+            capacity,
+            next_id: AtomicU64::new(1),
+            statements: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Prepare `query` against `table`, failing when the cache is full
+    pub fn prepare(&self, query: Query, table: &TableInfo) -> error::Result<StatementId> {
+        let statement = Arc::new(PreparedStatement::new(query, table)?);
+        let mut statements = self
+            .statements
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if statements.len() >= self.capacity {
+            return Err(error::CustomErrors::ResourceExhausted(format!(
+                "{} statements are already prepared",
+                statements.len()
+            ))
+            .into());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        statements.insert(id, statement);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: StatementId) -> error::Result<Arc<PreparedStatement>> {
+        self.statements
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!("unknown statement {}", id)).into()
+            })
+    }
+
+    /// Forget a statement, returning whether it was prepared. Executions already running
+    /// finish.
+    pub fn close(&self, id: StatementId) -> bool {
+        self.statements
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id)
+            .is_some()
+    }
+
+    /// Forget every statement reading `table`, for when its definition changes and their
+    /// plans may no longer hold. Returns how many were forgotten.
+    pub fn invalidate(&self, table: &str) -> usize {
+        let mut statements = self
+            .statements
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let before = statements.len();
+        statements.retain(|_, statement| statement.query.table != table);
+        before - statements.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+    use faiss::DistanceMetric;
+    use query_parser::Interval;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{IndexKind, MemoryTable};
+
+    fn table() -> (MemoryTable, TableInfo) {
+        let columns = vec!["id".into(), "source".into(), "vector".into(), "ts".into()];
+        let mut table = MemoryTable::new(columns);
+        for (id, source, vector) in [
+            (0, "a", 0b0000_0000),
+            (1, "b", 0b0000_0001),
+            (2, "a", 0b0000_0011),
+            (3, "b", 0b0000_0111),
+        ] {
+            table
+                .insert(vec![
+                    DataValue::I64(id),
+                    DataValue::String(source.into()),
+                    DataValue::Bytes(vec![vector]),
+                    DataValue::DateTime(day(id)),
+                ])
+                .unwrap();
+        }
+        let info = TableInfo::new("docs", 4)
+            .with_column("id", ColumnTypes::I64)
+            .with_column("source", ColumnTypes::String)
+            .with_column("vector", ColumnTypes::BinaryVector(8))
+            .with_column("ts", ColumnTypes::DateTime)
+            .with_index("id", IndexKind::Scalar, 4)
+            .with_index("vector", IndexKind::Vector(DistanceMetric::Hamming), 4);
+        (table, info)
+    }
+
+    fn day(n: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(n * 86_400, 0).unwrap()
+    }
+
+    fn prepare(select: &str) -> error::Result<PreparedStatement> {
+        let (_, info) = table();
+        PreparedStatement::new(Query::parse(select, vec![], &info.columns)?, &info)
+    }
+
+    async fn ids(
+        statement: &PreparedStatement,
+        parameters: Vec<DataValue>,
+    ) -> error::Result<Vec<DataValue>> {
+        let (table, _) = table();
+        let results = statement.execute(&table, &parameters, ExecutionOptions::default())?;
+        let rows = results.rows.collect::<error::Result<Vec<_>>>().await?;
+        Ok(rows.into_iter().map(|row| row[0].clone()).collect())
+    }
+
+    #[test]
+    fn infers_parameters() {
+        use ParameterType::*;
+        for (select, expected) in [
+            ("select id from docs", vec![]),
+            (
+                "select id from docs where (id > $1) && (source == $2)",
+                vec![Column(ColumnTypes::I64), Column(ColumnTypes::String)],
+            ),
+            (
+                "select id from docs where vector topk $2",
+                vec![Vector(Some(8)), Count],
+            ),
+            (
+                "select id from docs where vector within $1 of $3",
+                vec![Number, Any, Vector(Some(8))],
+            ),
+            (
+                "select id from docs where id in ($1, 2) order by distance(vector, $2)",
+                vec![Column(ColumnTypes::I64), Vector(Some(8))],
+            ),
+            ("select id from docs where $1 == 2", vec![Any]),
+            (
+                "select id from docs where lower(source) == lower($1)",
+                vec![Column(ColumnTypes::String)],
+            ),
+            (
+                "select id from docs where hamming(vector, $1) < $2 + 1",
+                vec![Vector(Some(8)), Number],
+            ),
+            (
+                "select id from docs where ts + $1 > now() - $2",
+                vec![Column(ColumnTypes::Interval), Any],
+            ),
+        ] {
+            assert_eq!(
+                prepare(select).unwrap().parameters(),
+                expected,
+                "{}",
+                select
+            );
+        }
+
+        assert!(prepare("select id from docs where (id == $1) && (source == $1)").is_err());
+        assert!(prepare("select id from docs where vector topk $1").is_err());
+        assert!(prepare("select id from docs where id == $0").is_err());
+        assert!(prepare("select id from docs where lower(id) == $1").is_err());
+    }
+
+    #[tokio::test]
+    async fn executes_with_parameters() {
+        let statement = prepare("select id from docs where (id >= $1) && (source == $2)").unwrap();
+        for (parameters, expected) in [
+            (
+                vec![DataValue::I64(1), DataValue::String("a".into())],
+                vec![2],
+            ),
+            (
+                vec![DataValue::I64(0), DataValue::String("b".into())],
+                vec![1, 3],
+            ),
+            (vec![DataValue::F64(0.5), DataValue::Null], vec![]),
+        ] {
+            let expected: Vec<_> = expected.into_iter().map(DataValue::I64).collect();
+            assert_eq!(ids(&statement, parameters).await.unwrap(), expected);
+        }
+
+        let statement = prepare("select id from docs where vector topk $2").unwrap();
+        let parameters = vec![DataValue::Bytes(vec![0b0000_0111]), DataValue::I64(2)];
+        let expected = vec![DataValue::I64(3), DataValue::I64(2)];
+        assert_eq!(ids(&statement, parameters).await.unwrap(), expected);
+
+        let statement = prepare("select id from docs where ts + $1 >= $2").unwrap();
+        let two_days = Interval::from_micros(2 * 86_400_000_000);
+        let parameters = vec![DataValue::Interval(two_days), DataValue::DateTime(day(3))];
+        let expected: Vec<_> = (1..4).map(DataValue::I64).collect();
+        assert_eq!(ids(&statement, parameters).await.unwrap(), expected);
+        let parameters = vec![
+            DataValue::String("2 days".into()),
+            DataValue::DateTime(day(3)),
+        ];
+        assert!(ids(&statement, parameters).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_parameters() {
+        let statement = prepare("select id from docs where (id >= $1) && (source == $2)").unwrap();
+        for parameters in [
+            vec![],
+            vec![DataValue::I64(1)],
+            vec![
+                DataValue::I64(1),
+                DataValue::String("a".into()),
+                DataValue::Null,
+            ],
+            vec![DataValue::String("1".into()), DataValue::String("a".into())],
+            vec![DataValue::I64(1), DataValue::Bool(true)],
+        ] {
+            assert!(ids(&statement, parameters).await.is_err());
+        }
+
+        let statement = prepare("select id from docs where vector topk $2").unwrap();
+        for parameters in [
+            vec![DataValue::Bytes(vec![0]), DataValue::I64(-1)],
+            vec![DataValue::Bytes(vec![0]), DataValue::F64(2.0)],
+            vec![DataValue::Bytes(vec![0, 0]), DataValue::I64(2)],
+            vec![DataValue::Null, DataValue::I64(2)],
+        ] {
+            assert!(ids(&statement, parameters).await.is_err());
+        }
+    }
+
+    #[test]
+    fn caches_statements() {
+        let (_, info) = table();
+        let cache = StatementCache::new(2);
+        let prepare = |select: &str| {
+            cache.prepare(Query::parse(select, vec![], &info.columns).unwrap(), &info)
+        };
+
+        let first = prepare("select id from docs where id == $1").unwrap();
+        let second = prepare("select id from docs where source == $1").unwrap();
+        assert_ne!(first, second);
+        assert!(prepare("select id from docs").is_err());
+        assert_eq!(
+            cache.get(second).unwrap().parameters(),
+            [ParameterType::Column(ColumnTypes::String)]
+        );
+
+        assert!(cache.close(first));
+        assert!(!cache.close(first));
+        assert!(cache.get(first).is_err());
+        assert!(prepare("select id from docs").is_ok());
+
+        assert_eq!(cache.invalidate("other"), 0);
+        assert_eq!(cache.invalidate("docs"), 2);
+        assert!(cache.get(second).is_err());
+    }
+}
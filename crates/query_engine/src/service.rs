@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use intake::{DataValue, Query};
+use sifter_proto::statements::{
+    prepare_request, statements_server::Statements, CloseRequest, CloseResponse, ExecuteRequest,
+    ExecuteResponse, PrepareRequest, PrepareResponse, Row,
+};
+use tokio_stream::StreamExt;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    catalog::TableInfo, executor::ExecutionOptions, prepared::StatementCache, source::TableSource,
+};
+
+/// Tables statements can read: what the planner knows about each one and where its rows are.
+pub trait Tables: Send + Sync + 'static {
+    fn table(&self, name: &str) -> Option<(TableInfo, Arc<dyn TableSource>)>;
+}
+
+/// gRPC service preparing statements against `tables` and executing them.
+pub struct StatementService<T> {
+    tables: T,
+    statements: StatementCache,
+}
+
+impl<T: Tables> StatementService<T> {
+    /// Serve `tables`, keeping at most `capacity` statements prepared at once
+    pub fn new(tables: T, capacity: usize) -> Self {
+        StatementService {
+            tables,
+            statements: StatementCache::new(capacity),
+        }
+    }
+
+    pub fn statements(&self) -> &StatementCache {
+        &self.statements
+    }
+}
+
+fn unknown_table(name: &str) -> Status {
+    Status::not_found(format!("unknown table {}", name))
+}
+
+fn status(error: error::Error) -> Status {
+    match error.downcast_ref::<error::CustomErrors>() {
+        Some(error::CustomErrors::InvalidArguments(_)) => {
+            Status::invalid_argument(error.to_string())
+        }
+        Some(error::CustomErrors::ResourceExhausted(_)) => {
+            Status::resource_exhausted(error.to_string())
+        }
+        Some(error::CustomErrors::Cancelled) => Status::cancelled(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Tables> Statements for StatementService<T> {
+    async fn prepare(
+        &self,
+        request: Request<PrepareRequest>,
+    ) -> Result<Response<PrepareResponse>, Status> {
+        let select = match request.into_inner().statement {
+            Some(prepare_request::Statement::Text(text)) => intake::parse_select(&text),
+            Some(prepare_request::Statement::Select(select)) => select.try_into(),
+            None => return Err(Status::invalid_argument("missing statement")),
+        }
+        .map_err(status)?;
+        let (info, _) = self
+            .tables
+            .table(&select.table)
+            .ok_or_else(|| unknown_table(&select.table))?;
+        let query = Query::from_select(select, vec![], &info.columns).map_err(status)?;
+        let id = self.statements.prepare(query, &info).map_err(status)?;
+        let statement = self.statements.get(id).map_err(status)?;
+        Ok(Response::new(PrepareResponse {
+            statement_id: id,
+            parameter_count: statement.parameters().len() as u32,
+        }))
+    }
+
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let request = request.into_inner();
+        let statement = self.statements.get(request.statement_id).map_err(status)?;
+        let parameters = request
+            .parameters
+            .into_iter()
+            .map(DataValue::try_from)
+            .collect::<error::Result<Vec<_>>>()
+            .map_err(status)?;
+        let table = &statement.query().table;
+        let (_, source) = self
+            .tables
+            .table(table)
+            .ok_or_else(|| unknown_table(table))?;
+
+        let mut results = statement
+            .execute(source.as_ref(), &parameters, ExecutionOptions::default())
+            .map_err(status)?;
+        let mut rows = Vec::new();
+        while let Some(values) = results.rows.next().await {
+            rows.push(Row {
+                values: values
+                    .map_err(status)?
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            });
+        }
+        Ok(Response::new(ExecuteResponse {
+            columns: results.columns,
+            rows,
+        }))
+    }
+
+    async fn close(
+        &self,
+        request: Request<CloseRequest>,
+    ) -> Result<Response<CloseResponse>, Status> {
+        let closed = self.statements.close(request.get_ref().statement_id);
+        Ok(Response::new(CloseResponse { closed }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use intake::ColumnTypes;
+    use sifter_proto::statements::Value;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{IndexKind, MemoryTable};
+
+    struct Docs(Arc<MemoryTable>);
+
+    impl Tables for Docs {
+        fn table(&self, name: &str) -> Option<(TableInfo, Arc<dyn TableSource>)> {
+            let info = TableInfo::new("docs", 3)
+                .with_column("id", ColumnTypes::I64)
+                .with_column("source", ColumnTypes::String)
+                .with_index("id", IndexKind::Scalar, 3);
+            (name == "docs").then(|| (info, self.0.clone() as Arc<dyn TableSource>))
+        }
+    }
+
+    fn service() -> StatementService<Docs> {
+        let mut table = MemoryTable::new(vec!["id".into(), "source".into()]);
+        for (id, source) in [(0, "a"), (1, "b"), (2, "a")] {
+            table
+                .insert(vec![DataValue::I64(id), DataValue::String(source.into())])
+                .unwrap();
+        }
+        StatementService::new(Docs(Arc::new(table)), 8)
+    }
+
+    async fn execute(
+        service: &StatementService<Docs>,
+        statement_id: u64,
+        parameters: Vec<DataValue>,
+    ) -> Result<ExecuteResponse, Status> {
+        let request = ExecuteRequest {
+            statement_id,
+            parameters: parameters.into_iter().map(Value::from).collect(),
+        };
+        Ok(service.execute(Request::new(request)).await?.into_inner())
+    }
+
+    #[tokio::test]
+    async fn prepares_and_executes() {
+        let service = service();
+        let prepare = |statement: &str| {
+            service.prepare(Request::new(PrepareRequest {
+                statement: Some(prepare_request::Statement::Text(statement.to_string())),
+            }))
+        };
+        let prepared = prepare("select id from docs where source == $1")
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(prepared.parameter_count, 1);
+
+        let id = prepared.statement_id;
+        for (source, expected) in [("a", vec![0, 2]), ("b", vec![1])] {
+            let response = execute(&service, id, vec![DataValue::String(source.into())])
+                .await
+                .unwrap();
+            assert_eq!(response.columns, vec!["id"]);
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|id| Row {
+                    values: vec![DataValue::I64(id).into()],
+                })
+                .collect();
+            assert_eq!(response.rows, expected);
+        }
+
+        let code = |result: Result<ExecuteResponse, Status>| result.unwrap_err().code();
+        assert_eq!(
+            code(execute(&service, id, vec![]).await),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            code(execute(&service, id, vec![DataValue::I64(1)]).await),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            prepare("select id from others").await.unwrap_err().code(),
+            Code::NotFound
+        );
+
+        let close = || service.close(Request::new(CloseRequest { statement_id: id }));
+        assert!(close().await.unwrap().into_inner().closed);
+        assert!(!close().await.unwrap().into_inner().closed);
+        assert_eq!(
+            code(execute(&service, id, vec![DataValue::Null]).await),
+            Code::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn prepares_parsed_statements() {
+        let service = service();
+        let select = query_parser::query::SelectParser::new()
+            .parse("select id from docs where id > $1")
+            .unwrap();
+        let request = PrepareRequest {
+            statement: Some(prepare_request::Statement::Select(select.into())),
+        };
+        let id = service
+            .prepare(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner()
+            .statement_id;
+        let response = execute(&service, id, vec![DataValue::I64(1)])
+            .await
+            .unwrap();
+        let expected = vec![Row {
+            values: vec![DataValue::I64(2).into()],
+        }];
+        assert_eq!(response.rows, expected);
+
+        let missing = PrepareRequest { statement: None };
+        let status = service.prepare(Request::new(missing)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
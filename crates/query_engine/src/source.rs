@@ -0,0 +1,188 @@
+use std::{collections::BTreeSet, ops::Bound, pin::Pin};
+
+use async_stream::try_stream;
+use faiss::DistanceMetric;
+use futures_core::Stream;
+use intake::DataValue;
+
+use crate::eval::compare;
+
+pub type RowId = u64;
+
+/// Row ids in batches, so a scan never holds more than a batch of them at once.
+pub type RowIdStream<'a> = Pin<Box<dyn Stream<Item = error::Result<Vec<RowId>>> + Send + 'a>>;
+
+/// Row ids in each batch a `MemoryTable` scan yields
+const ID_BATCH: usize = 1024;
+
+/// Bounds on the values an index scan returns.
+pub type KeyRange = (Bound<DataValue>, Bound<DataValue>);
+
+/// How many results a vector search returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchLimit {
+    /// The `k` nearest vectors
+    TopK(usize),
+    /// Every vector within a distance
+    Within(f64),
+}
+
+/// A vector search hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbour {
+    pub row: RowId,
+    pub distance: f64,
+}
+
+/// Storage the executor reads a table from.
+#[async_trait::async_trait]
+pub trait TableSource: Send + Sync {
+    /// Ids of every row in the table
+    fn row_ids(&self) -> RowIdStream<'_>;
+
+    /// Ids of the rows whose indexed `column` value is within `range`
+    fn index_scan<'a>(&'a self, column: &'a str, range: &'a KeyRange) -> RowIdStream<'a>;
+
+    /// Search the vector index on `column`. When `candidates` is set only those rows are
+    /// considered.
+    async fn vector_search(
+        &self,
+        column: &str,
+        target: &[u8],
+        metric: DistanceMetric,
+        limit: SearchLimit,
+        candidates: Option<&BTreeSet<RowId>>,
+    ) -> error::Result<Vec<Neighbour>>;
+
+    /// Read `columns` of a row, in the order they were asked for
+    async fn fetch(&self, row: RowId, columns: &[String]) -> error::Result<Vec<DataValue>>;
+}
+
+/// Table that keeps every row in memory and answers index lookups by scanning.
+pub struct MemoryTable {
+    columns: Vec<String>,
+    rows: Vec<Vec<DataValue>>,
+}
+
+impl MemoryTable {
+    pub fn new(columns: Vec<String>) -> Self {
+        MemoryTable {
+            columns,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Append a row, returning its id
+    pub fn insert(&mut self, row: Vec<DataValue>) -> error::Result<RowId> {
+        if row.len() != self.columns.len() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "expected {} values, got {}",
+                self.columns.len(),
+                row.len()
+            ))
+            .into());
+        }
+        self.rows.push(row);
+        Ok(self.rows.len() as RowId - 1)
+    }
+
+    fn column(&self, name: &str) -> error::Result<usize> {
+        self.columns.iter().position(|c| c == name).ok_or_else(|| {
+            error::CustomErrors::InvalidArguments(format!("unknown column {}", name)).into()
+        })
+    }
+}
+
+fn in_range(value: &DataValue, (lower, upper): &KeyRange) -> bool {
+    let above = match lower {
+        Bound::Included(b) => compare(value, b).is_some_and(|o| o.is_ge()),
+        Bound::Excluded(b) => compare(value, b).is_some_and(|o| o.is_gt()),
+        Bound::Unbounded => *value != DataValue::Null,
+    };
+    let below = match upper {
+        Bound::Included(b) => compare(value, b).is_some_and(|o| o.is_le()),
+        Bound::Excluded(b) => compare(value, b).is_some_and(|o| o.is_lt()),
+        Bound::Unbounded => *value != DataValue::Null,
+    };
+    above && below
+}
+
+#[async_trait::async_trait]
+impl TableSource for MemoryTable {
+    fn row_ids(&self) -> RowIdStream<'_> {
+        let len = self.rows.len() as RowId;
+        Box::pin(try_stream! {
+            for start in (0..len).step_by(ID_BATCH) {
+                yield (start..len.min(start + ID_BATCH as RowId)).collect();
+            }
+        })
+    }
+
+    fn index_scan<'a>(&'a self, column: &'a str, range: &'a KeyRange) -> RowIdStream<'a> {
+        Box::pin(try_stream! {
+            let column = self.column(column)?;
+            for (i, rows) in self.rows.chunks(ID_BATCH).enumerate() {
+                yield rows
+                    .iter()
+                    .zip((i * ID_BATCH) as RowId..)
+                    .filter(|(row, _)| in_range(&row[column], range))
+                    .map(|(_, id)| id)
+                    .collect();
+            }
+        })
+    }
+
+    async fn vector_search(
+        &self,
+        column: &str,
+        target: &[u8],
+        metric: DistanceMetric,
+        limit: SearchLimit,
+        candidates: Option<&BTreeSet<RowId>>,
+    ) -> error::Result<Vec<Neighbour>> {
+        let column = self.column(column)?;
+        let distance = metric.into_fn::<u8>(target.len());
+
+        let mut hits = Vec::new();
+        for (id, row) in self.rows.iter().enumerate() {
+            let id = id as RowId;
+            if candidates.is_some_and(|c| !c.contains(&id)) {
+                continue;
+            }
+            match &row[column] {
+                DataValue::Bytes(v) if v.len() == target.len() => hits.push(Neighbour {
+                    row: id,
+                    distance: distance(v, target) as f64,
+                }),
+                DataValue::Null => {}
+                v => {
+                    return Err(error::CustomErrors::InvalidState(format!(
+                        "row {} has {:?} in a vector column",
+                        id, v
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.row.cmp(&b.row)));
+        match limit {
+            SearchLimit::TopK(k) => hits.truncate(k),
+            SearchLimit::Within(radius) => hits.retain(|hit| hit.distance <= radius),
+        }
+        Ok(hits)
+    }
+
+    async fn fetch(&self, row: RowId, columns: &[String]) -> error::Result<Vec<DataValue>> {
+        let values = self.rows.get(row as usize).ok_or_else(|| {
+            error::Error::from(error::CustomErrors::InvalidArguments(format!(
+                "unknown row {}",
+                row
+            )))
+        })?;
+        columns
+            .iter()
+            .map(|c| Ok(values[self.column(c)?].clone()))
+            .collect()
+    }
+}
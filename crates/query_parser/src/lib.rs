@@ -1,9 +1,17 @@
+use std::fmt;
+
 use chrono::{DateTime, Utc};
 use lalrpop_util::lalrpop_mod;
+use serde::{Deserialize, Serialize};
 
-lalrpop_mod!(pub query);
+mod proto;
+mod time;
 
-#[derive(Debug, PartialEq, Clone)]
+pub use time::{parse_datetime, Interval};
+
+lalrpop_mod!(#[allow(clippy::all)] pub query);
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Value {
     Double(f64),
     Integer(i64),
@@ -11,11 +19,153 @@ pub enum Value {
     Bool(bool),
     DateTime(DateTime<Utc>),
     UUID(uuid::Uuid),
+    Interval(Interval),
     ResourceTag(u32),
     Literal(String),
+    /// A field inside a json column, `meta.tags[0]`
+    Field(FieldPath),
+    /// Arithmetic or a function call computed from other values
+    Expr(Box<Expr>),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Path from a json column to a field inside it.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct FieldPath {
+    pub column: String,
+    pub segments: Vec<PathSegment>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum PathSegment {
+    /// Member of an object, `.key` or `["key"]`
+    Key(String),
+    /// Element of an array, `[0]`
+    Index(u64),
+}
+
+impl Value {
+    /// Whether the value is read from the row, either a column or a field inside one
+    pub fn is_column(&self) -> bool {
+        matches!(self, Value::Literal(_) | Value::Field(_))
+    }
+
+    /// Read what a single quoted literal holds: a uuid, a time or an interval
+    pub fn from_quoted(s: &str) -> Option<Value> {
+        if let Ok(u) = uuid::Uuid::parse_str(s) {
+            return Some(Value::UUID(u));
+        }
+        parse_datetime(s)
+            .map(Value::DateTime)
+            .or_else(|| s.parse().ok().map(Value::Interval))
+    }
+
+    /// Whether the value is the same for every row, it doesn't read a column
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Value::Literal(_) | Value::Field(_) => false,
+            Value::Expr(expr) => expr.operands().into_iter().all(Value::is_constant),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum Expr {
+    Arithmetic(ArithmeticOp, Value, Value),
+    Call(Function, Vec<Value>),
+}
+
+impl Expr {
+    pub fn operands(&self) -> Vec<&Value> {
+        match self {
+            Expr::Arithmetic(_, a, b) => vec![a, b],
+            Expr::Call(_, arguments) => arguments.iter().collect(),
+        }
+    }
+
+    /// The same expression with every operand replaced by `f` of it
+    pub fn map_operands(&self, mut f: impl FnMut(&Value) -> Value) -> Expr {
+        match self {
+            Expr::Arithmetic(op, a, b) => Expr::Arithmetic(*op, f(a), f(b)),
+            Expr::Call(function, arguments) => {
+                Expr::Call(*function, arguments.iter().map(f).collect())
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum ArithmeticOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl ArithmeticOp {
+    /// Operators of higher precedence bind tighter
+    fn precedence(&self) -> u8 {
+        match self {
+            ArithmeticOp::Add | ArithmeticOp::Sub => 0,
+            ArithmeticOp::Mul | ArithmeticOp::Div => 1,
+        }
+    }
+}
+
+/// Built in functions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Function {
+    /// The current time, `now()`
+    Now,
+    /// A datetime truncated to a unit, `date_trunc("day", ts)`
+    DateTrunc,
+    /// A string in lower case, `lower(name)`
+    Lower,
+    /// Number of characters of a string, bytes of a byte string or entries of a json value,
+    /// `len(name)`
+    Len,
+    /// Hamming distance between two binary vectors, `hamming(vec, $1)`
+    Hamming,
+    /// Cosine distance between two binary vectors, `cosine(vec, $1)`
+    Cosine,
+}
+
+impl Function {
+    pub const ALL: [Function; 6] = [
+        Function::Now,
+        Function::DateTrunc,
+        Function::Lower,
+        Function::Len,
+        Function::Hamming,
+        Function::Cosine,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Now => "now",
+            Function::DateTrunc => "date_trunc",
+            Function::Lower => "lower",
+            Function::Len => "len",
+            Function::Hamming => "hamming",
+            Function::Cosine => "cosine",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Function> {
+        Function::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    /// Number of arguments the function takes
+    pub fn arity(&self) -> usize {
+        match self {
+            Function::Now => 0,
+            Function::Lower | Function::Len => 1,
+            Function::DateTrunc | Function::Hamming | Function::Cosine => 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum OpTree {
     Eq(Value, Value),
     Neq(Value, Value),
@@ -25,12 +175,698 @@ pub enum OpTree {
     Gte(Value, Value),
     Within(Value, Value),
     TopK(Value, Value),
+    /// Vectors within a distance of a target, `vector within 4 of $1 using hamming`
+    WithinOf(Value, Value, VectorTarget),
+    /// The k vectors nearest to a target, `vector topk 10 near $1 using hamming`
+    TopKNear(Value, Value, VectorTarget),
+    /// Rows found by several `topk` searches, ranked by combining their rankings
+    Fuse(FusionMethod, Vec<OpTree>),
+    /// Equal to one of the listed values
+    In(Value, Vec<Value>),
+    /// Within an inclusive range
+    Between(Value, Value, Value),
+    /// Matches a pattern where `%` stands for any run of characters and `_` for any single one
+    Like(Value, Value),
+    IsNull(Value),
+    IsNotNull(Value),
     And(Box<OpTree>, Box<OpTree>),
     Or(Box<OpTree>, Box<OpTree>),
     Not(Box<OpTree>),
     Value(Value),
 }
 
+/// The vector a vector predicate compares against and, optionally, the distance metric it
+/// compares with. Without a metric the one of the column's index is used.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct VectorTarget {
+    pub vector: Value,
+    pub metric: Option<String>,
+}
+
+/// Reciprocal rank fusion constant used when `rrf` doesn't set one
+pub const DEFAULT_RRF_K: u64 = 60;
+
+/// How `fuse` combines the rankings of its searches.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum FusionMethod {
+    /// Reciprocal rank fusion: a row scores `1 / (k + rank)` summed over the searches
+    Rrf(u64),
+    /// A row scores its similarity in each search, normalised to `[0, 1]`, times the search's
+    /// weight
+    Weighted(Vec<f64>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum SortExpr {
+    Column(String),
+    /// Distance between a vector column and a target vector
+    Distance(String, Value),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SortKey {
+    pub expr: SortExpr,
+    pub direction: Direction,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum AggregateFn {
+    Count,
+    CountDistinct,
+    Min,
+    Max,
+    Sum,
+    Avg,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub function: AggregateFn,
+    /// Column the aggregate reads, `None` for `count(*)`
+    pub column: Option<String>,
+}
+
+/// A full query: the rows to match and how to order and page through them. Queries with
+/// aggregates return one row per group instead of one per match.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct QueryAst {
+    pub filter: OpTree,
+    pub aggregates: Vec<Aggregate>,
+    pub group_by: Vec<String>,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+/// An entry in a `select` list.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum SelectItem {
+    Column(String),
+    Aggregate(Aggregate),
+}
+
+/// Ordering and paging that end a query.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub(crate) struct Paging {
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+/// A `select` statement: the table to read, what to return for each row or group in the order
+/// it was listed, and the query picking the rows. Aggregates in the select list are also part
+/// of the query.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SelectAst {
+    pub table: String,
+    pub items: Vec<SelectItem>,
+    pub query: QueryAst,
+}
+
+impl SelectAst {
+    pub(crate) fn new(
+        items: Vec<SelectItem>,
+        table: String,
+        filter: Option<OpTree>,
+        group_by: Option<Vec<String>>,
+        paging: Paging,
+    ) -> Self {
+        let aggregates = items
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::Column(_) => None,
+                SelectItem::Aggregate(aggregate) => Some(aggregate.clone()),
+            })
+            .collect();
+        SelectAst {
+            table,
+            items,
+            query: QueryAst {
+                filter: filter.unwrap_or(OpTree::Value(Value::Bool(true))),
+                aggregates,
+                group_by: group_by.unwrap_or_default(),
+                order_by: paging.order_by,
+                limit: paging.limit,
+                offset: paging.offset,
+            },
+        }
+    }
+
+    /// The columns in the select list, in order
+    pub fn columns(&self) -> impl Iterator<Item = &String> {
+        self.items.iter().filter_map(|item| match item {
+            SelectItem::Column(column) => Some(column),
+            SelectItem::Aggregate(_) => None,
+        })
+    }
+}
+
+/// Column type as written, e.g. `binary_vector(512)`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TypeAst {
+    pub name: String,
+    pub size: Option<u64>,
+}
+
+/// Index definition. Names, metrics and methods are checked when the statement is turned
+/// into a table definition.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexAst {
+    /// Defaults to the column name
+    pub name: Option<String>,
+    pub column: String,
+    pub metric: Option<String>,
+    pub method: Option<String>,
+    pub options: Vec<(String, Value)>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColumnAst {
+    pub name: String,
+    pub data_type: TypeAst,
+    /// Field the column is generated from, `source string as (meta.source)`
+    pub generated: Option<FieldPath>,
+    pub primary_key: bool,
+    pub index: Option<IndexAst>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AlterAst {
+    AddColumn(ColumnAst),
+    AddIndex(IndexAst),
+}
+
+/// Any statement of the query language.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+    Select(SelectAst),
+    CreateTable { name: String, columns: Vec<ColumnAst> },
+    DropTable { name: String },
+    AlterTable { table: String, change: AlterAst },
+    CreateIndex { table: String, index: IndexAst },
+}
+
+impl From<OpTree> for QueryAst {
+    fn from(filter: OpTree) -> Self {
+        QueryAst {
+            filter,
+            aggregates: Vec::new(),
+            group_by: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        }
+    }
+}
+
+/// Write a double the way the parser reads it, with a decimal point and without an exponent.
+/// Infinities and NaN have no literal.
+fn write_double(f: &mut fmt::Formatter<'_>, d: f64) -> fmt::Result {
+    let s = d.to_string();
+    if s.contains('.') || !d.is_finite() {
+        write!(f, "{}", s)
+    } else {
+        write!(f, "{}.0", s)
+    }
+}
+
+/// Quote a string, escaping what the parser would otherwise misread
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Double(d) => write_double(f, *d),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::String(s) => write_string(f, s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::DateTime(d) => write!(f, "'{}'", d.to_rfc3339()),
+            Value::UUID(u) => write!(f, "'{}'", u),
+            Value::Interval(i) => write!(f, "'{}'", i),
+            Value::ResourceTag(r) => write!(f, "${}", r),
+            Value::Literal(l) => write!(f, "{}", Name(l)),
+            Value::Field(path) => write!(f, "{}", path),
+            Value::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Wraps operands that would otherwise bind to a neighbouring operator
+        fn operand(f: &mut fmt::Formatter<'_>, v: &Value, wrap: impl Fn(u8) -> bool) -> fmt::Result {
+            match v {
+                Value::Expr(expr) => match &**expr {
+                    Expr::Arithmetic(op, ..) if wrap(op.precedence()) => write!(f, "({})", expr),
+                    _ => write!(f, "{}", expr),
+                },
+                v => write!(f, "{}", v),
+            }
+        }
+
+        match self {
+            Expr::Arithmetic(op, a, b) => {
+                let precedence = op.precedence();
+                // Operators are left associative, so the right operand is wrapped on ties
+                operand(f, a, |p| p < precedence)?;
+                write!(f, " {} ", op)?;
+                operand(f, b, |p| p <= precedence)
+            }
+            Expr::Call(function, arguments) => {
+                write!(f, "{}(", function.name())?;
+                for (i, argument) in arguments.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", argument)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Sub => "-",
+            ArithmeticOp::Mul => "*",
+            ArithmeticOp::Div => "/",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Name(&self.column))?;
+        for segment in &self.segments {
+            match segment {
+                PathSegment::Key(key) if is_identifier(key) => write!(f, ".{}", key)?,
+                PathSegment::Key(key) => {
+                    write!(f, "[")?;
+                    write_string(f, key)?;
+                    write!(f, "]")?;
+                }
+                PathSegment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Words the grammar gives a meaning to. Columns, tables and other names that are one of
+/// them, or aren't made of letters, digits and `_`, have to be quoted with backticks.
+pub const RESERVED_WORDS: &[&str] = &[
+    "add",
+    "alter",
+    "and",
+    "as",
+    "asc",
+    "avg",
+    "between",
+    "binary_vector",
+    "by",
+    "column",
+    "count",
+    "create",
+    "desc",
+    "distance",
+    "distinct",
+    "drop",
+    "false",
+    "from",
+    "fuse",
+    "group",
+    "in",
+    "index",
+    "interval",
+    "is",
+    "key",
+    "like",
+    "limit",
+    "max",
+    "min",
+    "near",
+    "not",
+    "null",
+    "of",
+    "offset",
+    "on",
+    "order",
+    "primary",
+    "rrf",
+    "select",
+    "sum",
+    "table",
+    "topk",
+    "true",
+    "using",
+    "weighted",
+    "where",
+    "with",
+    "within",
+];
+
+/// Whether `s` lexes as an identifier
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_WORDS.contains(&s)
+}
+
+/// A column, table or other name, quoted with backticks unless it lexes as an identifier
+struct Name<'a>(&'a str);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_identifier(self.0) {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "`{}`", self.0.replace('`', "``"))
+        }
+    }
+}
+
+impl fmt::Display for VectorTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.vector)?;
+        if let Some(metric) = &self.metric {
+            write!(f, " using {}", Name(metric))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FusionMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FusionMethod::Rrf(k) => write!(f, "rrf({})", k),
+            FusionMethod::Weighted(weights) => {
+                write!(f, "weighted(")?;
+                for (i, weight) in weights.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_double(f, *weight)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for OpTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Operands of `&&` and `||` have to be wrapped unless they are plain values
+        fn operand(f: &mut fmt::Formatter<'_>, t: &OpTree) -> fmt::Result {
+            match t {
+                OpTree::Value(v) => predicate(f, v),
+                t => write!(f, "({})", t),
+            }
+        }
+
+        /// A value standing alone as a predicate is wrapped if it is arithmetic
+        fn predicate(f: &mut fmt::Formatter<'_>, v: &Value) -> fmt::Result {
+            match v {
+                Value::Expr(expr) if matches!(**expr, Expr::Arithmetic(..)) => {
+                    write!(f, "({})", v)
+                }
+                v => write!(f, "{}", v),
+            }
+        }
+
+        match self {
+            OpTree::Eq(a, b) => write!(f, "{} == {}", a, b),
+            OpTree::Neq(a, b) => write!(f, "{} != {}", a, b),
+            OpTree::Lt(a, b) => write!(f, "{} < {}", a, b),
+            OpTree::Lte(a, b) => write!(f, "{} <= {}", a, b),
+            OpTree::Gt(a, b) => write!(f, "{} > {}", a, b),
+            OpTree::Gte(a, b) => write!(f, "{} >= {}", a, b),
+            OpTree::Within(a, b) => write!(f, "{} within {}", a, b),
+            OpTree::TopK(a, b) => write!(f, "{} topk {}", a, b),
+            OpTree::WithinOf(a, b, target) => write!(f, "{} within {} of {}", a, b, target),
+            OpTree::TopKNear(a, b, target) => write!(f, "{} topk {} near {}", a, b, target),
+            OpTree::Fuse(method, searches) => {
+                write!(f, "fuse({}", method)?;
+                for search in searches {
+                    write!(f, ", {}", search)?;
+                }
+                write!(f, ")")
+            }
+            OpTree::In(a, values) => {
+                write!(f, "{} in (", a)?;
+                for (i, v) in values.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}", sep, v)?;
+                }
+                write!(f, ")")
+            }
+            OpTree::Between(a, lower, upper) => {
+                write!(f, "{} between {} and {}", a, lower, upper)
+            }
+            OpTree::Like(a, pattern) => write!(f, "{} like {}", a, pattern),
+            OpTree::IsNull(a) => write!(f, "{} is null", a),
+            OpTree::IsNotNull(a) => write!(f, "{} is not null", a),
+            OpTree::And(a, b) => {
+                operand(f, a)?;
+                write!(f, " && ")?;
+                operand(f, b)
+            }
+            OpTree::Or(a, b) => {
+                operand(f, a)?;
+                write!(f, " || ")?;
+                operand(f, b)
+            }
+            OpTree::Not(t) => {
+                write!(f, "!")?;
+                match t.as_ref() {
+                    OpTree::Value(v) => predicate(f, v),
+                    t => write!(f, "({})", t),
+                }
+            }
+            OpTree::Value(v) => predicate(f, v),
+        }
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.expr {
+            SortExpr::Column(c) => write!(f, "{}", Name(c))?,
+            SortExpr::Distance(c, v) => write!(f, "distance({}, {})", Name(c), v)?,
+        }
+        match self.direction {
+            Direction::Asc => write!(f, " asc"),
+            Direction::Desc => write!(f, " desc"),
+        }
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let column = match &self.column {
+            Some(column) => Name(column).to_string(),
+            None => "*".to_string(),
+        };
+        match self.function {
+            AggregateFn::Count => write!(f, "count({})", column),
+            AggregateFn::CountDistinct => write!(f, "count(distinct {})", column),
+            AggregateFn::Min => write!(f, "min({})", column),
+            AggregateFn::Max => write!(f, "max({})", column),
+            AggregateFn::Sum => write!(f, "sum({})", column),
+            AggregateFn::Avg => write!(f, "avg({})", column),
+        }
+    }
+}
+
+impl fmt::Display for QueryAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.aggregates.is_empty() {
+            write!(f, "{}", self.filter)?;
+        } else {
+            for (i, aggregate) in self.aggregates.iter().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                write!(f, "{}{}", sep, aggregate)?;
+            }
+            for (i, column) in self.group_by.iter().enumerate() {
+                let sep = if i == 0 { " group by " } else { ", " };
+                write!(f, "{}{}", sep, Name(column))?;
+            }
+            write!(f, " where ")?;
+            match &self.filter {
+                filter @ OpTree::Value(_) => write!(f, "{}", filter)?,
+                filter => write!(f, "({})", filter)?,
+            }
+        }
+        self.fmt_paging(f)
+    }
+}
+
+impl QueryAst {
+    fn fmt_paging(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, key) in self.order_by.iter().enumerate() {
+            let sep = if i == 0 { " order by " } else { ", " };
+            write!(f, "{}{}", sep, key)?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " limit {}", limit)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SelectAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "select ")?;
+        for (i, item) in self.items.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            match item {
+                SelectItem::Column(column) => write!(f, "{}{}", sep, Name(column))?,
+                SelectItem::Aggregate(aggregate) => write!(f, "{}{}", sep, aggregate)?,
+            }
+        }
+        write!(f, " from {}", Name(&self.table))?;
+        if self.query.filter != OpTree::Value(Value::Bool(true)) {
+            write!(f, " where {}", self.query.filter)?;
+        }
+        for (i, column) in self.query.group_by.iter().enumerate() {
+            let sep = if i == 0 { " group by " } else { ", " };
+            write!(f, "{}{}", sep, Name(column))?;
+        }
+        self.query.fmt_paging(f)
+    }
+}
+
+impl fmt::Display for TypeAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(size) = self.size {
+            write!(f, "({})", size)?;
+        }
+        Ok(())
+    }
+}
+
+impl IndexAst {
+    /// Everything after the indexed column, shared by inline and standalone definitions
+    fn fmt_options(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(method) = &self.method {
+            write!(f, " using {}", Name(method))?;
+        }
+        for (i, (key, value)) in self.options.iter().enumerate() {
+            let sep = if i == 0 { " with (" } else { ", " };
+            write!(f, "{}{} = {}", sep, Name(key), value)?;
+        }
+        if !self.options.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IndexAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "index")?;
+        if let Some(name) = &self.name {
+            write!(f, " {}", Name(name))?;
+        }
+        write!(f, " ({}", Name(&self.column))?;
+        if let Some(metric) = &self.metric {
+            write!(f, " {}", Name(metric))?;
+        }
+        write!(f, ")")?;
+        self.fmt_options(f)
+    }
+}
+
+impl fmt::Display for ColumnAst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", Name(&self.name), self.data_type)?;
+        if let Some(path) = &self.generated {
+            write!(f, " as ({})", path)?;
+        }
+        if self.primary_key {
+            write!(f, " primary key")?;
+        }
+        if let Some(index) = &self.index {
+            write!(f, " index")?;
+            if let Some(metric) = &index.metric {
+                write!(f, " {}", Name(metric))?;
+            }
+            index.fmt_options(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Select(select) => write!(f, "{}", select),
+            Statement::CreateTable { name, columns } => {
+                write!(f, "create table {} (", Name(name))?;
+                for (i, column) in columns.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}", sep, column)?;
+                }
+                write!(f, ")")
+            }
+            Statement::DropTable { name } => write!(f, "drop table {}", Name(name)),
+            Statement::AlterTable { table, change } => match change {
+                AlterAst::AddColumn(column) => {
+                    write!(f, "alter table {} add column {}", Name(table), column)
+                }
+                AlterAst::AddIndex(index) => {
+                    write!(f, "alter table {} add {}", Name(table), index)
+                }
+            },
+            Statement::CreateIndex { table, index } => {
+                let name = index.name.as_deref().unwrap_or(&index.column);
+                write!(
+                    f,
+                    "create index {} on {} ({}",
+                    Name(name),
+                    Name(table),
+                    Name(&index.column)
+                )?;
+                if let Some(metric) = &index.metric {
+                    write!(f, " {}", Name(metric))?;
+                }
+                write!(f, ")")?;
+                index.fmt_options(f)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -50,6 +886,8 @@ mod tests {
         test_match!("0x0000A100", Value::Integer(0x0000A100));
         test_match!("1", Value::Integer(1));
         test_match!("1.0", Value::Double(1.0));
+        test_match!("-12", Value::Integer(-12));
+        test_match!("-0.5", Value::Double(-0.5));
         test_match!("true", Value::Bool(true));
         test_match!("false", Value::Bool(false));
         test_match!(
@@ -67,6 +905,17 @@ mod tests {
 
         let uuid = uuid::Uuid::new_v4();
         test_match!(format!("'{:?}'", uuid.clone()), Value::UUID(uuid));
+        let midnight = Value::DateTime(parse_datetime("2020-01-01T00:00:00Z").unwrap());
+        test_match!("'2020-01-01'", midnight.clone());
+        test_match!("'2020-01-01 05:30:00+0530'", midnight.clone());
+        test_match!("'@1577836800000ms'", midnight);
+        let day = Value::Interval(Interval::from_micros(86_400_000_000));
+        test_match!("'1 day'", day.clone());
+        test_match!("interval 'P1D'", day);
+        assert!(parser.parse("interval '2020-01-01'").is_err());
+        assert!(parser.parse("'tomorrow'").is_err());
+        let named = parser.parse("'2020-01-01 00:00:00 Europe/Paris'").unwrap_err();
+        assert!(named.to_string().contains("named time zone"), "{}", named);
         test_match!(
             r#""\"I'm a string!""#,
             Value::String(r#""I'm a string!"#.into())
@@ -141,4 +990,873 @@ mod tests {
         assert_eq!(parser.parse("!(a == 1)"), Ok(OpTree::Not(Box::new(eq!(lit!("a"), int!(1))))));
         assert_eq!(parser.parse("!a"), Ok(OpTree::Not(Box::new(OpTree::Value(lit!("a"))))));
     }
+
+    #[test]
+    fn parses_vector_targets() {
+        let parser = query::ScopeParser::new();
+        let target = |vector, metric: Option<&str>| VectorTarget {
+            vector,
+            metric: metric.map(String::from),
+        };
+
+        for (input, expected) in [
+            (
+                "v within 4 of $1",
+                OpTree::WithinOf(
+                    Value::Literal("v".into()),
+                    Value::Integer(4),
+                    target(Value::ResourceTag(1), None),
+                ),
+            ),
+            (
+                "v within 0.5 of $2 using hamming",
+                OpTree::WithinOf(
+                    Value::Literal("v".into()),
+                    Value::Double(0.5),
+                    target(Value::ResourceTag(2), Some("hamming")),
+                ),
+            ),
+            (
+                "v topk 10 near $1 using hamming",
+                OpTree::TopKNear(
+                    Value::Literal("v".into()),
+                    Value::Integer(10),
+                    target(Value::ResourceTag(1), Some("hamming")),
+                ),
+            ),
+            (
+                "v topk $1 near $2",
+                OpTree::TopKNear(
+                    Value::Literal("v".into()),
+                    Value::ResourceTag(1),
+                    target(Value::ResourceTag(2), None),
+                ),
+            ),
+        ] {
+            assert_eq!(parser.parse(input), Ok(expected.clone()), "{}", input);
+            assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+        }
+
+        let expected = OpTree::And(
+            Box::new(OpTree::TopKNear(
+                Value::Literal("v".into()),
+                Value::Integer(10),
+                target(Value::ResourceTag(1), None),
+            )),
+            Box::new(OpTree::Eq(Value::Literal("a".into()), Value::Integer(1))),
+        );
+        assert_eq!(
+            parser.parse("(v topk 10 near $1) && (a == 1)"),
+            Ok(expected)
+        );
+
+        assert!(parser.parse("v topk 10 near").is_err());
+        assert!(parser.parse("v within 4 of $1 using").is_err());
+        assert!(parser.parse("v topk 10 using hamming").is_err());
+    }
+
+    #[test]
+    fn parses_fusion() {
+        let parser = query::QueryParser::new();
+        let search = |column: &str, tag| {
+            OpTree::TopKNear(
+                Value::Literal(column.into()),
+                Value::Integer(50),
+                VectorTarget {
+                    vector: Value::ResourceTag(tag),
+                    metric: None,
+                },
+            )
+        };
+
+        let input = "fuse(rrf, vec1 topk 50 near $1, vec2 topk 50 near $2) limit 10";
+        let expected = QueryAst {
+            limit: Some(10),
+            ..QueryAst::from(OpTree::Fuse(
+                FusionMethod::Rrf(DEFAULT_RRF_K),
+                vec![search("vec1", 1), search("vec2", 2)],
+            ))
+        };
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        let input = "(fuse(weighted(0.7, 1), vec1 topk 50 near $1, (vec2 topk 50 near $2))) && (a == 1)";
+        let expected = QueryAst::from(OpTree::And(
+            Box::new(OpTree::Fuse(
+                FusionMethod::Weighted(vec![0.7, 1.0]),
+                vec![search("vec1", 1), search("vec2", 2)],
+            )),
+            Box::new(OpTree::Eq(Value::Literal("a".into()), Value::Integer(1))),
+        ));
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        assert_eq!(
+            parser.parse("fuse(rrf(10), v topk 5)").map(|q| q.filter),
+            Ok(OpTree::Fuse(
+                FusionMethod::Rrf(10),
+                vec![OpTree::TopK(Value::Literal("v".into()), Value::Integer(5))]
+            ))
+        );
+        assert!(parser.parse("fuse(rrf)").is_err());
+        assert!(parser.parse("fuse(sum, v topk 5)").is_err());
+        assert!(parser.parse("fuse(weighted(), v topk 5)").is_err());
+    }
+
+    #[test]
+    fn parses_predicates() {
+        let parser = query::ScopeParser::new();
+        let col = || Value::Literal("a".into());
+
+        for (input, expected) in [
+            (
+                "a in (1, $1, \"x\")",
+                OpTree::In(
+                    col(),
+                    vec![
+                        Value::Integer(1),
+                        Value::ResourceTag(1),
+                        Value::String("x".into()),
+                    ],
+                ),
+            ),
+            (
+                "a between 1 and 2.5",
+                OpTree::Between(col(), Value::Integer(1), Value::Double(2.5)),
+            ),
+            (
+                "a like \"foo%\"",
+                OpTree::Like(col(), Value::String("foo%".into())),
+            ),
+            ("a is null", OpTree::IsNull(col())),
+            ("a is not null", OpTree::IsNotNull(col())),
+        ] {
+            assert_eq!(parser.parse(input), Ok(expected.clone()));
+            assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+        }
+
+        assert_eq!(
+            parser.parse("(a is null) || (b in (1))"),
+            Ok(OpTree::Or(
+                Box::new(OpTree::IsNull(col())),
+                Box::new(OpTree::In(
+                    Value::Literal("b".into()),
+                    vec![Value::Integer(1)]
+                ))
+            ))
+        );
+        assert!(parser.parse("a in ()").is_err());
+        assert!(parser.parse("a between 1").is_err());
+    }
+
+    #[test]
+    fn parses_expressions() {
+        let parser = query::ScopeParser::new();
+        let column = |c: &str| Value::Literal(c.into());
+        let arithmetic = |op, a, b| Value::Expr(Box::new(Expr::Arithmetic(op, a, b)));
+        let call = |function, arguments| Value::Expr(Box::new(Expr::Call(function, arguments)));
+
+        for (input, expected) in [
+            (
+                "a + 1 > b * 2",
+                OpTree::Gt(
+                    arithmetic(ArithmeticOp::Add, column("a"), Value::Integer(1)),
+                    arithmetic(ArithmeticOp::Mul, column("b"), Value::Integer(2)),
+                ),
+            ),
+            (
+                "a - b - -1 == a * (b + 1) / 2",
+                OpTree::Eq(
+                    arithmetic(
+                        ArithmeticOp::Sub,
+                        arithmetic(ArithmeticOp::Sub, column("a"), column("b")),
+                        Value::Integer(-1),
+                    ),
+                    arithmetic(
+                        ArithmeticOp::Div,
+                        arithmetic(
+                            ArithmeticOp::Mul,
+                            column("a"),
+                            arithmetic(ArithmeticOp::Add, column("b"), Value::Integer(1)),
+                        ),
+                        Value::Integer(2),
+                    ),
+                ),
+            ),
+            (
+                "date_trunc(\"day\", ts) < now()",
+                OpTree::Lt(
+                    call(
+                        Function::DateTrunc,
+                        vec![Value::String("day".into()), column("ts")],
+                    ),
+                    call(Function::Now, vec![]),
+                ),
+            ),
+            (
+                "hamming(vec, $1) + len(lower(name)) between 1 and 2",
+                OpTree::Between(
+                    arithmetic(
+                        ArithmeticOp::Add,
+                        call(Function::Hamming, vec![column("vec"), Value::ResourceTag(1)]),
+                        call(Function::Len, vec![call(Function::Lower, vec![column("name")])]),
+                    ),
+                    Value::Integer(1),
+                    Value::Integer(2),
+                ),
+            ),
+            (
+                "(a * 2 > 1) && (a)",
+                OpTree::And(
+                    Box::new(OpTree::Gt(
+                        arithmetic(ArithmeticOp::Mul, column("a"), Value::Integer(2)),
+                        Value::Integer(1),
+                    )),
+                    Box::new(OpTree::Value(column("a"))),
+                ),
+            ),
+            (
+                "(!(a - 1)) || (a * 2)",
+                OpTree::Or(
+                    Box::new(OpTree::Not(Box::new(OpTree::Value(arithmetic(
+                        ArithmeticOp::Sub,
+                        column("a"),
+                        Value::Integer(1),
+                    ))))),
+                    Box::new(OpTree::Value(arithmetic(
+                        ArithmeticOp::Mul,
+                        column("a"),
+                        Value::Integer(2),
+                    ))),
+                ),
+            ),
+        ] {
+            assert_eq!(parser.parse(input), Ok(expected.clone()));
+            assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+        }
+        for input in ["cosine(vec) < 0.5", "upper(name) == \"A\"", "a + 1", "(a) + 1 > 2"] {
+            assert!(parser.parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_field_paths() {
+        let parser = query::ScopeParser::new();
+        let field = |column: &str, segments: Vec<PathSegment>| {
+            Value::Field(FieldPath {
+                column: column.into(),
+                segments,
+            })
+        };
+        let key = |k: &str| PathSegment::Key(k.into());
+
+        for (input, expected) in [
+            (
+                "meta.tags[0] == \"x\"",
+                OpTree::Eq(
+                    field("meta", vec![key("tags"), PathSegment::Index(0)]),
+                    Value::String("x".into()),
+                ),
+            ),
+            (
+                "doc_meta.source_id is null",
+                OpTree::IsNull(field("doc_meta", vec![key("source_id")])),
+            ),
+            (
+                "meta[\"first name\"].len > 2",
+                OpTree::Gt(
+                    field("meta", vec![key("first name"), key("len")]),
+                    Value::Integer(2),
+                ),
+            ),
+            (
+                "snake_case != _private",
+                OpTree::Neq(
+                    Value::Literal("snake_case".into()),
+                    Value::Literal("_private".into()),
+                ),
+            ),
+        ] {
+            assert_eq!(parser.parse(input), Ok(expected.clone()));
+            assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+        }
+        assert!(parser.parse("meta.[0] == 1").is_err());
+        assert!(parser.parse("meta[-1] == 1").is_err());
+    }
+
+    #[test]
+    fn parses_ordering_and_paging() {
+        let parser = query::QueryParser::new();
+        let filter = OpTree::Eq(Value::Literal("a".into()), Value::Integer(1));
+
+        assert_eq!(parser.parse("a == 1"), Ok(QueryAst::from(filter.clone())));
+
+        let expected = QueryAst {
+            order_by: vec![
+                SortKey {
+                    expr: SortExpr::Column("b".into()),
+                    direction: Direction::Desc,
+                },
+                SortKey {
+                    expr: SortExpr::Distance("vector".into(), Value::ResourceTag(1)),
+                    direction: Direction::Asc,
+                },
+            ],
+            limit: Some(10),
+            offset: Some(20),
+            ..QueryAst::from(filter.clone())
+        };
+        let input = "a == 1 order by b desc, distance(vector, $1) limit 10 offset 20";
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        let expected = QueryAst {
+            limit: Some(5),
+            ..QueryAst::from(filter)
+        };
+        assert_eq!(parser.parse("(a == 1) limit 5"), Ok(expected));
+
+        assert!(parser.parse("a == 1 limit 1.5").is_err());
+        assert!(parser.parse("a == 1 offset 1 limit 1").is_err());
+    }
+
+    #[test]
+    fn quotes_reserved_words() {
+        let parser = query::QueryParser::new();
+        let expected = QueryAst {
+            aggregates: vec![Aggregate {
+                function: AggregateFn::Sum,
+                column: Some("count".into()),
+            }],
+            group_by: vec!["key".into()],
+            order_by: vec![SortKey {
+                expr: SortExpr::Column("key".into()),
+                direction: Direction::Asc,
+            }],
+            ..QueryAst::from(OpTree::Eq(
+                Value::Literal("first name".into()),
+                Value::Literal("a`b".into()),
+            ))
+        };
+        let input = "sum(`count`) group by `key` where (`first name` == `a``b`) order by `key`";
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(expected.to_string(), format!("{} asc", input));
+        assert_eq!(
+            parser.parse("`id` == 1"),
+            Ok(QueryAst::from(OpTree::Eq(
+                Value::Literal("id".into()),
+                Value::Integer(1)
+            )))
+        );
+        assert!(parser.parse("count == 1").is_err());
+        assert!(parser.parse("`a == 1").is_err());
+
+        // Every word the grammar gives a meaning to is listed
+        let grammar = include_str!("query.lalrpop");
+        for token in grammar.split(|c: char| c.is_whitespace() || "()<>?".contains(c)) {
+            let Some(word) = token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) else {
+                continue;
+            };
+            if !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                assert!(RESERVED_WORDS.contains(&word), "{} isn't reserved", word);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_aggregates() {
+        let parser = query::QueryParser::new();
+        let aggregate = |function, column: Option<&str>| Aggregate {
+            function,
+            column: column.map(String::from),
+        };
+
+        let expected = QueryAst {
+            aggregates: vec![aggregate(AggregateFn::Count, None)],
+            group_by: vec!["source".into()],
+            ..QueryAst::from(OpTree::Within(
+                Value::Literal("vector".into()),
+                Value::Integer(4),
+            ))
+        };
+        let input = "count(*) group by source where vector within 4";
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        let expected = QueryAst {
+            aggregates: vec![
+                aggregate(AggregateFn::Count, Some("a")),
+                aggregate(AggregateFn::CountDistinct, Some("b")),
+                aggregate(AggregateFn::Min, Some("c")),
+                aggregate(AggregateFn::Max, Some("c")),
+                aggregate(AggregateFn::Sum, Some("d")),
+                aggregate(AggregateFn::Avg, Some("d")),
+            ],
+            group_by: vec!["e".into(), "f".into()],
+            order_by: vec![SortKey {
+                expr: SortExpr::Column("f".into()),
+                direction: Direction::Desc,
+            }],
+            limit: Some(3),
+            ..QueryAst::from(OpTree::Value(Value::Bool(true)))
+        };
+        let input = "count(a), count(distinct b), min(c), max(c), sum(d), avg(d) \
+            group by e, f order by f desc limit 3";
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        assert!(parser.parse("count(*) where").is_err());
+        assert!(parser.parse("sum(*)").is_err());
+    }
+
+    #[test]
+    fn parses_select() {
+        let parser = query::SelectParser::new();
+
+        let input = "select a, b, vector from docs where (a == 1) && (vector topk 10) limit 5";
+        let expected = SelectAst {
+            table: "docs".into(),
+            items: ["a", "b", "vector"]
+                .into_iter()
+                .map(|c| SelectItem::Column(c.into()))
+                .collect(),
+            query: QueryAst {
+                limit: Some(5),
+                ..QueryAst::from(OpTree::And(
+                    Box::new(OpTree::Eq(Value::Literal("a".into()), Value::Integer(1))),
+                    Box::new(OpTree::TopK(
+                        Value::Literal("vector".into()),
+                        Value::Integer(10),
+                    )),
+                ))
+            },
+        };
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        // Aggregates and grouped columns keep their order in the select list
+        let input = "select count(*), source from docs group by source, id order by source desc";
+        let count = Aggregate {
+            function: AggregateFn::Count,
+            column: None,
+        };
+        let expected = SelectAst {
+            table: "docs".into(),
+            items: vec![
+                SelectItem::Aggregate(count.clone()),
+                SelectItem::Column("source".into()),
+            ],
+            query: QueryAst {
+                aggregates: vec![count],
+                group_by: vec!["source".into(), "id".into()],
+                order_by: vec![SortKey {
+                    expr: SortExpr::Column("source".into()),
+                    direction: Direction::Desc,
+                }],
+                ..QueryAst::from(OpTree::Value(Value::Bool(true)))
+            },
+        };
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected.clone()));
+        assert_eq!(expected.columns().collect::<Vec<_>>(), vec!["source"]);
+
+        assert!(parser.parse("select from docs").is_err());
+        assert!(parser.parse("select a where a == 1").is_err());
+        assert!(parser.parse("select a from docs group by a where a == 1").is_err());
+    }
+
+    #[test]
+    fn parses_statements() {
+        let parser = query::StatementParser::new();
+        let column = |name: &str, data_type: &str, size| ColumnAst {
+            name: name.into(),
+            data_type: TypeAst {
+                name: data_type.into(),
+                size,
+            },
+            generated: None,
+            primary_key: false,
+            index: None,
+        };
+
+        let input = "create table t (id uuid primary key, ts datetime, hash binary_vector(512) index hamming, meta json, source string as (meta.source) index)";
+        let expected = Statement::CreateTable {
+            name: "t".into(),
+            columns: vec![
+                ColumnAst {
+                    primary_key: true,
+                    ..column("id", "uuid", None)
+                },
+                column("ts", "datetime", None),
+                ColumnAst {
+                    index: Some(IndexAst {
+                        name: None,
+                        column: "hash".into(),
+                        metric: Some("hamming".into()),
+                        method: None,
+                        options: vec![],
+                    }),
+                    ..column("hash", "binary_vector", Some(512))
+                },
+                column("meta", "json", None),
+                ColumnAst {
+                    generated: Some(FieldPath {
+                        column: "meta".into(),
+                        segments: vec![PathSegment::Key("source".into())],
+                    }),
+                    index: Some(IndexAst {
+                        name: None,
+                        column: "source".into(),
+                        metric: None,
+                        method: None,
+                        options: vec![],
+                    }),
+                    ..column("source", "string", None)
+                },
+            ],
+        };
+        assert_eq!(parser.parse(input), Ok(expected.clone()));
+        assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+
+        let hnsw = IndexAst {
+            name: Some("hashidx".into()),
+            column: "hash".into(),
+            metric: None,
+            method: Some("hnsw".into()),
+            options: vec![
+                ("m".into(), Value::Integer(16)),
+                ("ef".into(), Value::Integer(200)),
+            ],
+        };
+        for (input, expected) in [
+            (
+                "drop table t",
+                Statement::DropTable { name: "t".into() },
+            ),
+            (
+                "alter table t add column score f64 index",
+                Statement::AlterTable {
+                    table: "t".into(),
+                    change: AlterAst::AddColumn(ColumnAst {
+                        index: Some(IndexAst {
+                            name: None,
+                            column: "score".into(),
+                            metric: None,
+                            method: None,
+                            options: vec![],
+                        }),
+                        ..column("score", "f64", None)
+                    }),
+                },
+            ),
+            (
+                "alter table t add index hashidx (hash) using hnsw with (m = 16, ef = 200)",
+                Statement::AlterTable {
+                    table: "t".into(),
+                    change: AlterAst::AddIndex(hnsw.clone()),
+                },
+            ),
+            (
+                "create index hashidx on t (hash) using hnsw with (m=16, ef=200)",
+                Statement::CreateIndex {
+                    table: "t".into(),
+                    index: hnsw,
+                },
+            ),
+        ] {
+            assert_eq!(parser.parse(input), Ok(expected.clone()));
+            assert_eq!(parser.parse(&expected.to_string()), Ok(expected));
+        }
+
+        assert!(matches!(
+            parser.parse("select a from t"),
+            Ok(Statement::Select(_))
+        ));
+        assert!(parser.parse("create table t ()").is_err());
+        assert!(parser.parse("create index on t (hash)").is_err());
+    }
+
+    mod round_trip {
+        use chrono::TimeZone;
+        use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+        use super::*;
+
+        /// Column names, including reserved words and others that have to be quoted
+        const COLUMNS: [&str; 9] = [
+            "a",
+            "b",
+            "id",
+            "name",
+            "vec1",
+            "snake_case",
+            "count",
+            "key",
+            "odd `name",
+        ];
+
+        /// Json keys, including ones that have to be quoted
+        const KEYS: [&str; 5] = ["tags", "_id", "first name", "a\"b", ""];
+
+        fn column(g: &mut Gen) -> String {
+            g.choose(&COLUMNS).unwrap().to_string()
+        }
+
+        /// Finite and, for weights, non-negative: the query language has no literal for others
+        fn double(g: &mut Gen) -> f64 {
+            let d = f64::arbitrary(g);
+            if d.is_finite() {
+                d
+            } else {
+                0.5
+            }
+        }
+
+        fn list<T>(g: &mut Gen, item: impl Fn(&mut Gen) -> T) -> Vec<T> {
+            let len = 1 + usize::arbitrary(g) % 3;
+            (0..len).map(|_| item(g)).collect()
+        }
+
+        /// A value predicates made of a single value can take, anything but an expression
+        fn atom(g: &mut Gen) -> Value {
+            match u8::arbitrary(g) % 10 {
+                0 => Value::Double(double(g)),
+                1 => Value::Integer(i64::arbitrary(g)),
+                2 => Value::String(String::arbitrary(g)),
+                3 => Value::Bool(bool::arbitrary(g)),
+                4 => {
+                    // Years 1 to 9999, which have four digits
+                    let seconds = i64::arbitrary(g).rem_euclid(315_537_897_600) - 62_135_596_800;
+                    let nanos = u32::arbitrary(g) % 1_000_000_000;
+                    Value::DateTime(Utc.timestamp_opt(seconds, nanos).unwrap())
+                }
+                5 => Value::UUID(uuid::Uuid::from_u128(u128::arbitrary(g))),
+                6 => Value::ResourceTag(u32::arbitrary(g)),
+                7 => Value::Literal(column(g)),
+                8 => Value::Interval(Interval {
+                    months: i32::arbitrary(g),
+                    micros: i64::arbitrary(g),
+                }),
+                _ => {
+                    let segment = |g: &mut Gen| {
+                        if bool::arbitrary(g) {
+                            PathSegment::Index(u64::arbitrary(g))
+                        } else {
+                            PathSegment::Key(g.choose(&KEYS).unwrap().to_string())
+                        }
+                    };
+                    Value::Field(FieldPath {
+                        column: column(g),
+                        segments: list(g, segment),
+                    })
+                }
+            }
+        }
+
+        fn value(g: &mut Gen, depth: usize) -> Value {
+            if depth == 0 || u8::arbitrary(g) % 3 != 0 {
+                return atom(g);
+            }
+            let expr = if bool::arbitrary(g) {
+                let ops = [
+                    ArithmeticOp::Add,
+                    ArithmeticOp::Sub,
+                    ArithmeticOp::Mul,
+                    ArithmeticOp::Div,
+                ];
+                let op = *g.choose(&ops).unwrap();
+                Expr::Arithmetic(op, value(g, depth - 1), value(g, depth - 1))
+            } else {
+                let function = *g.choose(&Function::ALL).unwrap();
+                let arguments = (0..function.arity())
+                    .map(|_| value(g, depth - 1))
+                    .collect();
+                Expr::Call(function, arguments)
+            };
+            Value::Expr(Box::new(expr))
+        }
+
+        impl Arbitrary for Value {
+            fn arbitrary(g: &mut Gen) -> Self {
+                value(g, 2)
+            }
+        }
+
+        fn target(g: &mut Gen) -> VectorTarget {
+            VectorTarget {
+                vector: Value::arbitrary(g),
+                metric: bool::arbitrary(g).then(|| "hamming".to_string()),
+            }
+        }
+
+        fn tree(g: &mut Gen, depth: usize) -> OpTree {
+            let v = |g: &mut Gen| Value::arbitrary(g);
+            let choices = if depth == 0 { 18 } else { 21 };
+            match u8::arbitrary(g) % choices {
+                0 => OpTree::Eq(v(g), v(g)),
+                1 => OpTree::Neq(v(g), v(g)),
+                2 => OpTree::Lt(v(g), v(g)),
+                3 => OpTree::Lte(v(g), v(g)),
+                4 => OpTree::Gt(v(g), v(g)),
+                5 => OpTree::Gte(v(g), v(g)),
+                6 => OpTree::Within(v(g), v(g)),
+                7 => OpTree::TopK(v(g), v(g)),
+                8 => OpTree::WithinOf(v(g), v(g), target(g)),
+                9 => OpTree::TopKNear(v(g), v(g), target(g)),
+                10 => OpTree::In(v(g), list(g, v)),
+                11 => OpTree::Between(v(g), v(g), v(g)),
+                12 => OpTree::Like(v(g), v(g)),
+                13 => OpTree::IsNull(v(g)),
+                14 => OpTree::IsNotNull(v(g)),
+                15 => OpTree::Value(v(g)),
+                16 => {
+                    let method = if bool::arbitrary(g) {
+                        FusionMethod::Rrf(u64::arbitrary(g))
+                    } else {
+                        FusionMethod::Weighted(list(g, |g| double(g).abs()))
+                    };
+                    let searches =
+                        list(g, |g| OpTree::TopKNear(v(g), v(g), target(g)));
+                    OpTree::Fuse(method, searches)
+                }
+                17 => OpTree::Not(Box::new(OpTree::Value(v(g)))),
+                18 => OpTree::And(Box::new(tree(g, depth - 1)), Box::new(tree(g, depth - 1))),
+                19 => OpTree::Or(Box::new(tree(g, depth - 1)), Box::new(tree(g, depth - 1))),
+                _ => OpTree::Not(Box::new(tree(g, depth - 1))),
+            }
+        }
+
+        impl Arbitrary for OpTree {
+            fn arbitrary(g: &mut Gen) -> Self {
+                tree(g, 3)
+            }
+        }
+
+        fn sort_key(g: &mut Gen) -> SortKey {
+            let expr = if bool::arbitrary(g) {
+                SortExpr::Column(column(g))
+            } else {
+                SortExpr::Distance(column(g), Value::arbitrary(g))
+            };
+            let direction = *g.choose(&[Direction::Asc, Direction::Desc]).unwrap();
+            SortKey { expr, direction }
+        }
+
+        fn aggregate(g: &mut Gen) -> Aggregate {
+            use AggregateFn::*;
+            let function = *g.choose(&[Count, CountDistinct, Min, Max, Sum, Avg]).unwrap();
+            let column = match function {
+                Count if bool::arbitrary(g) => None,
+                _ => Some(column(g)),
+            };
+            Aggregate { function, column }
+        }
+
+        impl Arbitrary for QueryAst {
+            fn arbitrary(g: &mut Gen) -> Self {
+                let aggregates = if bool::arbitrary(g) {
+                    list(g, aggregate)
+                } else {
+                    Vec::new()
+                };
+                let group_by = if aggregates.is_empty() {
+                    Vec::new()
+                } else {
+                    Vec::<()>::arbitrary(g).iter().map(|_| column(g)).collect()
+                };
+                QueryAst {
+                    filter: OpTree::arbitrary(g),
+                    aggregates,
+                    group_by,
+                    order_by: Vec::<()>::arbitrary(g).iter().map(|_| sort_key(g)).collect(),
+                    limit: Option::arbitrary(g),
+                    offset: Option::arbitrary(g),
+                }
+            }
+        }
+
+        impl Arbitrary for SelectAst {
+            fn arbitrary(g: &mut Gen) -> Self {
+                let mut query = QueryAst::arbitrary(g);
+                // Select lists always have a column or an aggregate, columns have to be grouped
+                // by alongside aggregates, which they can come before or after
+                let mut columns = if query.aggregates.is_empty() {
+                    list(g, column)
+                } else {
+                    query
+                        .group_by
+                        .iter()
+                        .filter(|_| bool::arbitrary(g))
+                        .cloned()
+                        .collect()
+                };
+                if query.aggregates.is_empty() && columns.is_empty() {
+                    query.aggregates.push(aggregate(g));
+                }
+                let mut aggregates = query.aggregates.clone();
+                let mut items = Vec::new();
+                while !columns.is_empty() || !aggregates.is_empty() {
+                    if aggregates.is_empty() || (!columns.is_empty() && bool::arbitrary(g)) {
+                        items.push(SelectItem::Column(columns.remove(0)));
+                    } else {
+                        items.push(SelectItem::Aggregate(aggregates.remove(0)));
+                    }
+                }
+                SelectAst {
+                    table: column(g),
+                    items,
+                    query,
+                }
+            }
+        }
+
+        fn check<T: Arbitrary + fmt::Debug>(property: fn(T) -> bool) {
+            QuickCheck::new().tests(2_000).quickcheck(property);
+        }
+
+        // Building a parser compiles its lexer, which is too slow to do for every case
+        thread_local! {
+            static VALUE: query::ValueParser = query::ValueParser::new();
+            static SCOPE: query::ScopeParser = query::ScopeParser::new();
+            static QUERY: query::QueryParser = query::QueryParser::new();
+            static SELECT: query::SelectParser = query::SelectParser::new();
+        }
+
+        #[test]
+        fn prints_parseable_values() {
+            check(|v: Value| VALUE.with(|p| p.parse(&v.to_string()) == Ok(v)));
+        }
+
+        #[test]
+        fn prints_parseable_trees() {
+            check(|t: OpTree| SCOPE.with(|p| p.parse(&t.to_string()) == Ok(t)));
+        }
+
+        #[test]
+        fn prints_parseable_queries() {
+            check(|q: QueryAst| QUERY.with(|p| p.parse(&q.to_string()) == Ok(q)));
+            check(|s: SelectAst| SELECT.with(|p| p.parse(&s.to_string()) == Ok(s)));
+        }
+
+        #[test]
+        fn encodes_with_serde() {
+            check(|s: SelectAst| {
+                let json = serde_json::to_string(&s).unwrap();
+                serde_json::from_str::<SelectAst>(&json).unwrap() == s
+            });
+        }
+
+        #[test]
+        fn encodes_as_protobuf() {
+            use prost::Message;
+            use sifter_proto::query::Select;
+
+            check(|s: SelectAst| {
+                let bytes = Select::from(s.clone()).encode_to_vec();
+                let decoded = Select::decode(bytes.as_slice()).unwrap();
+                SelectAst::try_from(decoded).unwrap() == s
+            });
+            assert!(SelectAst::try_from(Select::default()).is_err());
+        }
+    }
 }
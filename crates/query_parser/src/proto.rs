@@ -0,0 +1,558 @@
+use chrono::DateTime;
+use sifter_proto::query as proto;
+use sifter_proto::query::{fusion_method::Method, op_tree::Op, sort_key::Expr};
+
+use crate::{
+    Aggregate, AggregateFn, ArithmeticOp, Direction, Expr as Expression, FieldPath, Function,
+    FusionMethod, Interval, OpTree, PathSegment, QueryAst, SelectAst, SelectItem, SortExpr,
+    SortKey, Value, VectorTarget,
+};
+
+fn invalid(message: String) -> error::Error {
+    error::CustomErrors::InvalidArguments(message).into()
+}
+
+/// A field every encoded tree has to set
+fn required<T>(field: Option<T>, name: &str) -> error::Result<T> {
+    field.ok_or_else(|| invalid(format!("missing {}", name)))
+}
+
+fn value(field: Option<proto::Value>, name: &str) -> error::Result<Value> {
+    required(field, name)?.try_into()
+}
+
+impl From<Value> for proto::Value {
+    fn from(value: Value) -> Self {
+        use proto::value::Value as V;
+        let value = match value {
+            Value::Double(d) => V::Double(d),
+            Value::Integer(i) => V::Integer(i),
+            Value::String(s) => V::String(s),
+            Value::Bool(b) => V::Bool(b),
+            Value::DateTime(d) => V::DateTime(d.to_rfc3339()),
+            Value::UUID(u) => V::Uuid(u.as_bytes().to_vec()),
+            Value::ResourceTag(r) => V::ResourceTag(r),
+            Value::Literal(l) => V::Literal(l),
+            Value::Field(path) => V::Field(path.into()),
+            Value::Expr(expr) => V::Expression(Box::new((*expr).into())),
+            Value::Interval(i) => V::Interval(i.into()),
+        };
+        proto::Value { value: Some(value) }
+    }
+}
+
+impl TryFrom<proto::Value> for Value {
+    type Error = error::Error;
+
+    fn try_from(value: proto::Value) -> error::Result<Self> {
+        use proto::value::Value as V;
+        Ok(match required(value.value, "value")? {
+            V::Double(d) => Value::Double(d),
+            V::Integer(i) => Value::Integer(i),
+            V::String(s) => Value::String(s),
+            V::Bool(b) => Value::Bool(b),
+            V::DateTime(d) => Value::DateTime(
+                DateTime::parse_from_rfc3339(&d)
+                    .map_err(|e| invalid(format!("invalid datetime {}: {}", d, e)))?
+                    .into(),
+            ),
+            V::Uuid(u) => Value::UUID(
+                uuid::Uuid::from_slice(&u).map_err(|e| invalid(format!("invalid uuid: {}", e)))?,
+            ),
+            V::ResourceTag(r) => Value::ResourceTag(r),
+            V::Literal(l) => Value::Literal(l),
+            V::Field(path) => Value::Field(path.try_into()?),
+            V::Expression(expr) => Value::Expr(Box::new((*expr).try_into()?)),
+            V::Interval(i) => Value::Interval(i.into()),
+        })
+    }
+}
+
+impl From<Interval> for proto::Interval {
+    fn from(interval: Interval) -> Self {
+        proto::Interval {
+            months: interval.months,
+            micros: interval.micros,
+        }
+    }
+}
+
+impl From<proto::Interval> for Interval {
+    fn from(interval: proto::Interval) -> Self {
+        Interval {
+            months: interval.months,
+            micros: interval.micros,
+        }
+    }
+}
+
+impl From<Expression> for proto::Expression {
+    fn from(expr: Expression) -> Self {
+        use proto::expression::Expression as E;
+        let expression = match expr {
+            Expression::Arithmetic(op, left, right) => {
+                let op = match op {
+                    ArithmeticOp::Add => proto::ArithmeticOp::Add,
+                    ArithmeticOp::Sub => proto::ArithmeticOp::Sub,
+                    ArithmeticOp::Mul => proto::ArithmeticOp::Mul,
+                    ArithmeticOp::Div => proto::ArithmeticOp::Div,
+                };
+                E::Arithmetic(Box::new(proto::Arithmetic {
+                    op: op.into(),
+                    left: Some(Box::new(left.into())),
+                    right: Some(Box::new(right.into())),
+                }))
+            }
+            Expression::Call(function, arguments) => {
+                let function = match function {
+                    Function::Now => proto::Function::Now,
+                    Function::DateTrunc => proto::Function::DateTrunc,
+                    Function::Lower => proto::Function::Lower,
+                    Function::Len => proto::Function::Len,
+                    Function::Hamming => proto::Function::Hamming,
+                    Function::Cosine => proto::Function::Cosine,
+                };
+                E::Call(proto::Call {
+                    function: function.into(),
+                    arguments: arguments.into_iter().map(Into::into).collect(),
+                })
+            }
+        };
+        proto::Expression {
+            expression: Some(expression),
+        }
+    }
+}
+
+impl TryFrom<proto::Expression> for Expression {
+    type Error = error::Error;
+
+    fn try_from(expr: proto::Expression) -> error::Result<Self> {
+        use proto::expression::Expression as E;
+        Ok(match required(expr.expression, "expression")? {
+            E::Arithmetic(arithmetic) => {
+                let op = proto::ArithmeticOp::try_from(arithmetic.op)
+                    .map_err(|_| invalid(format!("unknown operator {}", arithmetic.op)))?;
+                let op = match op {
+                    proto::ArithmeticOp::Add => ArithmeticOp::Add,
+                    proto::ArithmeticOp::Sub => ArithmeticOp::Sub,
+                    proto::ArithmeticOp::Mul => ArithmeticOp::Mul,
+                    proto::ArithmeticOp::Div => ArithmeticOp::Div,
+                };
+                let operand = |v: Option<Box<proto::Value>>, name| value(v.map(|v| *v), name);
+                Expression::Arithmetic(
+                    op,
+                    operand(arithmetic.left, "left operand")?,
+                    operand(arithmetic.right, "right operand")?,
+                )
+            }
+            E::Call(call) => {
+                let function = proto::Function::try_from(call.function)
+                    .map_err(|_| invalid(format!("unknown function {}", call.function)))?;
+                let function = match function {
+                    proto::Function::Now => Function::Now,
+                    proto::Function::DateTrunc => Function::DateTrunc,
+                    proto::Function::Lower => Function::Lower,
+                    proto::Function::Len => Function::Len,
+                    proto::Function::Hamming => Function::Hamming,
+                    proto::Function::Cosine => Function::Cosine,
+                };
+                if call.arguments.len() != function.arity() {
+                    return Err(invalid(format!(
+                        "{} takes {} arguments, got {}",
+                        function.name(),
+                        function.arity(),
+                        call.arguments.len()
+                    )));
+                }
+                let arguments = call
+                    .arguments
+                    .into_iter()
+                    .map(Value::try_from)
+                    .collect::<error::Result<_>>()?;
+                Expression::Call(function, arguments)
+            }
+        })
+    }
+}
+
+impl From<FieldPath> for proto::FieldPath {
+    fn from(path: FieldPath) -> Self {
+        use proto::path_segment::Segment;
+        let segments = path
+            .segments
+            .into_iter()
+            .map(|segment| {
+                let segment = match segment {
+                    PathSegment::Key(key) => Segment::Key(key),
+                    PathSegment::Index(i) => Segment::Index(i),
+                };
+                proto::PathSegment {
+                    segment: Some(segment),
+                }
+            })
+            .collect();
+        proto::FieldPath {
+            column: path.column,
+            segments,
+        }
+    }
+}
+
+impl TryFrom<proto::FieldPath> for FieldPath {
+    type Error = error::Error;
+
+    fn try_from(path: proto::FieldPath) -> error::Result<Self> {
+        use proto::path_segment::Segment;
+        let segments = path
+            .segments
+            .into_iter()
+            .map(|segment| {
+                Ok(match required(segment.segment, "path segment")? {
+                    Segment::Key(key) => PathSegment::Key(key),
+                    Segment::Index(i) => PathSegment::Index(i),
+                })
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        if segments.is_empty() {
+            return Err(invalid(format!("field path of {} is empty", path.column)));
+        }
+        Ok(FieldPath {
+            column: path.column,
+            segments,
+        })
+    }
+}
+
+impl From<VectorTarget> for proto::VectorTarget {
+    fn from(target: VectorTarget) -> Self {
+        proto::VectorTarget {
+            vector: Some(target.vector.into()),
+            metric: target.metric,
+        }
+    }
+}
+
+impl TryFrom<proto::VectorTarget> for VectorTarget {
+    type Error = error::Error;
+
+    fn try_from(target: proto::VectorTarget) -> error::Result<Self> {
+        Ok(VectorTarget {
+            vector: value(target.vector, "target vector")?,
+            metric: target.metric,
+        })
+    }
+}
+
+impl From<FusionMethod> for proto::FusionMethod {
+    fn from(method: FusionMethod) -> Self {
+        let method = match method {
+            FusionMethod::Rrf(k) => Method::Rrf(k),
+            FusionMethod::Weighted(weights) => Method::Weighted(proto::Weights { weights }),
+        };
+        proto::FusionMethod {
+            method: Some(method),
+        }
+    }
+}
+
+impl TryFrom<proto::FusionMethod> for FusionMethod {
+    type Error = error::Error;
+
+    fn try_from(method: proto::FusionMethod) -> error::Result<Self> {
+        Ok(match required(method.method, "fusion method")? {
+            Method::Rrf(k) => FusionMethod::Rrf(k),
+            Method::Weighted(w) => FusionMethod::Weighted(w.weights),
+        })
+    }
+}
+
+fn comparison(left: Value, right: Value) -> proto::Comparison {
+    proto::Comparison {
+        left: Some(left.into()),
+        right: Some(right.into()),
+    }
+}
+
+fn vector_search(column: Value, limit: Value, target: VectorTarget) -> proto::VectorSearch {
+    proto::VectorSearch {
+        column: Some(column.into()),
+        limit: Some(limit.into()),
+        target: Some(target.into()),
+    }
+}
+
+fn logical(left: OpTree, right: OpTree) -> Box<proto::Logical> {
+    Box::new(proto::Logical {
+        left: Some(Box::new(left.into())),
+        right: Some(Box::new(right.into())),
+    })
+}
+
+impl From<OpTree> for proto::OpTree {
+    fn from(tree: OpTree) -> Self {
+        let op = match tree {
+            OpTree::Eq(a, b) => Op::Eq(comparison(a, b)),
+            OpTree::Neq(a, b) => Op::Neq(comparison(a, b)),
+            OpTree::Lt(a, b) => Op::Lt(comparison(a, b)),
+            OpTree::Lte(a, b) => Op::Lte(comparison(a, b)),
+            OpTree::Gt(a, b) => Op::Gt(comparison(a, b)),
+            OpTree::Gte(a, b) => Op::Gte(comparison(a, b)),
+            OpTree::Within(a, b) => Op::Within(comparison(a, b)),
+            OpTree::TopK(a, b) => Op::Topk(comparison(a, b)),
+            OpTree::WithinOf(a, b, t) => Op::WithinOf(vector_search(a, b, t)),
+            OpTree::TopKNear(a, b, t) => Op::TopkNear(vector_search(a, b, t)),
+            OpTree::Fuse(method, searches) => Op::Fuse(proto::Fuse {
+                method: Some(method.into()),
+                searches: searches.into_iter().map(Into::into).collect(),
+            }),
+            OpTree::In(a, values) => Op::In(proto::In {
+                value: Some(a.into()),
+                values: values.into_iter().map(Into::into).collect(),
+            }),
+            OpTree::Between(a, lower, upper) => Op::Between(proto::Between {
+                value: Some(a.into()),
+                lower: Some(lower.into()),
+                upper: Some(upper.into()),
+            }),
+            OpTree::Like(a, b) => Op::Like(comparison(a, b)),
+            OpTree::IsNull(a) => Op::IsNull(a.into()),
+            OpTree::IsNotNull(a) => Op::IsNotNull(a.into()),
+            OpTree::And(a, b) => Op::And(logical(*a, *b)),
+            OpTree::Or(a, b) => Op::Or(logical(*a, *b)),
+            OpTree::Not(t) => Op::Not(Box::new((*t).into())),
+            OpTree::Value(v) => Op::Value(v.into()),
+        };
+        proto::OpTree { op: Some(op) }
+    }
+}
+
+impl TryFrom<proto::OpTree> for OpTree {
+    type Error = error::Error;
+
+    fn try_from(tree: proto::OpTree) -> error::Result<Self> {
+        let operands = |c: proto::Comparison| -> error::Result<(Value, Value)> {
+            Ok((
+                value(c.left, "left operand")?,
+                value(c.right, "right operand")?,
+            ))
+        };
+        let search = |s: proto::VectorSearch| -> error::Result<(Value, Value, VectorTarget)> {
+            Ok((
+                value(s.column, "vector column")?,
+                value(s.limit, "search limit")?,
+                required(s.target, "vector target")?.try_into()?,
+            ))
+        };
+        let operand = |t: Option<Box<proto::OpTree>>| -> error::Result<Box<OpTree>> {
+            Ok(Box::new((*required(t, "operand")?).try_into()?))
+        };
+
+        Ok(match required(tree.op, "operator")? {
+            Op::Eq(c) => operands(c).map(|(a, b)| OpTree::Eq(a, b))?,
+            Op::Neq(c) => operands(c).map(|(a, b)| OpTree::Neq(a, b))?,
+            Op::Lt(c) => operands(c).map(|(a, b)| OpTree::Lt(a, b))?,
+            Op::Lte(c) => operands(c).map(|(a, b)| OpTree::Lte(a, b))?,
+            Op::Gt(c) => operands(c).map(|(a, b)| OpTree::Gt(a, b))?,
+            Op::Gte(c) => operands(c).map(|(a, b)| OpTree::Gte(a, b))?,
+            Op::Within(c) => operands(c).map(|(a, b)| OpTree::Within(a, b))?,
+            Op::Topk(c) => operands(c).map(|(a, b)| OpTree::TopK(a, b))?,
+            Op::WithinOf(s) => search(s).map(|(a, b, t)| OpTree::WithinOf(a, b, t))?,
+            Op::TopkNear(s) => search(s).map(|(a, b, t)| OpTree::TopKNear(a, b, t))?,
+            Op::Fuse(fuse) => OpTree::Fuse(
+                required(fuse.method, "fusion method")?.try_into()?,
+                fuse.searches
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<error::Result<_>>()?,
+            ),
+            Op::In(i) => OpTree::In(
+                value(i.value, "value")?,
+                i.values
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<error::Result<_>>()?,
+            ),
+            Op::Between(b) => OpTree::Between(
+                value(b.value, "value")?,
+                value(b.lower, "lower bound")?,
+                value(b.upper, "upper bound")?,
+            ),
+            Op::Like(c) => operands(c).map(|(a, b)| OpTree::Like(a, b))?,
+            Op::IsNull(v) => OpTree::IsNull(v.try_into()?),
+            Op::IsNotNull(v) => OpTree::IsNotNull(v.try_into()?),
+            Op::And(l) => OpTree::And(operand(l.left)?, operand(l.right)?),
+            Op::Or(l) => OpTree::Or(operand(l.left)?, operand(l.right)?),
+            Op::Not(t) => OpTree::Not(Box::new((*t).try_into()?)),
+            Op::Value(v) => OpTree::Value(v.try_into()?),
+        })
+    }
+}
+
+impl From<SortKey> for proto::SortKey {
+    fn from(key: SortKey) -> Self {
+        let expr = match key.expr {
+            SortExpr::Column(c) => Expr::Column(c),
+            SortExpr::Distance(column, target) => Expr::Distance(proto::Distance {
+                column,
+                target: Some(target.into()),
+            }),
+        };
+        proto::SortKey {
+            descending: key.direction == Direction::Desc,
+            expr: Some(expr),
+        }
+    }
+}
+
+impl TryFrom<proto::SortKey> for SortKey {
+    type Error = error::Error;
+
+    fn try_from(key: proto::SortKey) -> error::Result<Self> {
+        let expr = match required(key.expr, "sort expression")? {
+            Expr::Column(c) => SortExpr::Column(c),
+            Expr::Distance(d) => SortExpr::Distance(d.column, value(d.target, "target")?),
+        };
+        let direction = if key.descending {
+            Direction::Desc
+        } else {
+            Direction::Asc
+        };
+        Ok(SortKey { expr, direction })
+    }
+}
+
+impl From<Aggregate> for proto::Aggregate {
+    fn from(aggregate: Aggregate) -> Self {
+        let function = match aggregate.function {
+            AggregateFn::Count => proto::AggregateFn::Count,
+            AggregateFn::CountDistinct => proto::AggregateFn::CountDistinct,
+            AggregateFn::Min => proto::AggregateFn::Min,
+            AggregateFn::Max => proto::AggregateFn::Max,
+            AggregateFn::Sum => proto::AggregateFn::Sum,
+            AggregateFn::Avg => proto::AggregateFn::Avg,
+        };
+        proto::Aggregate {
+            function: function.into(),
+            column: aggregate.column,
+        }
+    }
+}
+
+impl TryFrom<proto::Aggregate> for Aggregate {
+    type Error = error::Error;
+
+    fn try_from(aggregate: proto::Aggregate) -> error::Result<Self> {
+        let function = proto::AggregateFn::try_from(aggregate.function)
+            .map_err(|_| invalid(format!("unknown aggregate function {}", aggregate.function)))?;
+        let function = match function {
+            proto::AggregateFn::Count => AggregateFn::Count,
+            proto::AggregateFn::CountDistinct => AggregateFn::CountDistinct,
+            proto::AggregateFn::Min => AggregateFn::Min,
+            proto::AggregateFn::Max => AggregateFn::Max,
+            proto::AggregateFn::Sum => AggregateFn::Sum,
+            proto::AggregateFn::Avg => AggregateFn::Avg,
+        };
+        Ok(Aggregate {
+            function,
+            column: aggregate.column,
+        })
+    }
+}
+
+impl From<QueryAst> for proto::Query {
+    fn from(query: QueryAst) -> Self {
+        proto::Query {
+            filter: Some(query.filter.into()),
+            aggregates: query.aggregates.into_iter().map(Into::into).collect(),
+            group_by: query.group_by,
+            order_by: query.order_by.into_iter().map(Into::into).collect(),
+            limit: query.limit,
+            offset: query.offset,
+        }
+    }
+}
+
+impl TryFrom<proto::Query> for QueryAst {
+    type Error = error::Error;
+
+    fn try_from(query: proto::Query) -> error::Result<Self> {
+        Ok(QueryAst {
+            filter: required(query.filter, "filter")?.try_into()?,
+            aggregates: query
+                .aggregates
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<error::Result<_>>()?,
+            group_by: query.group_by,
+            order_by: query
+                .order_by
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<error::Result<_>>()?,
+            limit: query.limit,
+            offset: query.offset,
+        })
+    }
+}
+
+impl From<SelectItem> for proto::SelectItem {
+    fn from(item: SelectItem) -> Self {
+        let item = match item {
+            SelectItem::Column(column) => proto::select_item::Item::Column(column),
+            SelectItem::Aggregate(aggregate) => {
+                proto::select_item::Item::Aggregate(aggregate.into())
+            }
+        };
+        proto::SelectItem { item: Some(item) }
+    }
+}
+
+impl TryFrom<proto::SelectItem> for SelectItem {
+    type Error = error::Error;
+
+    fn try_from(item: proto::SelectItem) -> error::Result<Self> {
+        Ok(match required(item.item, "item")? {
+            proto::select_item::Item::Column(column) => SelectItem::Column(column),
+            proto::select_item::Item::Aggregate(aggregate) => {
+                SelectItem::Aggregate(aggregate.try_into()?)
+            }
+        })
+    }
+}
+
+impl From<SelectAst> for proto::Select {
+    fn from(select: SelectAst) -> Self {
+        proto::Select {
+            table: select.table,
+            items: select.items.into_iter().map(Into::into).collect(),
+            query: Some(select.query.into()),
+        }
+    }
+}
+
+impl TryFrom<proto::Select> for SelectAst {
+    type Error = error::Error;
+
+    fn try_from(select: proto::Select) -> error::Result<Self> {
+        let items: Vec<SelectItem> = select
+            .items
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<error::Result<_>>()?;
+        let mut query: QueryAst = required(select.query, "query")?.try_into()?;
+        // The select list is the one source of the aggregates
+        query.aggregates = items
+            .iter()
+            .filter_map(|item| match item {
+                SelectItem::Aggregate(aggregate) => Some(aggregate.clone()),
+                SelectItem::Column(_) => None,
+            })
+            .collect();
+        Ok(SelectAst {
+            table: select.table,
+            items,
+            query,
+        })
+    }
+}
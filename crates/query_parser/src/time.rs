@@ -0,0 +1,356 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Duration, Months, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+/// A length of time, `'3 days'` or `'PT1H'`. Months are kept apart since their length depends
+/// on the date they are added to.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
+pub struct Interval {
+    pub months: i32,
+    pub micros: i64,
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Months(i32),
+    Micros(i64),
+}
+
+impl Unit {
+    fn from_name(name: &str) -> Option<Unit> {
+        let name = name.strip_suffix('s').unwrap_or(name);
+        Some(match name {
+            "microsecond" => Unit::Micros(1),
+            "millisecond" => Unit::Micros(1_000),
+            "second" => Unit::Micros(MICROS_PER_SECOND),
+            "minute" => Unit::Micros(60 * MICROS_PER_SECOND),
+            "hour" => Unit::Micros(3_600 * MICROS_PER_SECOND),
+            "day" => Unit::Micros(MICROS_PER_DAY),
+            "week" => Unit::Micros(7 * MICROS_PER_DAY),
+            "month" => Unit::Months(1),
+            "year" => Unit::Months(12),
+            _ => return None,
+        })
+    }
+}
+
+impl Interval {
+    pub fn from_micros(micros: i64) -> Self {
+        Interval { months: 0, micros }
+    }
+
+    /// Length used to order intervals, counting a month as 30 days
+    pub fn approximate_micros(&self) -> i128 {
+        self.months as i128 * 30 * MICROS_PER_DAY as i128 + self.micros as i128
+    }
+
+    pub fn checked_add(self, other: Interval) -> Option<Interval> {
+        Some(Interval {
+            months: self.months.checked_add(other.months)?,
+            micros: self.micros.checked_add(other.micros)?,
+        })
+    }
+
+    pub fn checked_neg(self) -> Option<Interval> {
+        Some(Interval {
+            months: self.months.checked_neg()?,
+            micros: self.micros.checked_neg()?,
+        })
+    }
+
+    pub fn checked_mul(self, n: i64) -> Option<Interval> {
+        Some(Interval {
+            months: i32::try_from(n).ok()?.checked_mul(self.months)?,
+            micros: self.micros.checked_mul(n)?,
+        })
+    }
+
+    /// Divide both parts by `n`, truncating each toward zero
+    pub fn checked_div(self, n: i64) -> Option<Interval> {
+        Some(Interval {
+            months: i32::try_from((self.months as i64).checked_div(n)?).ok()?,
+            micros: self.micros.checked_div(n)?,
+        })
+    }
+
+    /// The time this long after `time`, months first
+    pub fn add_to(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let months = Months::new(self.months.unsigned_abs());
+        let time = if self.months < 0 {
+            time.checked_sub_months(months)?
+        } else {
+            time.checked_add_months(months)?
+        };
+        time.checked_add_signed(Duration::microseconds(self.micros))
+    }
+
+    fn add_unit(&mut self, n: i64, unit: Unit) -> Option<()> {
+        match unit {
+            Unit::Months(months) => {
+                let n = i32::try_from(n).ok()?.checked_mul(months)?;
+                self.months = self.months.checked_add(n)?;
+            }
+            Unit::Micros(micros) => {
+                self.micros = self.micros.checked_add(n.checked_mul(micros)?)?
+            }
+        }
+        Some(())
+    }
+
+    /// Counts followed by units, `3 days` or `1 hour -30 minutes`
+    fn parse_units(s: &str) -> Option<Interval> {
+        let mut interval = Interval::default();
+        let mut words = s.split_whitespace();
+        let mut any = false;
+        while let Some(n) = words.next() {
+            let n = n.parse().ok()?;
+            interval.add_unit(n, Unit::from_name(words.next()?)?)?;
+            any = true;
+        }
+        any.then_some(interval)
+    }
+
+    /// ISO 8601 durations, `P1Y2M3W4DT5H6M7.5S`, optionally negated with a leading `-`
+    fn parse_iso(s: &str) -> Option<Interval> {
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let s = s.strip_prefix('P')?;
+        let (date, time) = match s.split_once('T') {
+            Some((date, time)) => (date, Some(time)),
+            None => (s, None),
+        };
+        let date_units = [
+            ('Y', Unit::Months(12)),
+            ('M', Unit::Months(1)),
+            ('W', Unit::Micros(7 * MICROS_PER_DAY)),
+            ('D', Unit::Micros(MICROS_PER_DAY)),
+        ];
+        let time_units = [
+            ('H', Unit::Micros(3_600 * MICROS_PER_SECOND)),
+            ('M', Unit::Micros(60 * MICROS_PER_SECOND)),
+            ('S', Unit::Micros(MICROS_PER_SECOND)),
+        ];
+
+        let mut interval = Interval::default();
+        let mut any = false;
+        for (mut part, units) in [
+            (date, &date_units[..]),
+            (time.unwrap_or(""), &time_units[..]),
+        ] {
+            for (designator, unit) in units {
+                let Some((n, rest)) = part.split_once(*designator) else {
+                    continue;
+                };
+                if n.is_empty() || n.contains(|c: char| c.is_ascii_alphabetic()) {
+                    return None;
+                }
+                match n.split_once('.') {
+                    // only seconds can have a fraction
+                    Some((seconds, fraction)) if *designator == 'S' && time.is_some() => {
+                        interval.add_unit(seconds.parse().ok()?, *unit)?;
+                        if fraction.is_empty() || fraction.len() > 6 {
+                            return None;
+                        }
+                        let micros: i64 = format!("{:0<6}", fraction).parse().ok()?;
+                        interval.add_unit(micros, Unit::Micros(1))?;
+                    }
+                    Some(_) => return None,
+                    None => interval.add_unit(n.parse().ok()?, *unit)?,
+                }
+                part = rest;
+                any = true;
+            }
+            if !part.is_empty() {
+                return None;
+            }
+        }
+        if !any || time == Some("") {
+            return None;
+        }
+        if negative {
+            interval.checked_neg()
+        } else {
+            Some(interval)
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> error::Result<Self> {
+        Interval::parse_iso(s)
+            .or_else(|| Interval::parse_units(s))
+            .ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!("invalid interval {}", s)).into()
+            })
+    }
+}
+
+/// Prints as counts of units the parser reads back, `1 year 2 months 3 days 4 hours`
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        let (years, months) = (self.months / 12, self.months % 12);
+        parts.extend([(years as i64, "year"), (months as i64, "month")]);
+        let mut micros = self.micros;
+        for (unit, name) in [
+            (MICROS_PER_DAY, "day"),
+            (3_600 * MICROS_PER_SECOND, "hour"),
+            (60 * MICROS_PER_SECOND, "minute"),
+            (MICROS_PER_SECOND, "second"),
+            (1, "microsecond"),
+        ] {
+            parts.push((micros / unit, name));
+            micros %= unit;
+        }
+
+        let mut first = true;
+        for (n, name) in parts.into_iter().filter(|(n, _)| *n != 0) {
+            if !first {
+                write!(f, " ")?;
+            }
+            write!(f, "{} {}{}", n, name, if n.abs() == 1 { "" } else { "s" })?;
+            first = false;
+        }
+        if first {
+            write!(f, "0 seconds")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a time in UTC from RFC 3339 or something close to it: a space instead of the `T`, an
+/// offset without its colon or minutes, or no offset for UTC. Also reads a date alone for its
+/// midnight. Named time zones (`America/New_York`, `EST`) aren't read, their offsets change
+/// with the date.
+///
+/// Times can also be given since the unix epoch, as `@` and whole seconds (`@1695686400`) or
+/// `@` and whole milliseconds ending in `ms` (`@1695686400000ms`).
+pub fn parse_datetime(s: &str) -> Option<DateTime<Utc>> {
+    if let Some(epoch) = s.strip_prefix('@') {
+        return match epoch.strip_suffix("ms") {
+            Some(millis) => Utc.timestamp_millis_opt(millis.parse().ok()?).single(),
+            None => Utc.timestamp_opt(epoch.parse().ok()?, 0).single(),
+        };
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Some(time.into());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%.f%#z"] {
+        if let Ok(time) = DateTime::parse_from_str(s, format) {
+            return Some(time.into());
+        }
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(time.and_utc());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_intervals() {
+        let hours = |n: i64| Interval::from_micros(n * 3_600 * MICROS_PER_SECOND);
+        for (s, expected) in [
+            ("3 days", hours(72)),
+            (
+                "1 hour 30 minutes",
+                hours(1)
+                    .checked_add(hours(1).checked_div(2).unwrap())
+                    .unwrap(),
+            ),
+            ("-2 weeks", hours(-14 * 24)),
+            (
+                "1 year -1 month",
+                Interval {
+                    months: 11,
+                    micros: 0,
+                },
+            ),
+            ("PT1H", hours(1)),
+            ("P1DT2H", hours(26)),
+            (
+                "P1Y2M",
+                Interval {
+                    months: 14,
+                    micros: 0,
+                },
+            ),
+            ("-PT0.5S", Interval::from_micros(-500_000)),
+        ] {
+            assert_eq!(s.parse::<Interval>().unwrap(), expected, "{}", s);
+            assert_eq!(expected.to_string().parse::<Interval>().unwrap(), expected);
+        }
+        for s in [
+            "",
+            "3",
+            "3 fortnights",
+            "P",
+            "PT",
+            "P1H",
+            "PT1.5M",
+            "1.5 days",
+            "P1D2",
+        ] {
+            assert!(s.parse::<Interval>().is_err(), "{}", s);
+        }
+        assert_eq!(Interval::default().to_string(), "0 seconds");
+        assert_eq!(hours(-25).to_string(), "-1 day -1 hour");
+    }
+
+    #[test]
+    fn adds_intervals_to_times() {
+        let time = parse_datetime("2020-01-31T12:00:00Z").unwrap();
+        let month = Interval {
+            months: 1,
+            micros: 0,
+        };
+        assert_eq!(month.add_to(time), parse_datetime("2020-02-29 12:00:00"));
+        let back = "-1 month -12 hours".parse::<Interval>().unwrap();
+        assert_eq!(back.add_to(time), parse_datetime("2019-12-31"));
+    }
+
+    #[test]
+    fn parses_datetimes() {
+        let expected = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        for s in [
+            "2020-01-01",
+            "2020-01-01T00:00:00Z",
+            "2020-01-01T00:00:00",
+            "2020-01-01 00:00:00.000",
+            "2020-01-01T05:30:00+05:30",
+            "2020-01-01T05:30:00+0530",
+            "2019-12-31 19:00:00-05",
+            "@1577836800",
+            "@1577836800000ms",
+        ] {
+            assert_eq!(parse_datetime(s), Some(expected), "{}", s);
+        }
+        for s in [
+            "2020-13-01",
+            "2020-01-01T25:00:00Z",
+            "@",
+            "@1.5",
+            "yesterday",
+            "2020-01-01 00:00:00 America/New_York",
+            "2020-01-01T00:00:00 EST",
+        ] {
+            assert_eq!(parse_datetime(s), None, "{}", s);
+        }
+    }
+}
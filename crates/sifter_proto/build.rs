@@ -1,8 +1,15 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
         .out_dir("src/proto")
-        .compile(&["proto/common.proto"], &["proto"])?;
+        .compile(
+            &[
+                "proto/common.proto",
+                "proto/query.proto",
+                "proto/statements.proto",
+            ],
+            &["proto"],
+        )?;
 
     Ok(())
 }
@@ -1,3 +1,4 @@
 mod proto;
 
 pub use proto::common::Test;
+pub use proto::{query, statements};
@@ -1,2 +1,7 @@
 #[path = "sifter.proto.common.rs"]
 pub mod common;
+#[path = "sifter.proto.query.rs"]
+pub mod query;
+#[allow(clippy::large_enum_variant)]
+#[path = "sifter.proto.statements.rs"]
+pub mod statements;
@@ -0,0 +1,439 @@
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Value {
+    #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11")]
+    pub value: ::core::option::Option<value::Value>,
+}
+/// Nested message and enum types in `Value`.
+pub mod value {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Value {
+        #[prost(double, tag = "1")]
+        Double(f64),
+        #[prost(int64, tag = "2")]
+        Integer(i64),
+        #[prost(string, tag = "3")]
+        String(::prost::alloc::string::String),
+        #[prost(bool, tag = "4")]
+        Bool(bool),
+        /// RFC 3339
+        #[prost(string, tag = "5")]
+        DateTime(::prost::alloc::string::String),
+        /// The 16 bytes of the uuid
+        #[prost(bytes, tag = "6")]
+        Uuid(::prost::alloc::vec::Vec<u8>),
+        /// `$n`, numbered from 1
+        #[prost(uint32, tag = "7")]
+        ResourceTag(u32),
+        /// A column
+        #[prost(string, tag = "8")]
+        Literal(::prost::alloc::string::String),
+        #[prost(message, tag = "9")]
+        Field(super::FieldPath),
+        #[prost(message, tag = "10")]
+        Expression(::prost::alloc::boxed::Box<super::Expression>),
+        #[prost(message, tag = "11")]
+        Interval(super::Interval),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Arithmetic {
+    #[prost(enumeration = "ArithmeticOp", tag = "1")]
+    pub op: i32,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub left: ::core::option::Option<::prost::alloc::boxed::Box<Value>>,
+    #[prost(message, optional, boxed, tag = "3")]
+    pub right: ::core::option::Option<::prost::alloc::boxed::Box<Value>>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Call {
+    #[prost(enumeration = "Function", tag = "1")]
+    pub function: i32,
+    #[prost(message, repeated, tag = "2")]
+    pub arguments: ::prost::alloc::vec::Vec<Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Expression {
+    #[prost(oneof = "expression::Expression", tags = "1, 2")]
+    pub expression: ::core::option::Option<expression::Expression>,
+}
+/// Nested message and enum types in `Expression`.
+pub mod expression {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Expression {
+        #[prost(message, tag = "1")]
+        Arithmetic(::prost::alloc::boxed::Box<super::Arithmetic>),
+        #[prost(message, tag = "2")]
+        Call(super::Call),
+    }
+}
+/// A length of time, months apart from the rest
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Interval {
+    #[prost(int32, tag = "1")]
+    pub months: i32,
+    #[prost(int64, tag = "2")]
+    pub micros: i64,
+}
+/// A field inside a json column
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FieldPath {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub segments: ::prost::alloc::vec::Vec<PathSegment>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PathSegment {
+    #[prost(oneof = "path_segment::Segment", tags = "1, 2")]
+    pub segment: ::core::option::Option<path_segment::Segment>,
+}
+/// Nested message and enum types in `PathSegment`.
+pub mod path_segment {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Segment {
+        #[prost(string, tag = "1")]
+        Key(::prost::alloc::string::String),
+        #[prost(uint64, tag = "2")]
+        Index(u64),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VectorTarget {
+    #[prost(message, optional, tag = "1")]
+    pub vector: ::core::option::Option<Value>,
+    #[prost(string, optional, tag = "2")]
+    pub metric: ::core::option::Option<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Weights {
+    #[prost(double, repeated, tag = "1")]
+    pub weights: ::prost::alloc::vec::Vec<f64>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FusionMethod {
+    #[prost(oneof = "fusion_method::Method", tags = "1, 2")]
+    pub method: ::core::option::Option<fusion_method::Method>,
+}
+/// Nested message and enum types in `FusionMethod`.
+pub mod fusion_method {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Method {
+        /// Reciprocal rank fusion constant
+        #[prost(uint64, tag = "1")]
+        Rrf(u64),
+        #[prost(message, tag = "2")]
+        Weighted(super::Weights),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Comparison {
+    #[prost(message, optional, tag = "1")]
+    pub left: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "2")]
+    pub right: ::core::option::Option<Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct VectorSearch {
+    #[prost(message, optional, tag = "1")]
+    pub column: ::core::option::Option<Value>,
+    /// `k` of `topk` or the radius of `within`
+    #[prost(message, optional, tag = "2")]
+    pub limit: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "3")]
+    pub target: ::core::option::Option<VectorTarget>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Fuse {
+    #[prost(message, optional, tag = "1")]
+    pub method: ::core::option::Option<FusionMethod>,
+    #[prost(message, repeated, tag = "2")]
+    pub searches: ::prost::alloc::vec::Vec<OpTree>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct In {
+    #[prost(message, optional, tag = "1")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(message, repeated, tag = "2")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Between {
+    #[prost(message, optional, tag = "1")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "2")]
+    pub lower: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "3")]
+    pub upper: ::core::option::Option<Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Logical {
+    #[prost(message, optional, boxed, tag = "1")]
+    pub left: ::core::option::Option<::prost::alloc::boxed::Box<OpTree>>,
+    #[prost(message, optional, boxed, tag = "2")]
+    pub right: ::core::option::Option<::prost::alloc::boxed::Box<OpTree>>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct OpTree {
+    #[prost(
+        oneof = "op_tree::Op",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20"
+    )]
+    pub op: ::core::option::Option<op_tree::Op>,
+}
+/// Nested message and enum types in `OpTree`.
+pub mod op_tree {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Op {
+        #[prost(message, tag = "1")]
+        Eq(super::Comparison),
+        #[prost(message, tag = "2")]
+        Neq(super::Comparison),
+        #[prost(message, tag = "3")]
+        Lt(super::Comparison),
+        #[prost(message, tag = "4")]
+        Lte(super::Comparison),
+        #[prost(message, tag = "5")]
+        Gt(super::Comparison),
+        #[prost(message, tag = "6")]
+        Gte(super::Comparison),
+        #[prost(message, tag = "7")]
+        Within(super::Comparison),
+        #[prost(message, tag = "8")]
+        Topk(super::Comparison),
+        #[prost(message, tag = "9")]
+        WithinOf(super::VectorSearch),
+        #[prost(message, tag = "10")]
+        TopkNear(super::VectorSearch),
+        #[prost(message, tag = "11")]
+        Fuse(super::Fuse),
+        #[prost(message, tag = "12")]
+        In(super::In),
+        #[prost(message, tag = "13")]
+        Between(super::Between),
+        #[prost(message, tag = "14")]
+        Like(super::Comparison),
+        #[prost(message, tag = "15")]
+        IsNull(super::Value),
+        #[prost(message, tag = "16")]
+        IsNotNull(super::Value),
+        #[prost(message, tag = "17")]
+        And(::prost::alloc::boxed::Box<super::Logical>),
+        #[prost(message, tag = "18")]
+        Or(::prost::alloc::boxed::Box<super::Logical>),
+        #[prost(message, tag = "19")]
+        Not(::prost::alloc::boxed::Box<super::OpTree>),
+        #[prost(message, tag = "20")]
+        Value(super::Value),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Distance {
+    #[prost(string, tag = "1")]
+    pub column: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub target: ::core::option::Option<Value>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SortKey {
+    #[prost(bool, tag = "3")]
+    pub descending: bool,
+    #[prost(oneof = "sort_key::Expr", tags = "1, 2")]
+    pub expr: ::core::option::Option<sort_key::Expr>,
+}
+/// Nested message and enum types in `SortKey`.
+pub mod sort_key {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Expr {
+        #[prost(string, tag = "1")]
+        Column(::prost::alloc::string::String),
+        #[prost(message, tag = "2")]
+        Distance(super::Distance),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Aggregate {
+    #[prost(enumeration = "AggregateFn", tag = "1")]
+    pub function: i32,
+    /// Not set for `count(*)`
+    #[prost(string, optional, tag = "2")]
+    pub column: ::core::option::Option<::prost::alloc::string::String>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Query {
+    #[prost(message, optional, tag = "1")]
+    pub filter: ::core::option::Option<OpTree>,
+    #[prost(message, repeated, tag = "2")]
+    pub aggregates: ::prost::alloc::vec::Vec<Aggregate>,
+    #[prost(string, repeated, tag = "3")]
+    pub group_by: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(message, repeated, tag = "4")]
+    pub order_by: ::prost::alloc::vec::Vec<SortKey>,
+    #[prost(uint64, optional, tag = "5")]
+    pub limit: ::core::option::Option<u64>,
+    #[prost(uint64, optional, tag = "6")]
+    pub offset: ::core::option::Option<u64>,
+}
+/// A column or an aggregate in a select list
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SelectItem {
+    #[prost(oneof = "select_item::Item", tags = "1, 2")]
+    pub item: ::core::option::Option<select_item::Item>,
+}
+/// Nested message and enum types in `SelectItem`.
+pub mod select_item {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Item {
+        #[prost(string, tag = "1")]
+        Column(::prost::alloc::string::String),
+        #[prost(message, tag = "2")]
+        Aggregate(super::Aggregate),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Select {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub query: ::core::option::Option<Query>,
+    /// Columns and aggregates in the order they are returned
+    #[prost(message, repeated, tag = "4")]
+    pub items: ::prost::alloc::vec::Vec<SelectItem>,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ArithmeticOp {
+    Add = 0,
+    Sub = 1,
+    Mul = 2,
+    Div = 3,
+}
+impl ArithmeticOp {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "ADD",
+            ArithmeticOp::Sub => "SUB",
+            ArithmeticOp::Mul => "MUL",
+            ArithmeticOp::Div => "DIV",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ADD" => Some(Self::Add),
+            "SUB" => Some(Self::Sub),
+            "MUL" => Some(Self::Mul),
+            "DIV" => Some(Self::Div),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Function {
+    Now = 0,
+    DateTrunc = 1,
+    Lower = 2,
+    Len = 3,
+    Hamming = 4,
+    Cosine = 5,
+}
+impl Function {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Function::Now => "NOW",
+            Function::DateTrunc => "DATE_TRUNC",
+            Function::Lower => "LOWER",
+            Function::Len => "LEN",
+            Function::Hamming => "HAMMING",
+            Function::Cosine => "COSINE",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "NOW" => Some(Self::Now),
+            "DATE_TRUNC" => Some(Self::DateTrunc),
+            "LOWER" => Some(Self::Lower),
+            "LEN" => Some(Self::Len),
+            "HAMMING" => Some(Self::Hamming),
+            "COSINE" => Some(Self::Cosine),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum AggregateFn {
+    Count = 0,
+    CountDistinct = 1,
+    Min = 2,
+    Max = 3,
+    Sum = 4,
+    Avg = 5,
+}
+impl AggregateFn {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            AggregateFn::Count => "COUNT",
+            AggregateFn::CountDistinct => "COUNT_DISTINCT",
+            AggregateFn::Min => "MIN",
+            AggregateFn::Max => "MAX",
+            AggregateFn::Sum => "SUM",
+            AggregateFn::Avg => "AVG",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "COUNT" => Some(Self::Count),
+            "COUNT_DISTINCT" => Some(Self::CountDistinct),
+            "MIN" => Some(Self::Min),
+            "MAX" => Some(Self::Max),
+            "SUM" => Some(Self::Sum),
+            "AVG" => Some(Self::Avg),
+            _ => None,
+        }
+    }
+}
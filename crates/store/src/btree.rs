@@ -0,0 +1,838 @@
+use std::{collections::VecDeque, mem, ops::Bound};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard};
+
+use crate::{
+    page::{PageId, PageLoan, PageManager, RawPage, PAGE_DATA_SIZE},
+    source::SourceTag,
+};
+
+/// Longest key a tree holds, small enough that every node fits several
+pub const MAX_KEY_SIZE: usize = 512;
+
+const MAGIC: &[u8; 8] = b"BTREE\0\0\x01";
+/// Kind, entry count and link of every node
+const NODE_HEADER_SIZE: usize = 11;
+/// Key length and value stored with each key
+const ENTRY_OVERHEAD: usize = 10;
+/// How full bulk loading packs nodes, leaving room for inserts after
+const BULK_FILL: usize = PAGE_DATA_SIZE * 9 / 10;
+
+fn not_a_tree(id: PageId) -> error::Error {
+    error::CustomErrors::InvalidState(format!("page {} isn't part of a btree", id)).into()
+}
+
+pub(crate) fn check_key(key: &[u8]) -> error::Result<()> {
+    if key.len() > MAX_KEY_SIZE {
+        return Err(error::CustomErrors::InvalidArguments(format!(
+            "btree keys are at most {} bytes, got {}",
+            MAX_KEY_SIZE,
+            key.len()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn read_root(meta: &RawPage) -> error::Result<PageId> {
+    if &meta.data[..8] != MAGIC {
+        return Err(not_a_tree(meta.header.id));
+    }
+    Ok(u64::from_le_bytes(meta.data[8..16].try_into().unwrap()))
+}
+
+fn write_root(page_store: &PageManager, meta: &mut RawPage, root: PageId) {
+    meta.data[..8].copy_from_slice(MAGIC);
+    meta.data[8..16].copy_from_slice(&root.to_le_bytes());
+    meta.header.size = 16;
+    page_store.log_write(meta, 0..16);
+}
+
+struct Node {
+    leaf: bool,
+    /// The next leaf, or the child holding the keys before the first entry's
+    link: PageId,
+    /// Values of a leaf, or the children holding the keys from each entry's on
+    entries: Vec<(Vec<u8>, u64)>,
+}
+
+impl Node {
+    fn leaf() -> Self {
+        Node {
+            leaf: true,
+            link: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    fn internal(link: PageId) -> Self {
+        Node {
+            leaf: false,
+            link,
+            entries: Vec::new(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let entries: usize = self.entries.iter().map(|(key, _)| key.len()).sum();
+        NODE_HEADER_SIZE + entries + self.entries.len() * ENTRY_OVERHEAD
+    }
+
+    fn fits(&self, key: &[u8], limit: usize) -> bool {
+        self.size() + key.len() + ENTRY_OVERHEAD <= limit
+    }
+
+    fn decode(page: &RawPage) -> error::Result<Self> {
+        let data = page.active_data();
+        let u64_at = |i: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(data.get(i..i + 8)?.try_into().ok()?))
+        };
+        let invalid = || not_a_tree(page.header.id);
+        let leaf = match data.first() {
+            Some(0) => true,
+            Some(1) => false,
+            _ => return Err(invalid()),
+        };
+        let count = data.get(1..3).ok_or_else(invalid)?;
+        let count = u16::from_le_bytes(count.try_into().unwrap()) as usize;
+        let link = u64_at(3).ok_or_else(invalid)?;
+        let mut entries = Vec::with_capacity(count);
+        let mut at = NODE_HEADER_SIZE;
+        for _ in 0..count {
+            let len = data.get(at..at + 2).ok_or_else(invalid)?;
+            let len = u16::from_le_bytes(len.try_into().unwrap()) as usize;
+            let key = data.get(at + 2..at + 2 + len).ok_or_else(invalid)?;
+            let value = u64_at(at + 2 + len).ok_or_else(invalid)?;
+            entries.push((key.to_vec(), value));
+            at += len + ENTRY_OVERHEAD;
+        }
+        Ok(Node {
+            leaf,
+            link,
+            entries,
+        })
+    }
+
+    fn encode(&self, page: &mut RawPage) {
+        let data = &mut page.data;
+        data[0] = if self.leaf { 0 } else { 1 };
+        data[1..3].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        data[3..11].copy_from_slice(&self.link.to_le_bytes());
+        let mut at = NODE_HEADER_SIZE;
+        for (key, value) in &self.entries {
+            data[at..at + 2].copy_from_slice(&(key.len() as u16).to_le_bytes());
+            data[at + 2..at + 2 + key.len()].copy_from_slice(key);
+            data[at + 2 + key.len()..at + ENTRY_OVERHEAD + key.len()]
+                .copy_from_slice(&value.to_le_bytes());
+            at += key.len() + ENTRY_OVERHEAD;
+        }
+        page.header.size = at as u64;
+    }
+
+    /// Encode into a page and log it. Only the bytes in use are, the rest is never read.
+    fn store(&self, page_store: &PageManager, page: &mut RawPage) {
+        self.encode(page);
+        page_store.log_write(page, 0..page.header.size as usize);
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|(entry, _)| entry.as_slice().cmp(key))
+    }
+
+    /// The child of an internal node that holds `key`
+    fn child(&self, key: &[u8]) -> PageId {
+        match self.search(key) {
+            Ok(i) => self.entries[i].1,
+            Err(0) => self.link,
+            Err(i) => self.entries[i - 1].1,
+        }
+    }
+
+    /// Move the upper half of the entries by size into a node to be stored in `right`,
+    /// returning the key separating the two
+    fn split(&mut self, right: PageId) -> (Vec<u8>, Node) {
+        let half = self.size() / 2;
+        let mut size = NODE_HEADER_SIZE;
+        let mut mid = 0;
+        while mid < self.entries.len() - 1 && size < half {
+            size += self.entries[mid].0.len() + ENTRY_OVERHEAD;
+            mid += 1;
+        }
+        let mut upper = self.entries.split_off(mid.max(1));
+        if self.leaf {
+            let separator = upper[0].0.clone();
+            let node = Node {
+                leaf: true,
+                link: mem::replace(&mut self.link, right),
+                entries: upper,
+            };
+            (separator, node)
+        } else {
+            let (separator, link) = upper.remove(0);
+            let node = Node {
+                leaf: false,
+                link,
+                entries: upper,
+            };
+            (separator, node)
+        }
+    }
+}
+
+struct ReadLatch {
+    page: OwnedRwLockReadGuard<RawPage>,
+    _loan: PageLoan,
+}
+
+struct WriteLatch {
+    page: OwnedRwLockWriteGuard<RawPage>,
+    _loan: PageLoan,
+}
+
+/// A B+tree over the pages of a source, mapping byte keys to u64 values such as row ids.
+/// Keys compare as bytes, so values should be encoded to keys that sort the way they do,
+/// and a non unique index appends the row id to make each key unique. The first page of
+/// the source points to the root.
+///
+/// Operations latch nodes top down, releasing a node's parent once it is sure the parent
+/// won't change, and scans move left to right along the leaves, so they can run
+/// concurrently without deadlocking. Removed entries leave nodes underfull rather than
+/// merging them. Nodes are logged as they change, and the log committed before a change
+/// returns.
+pub struct BTree<'a> {
+    page_store: &'a PageManager,
+    tag: SourceTag,
+    meta: PageId,
+}
+
+impl<'a> BTree<'a> {
+    /// An empty tree in a source without pages
+    pub async fn create(page_store: &'a PageManager, tag: SourceTag) -> error::Result<Self> {
+        if !page_store.list_pages(tag).is_empty() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "source {} already has pages",
+                tag
+            ))
+            .into());
+        }
+        let meta = page_store.new_page(tag).await?;
+        let root = page_store.new_page(tag).await?;
+        Node::leaf().store(page_store, &mut *root.write().await?);
+        write_root(page_store, &mut *meta.write().await?, root.id());
+        page_store.commit().await?;
+        Ok(BTree {
+            page_store,
+            tag,
+            meta: meta.id(),
+        })
+    }
+
+    /// The tree stored in a source
+    pub async fn open(page_store: &'a PageManager, tag: SourceTag) -> error::Result<Self> {
+        let Some(meta) = page_store.list_pages(tag).first().copied() else {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "source {} has no btree",
+                tag
+            ))
+            .into());
+        };
+        let tree = BTree {
+            page_store,
+            tag,
+            meta,
+        };
+        read_root(&tree.read_latch(meta).await?.page)?;
+        Ok(tree)
+    }
+
+    /// Build a tree in a source without pages from entries sorted by key
+    pub async fn bulk_load(
+        page_store: &'a PageManager,
+        tag: SourceTag,
+        entries: impl IntoIterator<Item = (Vec<u8>, u64)>,
+    ) -> error::Result<Self> {
+        let tree = BTree::create(page_store, tag).await?;
+        let meta = tree.write_latch(tree.meta).await?;
+        let first_leaf = read_root(&meta.page)?;
+
+        // First keys and pages of the nodes of each level, from the leaves up
+        let mut level = Vec::new();
+        let mut loan = page_store.aquire_page_loan(first_leaf).await?;
+        let mut node = Node::leaf();
+        for (key, value) in entries {
+            check_key(&key)?;
+            if matches!(node.entries.last(), Some((last, _)) if *last >= key) {
+                return Err(error::CustomErrors::InvalidArguments(
+                    "bulk loaded keys must be strictly increasing".to_string(),
+                )
+                .into());
+            }
+            if !node.entries.is_empty() && !node.fits(&key, BULK_FILL) {
+                let next = page_store.new_page(tag).await?;
+                node.link = next.id();
+                node.store(page_store, &mut *loan.write().await?);
+                level.push((node.entries[0].0.clone(), loan.id()));
+                loan = next;
+                node = Node::leaf();
+            }
+            node.entries.push((key, value));
+        }
+        node.store(page_store, &mut *loan.write().await?);
+        let first = node.entries.first().map(|(key, _)| key.clone());
+        level.push((first.unwrap_or_default(), loan.id()));
+
+        while level.len() > 1 {
+            let mut upper = Vec::new();
+            let mut children = level.into_iter();
+            let (mut first, child) = children.next().unwrap();
+            let mut node = Node::internal(child);
+            for (key, child) in children {
+                if !node.fits(&key, BULK_FILL) {
+                    let loan = page_store.new_page(tag).await?;
+                    node.store(page_store, &mut *loan.write().await?);
+                    upper.push((mem::replace(&mut first, key), loan.id()));
+                    node = Node::internal(child);
+                } else {
+                    node.entries.push((key, child));
+                }
+            }
+            let loan = page_store.new_page(tag).await?;
+            node.store(page_store, &mut *loan.write().await?);
+            upper.push((first, loan.id()));
+            level = upper;
+        }
+        let mut meta = meta;
+        write_root(page_store, &mut meta.page, level[0].1);
+        drop(meta);
+        page_store.commit().await?;
+        Ok(tree)
+    }
+
+    pub fn tag(&self) -> SourceTag {
+        self.tag
+    }
+
+    async fn read_latch(&self, id: PageId) -> error::Result<ReadLatch> {
+        let loan = self.page_store.aquire_page_loan(id).await?;
+        Ok(ReadLatch {
+            page: loan.read_owned().await?,
+            _loan: loan,
+        })
+    }
+
+    async fn write_latch(&self, id: PageId) -> error::Result<WriteLatch> {
+        let loan = self.page_store.aquire_page_loan(id).await?;
+        Ok(WriteLatch {
+            page: loan.write_owned().await?,
+            _loan: loan,
+        })
+    }
+
+    /// Latch down to the leaf that holds `key`, releasing each node once its child is latched
+    async fn find_leaf(&self, key: &[u8]) -> error::Result<(ReadLatch, Node)> {
+        let mut parent = self.read_latch(self.meta).await?;
+        let mut id = read_root(&parent.page)?;
+        loop {
+            let latch = self.read_latch(id).await?;
+            drop(parent);
+            let node = Node::decode(&latch.page)?;
+            if node.leaf {
+                return Ok((latch, node));
+            }
+            id = node.child(key);
+            parent = latch;
+        }
+    }
+
+    pub async fn get(&self, key: &[u8]) -> error::Result<Option<u64>> {
+        let (_latch, node) = self.find_leaf(key).await?;
+        Ok(node.search(key).ok().map(|i| node.entries[i].1))
+    }
+
+    /// Insert or replace the value of `key`, returning the value it replaced
+    pub async fn insert(&self, key: &[u8], value: u64) -> error::Result<Option<u64>> {
+        let replaced = self.insert_latched(key, value).await?;
+        self.page_store.commit().await?;
+        Ok(replaced)
+    }
+
+    async fn insert_latched(&self, key: &[u8], value: u64) -> error::Result<Option<u64>> {
+        check_key(key)?;
+        let mut meta = Some(self.write_latch(self.meta).await?);
+        let mut id = read_root(&meta.as_ref().unwrap().page)?;
+        // Nodes a split could reach, top down
+        let mut path: Vec<(WriteLatch, Node)> = Vec::new();
+        loop {
+            let latch = self.write_latch(id).await?;
+            let node = Node::decode(&latch.page)?;
+            // Separators from below can be as long as any key
+            let room = if node.leaf { key.len() } else { MAX_KEY_SIZE };
+            if node.size() + room + ENTRY_OVERHEAD <= PAGE_DATA_SIZE {
+                // A split stops here, so nothing above it changes
+                meta = None;
+                path.clear();
+            }
+            let leaf = node.leaf;
+            id = if leaf { 0 } else { node.child(key) };
+            path.push((latch, node));
+            if leaf {
+                break;
+            }
+        }
+
+        let (mut latch, mut node) = path.pop().unwrap();
+        let replaced = match node.search(key) {
+            Ok(i) => Some(mem::replace(&mut node.entries[i].1, value)),
+            Err(i) => {
+                node.entries.insert(i, (key.to_vec(), value));
+                None
+            }
+        };
+        while node.size() > PAGE_DATA_SIZE {
+            let right = self.page_store.new_page(self.tag).await?;
+            let (separator, right_node) = node.split(right.id());
+            right_node.store(self.page_store, &mut *right.write().await?);
+            node.store(self.page_store, &mut latch.page);
+            let left = latch.page.header.id;
+            match path.pop() {
+                Some((parent_latch, mut parent)) => {
+                    let (Ok(i) | Err(i)) = parent.search(&separator);
+                    parent.entries.insert(i, (separator, right.id()));
+                    (latch, node) = (parent_latch, parent);
+                }
+                None => {
+                    // The root split, so the meta page is still latched
+                    let mut meta = meta.take().ok_or_else(|| not_a_tree(left))?;
+                    let root = self.page_store.new_page(self.tag).await?;
+                    let mut root_node = Node::internal(left);
+                    root_node.entries.push((separator, right.id()));
+                    root_node.store(self.page_store, &mut *root.write().await?);
+                    write_root(self.page_store, &mut meta.page, root.id());
+                    return Ok(replaced);
+                }
+            }
+        }
+        node.store(self.page_store, &mut latch.page);
+        Ok(replaced)
+    }
+
+    /// Remove `key`, returning its value
+    pub async fn remove(&self, key: &[u8]) -> error::Result<Option<u64>> {
+        let removed = self.remove_latched(key).await?;
+        if removed.is_some() {
+            self.page_store.commit().await?;
+        }
+        Ok(removed)
+    }
+
+    async fn remove_latched(&self, key: &[u8]) -> error::Result<Option<u64>> {
+        // Nothing but the leaf changes, so internal nodes are only read latched
+        let mut parent = self.read_latch(self.meta).await?;
+        let mut id = read_root(&parent.page)?;
+        loop {
+            let latch = self.read_latch(id).await?;
+            let node = Node::decode(&latch.page)?;
+            if !node.leaf {
+                id = node.child(key);
+                parent = latch;
+                continue;
+            }
+            // The parent is still latched, so the leaf can't split before it is relatched
+            drop(latch);
+            let mut latch = self.write_latch(id).await?;
+            drop(parent);
+            let mut node = Node::decode(&latch.page)?;
+            let Ok(i) = node.search(key) else {
+                return Ok(None);
+            };
+            let (_, value) = node.entries.remove(i);
+            node.store(self.page_store, &mut latch.page);
+            return Ok(Some(value));
+        }
+    }
+
+    /// The entries from `start` to `end`, in order
+    pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> BTreeCursor<'_, 'a> {
+        BTreeCursor {
+            tree: self,
+            from: start.map(|key| key.to_vec()),
+            to: end.map(|key| key.to_vec()),
+            buffered: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// The entries whose keys start with `prefix`, in order
+    pub fn prefix(&self, prefix: &[u8]) -> BTreeCursor<'_, 'a> {
+        // The first key after every key starting with the prefix
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&u8::MAX) {
+            end.pop();
+        }
+        let end = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(&end[..])
+            }
+            None => Bound::Unbounded,
+        };
+        self.range(Bound::Included(prefix), end)
+    }
+}
+
+/// Walks the entries of a range one leaf at a time. It sees entries inserted ahead of it
+/// while it walks.
+pub struct BTreeCursor<'t, 'a> {
+    tree: &'t BTree<'a>,
+    /// Where the leaf after the buffered entries starts
+    from: Bound<Vec<u8>>,
+    to: Bound<Vec<u8>>,
+    buffered: VecDeque<(Vec<u8>, u64)>,
+    done: bool,
+}
+
+impl BTreeCursor<'_, '_> {
+    pub async fn next(&mut self) -> error::Result<Option<(Vec<u8>, u64)>> {
+        while self.buffered.is_empty() && !self.done {
+            self.fill().await?;
+        }
+        Ok(self.buffered.pop_front())
+    }
+
+    pub async fn collect(mut self) -> error::Result<Vec<(Vec<u8>, u64)>> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.next().await? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Buffer the entries in range of the next leaf that has any
+    async fn fill(&mut self) -> error::Result<()> {
+        let start = match &self.from {
+            Bound::Included(key) | Bound::Excluded(key) => key.as_slice(),
+            Bound::Unbounded => &[],
+        };
+        let (mut latch, mut node) = self.tree.find_leaf(start).await?;
+        loop {
+            for (key, value) in mem::take(&mut node.entries) {
+                let after_start = match &self.from {
+                    Bound::Included(start) => key >= *start,
+                    Bound::Excluded(start) => key > *start,
+                    Bound::Unbounded => true,
+                };
+                let before_end = match &self.to {
+                    Bound::Included(end) => key <= *end,
+                    Bound::Excluded(end) => key < *end,
+                    Bound::Unbounded => true,
+                };
+                if !before_end {
+                    self.done = true;
+                    break;
+                }
+                if after_start {
+                    self.buffered.push_back((key, value));
+                }
+            }
+            if !self.buffered.is_empty() || self.done {
+                break;
+            }
+            if node.link == 0 {
+                self.done = true;
+                break;
+            }
+            // Latch the next leaf before letting go of this one
+            let next = self.tree.read_latch(node.link).await?;
+            node = Node::decode(&next.page)?;
+            latch = next;
+        }
+        drop(latch);
+        if let Some((key, _)) = self.buffered.back() {
+            self.from = Bound::Excluded(key.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{page::PAGE_SIZE, Lfu, PageCache};
+
+    /// 68 byte keys, around 50 to a node
+    fn key(i: u64) -> Vec<u8> {
+        let mut key = i.to_be_bytes().to_vec();
+        key.extend([b'k'; 60]);
+        key
+    }
+
+    async fn manager(dir: &tempfile::TempDir) -> PageManager {
+        PageManager::open(dir.path(), 256 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+    }
+
+    async fn height(tree: &BTree<'_>) -> usize {
+        let mut id = read_root(&tree.read_latch(tree.meta).await.unwrap().page).unwrap();
+        let mut height = 1;
+        loop {
+            let node = Node::decode(&tree.read_latch(id).await.unwrap().page).unwrap();
+            if node.leaf {
+                return height;
+            }
+            id = node.link;
+            height += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn inserts_and_looks_up_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let tree = BTree::create(&manager, 1).await.unwrap();
+        let n = 3000;
+        for i in 0..n {
+            let i = i * 7919 % n;
+            assert_eq!(tree.insert(&key(i), i).await.unwrap(), None);
+        }
+        assert_eq!(height(&tree).await, 3);
+        for i in 0..n {
+            assert_eq!(tree.get(&key(i)).await.unwrap(), Some(i));
+        }
+        assert_eq!(tree.get(&key(n)).await.unwrap(), None);
+        assert_eq!(tree.get(b"").await.unwrap(), None);
+        assert_eq!(tree.insert(&key(5), 50).await.unwrap(), Some(5));
+        assert_eq!(tree.get(&key(5)).await.unwrap(), Some(50));
+        assert!(tree.insert(&[0; MAX_KEY_SIZE + 1], 0).await.is_err());
+        assert!(BTree::create(&manager, 1).await.is_err());
+
+        assert_eq!(tree.remove(&key(5)).await.unwrap(), Some(50));
+        assert_eq!(tree.remove(&key(5)).await.unwrap(), None);
+        assert_eq!(tree.get(&key(5)).await.unwrap(), None);
+
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded);
+        let keys: Vec<_> = all.collect().await.unwrap();
+        let expected: Vec<_> = (0..n).filter(|i| *i != 5).map(|i| (key(i), i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    async fn keys(cursor: BTreeCursor<'_, '_>) -> Vec<Vec<u8>> {
+        let entries = cursor.collect().await.unwrap();
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[tokio::test]
+    async fn scans_ranges_and_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let tree = BTree::create(&manager, 1).await.unwrap();
+        let words: [&[u8]; 7] = [
+            b"apple",
+            b"apricot",
+            b"ap\xff",
+            b"ap\xff\xff",
+            b"aq",
+            b"banana",
+            b"cherry",
+        ];
+        for (i, word) in words.iter().enumerate() {
+            tree.insert(word, i as u64).await.unwrap();
+        }
+        // Enough other keys to span several leaves
+        for i in 0..500 {
+            tree.insert(&key(i), i).await.unwrap();
+        }
+
+        assert_eq!(
+            keys(tree.prefix(b"ap")).await,
+            vec![&b"apple"[..], b"apricot", b"ap\xff", b"ap\xff\xff"]
+        );
+        assert_eq!(
+            keys(tree.prefix(b"ap\xff")).await,
+            vec![&b"ap\xff"[..], b"ap\xff\xff"]
+        );
+        assert!(keys(tree.prefix(b"z")).await.is_empty());
+        assert_eq!(
+            keys(tree.range(Bound::Excluded(b"apple"), Bound::Included(b"banana"))).await,
+            vec![&b"apricot"[..], b"ap\xff", b"ap\xff\xff", b"aq", b"banana"]
+        );
+        assert_eq!(
+            keys(tree.range(Bound::Included(b"b"), Bound::Unbounded)).await,
+            vec![&b"banana"[..], b"cherry"]
+        );
+
+        let zero = 0u64.to_be_bytes();
+        let numbers = keys(tree.prefix(&zero[..6])).await;
+        assert_eq!(numbers, (0..500).map(key).collect::<Vec<_>>());
+        let (start, end) = (key(100), key(300));
+        let mut cursor = tree.range(Bound::Included(&start), Bound::Excluded(&end));
+        for i in 100..300 {
+            assert_eq!(cursor.next().await.unwrap(), Some((key(i), i)));
+        }
+        assert_eq!(cursor.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn orders_encoded_values() {
+        use chrono::{TimeZone, Utc};
+        use intake::{encode_key, DataValue};
+
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let tree = BTree::create(&manager, 1).await.unwrap();
+        let time = |secs, nanos| DataValue::DateTime(Utc.timestamp_opt(secs, nanos).unwrap());
+        let uuid = |n| DataValue::UUID(uuid::Uuid::from_u128(n));
+        // In key order, values of each type after those of the types before it
+        let values = [
+            DataValue::Null,
+            DataValue::Bool(false),
+            DataValue::Bool(true),
+            DataValue::I64(i64::MIN),
+            DataValue::I64(-256),
+            DataValue::I64(-1),
+            DataValue::I64(0),
+            DataValue::I64(255),
+            DataValue::I64(i64::MAX),
+            DataValue::F64(f64::NEG_INFINITY),
+            DataValue::F64(-1.5),
+            DataValue::F64(-f64::MIN_POSITIVE),
+            DataValue::F64(0.0),
+            DataValue::F64(0.25),
+            DataValue::F64(1e300),
+            DataValue::F64(f64::INFINITY),
+            DataValue::F64(f64::NAN),
+            DataValue::String("".to_string()),
+            DataValue::String("a".to_string()),
+            DataValue::String("a\0".to_string()),
+            DataValue::String("a\0b".to_string()),
+            DataValue::String("ab".to_string()),
+            DataValue::String("b".to_string()),
+            time(-86_400, 0),
+            time(-1, 999_999_999),
+            time(0, 0),
+            time(0, 1),
+            time(1_695_686_400, 0),
+            uuid(0),
+            uuid(1),
+            uuid(1 << 64),
+            uuid(u128::MAX),
+        ];
+        let n = values.len();
+        for i in 0..n {
+            let i = i * 7 % n;
+            tree.insert(&encode_key(&values[i..=i]), i as u64)
+                .await
+                .unwrap();
+        }
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded);
+        let found: Vec<_> = all.collect().await.unwrap();
+        assert_eq!(
+            found.iter().map(|(_, i)| *i).collect::<Vec<_>>(),
+            (0..n as u64).collect::<Vec<_>>()
+        );
+        for (i, value) in values.iter().enumerate() {
+            let key = encode_key(std::slice::from_ref(value));
+            assert_eq!(tree.get(&key).await.unwrap(), Some(i as u64));
+        }
+
+        // Keys of one type share their first byte, so a range over them is a prefix scan
+        let ints = encode_key(&[DataValue::I64(0)]);
+        let found = tree.prefix(&ints[..1]).collect().await.unwrap();
+        assert_eq!(
+            found.iter().map(|(_, i)| *i).collect::<Vec<_>>(),
+            (3..9).collect::<Vec<_>>()
+        );
+        let (start, end) = (encode_key(&[time(-1, 0)]), encode_key(&[time(1, 0)]));
+        let found = tree.range(Bound::Included(&start), Bound::Excluded(&end));
+        let found = found.collect().await.unwrap();
+        assert_eq!(
+            found.iter().map(|(_, i)| *i).collect::<Vec<_>>(),
+            [24, 25, 26]
+        );
+    }
+
+    #[tokio::test]
+    async fn bulk_loads_sorted_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let n = 5000;
+        let tree = BTree::bulk_load(&manager, 1, (0..n).map(|i| (key(i * 2), i)))
+            .await
+            .unwrap();
+        assert_eq!(height(&tree).await, 3);
+        for i in 0..n {
+            assert_eq!(tree.get(&key(i * 2)).await.unwrap(), Some(i));
+        }
+        // Inserting between loaded keys still works
+        for i in 0..n {
+            tree.insert(&key(i * 2 + 1), i).await.unwrap();
+        }
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(all.collect().await.unwrap().len(), 2 * n as usize);
+        manager.flush().await.unwrap();
+        drop(manager);
+
+        let manager = self::manager(&dir).await;
+        let tree = BTree::open(&manager, 1).await.unwrap();
+        assert_eq!(tree.get(&key(777)).await.unwrap(), Some(388));
+        assert!(BTree::open(&manager, 2).await.is_err());
+
+        let unsorted = [(key(2), 0), (key(1), 1)];
+        assert!(BTree::bulk_load(&manager, 3, unsorted).await.is_err());
+        let empty = BTree::bulk_load(&manager, 4, []).await.unwrap();
+        assert_eq!(empty.get(&key(0)).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn latches_concurrent_access() {
+        let dir = tempfile::tempdir().unwrap();
+        // A cache small enough that pages are evicted as the tree grows
+        let manager = manager(&dir)
+            .await
+            .with_cache(PageCache::new(48, Box::<Lfu>::default()));
+        let manager: &'static PageManager = Box::leak(Box::new(manager));
+        let tree = Arc::new(BTree::create(manager, 1).await.unwrap());
+        let (writers, per_writer) = (4, 750);
+
+        let mut tasks = Vec::new();
+        for writer in 0..writers {
+            let tree = tree.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..per_writer {
+                    let i = i * writers + writer;
+                    tree.insert(&key(i), i).await.unwrap();
+                    assert_eq!(tree.get(&key(i)).await.unwrap(), Some(i));
+                    // Removals latch past nodes that concurrent inserts split
+                    if i >= writers && (i - writers) % 3 == 0 {
+                        let removed = i - writers;
+                        assert_eq!(tree.remove(&key(removed)).await.unwrap(), Some(removed));
+                    }
+                }
+            }));
+        }
+        for _ in 0..2 {
+            let tree = tree.clone();
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..20 {
+                    let all = tree.range(Bound::Unbounded, Bound::Unbounded);
+                    let keys: Vec<_> = all.collect().await.unwrap();
+                    assert!(keys.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded);
+        let expected: Vec<_> = (0..writers * per_writer)
+            .filter(|i| i % 3 != 0 || *i + writers >= writers * per_writer)
+            .map(|i| (key(i), i))
+            .collect();
+        assert_eq!(all.collect().await.unwrap(), expected);
+        assert!(manager.cache_stats().evictions > 0);
+    }
+}
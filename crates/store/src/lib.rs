@@ -1,2 +1,30 @@
+use std::io;
+
+// Pages and the log are read and written at offsets with `std::os::unix::fs::FileExt`
+#[cfg(not(unix))]
+compile_error!("store only supports unix targets");
+
+mod btree;
 mod page;
-mod source;
\ No newline at end of file
+mod segment;
+mod source;
+mod table;
+mod wal;
+
+pub use btree::{BTree, BTreeCursor, MAX_KEY_SIZE};
+pub use page::{
+    CacheStats, Clock, EvictionPolicy, Lfu, LruK, PageCache, PageHeader, PageId, PageLoan,
+    PageManager, RWAAppendGuard, RWABuffer, RWAReadGuard, RWAWriteGuard, RawPage, PAGE_DATA_SIZE,
+    PAGE_SIZE,
+};
+pub use segment::{ColumnChunk, Encoding, Segment, ZoneMap, CHUNK_ROWS};
+pub use source::{SourceBlocks, SourceTag};
+pub use table::{RowId, Table};
+pub use wal::{Lsn, SyncPolicy, Wal, WalOptions, WalRecord, WalStats};
+
+/// Run file IO off the async runtime
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> error::Result<T> {
+    Ok(tokio::task::spawn_blocking(f).await??)
+}
@@ -1,15 +1,833 @@
-use super::RawPage;
-use tokio::sync::RwLock;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{
+    Mutex, MutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock, RwLockReadGuard,
+    RwLockWriteGuard,
+};
+
+use super::{PageId, RawPage};
+
+/// Reads and writes the cache hands to its caller
+pub type IoFuture<'a, T> = Pin<Box<dyn Future<Output = error::Result<T>> + Send + 'a>>;
+
+/// Decides which cached page to evict. Items are numbered from 0 up to the cache's capacity.
+pub trait EvictionPolicy: Send {
+    /// A page was loaded into `item`
+    fn loaded(&mut self, item: usize);
+    /// The page in `item` was pinned again
+    fn accessed(&mut self, item: usize);
+    /// Item to evict among those `evictable` allows
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// Evicts the page pinned the fewest times since it was loaded
+#[derive(Default)]
+pub struct Lfu {
+    counts: Vec<u64>,
+}
+
+impl EvictionPolicy for Lfu {
+    fn loaded(&mut self, item: usize) {
+        if self.counts.len() <= item {
+            self.counts.resize(item + 1, 0);
+        }
+        self.counts[item] = 1;
+    }
+
+    fn accessed(&mut self, item: usize) {
+        self.counts[item] += 1;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..self.counts.len())
+            .filter(|item| evictable(*item))
+            .min_by_key(|item| self.counts[*item])
+    }
+}
+
+/// Sweeps a hand over the pages, evicting the first one not used since the last sweep
+#[derive(Default)]
+pub struct Clock {
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl EvictionPolicy for Clock {
+    fn loaded(&mut self, item: usize) {
+        if self.referenced.len() <= item {
+            self.referenced.resize(item + 1, false);
+        }
+        self.referenced[item] = true;
+    }
+
+    fn accessed(&mut self, item: usize) {
+        self.referenced[item] = true;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let len = self.referenced.len();
+        // Two sweeps clear every reference, so a third can't find anything new
+        for _ in 0..2 * len {
+            let item = self.hand;
+            self.hand = (self.hand + 1) % len;
+            if !evictable(item) {
+                continue;
+            }
+            if !self.referenced[item] {
+                return Some(item);
+            }
+            self.referenced[item] = false;
+        }
+        None
+    }
+}
+
+/// Evicts the page whose `k`th most recent use is oldest. Pages used fewer than `k` times
+/// go first, least recently used first.
+pub struct LruK {
+    k: usize,
+    history: Vec<VecDeque<u64>>,
+    now: u64,
+}
+
+impl LruK {
+    pub fn new(k: usize) -> Self {
+        LruK {
+            k: k.max(1),
+            history: Vec::new(),
+            now: 0,
+        }
+    }
+}
+
+impl EvictionPolicy for LruK {
+    fn loaded(&mut self, item: usize) {
+        if self.history.len() <= item {
+            self.history.resize(item + 1, VecDeque::new());
+        }
+        self.history[item].clear();
+        self.accessed(item);
+    }
+
+    fn accessed(&mut self, item: usize) {
+        self.now += 1;
+        let history = &mut self.history[item];
+        history.push_front(self.now);
+        history.truncate(self.k);
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..self.history.len())
+            .filter(|item| evictable(*item))
+            .min_by_key(|item| {
+                let history = &self.history[*item];
+                match history.get(self.k - 1) {
+                    Some(kth) => (true, *kth),
+                    None => (false, history.front().copied().unwrap_or(0)),
+                }
+            })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty pages written back when evicted
+    pub write_backs: u64,
+}
 
 struct CacheItem {
-    dirty: bool,
-    data: RwLock<RawPage>
+    pins: AtomicUsize,
+    dirty: AtomicBool,
+    data: Arc<RwLock<RawPage>>,
+}
+
+struct CacheState {
+    items: Vec<Arc<CacheItem>>,
+    /// Page held by each item
+    pages: Vec<Option<PageId>>,
+    table: HashMap<PageId, usize>,
+    /// Items whose page was removed
+    free: Vec<usize>,
+    policy: Box<dyn EvictionPolicy>,
+}
+
+/// Buffer pool holding at most `capacity` pages in memory. Pinned pages stay put, the others
+/// are evicted as the policy decides, dirty ones written back first.
+pub struct PageCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+}
+
+/// A page pinned in the cache. It can't be evicted until every loan on it is dropped.
+pub struct PageLoan {
+    id: PageId,
+    item: Arc<CacheItem>,
+    /// Whether the page belongs to a sealed source, so it can only be read
+    sealed: bool,
+}
+
+impl PageLoan {
+    fn pin(id: PageId, item: Arc<CacheItem>) -> Self {
+        item.pins.fetch_add(1, Ordering::AcqRel);
+        PageLoan {
+            id,
+            item,
+            sealed: false,
+        }
+    }
+
+    pub fn id(&self) -> PageId {
+        self.id
+    }
+
+    /// The loan, refusing writes if `sealed`
+    pub(crate) fn sealed(mut self, sealed: bool) -> Self {
+        self.sealed = sealed;
+        self
+    }
+
+    fn check_writable(&self) -> error::Result<()> {
+        if self.sealed {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "page {} is sealed",
+                self.id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check(&self, page: &RawPage) -> error::Result<()> {
+        if page.header.id != self.id {
+            return Err(error::CustomErrors::InvalidState(format!(
+                "page {} failed to load",
+                self.id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    pub async fn read(&self) -> error::Result<RwLockReadGuard<'_, RawPage>> {
+        let page = self.item.data.read().await;
+        self.check(&page)?;
+        Ok(page)
+    }
+
+    /// Lock the page for writing, marking it dirty
+    pub async fn write(&self) -> error::Result<RwLockWriteGuard<'_, RawPage>> {
+        self.check_writable()?;
+        let page = self.item.data.write().await;
+        self.check(&page)?;
+        self.item.dirty.store(true, Ordering::Release);
+        Ok(page)
+    }
+
+    /// Like `read`, with a guard that doesn't borrow the loan. Keep the loan alongside it.
+    pub(crate) async fn read_owned(&self) -> error::Result<OwnedRwLockReadGuard<RawPage>> {
+        let page = self.item.data.clone().read_owned().await;
+        self.check(&page)?;
+        Ok(page)
+    }
+
+    /// Like `write`, with a guard that doesn't borrow the loan. Keep the loan alongside it.
+    pub(crate) async fn write_owned(&self) -> error::Result<OwnedRwLockWriteGuard<RawPage>> {
+        self.check_writable()?;
+        let page = self.item.data.clone().write_owned().await;
+        self.check(&page)?;
+        self.item.dirty.store(true, Ordering::Release);
+        Ok(page)
+    }
 }
 
-struct LFUPageCache {
-    items: Box<[CacheItem]>,
+impl Clone for PageLoan {
+    fn clone(&self) -> Self {
+        PageLoan::pin(self.id, self.item.clone()).sealed(self.sealed)
+    }
 }
 
-impl LFUPageCache {
-    // fn get(&self, key: PageId);
-}
\ No newline at end of file
+impl fmt::Debug for PageLoan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageLoan").field("id", &self.id).finish()
+    }
+}
+
+impl Drop for PageLoan {
+    fn drop(&mut self) {
+        self.item.pins.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Write a pinned page back if it is dirty, returning whether it was. Pages `write_back`
+/// holds back stay dirty.
+async fn write_item<'a>(
+    loan: &PageLoan,
+    write_back: &impl Fn(PageId, Box<[u8]>) -> IoFuture<'a, bool>,
+) -> error::Result<bool> {
+    let page = loan.item.data.read().await;
+    // Writers are locked out while the page is read, so clearing first loses nothing
+    if page.header.id != loan.id || !loan.item.dirty.swap(false, Ordering::AcqRel) {
+        return Ok(false);
+    }
+    match write_back(loan.id, page.to_bytes()).await {
+        Ok(true) => Ok(true),
+        Ok(false) => {
+            loan.item.dirty.store(true, Ordering::Release);
+            Ok(false)
+        }
+        Err(e) => {
+            loan.item.dirty.store(true, Ordering::Release);
+            Err(e)
+        }
+    }
+}
+
+fn invalid_state(message: &str) -> error::Error {
+    error::CustomErrors::InvalidState(message.to_string()).into()
+}
+
+impl PageCache {
+    pub fn new(capacity: usize, policy: Box<dyn EvictionPolicy>) -> Self {
+        PageCache {
+            capacity: capacity.max(1),
+            state: Mutex::new(CacheState {
+                items: Vec::new(),
+                pages: Vec::new(),
+                table: HashMap::new(),
+                free: Vec::new(),
+                policy,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            write_backs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            write_backs: self.write_backs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Pin page `id`, reading it with `load` when it isn't cached. Making room writes the
+    /// evicted page back with `write_back` if it is dirty, which returns whether it did.
+    /// Pages it holds back stay cached.
+    pub async fn pin<'a>(
+        &self,
+        id: PageId,
+        load: impl FnOnce() -> IoFuture<'a, Box<RawPage>>,
+        write_back: impl Fn(PageId, Box<[u8]>) -> IoFuture<'a, bool>,
+    ) -> error::Result<PageLoan> {
+        let mut state = self.state.lock().await;
+        if let Some(&item) = state.table.get(&id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            state.policy.accessed(item);
+            return Ok(PageLoan::pin(id, state.items[item].clone()));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let (mut state, slot) = self.reserve(state, &write_back).await?;
+        // Someone else may have loaded the page while a victim was written back
+        if let Some(&item) = state.table.get(&id) {
+            state.free.push(slot);
+            state.policy.accessed(item);
+            return Ok(PageLoan::pin(id, state.items[item].clone()));
+        }
+        let item = state.items[slot].clone();
+        // Anyone pinning the page before it is loaded waits on this guard
+        let mut page = item
+            .data
+            .clone()
+            .try_write_owned()
+            .map_err(|_| invalid_state("an unpinned page is locked"))?;
+        item.dirty.store(false, Ordering::Release);
+        state.pages[slot] = Some(id);
+        state.table.insert(id, slot);
+        state.policy.loaded(slot);
+        let loan = PageLoan::pin(id, item);
+        drop(state);
+
+        match load().await {
+            Ok(loaded) => {
+                *page = *loaded;
+                Ok(loan)
+            }
+            Err(e) => {
+                // No page has id 0, so waiting loans see the load failed
+                page.header.id = 0;
+                drop(page);
+                let mut state = self.state.lock().await;
+                if state.table.get(&id) == Some(&slot) {
+                    state.table.remove(&id);
+                    state.pages[slot] = None;
+                    state.free.push(slot);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// An item holding no page, evicting one if the cache is full. The state lock is let go
+    /// while a dirty victim is written back, and taken again for the item it returns.
+    async fn reserve<'s, 'a>(
+        &'s self,
+        mut state: MutexGuard<'s, CacheState>,
+        write_back: &impl Fn(PageId, Box<[u8]>) -> IoFuture<'a, bool>,
+    ) -> error::Result<(MutexGuard<'s, CacheState>, usize)> {
+        // Victims whose write back was held back
+        let mut held = Vec::new();
+        loop {
+            while let Some(slot) = state.free.pop() {
+                if state.pages[slot].is_none()
+                    && state.items[slot].pins.load(Ordering::Acquire) == 0
+                {
+                    return Ok((state, slot));
+                }
+            }
+            if state.items.len() < self.capacity {
+                state.items.push(Arc::new(CacheItem {
+                    pins: AtomicUsize::new(0),
+                    dirty: AtomicBool::new(false),
+                    data: Arc::new(RwLock::new(*RawPage::new(0, 0))),
+                }));
+                state.pages.push(None);
+                let slot = state.items.len() - 1;
+                return Ok((state, slot));
+            }
+
+            let CacheState { items, policy, .. } = &mut *state;
+            let victim = policy
+                .victim(&|slot| {
+                    items[slot].pins.load(Ordering::Acquire) == 0 && !held.contains(&slot)
+                })
+                .ok_or_else(|| {
+                    error::CustomErrors::ResourceExhausted(format!(
+                        "all {} cached pages are pinned or held back",
+                        self.capacity
+                    ))
+                })?;
+            let Some(old) = state.pages[victim] else {
+                return Ok((state, victim));
+            };
+            let item = state.items[victim].clone();
+            if item.dirty.load(Ordering::Acquire) {
+                // Pinned, so nothing else evicts it while it's written without the lock
+                let loan = PageLoan::pin(old, item.clone());
+                drop(state);
+                if write_item(&loan, write_back).await? {
+                    self.write_backs.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    held.push(victim);
+                }
+                state = self.state.lock().await;
+                drop(loan);
+                // Used again while it was written, so it has to stay
+                if state.pages[victim] != Some(old)
+                    || item.pins.load(Ordering::Acquire) > 0
+                    || item.dirty.load(Ordering::Acquire)
+                {
+                    continue;
+                }
+            }
+            state.table.remove(&old);
+            state.pages[victim] = None;
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            return Ok((state, victim));
+        }
+    }
+
+    /// Write every dirty page back in one batch, keeping them cached. `write_back` returns the
+    /// pages it held back, which stay dirty and are returned.
+    pub async fn flush<'a>(
+        &self,
+        write_back: impl FnOnce(Vec<(PageId, Box<[u8]>)>) -> IoFuture<'a, Vec<PageId>>,
+    ) -> error::Result<Vec<PageId>> {
+        // Pinned so they can't be evicted while they're written
+        let dirty: Vec<_> = {
+            let state = self.state.lock().await;
+            state
+                .pages
+                .iter()
+                .zip(&state.items)
+                .filter(|(_, item)| item.dirty.load(Ordering::Acquire))
+                .filter_map(|(page, item)| Some(PageLoan::pin((*page)?, item.clone())))
+                .collect()
+        };
+        let mut written = Vec::with_capacity(dirty.len());
+        let mut pages = Vec::with_capacity(dirty.len());
+        for loan in &dirty {
+            let page = loan.item.data.read().await;
+            // Changes made after the copy dirty the page again, so they're written next time
+            if page.header.id == loan.id && loan.item.dirty.swap(false, Ordering::AcqRel) {
+                pages.push((loan.id, page.to_bytes()));
+                written.push(loan);
+            }
+        }
+        if pages.is_empty() {
+            return Ok(Vec::new());
+        }
+        match write_back(pages).await {
+            Ok(held) => {
+                for loan in written.iter().filter(|loan| held.contains(&loan.id)) {
+                    loan.item.dirty.store(true, Ordering::Release);
+                }
+                Ok(held)
+            }
+            Err(e) => {
+                for loan in written {
+                    loan.item.dirty.store(true, Ordering::Release);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Forget a page without writing it back
+    pub async fn remove(&self, id: PageId) {
+        let mut state = self.state.lock().await;
+        if let Some(slot) = state.table.remove(&id) {
+            state.pages[slot] = None;
+            state.items[slot].dirty.store(false, Ordering::Release);
+            state.free.push(slot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex as StdMutex, time::Duration};
+
+    use super::*;
+
+    /// Pages on a pretend disk, with their first byte counting writes
+    #[derive(Default)]
+    struct Disk {
+        pages: StdMutex<HashMap<PageId, Box<[u8]>>>,
+    }
+
+    impl Disk {
+        fn load(&self, id: PageId) -> IoFuture<'_, Box<RawPage>> {
+            let bytes = self.pages.lock().unwrap().get(&id).cloned();
+            Box::pin(async move {
+                match bytes {
+                    Some(bytes) => RawPage::from_bytes(&bytes),
+                    None => Ok(RawPage::new(id, 0)),
+                }
+            })
+        }
+
+        fn write_back(&self, id: PageId, bytes: Box<[u8]>) -> IoFuture<'_, bool> {
+            self.pages.lock().unwrap().insert(id, bytes);
+            Box::pin(async { Ok(true) })
+        }
+
+        fn write_all(&self, pages: Vec<(PageId, Box<[u8]>)>) -> IoFuture<'_, Vec<PageId>> {
+            self.pages.lock().unwrap().extend(pages);
+            Box::pin(async { Ok(Vec::new()) })
+        }
+
+        fn count(&self, id: PageId) -> u8 {
+            self.pages
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map_or(0, |bytes| RawPage::from_bytes(bytes).unwrap().data[0])
+        }
+    }
+
+    async fn pin(cache: &PageCache, disk: &Disk, id: PageId) -> error::Result<PageLoan> {
+        cache
+            .pin(id, || disk.load(id), |id, bytes| disk.write_back(id, bytes))
+            .await
+    }
+
+    /// Victims after loading items 0 to 3 and pinning `accessed` again
+    fn victims(mut policy: impl EvictionPolicy, accessed: &[usize]) -> Vec<usize> {
+        for item in 0..4 {
+            policy.loaded(item);
+        }
+        for item in accessed {
+            policy.accessed(*item);
+        }
+        let mut victims = Vec::new();
+        while let Some(victim) = policy.victim(&|item| !victims.contains(&item)) {
+            victims.push(victim);
+        }
+        victims
+    }
+
+    #[test]
+    fn policies_pick_victims() {
+        assert_eq!(
+            victims(Lfu::default(), &[0, 0, 1, 3, 3, 3]),
+            vec![2, 1, 0, 3]
+        );
+        assert_eq!(victims(Clock::default(), &[]), vec![0, 1, 2, 3]);
+        assert_eq!(victims(LruK::new(2), &[3, 0, 1, 0]), vec![2, 1, 3, 0]);
+        assert_eq!(victims(LruK::new(1), &[1, 0]), vec![2, 3, 1, 0]);
+
+        // Clock spares pages used since the hand last passed
+        let mut clock = Clock::default();
+        for item in 0..3 {
+            clock.loaded(item);
+        }
+        assert_eq!(clock.victim(&|_| true), Some(0));
+        clock.loaded(0);
+        clock.accessed(2);
+        assert_eq!(clock.victim(&|_| true), Some(1));
+    }
+
+    #[tokio::test]
+    async fn counts_hits_and_writes_back_evicted_pages() {
+        let disk = Disk::default();
+        let cache = PageCache::new(2, Box::<Lfu>::default());
+        for id in [1, 2, 1] {
+            let loan = pin(&cache, &disk, id).await.unwrap();
+            loan.write().await.unwrap().data[0] += 1;
+        }
+        // 2 is used least, so goes first
+        pin(&cache, &disk, 3).await.unwrap();
+        assert_eq!(disk.count(2), 1);
+        assert_eq!(disk.count(1), 0);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                evictions: 1,
+                write_backs: 1,
+            }
+        );
+
+        cache.flush(|pages| disk.write_all(pages)).await.unwrap();
+        assert_eq!(disk.count(1), 2);
+        // Clean pages aren't written again
+        cache.remove(1).await;
+        pin(&cache, &disk, 4).await.unwrap();
+        assert_eq!(cache.stats().write_backs, 1);
+    }
+
+    #[tokio::test]
+    async fn never_evicts_pinned_pages() {
+        let disk = Disk::default();
+        let cache = PageCache::new(2, Box::new(Clock::default()));
+        let one = pin(&cache, &disk, 1).await.unwrap();
+        let two = pin(&cache, &disk, 2).await.unwrap();
+        let full = pin(&cache, &disk, 3).await.unwrap_err();
+        assert!(matches!(
+            full.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::ResourceExhausted(_))
+        ));
+
+        let copy = two.clone();
+        drop(two);
+        assert!(pin(&cache, &disk, 3).await.is_err());
+        drop(copy);
+        pin(&cache, &disk, 3).await.unwrap();
+        assert_eq!(one.read().await.unwrap().header.id, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_loads_fail_every_loan() {
+        let disk = Disk::default();
+        let cache = PageCache::new(1, Box::<Lfu>::default());
+        let failed = cache
+            .pin(
+                1,
+                || Box::pin(async { Err(invalid_state("unreadable")) }),
+                |id, bytes| disk.write_back(id, bytes),
+            )
+            .await;
+        assert!(failed.is_err());
+        let loan = pin(&cache, &disk, 1).await.unwrap();
+        assert_eq!(loan.read().await.unwrap().header.id, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_pages_held_back_dirty() {
+        let disk = Disk::default();
+        let cache = PageCache::new(2, Box::<Lfu>::default());
+        for id in [1, 2, 2] {
+            let loan = pin(&cache, &disk, id).await.unwrap();
+            loan.write().await.unwrap().data[0] += 1;
+        }
+        // Page 1 can't be written yet, so the flush writes the rest and 2 is evicted instead
+        let hold_one = |pages: Vec<(PageId, Box<[u8]>)>| {
+            let (held, written) = pages.into_iter().partition(|(id, _)| *id == 1);
+            let disk = &disk;
+            Box::pin(async move {
+                disk.write_all(written).await?;
+                Ok(held.into_iter().map(|(id, _)| id).collect())
+            }) as IoFuture<'_, Vec<PageId>>
+        };
+        assert_eq!(cache.flush(hold_one).await.unwrap(), vec![1]);
+        assert_eq!((disk.count(1), disk.count(2)), (0, 2));
+        let three = cache
+            .pin(
+                3,
+                || disk.load(3),
+                |id, bytes| match id {
+                    1 => Box::pin(async { Ok(false) }),
+                    _ => disk.write_back(id, bytes),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().write_backs, 0);
+
+        // Held back with everything else pinned, there's no room
+        let full = cache
+            .pin(4, || disk.load(4), |_, _| Box::pin(async { Ok(false) }))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            full.downcast_ref::<error::CustomErrors>(),
+            Some(error::CustomErrors::ResourceExhausted(_))
+        ));
+        drop(three);
+        cache.flush(|pages| disk.write_all(pages)).await.unwrap();
+        assert_eq!(disk.count(1), 1);
+    }
+
+    #[tokio::test]
+    async fn pins_pages_while_flushing_them() {
+        let disk = Disk::default();
+        let cache = PageCache::new(2, Box::<Lfu>::default());
+        let one = pin(&cache, &disk, 1).await.unwrap();
+        one.write().await.unwrap().data[0] = 1;
+        drop(one);
+        // 2 is used more, so 1 would be the victim if it weren't being flushed
+        for _ in 0..3 {
+            pin(&cache, &disk, 2).await.unwrap();
+        }
+
+        let written = tokio::sync::Notify::new();
+        let flushing = cache.flush(|pages| {
+            let written = &written;
+            let disk = &disk;
+            Box::pin(async move {
+                written.notified().await;
+                disk.write_all(pages).await
+            })
+        });
+        let evicting = async {
+            let three = pin(&cache, &disk, 3).await;
+            written.notify_one();
+            three
+        };
+        let (flushed, three) = tokio::join!(flushing, evicting);
+        flushed.unwrap();
+        assert_eq!(three.unwrap().read().await.unwrap().header.id, 3);
+        assert_eq!(disk.count(1), 1);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn pins_cached_pages_while_writing_a_victim_back() {
+        let disk = Disk::default();
+        let cache = PageCache::new(2, Box::<Lfu>::default());
+        let one = pin(&cache, &disk, 1).await.unwrap();
+        one.write().await.unwrap().data[0] = 1;
+        drop(one);
+        for _ in 0..3 {
+            pin(&cache, &disk, 2).await.unwrap();
+        }
+
+        // Evicting 1 waits on its write back, which only finishes once 2 has been pinned
+        let written = tokio::sync::Notify::new();
+        let evicting = cache.pin(
+            3,
+            || disk.load(3),
+            |id, bytes| {
+                let written = &written;
+                let disk = &disk;
+                Box::pin(async move {
+                    written.notified().await;
+                    disk.write_back(id, bytes).await
+                })
+            },
+        );
+        let hit = async {
+            let two = pin(&cache, &disk, 2).await;
+            written.notify_one();
+            two
+        };
+        let (three, two) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(evicting, hit)
+        })
+        .await
+        .expect("the cache stayed locked during a write back");
+        assert_eq!(three.unwrap().id(), 3);
+        assert_eq!(two.unwrap().id(), 2);
+        assert_eq!(disk.count(1), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn keeps_pinned_pages_under_contention() {
+        let disk = Arc::new(Disk::default());
+        let cache = Arc::new(PageCache::new(4, Box::new(LruK::new(2))));
+        let tasks: Vec<_> = (0..8u64)
+            .map(|task| {
+                let (disk, cache) = (disk.clone(), cache.clone());
+                tokio::spawn(async move {
+                    for i in 0..50u64 {
+                        let id = 1 + (task * 7 + i * 3) % 6;
+                        // Every other task holds two pages at once, which can fill the cache
+                        let (loan, other) = loop {
+                            let other = if task % 2 == 0 {
+                                pin(&cache, &disk, 1 + (id % 6)).await.ok()
+                            } else {
+                                None
+                            };
+                            match pin(&cache, &disk, id).await {
+                                Ok(loan) => break (loan, other),
+                                Err(_) => {
+                                    drop(other);
+                                    tokio::task::yield_now().await
+                                }
+                            }
+                        };
+                        let mut page = loan.write().await.unwrap();
+                        assert_eq!(page.header.id, id);
+                        page.data[0] += 1;
+                        drop(page);
+                        tokio::task::yield_now().await;
+                        assert_eq!(loan.read().await.unwrap().header.id, id);
+                        if let Some(other) = other {
+                            assert_eq!(other.read().await.unwrap().header.id, other.id());
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        cache.flush(|pages| disk.write_all(pages)).await.unwrap();
+        let total: u64 = (1..=6).map(|id| disk.count(id) as u64).sum();
+        assert_eq!(total, 8 * 50);
+        assert!(cache.stats().evictions > 0);
+    }
+}
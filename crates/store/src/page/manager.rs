@@ -1,86 +1,1035 @@
-use std::{collections::{BTreeMap, HashMap}, mem::size_of, path::Path, sync::atomic::{AtomicU64, Ordering}};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    ops::Range,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
 
-use tokio::fs::{File, self};
+use serde::{Deserialize, Serialize};
 
-use crate::{source::{SourceTag, Source}};
-use serde::{Serialize, Deserialize};
+use super::{
+    cache::{CacheStats, IoFuture, Lfu, PageCache, PageLoan},
+    PageHeader, PageId, RawPage, PAGE_HEADER_SIZE, PAGE_SIZE,
+};
+use crate::{
+    blocking,
+    source::{SourceBlocks, SourceTag},
+    wal::{Lsn, Wal, WalRecord},
+};
 
-type PageId = u64;
-
-const GB: usize = 1_000_000_000; 
+const GB: u64 = 1_000_000_000;
 
 /// 4 GB
-const MAX_FILE_SIZE: usize = 4 * GB;
+const MAX_FILE_SIZE: u64 = 4 * GB;
 
+const INFO_FILE: &str = "page_manager_info.json";
+/// Pages written back since the data files were last synced, each after the offset it was
+/// written at
+const DOUBLE_WRITE_FILE: &str = "double_write.journal";
+const DOUBLE_WRITE_ENTRY: usize = 8 + PAGE_SIZE;
+/// Entries the journal holds before the data files are synced and it starts over, 512 KB
+const DOUBLE_WRITE_PAGES: usize = 128;
 
+type Files = Arc<Mutex<BTreeMap<u64, Arc<File>>>>;
 
-pub struct PageManager {
+/// Copies of the pages written in place since the data files were last synced
+struct Journal {
+    file: File,
+    entries: usize,
+}
+
+impl Journal {
+    /// Sync the pages written in place, so their copies can go
+    fn recycle(&mut self, files: &Files) -> io::Result<()> {
+        let files: Vec<_> = files
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        for file in files {
+            file.sync_data()?;
+        }
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.entries = 0;
+        Ok(())
+    }
+}
+
+struct State {
     page_offsets: BTreeMap<PageId, u64>,
-    files: BTreeMap<u64, File>,
     source_tag_lookups: HashMap<SourceTag, Vec<PageId>>,
-    last_page_id: AtomicU64
+    /// Offsets free to reuse
+    free_offsets: Vec<u64>,
+    /// Offsets of pages deleted since the last flush. Until the deletion is on disk the page
+    /// still exists after a crash, so its space can't be reused yet.
+    pending_free: Vec<u64>,
+    /// End of the space pages have been allocated in
+    next_offset: u64,
+    sealed_sources: HashSet<SourceTag>,
+    /// Pages of the sealed sources
+    sealed_pages: HashSet<PageId>,
+}
+
+/// Allocates fixed size pages across data files of at most `max_file_size` bytes and loans
+/// them out of a page cache. Page contents are written in place, the pages that exist and
+/// where they are only change on disk when the manager is flushed. Every page written back
+/// goes to a double write file first, which repairs pages a crash tore when they're opened.
+/// Pages written together share one sync of the file, and it is kept to a fixed number of
+/// pages by syncing the data files whenever it fills up.
+///
+/// With a log attached, pages allocated and deleted are logged, as are the changes callers
+/// log with `log_write`, so that a crash only loses what wasn't committed.
+pub struct PageManager {
+    dir: PathBuf,
+    max_file_size: u64,
+    state: Mutex<State>,
+    files: Files,
+    /// Held while writing pages back, so a flush can't drop their copies before they're synced
+    double_write: Arc<Mutex<Journal>>,
+    /// Log of the changes to the pages. Pages with changes it hasn't made durable are held
+    /// back until it has.
+    wal: Option<Wal>,
+    cache: PageCache,
+    last_page_id: AtomicU64,
+    /// Keeps flushes from interleaving
+    flushing: tokio::sync::Mutex<()>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SerializedPageManagerData {
     last_page_id: u64,
     page_offsets: BTreeMap<PageId, u64>,
-    source_tag_lookups: HashMap<SourceTag, Vec<PageId>>
+    source_tag_lookups: HashMap<SourceTag, Vec<PageId>>,
+    #[serde(default)]
+    free_offsets: Vec<u64>,
+    #[serde(default)]
+    next_offset: u64,
+    /// Offsets map to files by the file size they were allocated with
+    #[serde(default = "max_file_size")]
+    max_file_size: u64,
+    /// Sources whose pages can't change anymore
+    #[serde(default)]
+    sealed_sources: HashSet<SourceTag>,
 }
 
-async fn load_page_manager_file(dir: impl AsRef<Path>) -> error::Result<SerializedPageManagerData> {
-    let file_path = dir.as_ref().join("/page_manager_info.json");
-    let file_data = fs::read(file_path).await?;
+fn max_file_size() -> u64 {
+    MAX_FILE_SIZE
+}
+
+async fn load_page_manager_file(
+    dir: impl AsRef<Path>,
+) -> error::Result<Option<SerializedPageManagerData>> {
+    let file_path = dir.as_ref().join(INFO_FILE);
+    let file_data = match tokio::fs::read(file_path).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
     let data: SerializedPageManagerData = serde_json::from_slice(&file_data)?;
-    Ok(data)
+    Ok(Some(data))
+}
+
+/// Replace `dir/name` with `bytes` so that a crash leaves either the old or the new contents
+fn write_atomically(dir: &Path, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let temporary = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&temporary, dir.join(name))?;
+    File::open(dir)?.sync_all()
+}
+
+fn no_page(id: PageId) -> error::Error {
+    error::CustomErrors::InvalidArguments(format!("no page {}", id)).into()
+}
+
+fn sealed(id: PageId) -> error::Error {
+    error::CustomErrors::InvalidArguments(format!("page {} is sealed", id)).into()
 }
 
 impl PageManager {
+    /// Open the pages and the log in the configured data directory, redoing the changes a
+    /// crash kept from the pages
     pub async fn new() -> error::Result<Self> {
         let config = config::get_config().await;
-        let manager_data = load_page_manager_file(config.data_directory).await?;
-        Ok(PageManager {
-            files: BTreeMap::new(),
-            page_offsets: manager_data.page_offsets,
-            source_tag_lookups: manager_data.source_tag_lookups,
-            last_page_id: AtomicU64::new(manager_data.last_page_id)
+        PageManager::open(config.data_directory, MAX_FILE_SIZE)
+            .await?
+            .with_wal(Wal::new().await?)
+            .await
+    }
+
+    /// Open the pages in `dir`, creating it if needed, with an LFU cache of the configured
+    /// size. `max_file_size` only applies to new directories, existing ones keep the size
+    /// their pages were allocated with.
+    pub async fn open(dir: impl AsRef<Path>, max_file_size: u64) -> error::Result<Self> {
+        let config = config::get_config().await;
+        let cache = PageCache::new(config.page_cache_size / PAGE_SIZE, Box::<Lfu>::default());
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        let manager_data =
+            load_page_manager_file(&dir)
+                .await?
+                .unwrap_or(SerializedPageManagerData {
+                    last_page_id: 0,
+                    page_offsets: BTreeMap::new(),
+                    source_tag_lookups: HashMap::new(),
+                    free_offsets: Vec::new(),
+                    next_offset: 0,
+                    max_file_size,
+                    sealed_sources: HashSet::new(),
+                });
+        let next_offset = manager_data
+            .page_offsets
+            .values()
+            .chain(&manager_data.free_offsets)
+            .map(|offset| offset + PAGE_SIZE as u64)
+            .fold(manager_data.next_offset, u64::max);
+        let sealed_pages = manager_data
+            .sealed_sources
+            .iter()
+            .filter_map(|tag| manager_data.source_tag_lookups.get(tag))
+            .flatten()
+            .copied()
+            .collect();
+        let double_write = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(DOUBLE_WRITE_FILE))?;
+        let manager = PageManager {
+            dir,
+            max_file_size: manager_data.max_file_size,
+            state: Mutex::new(State {
+                page_offsets: manager_data.page_offsets,
+                source_tag_lookups: manager_data.source_tag_lookups,
+                free_offsets: manager_data.free_offsets,
+                pending_free: Vec::new(),
+                next_offset,
+                sealed_sources: manager_data.sealed_sources,
+                sealed_pages,
+            }),
+            files: Default::default(),
+            double_write: Arc::new(Mutex::new(Journal {
+                file: double_write,
+                entries: 0,
+            })),
+            wal: None,
+            cache,
+            last_page_id: AtomicU64::new(manager_data.last_page_id),
+            flushing: tokio::sync::Mutex::new(()),
+        };
+        manager.repair_torn_pages().await?;
+        Ok(manager)
+    }
+
+    /// Write the pages in the double write file back in place, one at a time. A crash can
+    /// only have torn pages whose copies were synced whole first. Torn copies never made it
+    /// in place, so they are skipped.
+    async fn repair_torn_pages(&self) -> error::Result<()> {
+        let mut at = 0;
+        loop {
+            let double_write = self.double_write.clone();
+            let entry = blocking(move || {
+                let journal = double_write.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut entry = vec![0; DOUBLE_WRITE_ENTRY];
+                match journal.file.read_exact_at(&mut entry, at) {
+                    Ok(()) => Ok(Some(entry)),
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+            .await?;
+            let Some(entry) = entry else {
+                break;
+            };
+            let (offset, page) = entry.split_at(8);
+            if RawPage::from_bytes(page).is_ok() {
+                let offset = u64::from_le_bytes(offset.try_into().unwrap());
+                self.write_at(offset, page.into()).await?;
+            }
+            at += DOUBLE_WRITE_ENTRY as u64;
+        }
+        let files = self.files.clone();
+        let double_write = self.double_write.clone();
+        blocking(move || {
+            let mut journal = double_write.lock().unwrap_or_else(PoisonError::into_inner);
+            journal.recycle(&files)
+        })
+        .await
+    }
+
+    /// Loan pages out of `cache` instead
+    pub fn with_cache(mut self, cache: PageCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Log changes to `wal`, once the changes in it are redone. Pages are held back until
+    /// the log is durable up to the lsn they were stamped with.
+    pub async fn with_wal(mut self, wal: Wal) -> error::Result<Self> {
+        wal.recover(&self).await?;
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Log a change just made to `page`, the bytes in `range` of its data and its size, and
+    /// stamp the page with the lsn. Logging while the page is still locked keeps the records
+    /// of a page in order. Does nothing without a log.
+    pub fn log_write(&self, page: &mut RawPage, range: Range<usize>) {
+        let Some(wal) = &self.wal else {
+            return;
+        };
+        let lsn = wal.append(&WalRecord::Write {
+            id: page.header.id,
+            offset: range.start as u32,
+            size: page.header.size,
+            data: page.data[range].to_vec(),
+        });
+        page.header.lsn = page.header.lsn.max(lsn);
+    }
+
+    /// Wait until every change logged so far is durable
+    pub async fn commit(&self) -> error::Result<()> {
+        match &self.wal {
+            Some(wal) => wal.commit(wal.last_lsn()).await,
+            None => Ok(()),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn list_pages(&self, tag: SourceTag) -> Vec<PageId> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .source_tag_lookups
+            .get(&tag)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Make the pages of a source read only and refuse new pages for it, for as long as it
+    /// has pages. Recorded on disk by the next flush, like the pages themselves, and logged.
+    pub fn seal_source(&self, tag: SourceTag) {
+        self.seal_pages(tag);
+        if let Some(wal) = &self.wal {
+            wal.append(&WalRecord::SealSource { tag });
+        }
+    }
+
+    /// Seal a source without logging it
+    pub(crate) fn seal_pages(&self, tag: SourceTag) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let pages = state
+            .source_tag_lookups
+            .get(&tag)
+            .cloned()
+            .unwrap_or_default();
+        state.sealed_pages.extend(pages);
+        state.sealed_sources.insert(tag);
+    }
+
+    pub fn is_sealed(&self, tag: SourceTag) -> bool {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.sealed_sources.contains(&tag)
+    }
+
+    fn page_sealed(&self, id: PageId) -> bool {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.sealed_pages.contains(&id)
+    }
+
+    /// Stream the data of the pages tagged `tag`, allocating them as it's written
+    pub fn get_source(&self, tag: SourceTag) -> SourceBlocks<'_> {
+        SourceBlocks::new(tag, self)
+    }
+
+    /// File holding `offset` and the position within it
+    fn location(&self, offset: u64) -> (u64, u64) {
+        let pages_per_file = (self.max_file_size / PAGE_SIZE as u64).max(1);
+        let file_size = pages_per_file * PAGE_SIZE as u64;
+        (offset / file_size, offset % file_size)
+    }
+
+    fn file(&self, index: u64) -> error::Result<Arc<File>> {
+        let mut files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(file) = files.get(&index) {
+            return Ok(file.clone());
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join(format!("pages_{:05}.data", index)))?;
+        let file = Arc::new(file);
+        files.insert(index, file.clone());
+        Ok(file)
+    }
+
+    fn offset(&self, id: PageId) -> Option<u64> {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .page_offsets
+            .get(&id)
+            .copied()
+    }
+
+    pub fn contains_page(&self, id: PageId) -> bool {
+        self.offset(id).is_some()
+    }
+
+    async fn read_at(&self, offset: u64) -> error::Result<Box<[u8]>> {
+        let (index, position) = self.location(offset);
+        let file = self.file(index)?;
+        blocking(move || {
+            let mut bytes = vec![0; PAGE_SIZE].into_boxed_slice();
+            file.read_exact_at(&mut bytes, position)?;
+            Ok(bytes)
         })
+        .await
+    }
+
+    async fn write_at(&self, offset: u64, bytes: Box<[u8]>) -> error::Result<()> {
+        let (index, position) = self.location(offset);
+        let file = self.file(index)?;
+        blocking(move || file.write_all_at(&bytes, position)).await
     }
 
-    fn list_pages(&self, tag: SourceTag) -> &Vec<PageId> {
-        self.source_tag_lookups
-            .entry(tag)
-            .or_default()
+    async fn read_page(&self, id: PageId) -> error::Result<Box<RawPage>> {
+        let offset = self.offset(id).ok_or_else(|| no_page(id))?;
+        let page = RawPage::from_bytes(&self.read_at(offset).await?)?;
+        if page.header.id != id {
+            return Err(error::CustomErrors::InvalidState(format!(
+                "page {} holds page {}",
+                id, page.header.id
+            ))
+            .into());
+        }
+        Ok(page)
     }
 
-    /// get or create a new source block
-    fn get_source<'a>(&'a self) -> Source<'a> {
-        todo!()
+    /// Write pages the cache evicts or flushes back in place, unless they were deleted. They
+    /// are synced to the double write file first, in batches that fit in it. Pages with
+    /// changes the log hasn't made durable are held back, returning their ids.
+    async fn write_pages(&self, pages: Vec<(PageId, Box<[u8]>)>) -> error::Result<Vec<PageId>> {
+        let durable: Option<Lsn> = self.wal.as_ref().map(|wal| wal.durable_lsn());
+        let (pages, held): (Vec<_>, Vec<_>) = pages.into_iter().partition(|(_, bytes)| {
+            durable.is_none_or(|durable| {
+                PageHeader::read_from(&bytes[..PAGE_HEADER_SIZE]).lsn <= durable
+            })
+        });
+        let held = held.into_iter().map(|(id, _)| id).collect();
+        let mut writes = Vec::with_capacity(pages.len());
+        for (id, bytes) in pages {
+            let Some(offset) = self.offset(id) else {
+                continue;
+            };
+            let (index, position) = self.location(offset);
+            writes.push((offset, self.file(index)?, position, bytes));
+        }
+        let files = self.files.clone();
+        let double_write = self.double_write.clone();
+        blocking(move || {
+            let mut journal = double_write.lock().unwrap_or_else(PoisonError::into_inner);
+            for batch in writes.chunks(DOUBLE_WRITE_PAGES) {
+                if journal.entries + batch.len() > DOUBLE_WRITE_PAGES {
+                    journal.recycle(&files)?;
+                }
+                let mut entries = Vec::with_capacity(batch.len() * DOUBLE_WRITE_ENTRY);
+                for (offset, _, _, bytes) in batch {
+                    entries.extend(offset.to_le_bytes());
+                    entries.extend_from_slice(bytes);
+                }
+                let at = (journal.entries * DOUBLE_WRITE_ENTRY) as u64;
+                journal.file.write_all_at(&entries, at)?;
+                journal.file.sync_data()?;
+                journal.entries += batch.len();
+                for (_, file, position, bytes) in batch {
+                    file.write_all_at(bytes, *position)?;
+                }
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(held)
     }
 
-    /// Get a page by id
-    async fn get_page(&self, id: PageId) -> error::Result<Option<RawPage>> {
-        todo!()
+    fn write_back<'a>(&'a self) -> impl Fn(PageId, Box<[u8]>) -> IoFuture<'a, bool> + 'a {
+        move |id, bytes| {
+            Box::pin(async move { Ok(self.write_pages(vec![(id, bytes)]).await?.is_empty()) })
+        }
     }
 
-    pub(crate) fn new_page(&self) -> &RawPage {
-        let id = self.last_page_id.fetch_add(1, Ordering::)
+    /// Pin a page holding `page` without reading it from disk
+    async fn pin_copy(&self, page: Box<RawPage>) -> error::Result<PageLoan> {
+        let id = page.header.id;
+        let copy = page.clone();
+        let loan = self
+            .cache
+            .pin(id, move || Box::pin(async { Ok(copy) }), self.write_back())
+            .await?;
+        *loan.write().await? = *page;
+        Ok(loan)
     }
 
-    pub(crate) fn delete_page(&self, id: PageId) {
-        todo!()
+    /// Get a copy of a page by id
+    pub async fn get_page(&self, id: PageId) -> error::Result<Option<Box<RawPage>>> {
+        if self.offset(id).is_none() {
+            return Ok(None);
+        }
+        let loan = self.aquire_page_loan(id).await?;
+        let page = loan.read().await?;
+        Ok(Some(Box::new(page.clone())))
     }
 
-    pub fn flush(&self) {
-        todo!()
+    /// Replace a page's contents, written back when it is evicted or flushed
+    pub async fn write_page(&self, page: &RawPage) -> error::Result<()> {
+        let id = page.header.id;
+        self.offset(id).ok_or_else(|| no_page(id))?;
+        if self.page_sealed(id) {
+            return Err(sealed(id));
+        }
+        self.pin_copy(Box::new(page.clone())).await?;
+        Ok(())
     }
+
+    /// Allocate an empty page for `tag`, reusing the space of deleted pages when possible
+    pub async fn new_page(&self, tag: SourceTag) -> error::Result<PageLoan> {
+        if self.is_sealed(tag) {
+            return Err(
+                error::CustomErrors::InvalidArguments(format!("source {} is sealed", tag)).into(),
+            );
+        }
+        let id = self.last_page_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut page = self.allocate(id, tag).await?;
+        if let Some(wal) = &self.wal {
+            page.header.lsn = wal.append(&WalRecord::NewPage { id, tag });
+        }
+        self.pin_copy(page).await
+    }
+
+    /// Recreate a page the log saw allocated, if a crash lost it
+    pub(crate) async fn restore_page(&self, id: PageId, tag: SourceTag) -> error::Result<()> {
+        if self.offset(id).is_some() {
+            return Ok(());
+        }
+        self.last_page_id.fetch_max(id, Ordering::SeqCst);
+        self.allocate(id, tag).await?;
+        Ok(())
+    }
+
+    async fn allocate(&self, id: PageId, tag: SourceTag) -> error::Result<Box<RawPage>> {
+        let offset = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            match state.free_offsets.pop() {
+                Some(offset) => offset,
+                None => {
+                    state.next_offset += PAGE_SIZE as u64;
+                    state.next_offset - PAGE_SIZE as u64
+                }
+            }
+        };
+
+        // The page only exists once it is on disk, so a flush can't record it half written
+        let page = RawPage::new(id, tag);
+        let written = self.write_at(offset, page.to_bytes()).await;
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = written {
+                state.free_offsets.push(offset);
+                return Err(e);
+            }
+            state.page_offsets.insert(id, offset);
+            state.source_tag_lookups.entry(tag).or_default().push(id);
+        }
+        Ok(page)
+    }
+
+    pub async fn delete_page(&self, id: PageId) -> error::Result<()> {
+        self.forget_page(id).await?;
+        if let Some(wal) = &self.wal {
+            wal.append(&WalRecord::DeletePage { id });
+        }
+        Ok(())
+    }
+
+    /// Delete a page without logging it
+    pub(crate) async fn forget_page(&self, id: PageId) -> error::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let offset = state.page_offsets.remove(&id).ok_or_else(|| no_page(id))?;
+            state.pending_free.push(offset);
+            state.source_tag_lookups.retain(|_, pages| {
+                pages.retain(|page| *page != id);
+                !pages.is_empty()
+            });
+            state.sealed_pages.remove(&id);
+            let State {
+                sealed_sources,
+                source_tag_lookups,
+                ..
+            } = &mut *state;
+            sealed_sources.retain(|tag| source_tag_lookups.contains_key(tag));
+        }
+        self.cache.remove(id).await;
+        Ok(())
+    }
+
+    /// Write back cached changes, make them durable and record which pages exist. Pages with
+    /// changes the log hasn't made durable stay cached, to be written by a later flush. With
+    /// a log, it is committed first and the records the flush covers are dropped after.
+    pub async fn flush(&self) -> error::Result<()> {
+        match &self.wal {
+            Some(wal) => wal.checkpoint(self).await,
+            None => self.flush_pages().await.map(drop),
+        }
+    }
+
+    /// Flush without the log, returning the pages held back
+    pub(crate) async fn flush_pages(&self) -> error::Result<Vec<PageId>> {
+        let _flushing = self.flushing.lock().await;
+        let held = self
+            .cache
+            .flush(|pages| Box::pin(self.write_pages(pages)))
+            .await?;
+        let (data, freed) = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let freed = mem::take(&mut state.pending_free);
+            let data = SerializedPageManagerData {
+                last_page_id: self.last_page_id.load(Ordering::SeqCst),
+                page_offsets: state.page_offsets.clone(),
+                source_tag_lookups: state.source_tag_lookups.clone(),
+                free_offsets: state.free_offsets.iter().chain(&freed).copied().collect(),
+                next_offset: state.next_offset,
+                max_file_size: self.max_file_size,
+                sealed_sources: state.sealed_sources.clone(),
+            };
+            (data, freed)
+        };
+        let bytes = serde_json::to_vec(&data)?;
+        let files = self.files.clone();
+        let double_write = self.double_write.clone();
+        let dir = self.dir.clone();
+        let result = blocking(move || {
+            // Pages written back are all synced in place, their copies aren't needed anymore
+            let mut journal = double_write.lock().unwrap_or_else(PoisonError::into_inner);
+            journal.recycle(&files)?;
+            write_atomically(&dir, INFO_FILE, &bytes)
+        })
+        .await;
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match result {
+            Ok(()) => state.free_offsets.extend(freed),
+            Err(_) => state.pending_free.extend(freed),
+        }
+        result.map(|()| held)
+    }
+
     /// Force pages into memory
-    fn force_load_pages(&self, ids: &[PageId]) {
-        todo!()
+    pub async fn force_load_pages(&self, ids: &[PageId]) -> error::Result<Vec<PageLoan>> {
+        let mut loans = Vec::with_capacity(ids.len());
+        for id in ids {
+            loans.push(self.aquire_page_loan(*id).await?);
+        }
+        Ok(loans)
     }
-    
-    /// Load a new page into memory and free old pages if needed
-    pub(crate) async fn aquire_page_loan(&self, id: PageId) -> error::Result<()> {
-        todo!()
+
+    /// Pin a page in the cache, loading it and evicting another if needed
+    pub async fn aquire_page_loan(&self, id: PageId) -> error::Result<PageLoan> {
+        self.offset(id).ok_or_else(|| no_page(id))?;
+        let loan = self
+            .cache
+            .pin(id, || Box::pin(self.read_page(id)), self.write_back())
+            .await?;
+        Ok(loan.sealed(self.page_sealed(id)))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn open(dir: &tempfile::TempDir) -> PageManager {
+        PageManager::open(dir.path(), 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+    }
+
+    async fn write(manager: &PageManager, tag: SourceTag, data: &[u8]) -> PageId {
+        let loan = manager.new_page(tag).await.unwrap();
+        let mut page = loan.write().await.unwrap();
+        page.data[..data.len()].copy_from_slice(data);
+        page.header.size = data.len() as u64;
+        loan.id()
+    }
+
+    async fn read(manager: &PageManager, id: PageId) -> Option<Vec<u8>> {
+        let page = manager.get_page(id).await.unwrap()?;
+        Some(page.active_data().to_vec())
+    }
+
+    #[tokio::test]
+    async fn allocates_reads_and_writes_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 1, b"b").await;
+        let c = write(&manager, 2, b"c").await;
+        assert_eq!(manager.list_pages(1), vec![a, b]);
+        assert_eq!(manager.list_pages(2), vec![c]);
+        assert_eq!(read(&manager, b).await.unwrap(), b"b");
+
+        manager.delete_page(b).await.unwrap();
+        assert_eq!(read(&manager, b).await, None);
+        assert_eq!(manager.list_pages(1), vec![a]);
+        assert!(manager.delete_page(b).await.is_err());
+        assert!(manager.write_page(&RawPage::new(b, 1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn spreads_pages_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let mut ids = Vec::new();
+        for i in 0..10u8 {
+            ids.push(write(&manager, 1, &[i]).await);
+        }
+        manager.flush().await.unwrap();
+        let files = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().ends_with(".data")
+            })
+            .count();
+        assert_eq!(files, 3);
+
+        let manager = open(&dir).await;
+        for (i, id) in ids.into_iter().enumerate() {
+            assert_eq!(read(&manager, id).await.unwrap(), vec![i as u8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_space_once_deletes_are_flushed() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        manager.flush().await.unwrap();
+
+        manager.delete_page(a).await.unwrap();
+        let b = write(&manager, 1, b"b").await;
+        assert_ne!(manager.offset(a), manager.offset(b));
+        let freed = manager.state.lock().unwrap().pending_free.clone();
+
+        manager.flush().await.unwrap();
+        let c = write(&manager, 1, b"c").await;
+        assert_eq!(manager.offset(c), freed.first().copied());
+        assert!(c > b);
+    }
+
+    #[tokio::test]
+    async fn refuses_writes_to_sealed_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 2, b"b").await;
+        manager.seal_source(1);
+        manager.flush().await.unwrap();
+
+        let manager = open(&dir).await;
+        assert!(manager.is_sealed(1) && !manager.is_sealed(2));
+        let loan = manager.aquire_page_loan(a).await.unwrap();
+        assert!(loan.write().await.is_err());
+        assert!(loan.clone().write_owned().await.is_err());
+        assert_eq!(loan.read().await.unwrap().active_data(), b"a");
+        drop(loan);
+        let page = manager.get_page(a).await.unwrap().unwrap();
+        assert!(manager.write_page(&page).await.is_err());
+        assert!(manager.new_page(1).await.is_err());
+        assert!(manager
+            .aquire_page_loan(b)
+            .await
+            .unwrap()
+            .write()
+            .await
+            .is_ok());
+
+        // Once its pages are gone the tag is free to use again
+        manager.delete_page(a).await.unwrap();
+        assert!(!manager.is_sealed(1));
+        write(&manager, 1, b"c").await;
+    }
+
+    #[tokio::test]
+    async fn crashes_lose_only_unflushed_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 1, b"b").await;
+        manager.flush().await.unwrap();
+
+        // Changes after the flush, then a crash before the next one
+        let c = write(&manager, 1, b"c").await;
+        manager.delete_page(a).await.unwrap();
+        let d = write(&manager, 2, b"d").await;
+        drop(manager);
+
+        let manager = open(&dir).await;
+        assert_eq!(read(&manager, a).await.unwrap(), b"a");
+        assert_eq!(read(&manager, b).await.unwrap(), b"b");
+        assert_eq!(read(&manager, c).await, None);
+        assert_eq!(read(&manager, d).await, None);
+        assert_eq!(manager.list_pages(1), vec![a, b]);
+        assert!(manager.list_pages(2).is_empty());
+
+        // New pages don't overwrite flushed ones
+        let e = write(&manager, 1, b"e").await;
+        assert!(e > b);
+        assert_eq!(read(&manager, a).await.unwrap(), b"a");
+        assert_eq!(read(&manager, b).await.unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn survives_torn_metadata_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        manager.flush().await.unwrap();
+        drop(manager);
+
+        // A crash while writing the next metadata leaves a partial temporary file
+        fs::write(dir.path().join(format!("{}.tmp", INFO_FILE)), b"{\"last_pa").unwrap();
+        let manager = open(&dir).await;
+        assert_eq!(read(&manager, a).await.unwrap(), b"a");
+        manager.flush().await.unwrap();
+        drop(manager);
+
+        fs::write(dir.path().join(INFO_FILE), b"{\"last_pa").unwrap();
+        assert!(PageManager::open(dir.path(), MAX_FILE_SIZE).await.is_err());
+        fs::write(dir.path().join(INFO_FILE), b"").unwrap();
+        assert!(PageManager::open(dir.path(), MAX_FILE_SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn survives_truncated_data_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 1, &[b'b'; 3000]).await;
+        manager.flush().await.unwrap();
+        let (index, position) = manager.location(manager.offset(b).unwrap());
+        drop(manager);
+
+        // Lose the second half of the last page
+        let path = dir.path().join(format!("pages_{:05}.data", index));
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(position + PAGE_SIZE as u64 / 2)
+            .unwrap();
+
+        let manager = open(&dir).await;
+        assert_eq!(read(&manager, a).await.unwrap(), b"a");
+        assert!(manager.get_page(b).await.is_err());
+
+        // New pages go after it, which leaves it zeroed rather than short
+        let c = write(&manager, 1, b"c").await;
+        manager.flush().await.unwrap();
+        drop(manager);
+        let manager = open(&dir).await;
+        let error = manager.aquire_page_loan(b).await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(error::CustomErrors::Corrupted(_))
+        ));
+        assert_eq!(read(&manager, c).await.unwrap(), b"c");
+        assert_eq!(read(&manager, a).await.unwrap(), b"a");
+    }
+
+    #[tokio::test]
+    async fn repairs_torn_page_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        manager.flush().await.unwrap();
+
+        let mut page = RawPage::new(a, 1);
+        page.data[..3].copy_from_slice(b"new");
+        page.header.size = 3;
+        manager
+            .write_pages(vec![(a, page.to_bytes())])
+            .await
+            .unwrap();
+        let (index, position) = manager.location(manager.offset(a).unwrap());
+        drop(manager);
+
+        // A crash halfway through writing the page in place, and through journaling the next
+        let file = File::options()
+            .write(true)
+            .open(dir.path().join(format!("pages_{:05}.data", index)))
+            .unwrap();
+        file.write_all_at(&[0xaa; PAGE_SIZE / 2], position + PAGE_SIZE as u64 / 2)
+            .unwrap();
+        let mut journal = File::options()
+            .append(true)
+            .open(dir.path().join(DOUBLE_WRITE_FILE))
+            .unwrap();
+        journal.write_all(&[1; PAGE_SIZE / 2]).unwrap();
+
+        let manager = open(&dir).await;
+        assert_eq!(read(&manager, a).await.unwrap(), b"new");
+        assert_eq!(
+            fs::metadata(dir.path().join(DOUBLE_WRITE_FILE))
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn caps_the_double_write_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_len = || {
+            fs::metadata(dir.path().join(DOUBLE_WRITE_FILE))
+                .unwrap()
+                .len()
+        };
+        let manager = open(&dir)
+            .await
+            .with_cache(PageCache::new(2, Box::<Lfu>::default()));
+        // Each evicted page is written back on its own, with no flush clearing the journal
+        let n = 2 * DOUBLE_WRITE_PAGES + 10;
+        let mut ids = Vec::new();
+        for i in 0..n as u32 {
+            ids.push(write(&manager, 1, &i.to_le_bytes()).await);
+        }
+        assert_eq!(manager.cache_stats().write_backs as usize, n - 2);
+        assert!(journal_len() > 0);
+        assert!(journal_len() <= (DOUBLE_WRITE_PAGES * DOUBLE_WRITE_ENTRY) as u64);
+        manager.flush().await.unwrap();
+        assert_eq!(journal_len(), 0);
+
+        // Written back together, then torn in place by a crash
+        let pages: Vec<_> = ids[..3]
+            .iter()
+            .map(|id| {
+                let mut page = RawPage::new(*id, 1);
+                page.data[..3].copy_from_slice(b"new");
+                page.header.size = 3;
+                (*id, page.to_bytes())
+            })
+            .collect();
+        manager.write_pages(pages).await.unwrap();
+        assert_eq!(journal_len(), 3 * DOUBLE_WRITE_ENTRY as u64);
+        let torn: Vec<_> = ids[..3]
+            .iter()
+            .map(|id| manager.location(manager.offset(*id).unwrap()))
+            .collect();
+        drop(manager);
+        for (index, position) in torn {
+            let file = File::options()
+                .write(true)
+                .open(dir.path().join(format!("pages_{:05}.data", index)))
+                .unwrap();
+            file.write_all_at(&[0xaa; 100], position + PAGE_SIZE as u64 - 100)
+                .unwrap();
+        }
+
+        let manager = open(&dir).await;
+        for id in &ids[..3] {
+            assert_eq!(read(&manager, *id).await.unwrap(), b"new");
+        }
+        assert_eq!(read(&manager, ids[3]).await.unwrap(), 3u32.to_le_bytes());
+        assert_eq!(journal_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn reports_corrupted_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 1, b"b").await;
+        manager.flush().await.unwrap();
+        let (index, position) = manager.location(manager.offset(a).unwrap());
+        drop(manager);
+
+        let file = File::options()
+            .write(true)
+            .open(dir.path().join(format!("pages_{:05}.data", index)))
+            .unwrap();
+        file.write_all_at(b"rot", position + 100).unwrap();
+
+        let manager = open(&dir).await;
+        let error = manager.aquire_page_loan(a).await.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(error::CustomErrors::Corrupted(_))
+        ));
+        assert_eq!(read(&manager, b).await.unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn shares_loaned_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir).await;
+        let a = write(&manager, 1, b"a").await;
+        let b = write(&manager, 1, b"b").await;
+
+        let loans = manager.force_load_pages(&[a, b]).await.unwrap();
+        let loan = manager.aquire_page_loan(a).await.unwrap();
+        loan.write().await.unwrap().data[0] = b'x';
+        assert_eq!(loans[0].read().await.unwrap().active_data(), b"x");
+
+        drop((loans, loan));
+        let loan = manager.aquire_page_loan(a).await.unwrap();
+        assert_eq!(loan.read().await.unwrap().active_data(), b"x");
+        assert!(manager.aquire_page_loan(b + 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_back_evicted_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = open(&dir)
+            .await
+            .with_cache(PageCache::new(2, Box::<Lfu>::default()));
+        let mut ids = Vec::new();
+        for i in 0..5u8 {
+            ids.push(write(&manager, 1, &[i]).await);
+        }
+        assert_eq!(manager.cache_stats().evictions, 3);
+        assert_eq!(manager.cache_stats().write_backs, 3);
+        assert_eq!(read(&manager, ids[0]).await.unwrap(), vec![0]);
+        assert!(manager.cache_stats().misses > 0);
+
+        // Pinned pages stay put, so a full cache of them can't load more
+        let pinned = manager.force_load_pages(&ids[..2]).await.unwrap();
+        assert!(manager.aquire_page_loan(ids[4]).await.is_err());
+        drop(pinned);
+
+        manager.flush().await.unwrap();
+        let manager = open(&dir).await;
+        for (i, id) in ids.into_iter().enumerate() {
+            assert_eq!(read(&manager, id).await.unwrap(), vec![i as u8]);
+        }
+    }
+}
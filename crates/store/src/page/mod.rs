@@ -1,21 +1,149 @@
-use crate::source::SourceTag;
+use crate::{source::SourceTag, wal::Lsn};
 
 mod cache;
 mod manager;
 mod rwa_lock;
 
+pub use cache::{CacheStats, Clock, EvictionPolicy, Lfu, LruK, PageCache, PageLoan};
+pub use manager::PageManager;
+pub use rwa_lock::{RWAAppendGuard, RWABuffer, RWAReadGuard, RWAWriteGuard};
 
-/// 4096 KB
-const PAGE_SIZE: usize = 4096 * 1000;
-struct PageHeader {
-    id: u64,
-    tag: SourceTag,
+pub type PageId = u64;
+
+/// 4 KB
+pub const PAGE_SIZE: usize = 4096;
+/// Bytes at the start of every page on disk reserved for its header
+pub const PAGE_HEADER_SIZE: usize = 64;
+/// Bytes of a page left for data
+pub const PAGE_DATA_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+/// Where the CRC32C of a page on disk is kept, computed with these bytes zeroed
+const CHECKSUM: std::ops::Range<usize> = 32..36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub id: PageId,
+    pub tag: SourceTag,
+    /// Bytes of data in use
+    pub size: u64,
+    /// Log sequence number of the last logged change applied to the page
+    pub lsn: Lsn,
+}
+
+impl PageHeader {
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[..8].copy_from_slice(&self.id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.lsn.to_le_bytes());
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        PageHeader {
+            id: u64_at(0),
+            tag: u64_at(8),
+            size: u64_at(16),
+            lsn: u64_at(24),
+        }
+    }
+}
+
+/// CRC32C of a page on disk, leaving out the checksum itself
+fn checksum(bytes: &[u8]) -> u32 {
+    let before = crc32c::crc32c(&bytes[..CHECKSUM.start]);
+    let zeroed = crc32c::crc32c_append(before, &[0; CHECKSUM.end - CHECKSUM.start]);
+    crc32c::crc32c_append(zeroed, &bytes[CHECKSUM.end..])
 }
 
 // Align data to 64 so that it can be index into as if it were collections of 64bit integers
-#[repr(align(64))]
-struct RawPage {
-    offset: u64,
-    header: PageHeader,
-    data: [u8; PAGE_SIZE - std::mem::size_of::<PageHeader>()],
-}
\ No newline at end of file
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct RawPage {
+    pub data: [u8; PAGE_DATA_SIZE],
+    pub header: PageHeader,
+}
+
+impl RawPage {
+    /// An empty page
+    pub fn new(id: PageId, tag: SourceTag) -> Box<Self> {
+        Box::new(RawPage {
+            data: [0; PAGE_DATA_SIZE],
+            header: PageHeader {
+                id,
+                tag,
+                size: 0,
+                lsn: 0,
+            },
+        })
+    }
+
+    /// The data in use
+    pub fn active_data(&self) -> &[u8] {
+        &self.data[..(self.header.size as usize).min(PAGE_DATA_SIZE)]
+    }
+
+    /// The page as it is laid out on disk, header first with a checksum of the page in it
+    pub fn to_bytes(&self) -> Box<[u8]> {
+        let mut bytes = vec![0; PAGE_SIZE].into_boxed_slice();
+        self.header.write_to(&mut bytes[..PAGE_HEADER_SIZE]);
+        bytes[PAGE_HEADER_SIZE..].copy_from_slice(&self.data);
+        let checksum = checksum(&bytes);
+        bytes[CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> error::Result<Box<Self>> {
+        if bytes.len() != PAGE_SIZE {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "pages are {} bytes, got {}",
+                PAGE_SIZE,
+                bytes.len()
+            ))
+            .into());
+        }
+        let header = PageHeader::read_from(&bytes[..PAGE_HEADER_SIZE]);
+        let stored = u32::from_le_bytes(bytes[CHECKSUM].try_into().unwrap());
+        if stored != checksum(bytes) {
+            return Err(error::CustomErrors::Corrupted(format!(
+                "page {} fails its checksum, it may be torn or damaged",
+                header.id
+            ))
+            .into());
+        }
+        let mut page = RawPage::new(header.id, header.tag);
+        page.header = header;
+        page.data.copy_from_slice(&bytes[PAGE_HEADER_SIZE..]);
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_pages() {
+        let mut page = RawPage::new(7, 3);
+        page.data[..5].copy_from_slice(b"hello");
+        page.header.size = 5;
+        page.header.lsn = 42;
+
+        let mut bytes = page.to_bytes();
+        assert_eq!(bytes.len(), PAGE_SIZE);
+        let read = RawPage::from_bytes(&bytes).unwrap();
+        assert_eq!(read.header, page.header);
+        assert_eq!(read.active_data(), b"hello");
+        assert!(RawPage::from_bytes(&bytes[1..]).is_err());
+
+        for flipped in [0, CHECKSUM.start, PAGE_SIZE - 1] {
+            bytes[flipped] ^= 1;
+            let error = RawPage::from_bytes(&bytes).err().unwrap();
+            assert!(matches!(
+                error.downcast_ref(),
+                Some(error::CustomErrors::Corrupted(_))
+            ));
+            bytes[flipped] ^= 1;
+        }
+        assert!(RawPage::from_bytes(&vec![0; PAGE_SIZE]).is_err());
+    }
+}
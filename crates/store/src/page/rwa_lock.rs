@@ -1,105 +1,551 @@
-use std::cell::UnsafeCell;
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io,
+    ops::{Deref, DerefMut, Range},
+    pin::Pin,
+    ptr, slice,
+    sync::PoisonError,
+    task::{Context, Poll, Waker},
+};
 
-use tokio::sync::{Mutex, MutexGuard, Semaphore, SemaphorePermit};
+#[cfg(feature = "loom")]
+use loom::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+#[cfg(not(feature = "loom"))]
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
 
+use tokio::io::AsyncWrite;
+
+#[derive(Default)]
+struct LockState {
+    readers: usize,
+    writer: bool,
+    appender: bool,
+    /// Readers and appenders waiting for the buffer
+    waiting: Vec<Waker>,
+    /// Writers waiting for the buffer, by id, in the order they came. Readers and appenders
+    /// wait behind them, so a steady stream of readers can't keep a writer out.
+    waiting_writers: VecDeque<(u64, Waker)>,
+    next_writer: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    Write,
+    Append,
+}
+
+impl LockState {
+    fn try_acquire(&mut self, access: Access) -> bool {
+        let free = match access {
+            Access::Read => !self.writer && self.waiting_writers.is_empty(),
+            Access::Write => !self.writer && !self.appender && self.readers == 0,
+            Access::Append => !self.writer && !self.appender && self.waiting_writers.is_empty(),
+        };
+        if free {
+            match access {
+                Access::Read => self.readers += 1,
+                Access::Write => self.writer = true,
+                Access::Append => self.appender = true,
+            }
+        }
+        free
+    }
+
+    fn release(&mut self, access: Access) -> Vec<Waker> {
+        match access {
+            Access::Read => self.readers -= 1,
+            Access::Write => self.writer = false,
+            Access::Append => self.appender = false,
+        }
+        self.wakers()
+    }
+
+    /// Waiters that may be able to acquire the buffer now: the first writer once the buffer is
+    /// free, or every reader and appender when no writer waits
+    fn wakers(&mut self) -> Vec<Waker> {
+        match self.waiting_writers.front() {
+            None => std::mem::take(&mut self.waiting),
+            Some(_) if self.writer || self.appender || self.readers > 0 => Vec::new(),
+            // It stays queued until it acquires the buffer or gives up
+            Some((_, waker)) => vec![waker.clone()],
+        }
+    }
+
+    /// Queue a writer, or update the waker of one already queued
+    fn wait_to_write(&mut self, id: &mut Option<u64>, waker: &Waker) {
+        let queued = id.and_then(|id| self.waiting_writers.iter_mut().find(|(i, _)| *i == id));
+        match queued {
+            Some((_, queued)) if queued.will_wake(waker) => {}
+            Some((_, queued)) => *queued = waker.clone(),
+            None => {
+                let next = self.next_writer;
+                self.next_writer += 1;
+                self.waiting_writers.push_back((next, waker.clone()));
+                *id = Some(next);
+            }
+        }
+    }
+
+    fn stop_waiting(&mut self, id: u64) {
+        self.waiting_writers.retain(|(i, _)| *i != id);
+    }
+}
+
+/// A writer queued in `waiting_writers` until it acquires the buffer or gives up waiting
+struct WaitingWriter<'a> {
+    buffer: &'a RWABuffer,
+    id: Option<u64>,
+}
+
+impl Drop for WaitingWriter<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            // It may have been woken in place of another waiter, which has to be woken now
+            let waiting = {
+                let mut state = self.buffer.lock();
+                state.stop_waiting(id);
+                state.wakers()
+            };
+            waiting.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+/// A fixed size buffer locked for reading, writing or appending. Any number of readers can
+/// hold it alongside one appender, and readers see what is appended while they hold it. A
+/// writer has it to itself, and goes ahead of readers and appenders that come after it.
+pub struct RWABuffer {
+    state: Mutex<LockState>,
+    data: *mut u8,
+    capacity: usize,
+    /// Bytes in use. Only the appender grows it, after writing the bytes it takes in.
+    in_use: AtomicUsize,
+    /// Loom doesn't see accesses through `data`, so each byte has a cell that every read and
+    /// write of it goes through
+    #[cfg(feature = "loom")]
+    cells: Box<[UnsafeCell<()>]>,
+}
+
+// SAFETY: the lock state decides who can touch the data. Readers only see the bytes in use,
+// the appender only writes past them and a writer has the buffer to itself.
+unsafe impl Send for RWABuffer {}
+unsafe impl Sync for RWABuffer {}
+
+impl RWABuffer {
+    /// A buffer of which the first `in_use` bytes hold data
+    pub fn new(buffer: Box<[u8]>, in_use: usize) -> Self {
+        assert!(
+            in_use <= buffer.len(),
+            "{} bytes in use of a {} byte buffer",
+            in_use,
+            buffer.len()
+        );
+        let capacity = buffer.len();
+        RWABuffer {
+            state: Mutex::new(LockState::default()),
+            data: Box::into_raw(buffer) as *mut u8,
+            capacity,
+            in_use: AtomicUsize::new(in_use),
+            #[cfg(feature = "loom")]
+            cells: (0..capacity).map(|_| UnsafeCell::new(())).collect(),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        RWABuffer::new(vec![0; capacity].into_boxed_slice(), 0)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The buffer and the bytes of it in use
+    pub fn into_inner(self) -> (Box<[u8]>, usize) {
+        let in_use = self.in_use.load(Ordering::Acquire);
+        let buffer = unsafe { self.take_buffer() };
+        std::mem::forget(self);
+        (buffer, in_use)
+    }
+
+    /// SAFETY: only once, when nothing else can use the buffer
+    unsafe fn take_buffer(&self) -> Box<[u8]> {
+        self.bytes_mut(0..self.capacity);
+        Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, self.capacity))
+    }
+
+    /// SAFETY: nothing may be writing to `range`
+    unsafe fn bytes(&self, range: Range<usize>) -> &[u8] {
+        #[cfg(feature = "loom")]
+        for cell in &self.cells[range.clone()] {
+            cell.with(drop);
+        }
+        slice::from_raw_parts(self.data.add(range.start), range.len())
+    }
+
+    /// SAFETY: nothing else may be reading or writing `range`
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn bytes_mut(&self, range: Range<usize>) -> &mut [u8] {
+        #[cfg(feature = "loom")]
+        for cell in &self.cells[range.clone()] {
+            cell.with_mut(drop);
+        }
+        slice::from_raw_parts_mut(self.data.add(range.start), range.len())
+    }
+
+    fn lock(&self) -> impl DerefMut<Target = LockState> + '_ {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn acquire(&self, access: Access) {
+        let mut writer = WaitingWriter {
+            buffer: self,
+            id: None,
+        };
+        poll_fn(|cx| {
+            let mut state = self.lock();
+            if state.try_acquire(access) {
+                if let Some(id) = writer.id.take() {
+                    state.stop_waiting(id);
+                }
+                return Poll::Ready(());
+            }
+            match access {
+                Access::Write => state.wait_to_write(&mut writer.id, cx.waker()),
+                Access::Read | Access::Append => state.waiting.push(cx.waker().clone()),
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn release(&self, access: Access) {
+        let waiting = self.lock().release(access);
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+
+    pub async fn read(&self) -> RWAReadGuard<'_> {
+        self.acquire(Access::Read).await;
+        RWAReadGuard { buffer: self }
+    }
+
+    pub async fn write(&self) -> RWAWriteGuard<'_> {
+        self.acquire(Access::Write).await;
+        RWAWriteGuard { buffer: self }
+    }
+
+    pub async fn append(&self) -> RWAAppendGuard<'_> {
+        self.acquire(Access::Append).await;
+        RWAAppendGuard { buffer: self }
+    }
+
+    /// SAFETY: nothing may be writing to the bytes in use
+    unsafe fn active_data(&self) -> &[u8] {
+        self.bytes(0..self.in_use.load(Ordering::Acquire))
+    }
+}
+
+impl Drop for RWABuffer {
+    fn drop(&mut self) {
+        drop(unsafe { self.take_buffer() });
+    }
+}
+
+/// Shared access to the bytes in use, including any appended while the guard is held
 pub struct RWAReadGuard<'a> {
-    permit: SemaphorePermit<'a>,
-    data: &'a [u8],
+    buffer: &'a RWABuffer,
+}
+
+impl Deref for RWAReadGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: no writer while there are readers, and the appender writes past the data
+        unsafe { self.buffer.active_data() }
+    }
 }
 
+impl Drop for RWAReadGuard<'_> {
+    fn drop(&mut self) {
+        self.buffer.release(Access::Read);
+    }
+}
+
+/// Exclusive access to the bytes in use
 pub struct RWAWriteGuard<'a> {
-    permit: SemaphorePermit<'a>,
-    data: &'a mut [u8],
+    buffer: &'a RWABuffer,
 }
 
-pub struct RWAAppendGuard<'a> {
-    lock: MutexGuard<'a, ()>,
-    data: &'a mut [u8],
-    in_use_val: &'a mut usize,
-    // TODO collect this somehow (probably by turning data into an asyncwriter)
-    data_appended: usize,
+impl Deref for RWAWriteGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the writer holds the buffer alone
+        unsafe { self.buffer.active_data() }
+    }
 }
 
-impl<'a> Drop for RWAAppendGuard<'a> {
+impl DerefMut for RWAWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let in_use = self.buffer.in_use.load(Ordering::Acquire);
+        // SAFETY: the writer holds the buffer alone
+        unsafe { self.buffer.bytes_mut(0..in_use) }
+    }
+}
+
+impl Drop for RWAWriteGuard<'_> {
     fn drop(&mut self) {
-        *self.in_use_val += self.data_appended;
+        self.buffer.release(Access::Write);
     }
 }
 
-pub struct RWAInner {
-    data: Box<[u8]>,
-    in_use: usize,
+/// Adds data past the bytes in use, which readers see as soon as it's appended. Derefs to
+/// the bytes in use.
+pub struct RWAAppendGuard<'a> {
+    buffer: &'a RWABuffer,
 }
 
-impl RWAInner {
-    fn active_data(&self) -> &[u8] {
-        &self.data[..self.in_use]
+impl RWAAppendGuard<'_> {
+    /// Bytes left to append
+    pub fn remaining(&self) -> usize {
+        self.buffer.capacity - self.buffer.in_use.load(Ordering::Acquire)
     }
 
-    fn active_data_mut(&mut self) -> &mut [u8] {
-        &mut self.data[..self.in_use]
+    /// Append as much of `bytes` as fits, returning how much did
+    pub fn append(&mut self, bytes: &[u8]) -> usize {
+        let in_use = self.buffer.in_use.load(Ordering::Acquire);
+        let len = bytes.len().min(self.buffer.capacity - in_use);
+        // SAFETY: only the appender writes past the bytes in use, and nothing reads there
+        unsafe { self.buffer.bytes_mut(in_use..in_use + len) }.copy_from_slice(&bytes[..len]);
+        self.buffer.in_use.store(in_use + len, Ordering::Release);
+        len
     }
+}
 
-    fn reserve_data_mut(&mut self) -> (&mut [u8], &mut usize) {
-        (&mut self.data[self.in_use..], &mut self.in_use)
+impl Deref for RWAAppendGuard<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: no writer while appending, and only this guard adds data
+        unsafe { self.buffer.active_data() }
     }
 }
 
-pub struct RWABuffer {
-    rwlock: Semaphore,
-    alock: Mutex<()>,
-    num_permits: u32,
-    data: UnsafeCell<RWAInner>,
+/// Writes nothing once the buffer is full
+impl AsyncWrite for RWAAppendGuard<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(self.get_mut().append(buf)))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
 }
 
-impl RWABuffer {
-    fn new(buffer: Box<[u8]>, in_use: usize) -> Self {
-        let max_permits = Semaphore::MAX_PERMITS as u32;
-        RWABuffer {
-            rwlock: Semaphore::new(max_permits as usize),
-            alock: Mutex::new(()),
-            num_permits: max_permits,
-            data: UnsafeCell::new(RWAInner {
-                data: buffer,
-                in_use,
-            }),
-        }
+impl Drop for RWAAppendGuard<'_> {
+    fn drop(&mut self) {
+        self.buffer.release(Access::Append);
     }
+}
 
-    async fn read(&self) -> error::Result<RWAReadGuard<'_>> {
-        let permit = self.rwlock.acquire().await?;
+#[cfg(all(test, not(feature = "loom")))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
 
-        // SAFETY: We acquired the permit to access this data only if it is immutable
-        let data = unsafe { &*self.data.get() };
+    use tokio::{io::AsyncWriteExt, time::timeout};
 
-        Ok(RWAReadGuard {
-            permit,
-            data: data.active_data(),
-        })
+    use super::*;
+
+    async fn blocked<T>(acquire: impl std::future::Future<Output = T>) -> bool {
+        timeout(Duration::from_millis(20), acquire).await.is_err()
     }
 
-    async fn write(&self) -> error::Result<RWAWriteGuard<'_>> {
-        let permit = self.rwlock.acquire_many(self.num_permits).await?;
-        // SAFETY: We acquired the permit to access this data only if it is immutable
-        let data = unsafe { &mut *self.data.get() };
+    #[tokio::test]
+    async fn readers_share_with_an_appender() {
+        let buffer = RWABuffer::new(b"ab\0\0\0".to_vec().into_boxed_slice(), 2);
+        let first = buffer.read().await;
+        let second = buffer.read().await;
+        let mut appender = buffer.append().await;
+        assert!(blocked(buffer.append()).await);
+        assert!(blocked(buffer.write()).await);
 
-        Ok(RWAWriteGuard {
-            permit,
-            data: data.active_data_mut(),
-        })
+        assert_eq!(appender.append(b"c"), 1);
+        assert_eq!(&*first, b"abc");
+        assert_eq!(&*second, b"abc");
+        appender.write_all(b"de").await.unwrap();
+        assert_eq!(&*first, b"abcde");
+        assert_eq!(appender.remaining(), 0);
+        assert!(appender.write_all(b"f").await.is_err());
+        drop((first, second, appender));
+
+        let mut writer = buffer.write().await;
+        assert!(blocked(buffer.read()).await);
+        assert!(blocked(buffer.append()).await);
+        writer[0] = b'z';
+        drop(writer);
+        assert_eq!(&*buffer.read().await, b"zbcde");
+        assert_eq!(&buffer.into_inner().0[..], b"zbcde");
     }
 
-    /// Get append guard
-    async fn append(&self) -> error::Result<RWAAppendGuard> {
-        let lock = self.alock.lock().await;
-        let data = unsafe { &mut *self.data.get() };
-        let (data, in_use_val) = data.reserve_data_mut();
+    #[tokio::test]
+    async fn wakes_waiting_writers() {
+        let buffer = Arc::new(RWABuffer::new(vec![0; 1].into_boxed_slice(), 1));
+        let reader = buffer.read().await;
+        let writer = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.write().await[0] = 1 }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(reader[0], 0);
+        drop(reader);
+        writer.await.unwrap();
+        assert_eq!(buffer.read().await[0], 1);
+    }
 
-        Ok(RWAAppendGuard {
-            lock,
-            data,
-            in_use_val,
-            data_appended: 0,
-        })
+    #[tokio::test]
+    async fn writers_go_ahead_of_later_readers() {
+        let buffer = Arc::new(RWABuffer::new(vec![0; 1].into_boxed_slice(), 1));
+        let reader = buffer.read().await;
+        let writer = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.write().await[0] = 1 }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Readers and appenders queue behind the waiting writer
+        assert!(blocked(buffer.read()).await);
+        assert!(blocked(buffer.append()).await);
+        let late_reader = tokio::spawn({
+            let buffer = buffer.clone();
+            async move { buffer.read().await[0] }
+        });
+        drop(reader);
+        writer.await.unwrap();
+        assert_eq!(late_reader.await.unwrap(), 1);
+
+        // A writer that gives up waiting lets the readers behind it go
+        let reader = buffer.read().await;
+        assert!(blocked(buffer.write()).await);
+        assert_eq!(buffer.read().await[0], 1);
+        drop(reader);
+    }
+
+    #[tokio::test]
+    async fn wakes_writers_behind_one_that_gave_up() {
+        let buffer = Arc::new(RWABuffer::new(vec![0; 1].into_boxed_slice(), 1));
+        let reader = buffer.read().await;
+        assert!(blocked(buffer.write()).await);
+        let writers: Vec<_> = (1..=2)
+            .map(|i| {
+                let buffer = buffer.clone();
+                tokio::spawn(async move { buffer.write().await[0] += i })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(reader);
+        for writer in writers {
+            timeout(Duration::from_secs(1), writer)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(buffer.read().await[0], 3);
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod loom_tests {
+    use loom::{future::block_on, model::Builder, sync::Arc, thread};
+
+    use super::*;
+
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    #[test]
+    fn readers_see_whole_appends() {
+        model(|| {
+            let buffer = Arc::new(RWABuffer::with_capacity(4));
+            let appender = thread::spawn({
+                let buffer = buffer.clone();
+                move || {
+                    let mut guard = block_on(buffer.append());
+                    guard.append(&[1, 2]);
+                    guard.append(&[3]);
+                }
+            });
+            let guard = block_on(buffer.read());
+            let seen = guard.to_vec();
+            assert!([&[][..], &[1, 2], &[1, 2, 3]].contains(&&seen[..]));
+            // Appends after acquiring are visible, and never taken back
+            assert!(guard.starts_with(&seen));
+            drop(guard);
+            appender.join().unwrap();
+            assert_eq!(&*block_on(buffer.read()), &[1, 2, 3]);
+        });
+    }
+
+    #[test]
+    fn writers_exclude_each_other() {
+        model(|| {
+            let buffer = Arc::new(RWABuffer::new(vec![0; 1].into_boxed_slice(), 1));
+            let increment = |buffer: &RWABuffer| {
+                let mut guard = block_on(buffer.write());
+                let value = guard[0];
+                thread::yield_now();
+                guard[0] = value + 1;
+            };
+            let writer = thread::spawn({
+                let buffer = buffer.clone();
+                move || increment(&buffer)
+            });
+            increment(&buffer);
+            writer.join().unwrap();
+            assert_eq!(block_on(buffer.read())[0], 2);
+        });
+    }
+
+    #[test]
+    fn writers_exclude_appenders() {
+        model(|| {
+            let buffer = Arc::new(RWABuffer::new(vec![0; 2].into_boxed_slice(), 1));
+            let writer = thread::spawn({
+                let buffer = buffer.clone();
+                move || block_on(buffer.write())[0] = 1
+            });
+            {
+                let mut guard = block_on(buffer.append());
+                let value = guard[0];
+                thread::yield_now();
+                assert_eq!(guard[0], value);
+                guard.append(&[value]);
+            }
+            writer.join().unwrap();
+            let guard = block_on(buffer.read());
+            assert_eq!(guard[0], 1);
+            assert_eq!(guard.len(), 2);
+        });
     }
 }
@@ -0,0 +1,377 @@
+use std::collections::BTreeMap;
+
+/// How the values of a column chunk are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Plain,
+    /// Offsets from the smallest value, in as few bits as the largest needs
+    BitPack,
+    /// The first value, then differences from the previous value bit packed
+    Delta,
+    /// Each value once per run of it, with the run's length
+    Rle,
+    /// Indexes into the sorted distinct values
+    Dictionary,
+}
+
+impl Encoding {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Encoding::Plain => 0,
+            Encoding::BitPack => 1,
+            Encoding::Delta => 2,
+            Encoding::Rle => 3,
+            Encoding::Dictionary => 4,
+        }
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> error::Result<Self> {
+        Ok(match byte {
+            0 => Encoding::Plain,
+            1 => Encoding::BitPack,
+            2 => Encoding::Delta,
+            3 => Encoding::Rle,
+            4 => Encoding::Dictionary,
+            _ => return Err(corrupt("unknown encoding")),
+        })
+    }
+}
+
+pub(crate) fn corrupt(message: &str) -> error::Error {
+    error::CustomErrors::InvalidState(format!("corrupt segment: {}", message)).into()
+}
+
+/// Reads a chunk's bytes front to back
+pub(crate) struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    pub(crate) fn new(bytes: &'b [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> error::Result<&'b [u8]> {
+        if self.bytes.len() < len {
+            return Err(corrupt("ends early"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> error::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> error::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> error::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn bit_width(max: u64) -> u8 {
+    (64 - max.leading_zeros()) as u8
+}
+
+/// Append the low `width` bits of each value
+fn pack_bits(values: impl IntoIterator<Item = u64>, width: u8, out: &mut Vec<u8>) {
+    let (mut pending, mut bits) = (0u128, 0);
+    for value in values {
+        pending |= (value as u128) << bits;
+        bits += width as u32;
+        while bits >= 8 {
+            out.push(pending as u8);
+            pending >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits > 0 {
+        out.push(pending as u8);
+    }
+}
+
+fn unpack_bits(reader: &mut Reader, width: u8, count: usize) -> error::Result<Vec<u64>> {
+    let width = width as usize;
+    if width > 64 {
+        return Err(corrupt("values wider than 64 bits"));
+    }
+    let bytes = reader.take((count * width).div_ceil(8))?;
+    let mask = if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    };
+    Ok((0..count)
+        .map(|i| {
+            let (start, shift) = (i * width / 8, i * width % 8);
+            let mut window = [0; 16];
+            let end = bytes.len().min(start + 16);
+            window[..end - start].copy_from_slice(&bytes[start..end]);
+            (u128::from_le_bytes(window) >> shift) as u64 & mask
+        })
+        .collect())
+}
+
+/// Frame of reference: the smallest value, then every value's offset from it bit packed
+fn write_offsets(values: &[i64], out: &mut Vec<u8>) {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let offsets = values.iter().map(|v| v.wrapping_sub(min) as u64);
+    let width = bit_width(offsets.clone().max().unwrap_or(0));
+    out.extend(min.to_le_bytes());
+    out.push(width);
+    pack_bits(offsets, width, out);
+}
+
+fn read_offsets(reader: &mut Reader, count: usize) -> error::Result<Vec<i64>> {
+    let min = reader.u64()? as i64;
+    let width = reader.u8()?;
+    let offsets = unpack_bits(reader, width, count)?;
+    Ok(offsets
+        .into_iter()
+        .map(|o| min.wrapping_add(o as i64))
+        .collect())
+}
+
+fn runs<T: PartialEq + Clone>(values: &[T]) -> (Vec<T>, Vec<i64>) {
+    let (mut run_values, mut lengths) = (Vec::new(), Vec::<i64>::new());
+    for value in values {
+        match (run_values.last(), lengths.last_mut()) {
+            (Some(last), Some(length)) if last == value => *length += 1,
+            _ => {
+                run_values.push(value.clone());
+                lengths.push(1);
+            }
+        }
+    }
+    (run_values, lengths)
+}
+
+fn expand<T: Clone>(run_values: Vec<T>, lengths: Vec<i64>, count: usize) -> error::Result<Vec<T>> {
+    let mut values = Vec::with_capacity(count);
+    for (value, length) in run_values.into_iter().zip(lengths) {
+        if length < 0 || values.len() + length as usize > count {
+            return Err(corrupt("runs past the end of the chunk"));
+        }
+        values.extend(std::iter::repeat_n(value, length as usize));
+    }
+    Ok(values)
+}
+
+/// Sorted distinct values and the index of each value among them
+fn dictionary<T: Ord + Clone>(values: &[T]) -> (Vec<T>, Vec<u64>) {
+    let mut indexes: BTreeMap<&T, u64> = values.iter().map(|v| (v, 0)).collect();
+    for (i, index) in indexes.values_mut().enumerate() {
+        *index = i as u64;
+    }
+    let positions = values.iter().map(|v| indexes[v]).collect();
+    (indexes.into_keys().cloned().collect(), positions)
+}
+
+fn write_indexes(indexes: &[u64], distinct: usize, out: &mut Vec<u8>) {
+    let width = bit_width(distinct.saturating_sub(1) as u64);
+    out.push(width);
+    pack_bits(indexes.iter().copied(), width, out);
+}
+
+fn read_indexes(reader: &mut Reader, distinct: usize, count: usize) -> error::Result<Vec<usize>> {
+    let width = reader.u8()?;
+    let indexes = unpack_bits(reader, width, count)?;
+    indexes
+        .into_iter()
+        .map(|i| {
+            let i = i as usize;
+            (i < distinct)
+                .then_some(i)
+                .ok_or_else(|| corrupt("index past the dictionary"))
+        })
+        .collect()
+}
+
+/// The smallest encoding of integers
+pub(crate) fn encode_ints(values: &[i64]) -> (Encoding, Vec<u8>) {
+    let mut encoded = Vec::new();
+
+    let mut plain = Vec::with_capacity(values.len() * 8);
+    values.iter().for_each(|v| plain.extend(v.to_le_bytes()));
+    encoded.push((Encoding::Plain, plain));
+
+    let mut packed = Vec::new();
+    write_offsets(values, &mut packed);
+    encoded.push((Encoding::BitPack, packed));
+
+    let deltas: Vec<_> = values.windows(2).map(|w| w[1].wrapping_sub(w[0])).collect();
+    let mut delta = values.first().copied().unwrap_or(0).to_le_bytes().to_vec();
+    write_offsets(&deltas, &mut delta);
+    encoded.push((Encoding::Delta, delta));
+
+    let (run_values, lengths) = runs(values);
+    let mut rle = (run_values.len() as u32).to_le_bytes().to_vec();
+    write_offsets(&run_values, &mut rle);
+    write_offsets(&lengths, &mut rle);
+    encoded.push((Encoding::Rle, rle));
+
+    let (distinct, indexes) = dictionary(values);
+    let mut dict = (distinct.len() as u32).to_le_bytes().to_vec();
+    write_offsets(&distinct, &mut dict);
+    write_indexes(&indexes, distinct.len(), &mut dict);
+    encoded.push((Encoding::Dictionary, dict));
+
+    encoded
+        .into_iter()
+        .min_by_key(|(_, bytes)| bytes.len())
+        .unwrap()
+}
+
+pub(crate) fn decode_ints(
+    encoding: Encoding,
+    reader: &mut Reader,
+    count: usize,
+) -> error::Result<Vec<i64>> {
+    match encoding {
+        Encoding::Plain => (0..count).map(|_| Ok(reader.u64()? as i64)).collect(),
+        Encoding::BitPack => read_offsets(reader, count),
+        Encoding::Delta => {
+            let first = reader.u64()? as i64;
+            let deltas = read_offsets(reader, count.saturating_sub(1))?;
+            let mut values = Vec::with_capacity(count);
+            values.extend((count > 0).then_some(first));
+            for delta in deltas {
+                values.push(values[values.len() - 1].wrapping_add(delta));
+            }
+            Ok(values)
+        }
+        Encoding::Rle => {
+            let runs = reader.u32()? as usize;
+            let run_values = read_offsets(reader, runs)?;
+            let lengths = read_offsets(reader, runs)?;
+            expand(run_values, lengths, count)
+        }
+        Encoding::Dictionary => {
+            let distinct = reader.u32()? as usize;
+            let dict = read_offsets(reader, distinct)?;
+            let indexes = read_indexes(reader, distinct, count)?;
+            Ok(indexes.into_iter().map(|i| dict[i]).collect())
+        }
+    }
+}
+
+fn write_plain_bytes(values: &[Vec<u8>], out: &mut Vec<u8>) {
+    let lengths: Vec<_> = values.iter().map(|v| v.len() as i64).collect();
+    write_offsets(&lengths, out);
+    values.iter().for_each(|v| out.extend(v));
+}
+
+fn read_plain_bytes(reader: &mut Reader, count: usize) -> error::Result<Vec<Vec<u8>>> {
+    let lengths = read_offsets(reader, count)?;
+    lengths
+        .into_iter()
+        .map(|len| {
+            let len = usize::try_from(len).map_err(|_| corrupt("negative length"))?;
+            Ok(reader.take(len)?.to_vec())
+        })
+        .collect()
+}
+
+/// The smallest encoding of byte strings. Bit packing and deltas only apply to integers.
+pub(crate) fn encode_bytes(values: &[Vec<u8>]) -> (Encoding, Vec<u8>) {
+    let mut plain = Vec::new();
+    write_plain_bytes(values, &mut plain);
+
+    let (run_values, lengths) = runs(values);
+    let mut rle = (run_values.len() as u32).to_le_bytes().to_vec();
+    write_plain_bytes(&run_values, &mut rle);
+    write_offsets(&lengths, &mut rle);
+
+    let (distinct, indexes) = dictionary(values);
+    let mut dict = (distinct.len() as u32).to_le_bytes().to_vec();
+    write_plain_bytes(&distinct, &mut dict);
+    write_indexes(&indexes, distinct.len(), &mut dict);
+
+    [
+        (Encoding::Plain, plain),
+        (Encoding::Rle, rle),
+        (Encoding::Dictionary, dict),
+    ]
+    .into_iter()
+    .min_by_key(|(_, bytes)| bytes.len())
+    .unwrap()
+}
+
+pub(crate) fn decode_bytes(
+    encoding: Encoding,
+    reader: &mut Reader,
+    count: usize,
+) -> error::Result<Vec<Vec<u8>>> {
+    match encoding {
+        Encoding::Plain => read_plain_bytes(reader, count),
+        Encoding::Rle => {
+            let runs = reader.u32()? as usize;
+            let run_values = read_plain_bytes(reader, runs)?;
+            let lengths = read_offsets(reader, runs)?;
+            expand(run_values, lengths, count)
+        }
+        Encoding::Dictionary => {
+            let distinct = reader.u32()? as usize;
+            let dict = read_plain_bytes(reader, distinct)?;
+            let indexes = read_indexes(reader, distinct, count)?;
+            Ok(indexes.into_iter().map(|i| dict[i].clone()).collect())
+        }
+        Encoding::BitPack | Encoding::Delta => Err(corrupt("byte strings can't be bit packed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(values: &[i64]) -> Encoding {
+        let (encoding, bytes) = encode_ints(values);
+        let decoded = decode_ints(encoding, &mut Reader::new(&bytes), values.len()).unwrap();
+        assert_eq!(decoded, values, "{:?}", encoding);
+        encoding
+    }
+
+    #[test]
+    fn picks_the_smallest_int_encoding() {
+        assert_eq!(
+            round_trip(&(0..1000).map(|i| i % 50).collect::<Vec<_>>()),
+            Encoding::BitPack
+        );
+        let timestamps: Vec<_> = (0..1000).map(|i| 1_700_000_000_000 + i * 1000).collect();
+        assert_eq!(round_trip(&timestamps), Encoding::Delta);
+        let runs: Vec<_> = (0..1000).map(|i| (i / 250) * 1_000_000_007).collect();
+        assert_eq!(round_trip(&runs), Encoding::Rle);
+        let spread: Vec<_> = (0..1000).map(|i| [i64::MIN, 0, i64::MAX][i % 3]).collect();
+        assert_eq!(round_trip(&spread), Encoding::Dictionary);
+        round_trip(&[]);
+        round_trip(&[i64::MIN, i64::MAX, -1, 0, 1]);
+        round_trip(&[7]);
+    }
+
+    #[test]
+    fn encodes_byte_strings() {
+        let round_trip = |values: &[Vec<u8>]| {
+            let (encoding, bytes) = encode_bytes(values);
+            let decoded = decode_bytes(encoding, &mut Reader::new(&bytes), values.len()).unwrap();
+            assert_eq!(decoded, values);
+            encoding
+        };
+        let names: Vec<_> = (0..300)
+            .map(|i| format!("name {}", i % 7).into_bytes())
+            .collect();
+        assert_eq!(round_trip(&names), Encoding::Dictionary);
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(round_trip(&sorted), Encoding::Rle);
+        let unique: Vec<_> = (0..300).map(|i| format!("{}", i).into_bytes()).collect();
+        assert_eq!(round_trip(&unique), Encoding::Plain);
+        round_trip(&[]);
+
+        let (_, bytes) = encode_bytes(&names);
+        assert!(decode_bytes(Encoding::Plain, &mut Reader::new(&bytes[..10]), 300).is_err());
+    }
+}
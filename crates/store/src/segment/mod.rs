@@ -0,0 +1,731 @@
+use std::{
+    io::SeekFrom,
+    ops::{Bound, RangeBounds},
+};
+
+use chrono::{TimeZone, Utc};
+use intake::{Column, ColumnTypes, DataValue};
+use query_parser::Interval;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{page::PageManager, source::SourceTag};
+use encoding::{corrupt, Reader};
+
+pub use encoding::Encoding;
+
+mod encoding;
+
+/// Rows in each chunk of a column
+pub const CHUNK_ROWS: usize = 4096;
+
+const MAGIC: &[u8; 8] = b"SEGMENT\x02";
+/// Directory offset and length, rows, columns and magic
+const FOOTER_SIZE: usize = 32;
+
+/// Chunks whose values are integers, the rest are byte strings
+const INTS: u8 = 0;
+const BYTES: u8 = 1;
+
+/// Code of a column type in the directory, with the bits of a vector
+fn type_code(data_type: ColumnTypes) -> (u8, u32) {
+    match data_type {
+        ColumnTypes::I64 => (0, 0),
+        ColumnTypes::F64 => (1, 0),
+        ColumnTypes::String => (2, 0),
+        ColumnTypes::Bool => (3, 0),
+        ColumnTypes::DateTime => (4, 0),
+        ColumnTypes::UUID => (5, 0),
+        ColumnTypes::Bytes => (6, 0),
+        ColumnTypes::BinaryVector(bits) => (7, bits),
+        ColumnTypes::Json => (8, 0),
+        ColumnTypes::Interval => (9, 0),
+    }
+}
+
+fn from_type_code(code: u8, bits: u32) -> error::Result<ColumnTypes> {
+    Ok(match code {
+        0 => ColumnTypes::I64,
+        1 => ColumnTypes::F64,
+        2 => ColumnTypes::String,
+        3 => ColumnTypes::Bool,
+        4 => ColumnTypes::DateTime,
+        5 => ColumnTypes::UUID,
+        6 => ColumnTypes::Bytes,
+        7 => ColumnTypes::BinaryVector(bits),
+        8 => ColumnTypes::Json,
+        9 => ColumnTypes::Interval,
+        _ => return Err(corrupt("unknown column type")),
+    })
+}
+
+fn describe(columns: &[(String, ColumnTypes)]) -> String {
+    let columns: Vec<_> = columns
+        .iter()
+        .map(|(name, data_type)| format!("{} {:?}", name, data_type))
+        .collect();
+    columns.join(", ")
+}
+
+fn key(value: &DataValue) -> Vec<u8> {
+    intake::encode_key(std::slice::from_ref(value))
+}
+
+/// The least and greatest values of a chunk, in the order of `intake::encode_key`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZoneMap {
+    /// Encoded keys of the least and greatest values, `None` when every value is null
+    bounds: Option<(Vec<u8>, Vec<u8>)>,
+    pub rows: u32,
+    pub nulls: u32,
+}
+
+impl ZoneMap {
+    fn new(values: &[DataValue]) -> Self {
+        let keys = values.iter().filter(|v| **v != DataValue::Null).map(key);
+        let bounds = keys.fold(
+            None,
+            |bounds: Option<(Vec<u8>, Vec<u8>)>, key| match bounds {
+                None => Some((key.clone(), key)),
+                Some((min, max)) if key < min => Some((key, max)),
+                Some((min, max)) if key > max => Some((min, key)),
+                bounds => bounds,
+            },
+        );
+        ZoneMap {
+            bounds,
+            rows: values.len() as u32,
+            nulls: values.iter().filter(|v| **v == DataValue::Null).count() as u32,
+        }
+    }
+
+    fn bound(key: &[u8]) -> DataValue {
+        let mut values = intake::decode_key(key).unwrap_or_default();
+        values.pop().unwrap_or(DataValue::Null)
+    }
+
+    pub fn min(&self) -> Option<DataValue> {
+        self.bounds.as_ref().map(|(min, _)| ZoneMap::bound(min))
+    }
+
+    pub fn max(&self) -> Option<DataValue> {
+        self.bounds.as_ref().map(|(_, max)| ZoneMap::bound(max))
+    }
+
+    /// Whether the chunk can hold a value between `start` and `end`
+    pub fn overlaps(&self, start: Bound<&DataValue>, end: Bound<&DataValue>) -> bool {
+        let (start, end) = (start.map(key), end.map(key));
+        self.overlaps_keys(start.as_ref(), end.as_ref())
+    }
+
+    fn overlaps_keys(&self, start: Bound<&Vec<u8>>, end: Bound<&Vec<u8>>) -> bool {
+        let Some((min, max)) = &self.bounds else {
+            return false;
+        };
+        let above_start = match start {
+            Bound::Included(start) => max >= start,
+            Bound::Excluded(start) => max > start,
+            Bound::Unbounded => true,
+        };
+        let below_end = match end {
+            Bound::Included(end) => min <= end,
+            Bound::Excluded(end) => min < end,
+            Bound::Unbounded => true,
+        };
+        above_start && below_end
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend(self.rows.to_le_bytes());
+        out.extend(self.nulls.to_le_bytes());
+        let (min, max) = self.bounds.clone().unwrap_or_default();
+        for key in [min, max] {
+            out.extend((key.len() as u32).to_le_bytes());
+            out.extend(key);
+        }
+    }
+
+    fn read_from(reader: &mut Reader) -> error::Result<Self> {
+        let rows = reader.u32()?;
+        let nulls = reader.u32()?;
+        let mut key = || -> error::Result<Vec<u8>> {
+            let len = reader.u32()? as usize;
+            Ok(reader.take(len)?.to_vec())
+        };
+        let (min, max) = (key()?, key()?);
+        Ok(ZoneMap {
+            bounds: (!min.is_empty()).then_some((min, max)),
+            rows,
+            nulls,
+        })
+    }
+}
+
+/// Where a chunk of a column is in the segment and what it holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnChunk {
+    offset: u64,
+    len: u32,
+    pub encoding: Encoding,
+    pub zone_map: ZoneMap,
+}
+
+/// The integer a value is stored as, if its type has one
+fn to_int(value: &DataValue) -> Option<i64> {
+    match value {
+        DataValue::I64(i) => Some(*i),
+        DataValue::F64(f) => Some(f.to_bits() as i64),
+        DataValue::Bool(b) => Some(*b as i64),
+        DataValue::DateTime(dt) => dt.timestamp_nanos_opt(),
+        _ => None,
+    }
+}
+
+fn from_int(data_type: ColumnTypes, i: i64) -> error::Result<DataValue> {
+    Ok(match data_type {
+        ColumnTypes::I64 => DataValue::I64(i),
+        ColumnTypes::F64 => DataValue::F64(f64::from_bits(i as u64)),
+        ColumnTypes::Bool => DataValue::Bool(i != 0),
+        ColumnTypes::DateTime => DataValue::DateTime(Utc.timestamp_nanos(i)),
+        _ => return Err(corrupt("integers in a column of another type")),
+    })
+}
+
+fn to_bytes(column: &Column, value: &DataValue) -> error::Result<Vec<u8>> {
+    Ok(match (column.data_type, value) {
+        (ColumnTypes::I64, DataValue::I64(i)) => i.to_le_bytes().to_vec(),
+        (ColumnTypes::F64, DataValue::F64(f)) => f.to_le_bytes().to_vec(),
+        (ColumnTypes::Bool, DataValue::Bool(b)) => vec![*b as u8],
+        (ColumnTypes::DateTime, DataValue::DateTime(dt)) => {
+            let mut bytes = dt.timestamp().to_le_bytes().to_vec();
+            bytes.extend(dt.timestamp_subsec_nanos().to_le_bytes());
+            bytes
+        }
+        (ColumnTypes::UUID, DataValue::UUID(uuid)) => uuid.as_bytes().to_vec(),
+        (ColumnTypes::Interval, DataValue::Interval(interval)) => {
+            let mut bytes = interval.months.to_le_bytes().to_vec();
+            bytes.extend(interval.micros.to_le_bytes());
+            bytes
+        }
+        (ColumnTypes::String, DataValue::String(s)) => s.as_bytes().to_vec(),
+        (ColumnTypes::Bytes | ColumnTypes::BinaryVector(_), DataValue::Bytes(b)) => b.clone(),
+        (ColumnTypes::Json, DataValue::Json(json)) => json.to_string().into_bytes(),
+        (data_type, value) => {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "column {} holds {:?}, got {:?}",
+                column.name, data_type, value
+            ))
+            .into())
+        }
+    })
+}
+
+fn from_bytes(data_type: ColumnTypes, bytes: Vec<u8>) -> error::Result<DataValue> {
+    let fixed = |len: usize| -> error::Result<&[u8]> {
+        (bytes.len() == len)
+            .then_some(&bytes[..])
+            .ok_or_else(|| corrupt("value size"))
+    };
+    let i64_at = |b: &[u8], i: usize| i64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+    Ok(match data_type {
+        ColumnTypes::I64 => DataValue::I64(i64_at(fixed(8)?, 0)),
+        ColumnTypes::F64 => DataValue::F64(f64::from_bits(i64_at(fixed(8)?, 0) as u64)),
+        ColumnTypes::Bool => DataValue::Bool(fixed(1)?[0] != 0),
+        ColumnTypes::DateTime => {
+            let b = fixed(12)?;
+            let nanos = u32::from_le_bytes(b[8..].try_into().unwrap());
+            Utc.timestamp_opt(i64_at(b, 0), nanos)
+                .single()
+                .map(DataValue::DateTime)
+                .ok_or_else(|| corrupt("datetime out of range"))?
+        }
+        ColumnTypes::UUID => DataValue::UUID(uuid::Uuid::from_slice(fixed(16)?).unwrap()),
+        ColumnTypes::Interval => {
+            let b = fixed(12)?;
+            DataValue::Interval(Interval {
+                months: i32::from_le_bytes(b[..4].try_into().unwrap()),
+                micros: i64_at(b, 4),
+            })
+        }
+        ColumnTypes::String => {
+            DataValue::String(String::from_utf8(bytes).map_err(|_| corrupt("string"))?)
+        }
+        ColumnTypes::Bytes | ColumnTypes::BinaryVector(_) => DataValue::Bytes(bytes),
+        ColumnTypes::Json => {
+            DataValue::Json(serde_json::from_slice(&bytes).map_err(|_| corrupt("json"))?)
+        }
+    })
+}
+
+/// A null bitmap when there are nulls, then the non null values as integers when they all
+/// have one and as byte strings when they don't
+fn encode_chunk(column: &Column, values: &[DataValue]) -> error::Result<(Encoding, Vec<u8>)> {
+    let present: Vec<_> = values.iter().filter(|v| **v != DataValue::Null).collect();
+    let mut chunk = Vec::new();
+    if present.len() == values.len() {
+        chunk.push(0);
+    } else {
+        chunk.push(1);
+        let mut bitmap = vec![0u8; values.len().div_ceil(8)];
+        for (i, value) in values.iter().enumerate() {
+            if *value == DataValue::Null {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        chunk.extend(bitmap);
+    }
+
+    let bytes: Vec<_> = present
+        .iter()
+        .map(|v| to_bytes(column, v))
+        .collect::<error::Result<_>>()?;
+    let ints: Option<Vec<_>> = present.iter().map(|v| to_int(v)).collect();
+    let (kind, (encoding, encoded)) = match ints {
+        Some(ints) => (INTS, encoding::encode_ints(&ints)),
+        None => (BYTES, encoding::encode_bytes(&bytes)),
+    };
+    chunk.push(kind);
+    chunk.push(encoding.to_byte());
+    chunk.extend(encoded);
+    Ok((encoding, chunk))
+}
+
+fn decode_chunk(column: &Column, rows: usize, chunk: &[u8]) -> error::Result<Vec<DataValue>> {
+    let mut reader = Reader::new(chunk);
+    let nulls = match reader.u8()? {
+        0 => None,
+        _ => Some(reader.take(rows.div_ceil(8))?),
+    };
+    let is_null = |i: usize| nulls.is_some_and(|bitmap| bitmap[i / 8] & 1 << (i % 8) != 0);
+    let present = (0..rows).filter(|i| !is_null(*i)).count();
+
+    let kind = reader.u8()?;
+    let encoding = Encoding::from_byte(reader.u8()?)?;
+    let mut values: Box<dyn Iterator<Item = error::Result<DataValue>>> = match kind {
+        INTS => {
+            let ints = encoding::decode_ints(encoding, &mut reader, present)?;
+            Box::new(ints.into_iter().map(|i| from_int(column.data_type, i)))
+        }
+        BYTES => {
+            let bytes = encoding::decode_bytes(encoding, &mut reader, present)?;
+            Box::new(bytes.into_iter().map(|b| from_bytes(column.data_type, b)))
+        }
+        _ => return Err(corrupt("unknown chunk kind")),
+    };
+    (0..rows)
+        .map(|i| match is_null(i) {
+            true => Ok(DataValue::Null),
+            false => values
+                .next()
+                .unwrap_or_else(|| Err(corrupt("missing values"))),
+        })
+        .collect()
+}
+
+/// Rows stored column by column in a source of their own. Every column is split into chunks
+/// of `CHUNK_ROWS` rows, each in the smallest of the encodings that fit it and with a zone
+/// map to skip it by. The source is sealed once written, so its pages never change after.
+pub struct Segment<'a> {
+    page_store: &'a PageManager,
+    tag: SourceTag,
+    columns: Vec<Column>,
+    rows: u64,
+    /// Chunks of each column
+    chunks: Vec<Vec<ColumnChunk>>,
+}
+
+impl<'a> Segment<'a> {
+    /// Write rows given in column order to a source without pages
+    pub async fn write(
+        page_store: &'a PageManager,
+        tag: SourceTag,
+        columns: Vec<Column>,
+        rows: &[Vec<DataValue>],
+    ) -> error::Result<Self> {
+        if !page_store.list_pages(tag).is_empty() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "source {} already has pages, segments are written once",
+                tag
+            ))
+            .into());
+        }
+        if let Some(row) = rows.iter().find(|row| row.len() != columns.len()) {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "rows have {} columns, got {} values",
+                columns.len(),
+                row.len()
+            ))
+            .into());
+        }
+
+        let mut source = page_store.get_source(tag);
+        let mut offset = 0;
+        let mut chunks = Vec::new();
+        for (i, column) in columns.iter().enumerate() {
+            let mut column_chunks = Vec::new();
+            for group in rows.chunks(CHUNK_ROWS) {
+                let values: Vec<_> = group.iter().map(|row| row[i].clone()).collect();
+                let (encoding, bytes) = encode_chunk(column, &values)?;
+                source.write_all(&bytes).await?;
+                column_chunks.push(ColumnChunk {
+                    offset,
+                    len: bytes.len() as u32,
+                    encoding,
+                    zone_map: ZoneMap::new(&values),
+                });
+                offset += bytes.len() as u64;
+            }
+            chunks.push(column_chunks);
+        }
+
+        // The columns' names and types, then their chunks
+        let mut directory = Vec::new();
+        for column in &columns {
+            let (code, bits) = type_code(column.data_type);
+            directory.extend((column.name.len() as u32).to_le_bytes());
+            directory.extend(column.name.as_bytes());
+            directory.push(code);
+            directory.extend(bits.to_le_bytes());
+        }
+        for column_chunks in &chunks {
+            directory.extend((column_chunks.len() as u32).to_le_bytes());
+            for chunk in column_chunks {
+                directory.extend(chunk.offset.to_le_bytes());
+                directory.extend(chunk.len.to_le_bytes());
+                directory.push(chunk.encoding.to_byte());
+                chunk.zone_map.write_to(&mut directory);
+            }
+        }
+        let mut footer = offset.to_le_bytes().to_vec();
+        footer.extend((directory.len() as u32).to_le_bytes());
+        footer.extend((rows.len() as u64).to_le_bytes());
+        footer.extend((columns.len() as u32).to_le_bytes());
+        footer.extend(MAGIC);
+        source.write_all(&directory).await?;
+        source.write_all(&footer).await?;
+        source.flush().await?;
+        page_store.seal_source(tag);
+        page_store.commit().await?;
+
+        Ok(Segment {
+            page_store,
+            tag,
+            columns,
+            rows: rows.len() as u64,
+            chunks,
+        })
+    }
+
+    /// The segment in a source, with the columns it was written with
+    pub async fn open(
+        page_store: &'a PageManager,
+        tag: SourceTag,
+        columns: Vec<Column>,
+    ) -> error::Result<Self> {
+        if !page_store.is_sealed(tag) {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "source {} isn't a sealed segment",
+                tag
+            ))
+            .into());
+        }
+        let mut source = page_store.get_source(tag);
+        if source.size().await? < FOOTER_SIZE as u64 {
+            return Err(corrupt("no footer"));
+        }
+        source.seek(SeekFrom::End(-(FOOTER_SIZE as i64))).await?;
+        let mut footer = [0; FOOTER_SIZE];
+        source.read_exact(&mut footer).await?;
+        let mut reader = Reader::new(&footer);
+        let directory_offset = reader.u64()?;
+        let directory_len = reader.u32()? as usize;
+        let rows = reader.u64()?;
+        let column_count = reader.u32()? as usize;
+        if reader.take(8)? != MAGIC {
+            return Err(corrupt("no magic"));
+        }
+
+        let mut directory = vec![0; directory_len];
+        source.seek(SeekFrom::Start(directory_offset)).await?;
+        source.read_exact(&mut directory).await?;
+        let mut reader = Reader::new(&directory);
+        let mut stored = Vec::with_capacity(column_count);
+        for _ in 0..column_count {
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec())
+                .map_err(|_| corrupt("column name"))?;
+            let data_type = from_type_code(reader.u8()?, reader.u32()?)?;
+            stored.push((name, data_type));
+        }
+        let given: Vec<_> = columns
+            .iter()
+            .map(|column| (column.name.clone(), column.data_type))
+            .collect();
+        if stored != given {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "segment has columns {}, got {}",
+                describe(&stored),
+                describe(&given)
+            ))
+            .into());
+        }
+        let mut chunks = Vec::with_capacity(column_count);
+        for _ in 0..column_count {
+            let count = reader.u32()?;
+            let column_chunks = (0..count)
+                .map(|_| {
+                    Ok(ColumnChunk {
+                        offset: reader.u64()?,
+                        len: reader.u32()?,
+                        encoding: Encoding::from_byte(reader.u8()?)?,
+                        zone_map: ZoneMap::read_from(&mut reader)?,
+                    })
+                })
+                .collect::<error::Result<_>>()?;
+            chunks.push(column_chunks);
+        }
+        Ok(Segment {
+            page_store,
+            tag,
+            columns,
+            rows,
+            chunks,
+        })
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    fn column_index(&self, name: &str) -> error::Result<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.name == name)
+            .ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!("segment has no column {}", name))
+                    .into()
+            })
+    }
+
+    pub fn chunks(&self, column: &str) -> error::Result<&[ColumnChunk]> {
+        Ok(&self.chunks[self.column_index(column)?])
+    }
+
+    async fn read_chunk(
+        &self,
+        column: usize,
+        chunk: &ColumnChunk,
+    ) -> error::Result<Vec<DataValue>> {
+        let mut source = self.page_store.get_source(self.tag);
+        source.seek(SeekFrom::Start(chunk.offset)).await?;
+        let mut bytes = vec![0; chunk.len as usize];
+        source.read_exact(&mut bytes).await?;
+        decode_chunk(&self.columns[column], chunk.zone_map.rows as usize, &bytes)
+    }
+
+    /// Every value of a column, in row order
+    pub async fn column(&self, name: &str) -> error::Result<Vec<DataValue>> {
+        let column = self.column_index(name)?;
+        let mut values = Vec::with_capacity(self.rows as usize);
+        for chunk in &self.chunks[column] {
+            values.extend(self.read_chunk(column, chunk).await?);
+        }
+        Ok(values)
+    }
+
+    /// Rows whose value of a column is between `start` and `end`, and the value. Only reads
+    /// the chunks whose zone maps overlap the range.
+    pub async fn scan(
+        &self,
+        name: &str,
+        start: Bound<&DataValue>,
+        end: Bound<&DataValue>,
+    ) -> error::Result<Vec<(u64, DataValue)>> {
+        let column = self.column_index(name)?;
+        let (start, end) = (start.map(key), end.map(key));
+        let range = (start.as_ref(), end.as_ref());
+        let mut found = Vec::new();
+        let mut first_row = 0;
+        for chunk in &self.chunks[column] {
+            if chunk.zone_map.overlaps_keys(range.0, range.1) {
+                let values = self.read_chunk(column, chunk).await?;
+                for (i, value) in values.into_iter().enumerate() {
+                    if value != DataValue::Null
+                        && RangeBounds::<Vec<u8>>::contains(&range, &key(&value))
+                    {
+                        found.push((first_row + i as u64, value));
+                    }
+                }
+            }
+            first_row += chunk.zone_map.rows as u64;
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{page::PAGE_SIZE, Wal, WalOptions};
+
+    async fn manager(dir: &tempfile::TempDir) -> PageManager {
+        PageManager::open(dir.path(), 1024 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id", ColumnTypes::I64),
+            Column::new("at", ColumnTypes::DateTime),
+            Column::new("name", ColumnTypes::String),
+            Column::new("score", ColumnTypes::F64),
+            Column::new("uuid", ColumnTypes::UUID),
+            Column::new("doc", ColumnTypes::Json),
+        ]
+    }
+
+    fn rows(n: i64) -> Vec<Vec<DataValue>> {
+        (0..n)
+            .map(|i| {
+                vec![
+                    DataValue::I64(i),
+                    DataValue::DateTime(Utc.timestamp_opt(1_700_000_000 + i * 60, 0).unwrap()),
+                    DataValue::String(format!("name {}", i % 5)),
+                    if i % 4 == 0 {
+                        DataValue::Null
+                    } else {
+                        DataValue::F64(i as f64 / 8.0)
+                    },
+                    DataValue::UUID(uuid::Uuid::from_u128(i as u128 * 7919)),
+                    DataValue::Json(serde_json::json!({ "i": i / 1000 })),
+                ]
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = rows(10_000);
+        {
+            let manager = manager(&dir).await;
+            let segment = Segment::write(&manager, 1, columns(), &rows).await.unwrap();
+            assert_eq!(segment.rows(), 10_000);
+            assert!(Segment::write(&manager, 1, columns(), &rows).await.is_err());
+            manager.flush().await.unwrap();
+        }
+
+        let manager = manager(&dir).await;
+        assert!(Segment::open(&manager, 1, columns()[1..].to_vec())
+            .await
+            .is_err());
+        let mut retyped = columns();
+        retyped[3].data_type = ColumnTypes::I64;
+        let Err(e) = Segment::open(&manager, 1, retyped).await else {
+            panic!("opened a segment with the wrong column types");
+        };
+        assert!(e.to_string().contains("score F64"), "{}", e);
+        let mut renamed = columns();
+        renamed[2].name = "title".to_string();
+        assert!(Segment::open(&manager, 1, renamed).await.is_err());
+        assert!(Segment::open(&manager, 2, columns()).await.is_err());
+        // Its pages can't change, nor can it grow
+        let mut source = manager.get_source(1);
+        assert!(source.write_all(b"more").await.is_err());
+        source.seek(SeekFrom::End(0)).await.unwrap();
+        assert!(source.write_all(b"more").await.is_err());
+        // Only sealed sources are segments
+        manager.get_source(3).write_all(&[0; 64]).await.unwrap();
+        assert!(Segment::open(&manager, 3, columns()).await.is_err());
+        let segment = Segment::open(&manager, 1, columns()).await.unwrap();
+        assert_eq!(segment.rows(), 10_000);
+        for (i, column) in columns().iter().enumerate() {
+            let expected: Vec<_> = rows.iter().map(|row| row[i].clone()).collect();
+            assert_eq!(segment.column(&column.name).await.unwrap(), expected);
+        }
+        assert!(segment.column("missing").await.is_err());
+
+        let encodings = |name: &str| -> Vec<Encoding> {
+            let chunks = segment.chunks(name).unwrap();
+            chunks.iter().map(|chunk| chunk.encoding).collect()
+        };
+        assert_eq!(encodings("id"), [Encoding::Delta; 3]);
+        assert_eq!(encodings("at"), [Encoding::Delta; 3]);
+        assert_eq!(encodings("name"), [Encoding::Dictionary; 3]);
+        assert_eq!(encodings("doc"), [Encoding::Rle; 3]);
+        assert_eq!(encodings("uuid"), [Encoding::Plain; 3]);
+
+        let zone_map = &segment.chunks("score").unwrap()[1].zone_map;
+        assert_eq!((zone_map.rows, zone_map.nulls), (4096, 1024));
+        assert_eq!(zone_map.min(), Some(DataValue::F64(4097.0 / 8.0)));
+        assert_eq!(zone_map.max(), Some(DataValue::F64(8191.0 / 8.0)));
+    }
+
+    #[tokio::test]
+    async fn skips_chunks_by_zone_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let rows = rows(10_000);
+        let segment = Segment::write(&manager, 1, columns(), &rows).await.unwrap();
+
+        let (start, end) = (DataValue::I64(5000), DataValue::I64(5003));
+        let chunks = segment.chunks("id").unwrap();
+        let overlapping: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .zone_map
+                    .overlaps(Bound::Included(&start), Bound::Excluded(&end))
+            })
+            .collect();
+        assert_eq!(overlapping, [false, true, false]);
+        let found = segment
+            .scan("id", Bound::Included(&start), Bound::Excluded(&end))
+            .await
+            .unwrap();
+        let expected: Vec<_> = (5000..5003)
+            .map(|i| (i as u64, DataValue::I64(i)))
+            .collect();
+        assert_eq!(found, expected);
+
+        let high = DataValue::F64(1249.0);
+        let found = segment
+            .scan("score", Bound::Excluded(&high), Bound::Unbounded)
+            .await
+            .unwrap();
+        // Every fourth score is null
+        assert_eq!(found.len(), 6);
+        assert!(found.iter().all(|(row, _)| *row > 9992));
+        let name = DataValue::String("name 9".into());
+        let none = segment.scan("name", Bound::Included(&name), Bound::Unbounded);
+        assert!(none.await.unwrap().is_empty());
+        assert!(!chunks[0]
+            .zone_map
+            .overlaps(Bound::Included(&name), Bound::Unbounded));
+    }
+
+    #[tokio::test]
+    async fn redoes_logged_segments_after_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || async {
+            let wal = Wal::open(dir.path().join("wal"), WalOptions::default())
+                .await
+                .unwrap();
+            manager(&dir).await.with_wal(wal).await.unwrap()
+        };
+        let rows = rows(5000);
+        let manager = open().await;
+        Segment::write(&manager, 1, columns(), &rows).await.unwrap();
+        drop(manager);
+
+        let manager = open().await;
+        assert!(manager.is_sealed(1));
+        let segment = Segment::open(&manager, 1, columns()).await.unwrap();
+        let expected: Vec<_> = rows.iter().map(|row| row[2].clone()).collect();
+        assert_eq!(segment.column("name").await.unwrap(), expected);
+    }
+}
@@ -1,41 +1,371 @@
-use std::pin::Pin;
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
-use tokio::{io::AsyncRead, pin};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
 
-use crate::{page::Page, page_manager::PageManager};
+use crate::page::{PageId, PageManager, PAGE_DATA_SIZE};
 
 pub type SourceTag = u64;
 
+type Op<'a, T> = Pin<Box<dyn Future<Output = error::Result<T>> + Send + 'a>>;
 
-pub struct Source<'a> {
+enum State<'a> {
+    Idle,
+    Reading(Op<'a, Vec<u8>>),
+    /// Bytes written, and the page allocated for them if there was one
+    Writing(Op<'a, (usize, Option<PageId>)>),
+    /// Where to seek to once the source's size is known
+    Seeking(SeekFrom, Op<'a, u64>),
+}
+
+fn io_error(e: error::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// The data of every page tagged with a source, read and written as one stream. Pages are
+/// loaned from the page manager as the stream reaches them, and new ones are allocated when
+/// writes run past the last. Every page but the last is kept full, so a position maps
+/// straight to a page. Only one `SourceBlocks` should write to a source at a time.
+pub struct SourceBlocks<'a> {
     tag: SourceTag,
-    cur_page: &'a Page,
+    pages: Vec<PageId>,
     page_store: &'a PageManager,
+    position: u64,
+    state: State<'a>,
 }
 
-impl<'a> Source<'a> {
-    pub fn new(tag: SourceTag, page_size: usize, cur_page: &'a Page, page_store: &'a PageManager) -> Self {
-        Source {
+impl<'a> SourceBlocks<'a> {
+    pub fn new(tag: SourceTag, page_store: &'a PageManager) -> Self {
+        SourceBlocks {
             tag,
-            cur_page,
-            page_store
+            pages: page_store.list_pages(tag),
+            page_store,
+            position: 0,
+            state: State::Idle,
         }
     }
-}
 
-pub struct SourceReader<'a> {
-    source: Source<'a>,
-    offset: usize,
+    pub fn tag(&self) -> SourceTag {
+        self.tag
+    }
+
+    /// Can be used for determining the size of btree nodes to create
+    pub fn block_size(&self) -> usize {
+        PAGE_DATA_SIZE
+    }
+
+    /// Pages holding the source's data, in order
+    pub fn pages(&self) -> &[PageId] {
+        &self.pages
+    }
+
+    pub fn page_store(&self) -> &'a PageManager {
+        self.page_store
+    }
+
+    /// Bytes of data in the source
+    pub async fn size(&self) -> error::Result<u64> {
+        self.size_op().await
+    }
+
+    fn size_op(&self) -> Op<'a, u64> {
+        let page_store = self.page_store;
+        let last = self.pages.last().copied();
+        let full = (self.pages.len().saturating_sub(1) * PAGE_DATA_SIZE) as u64;
+        Box::pin(async move {
+            let Some(id) = last else {
+                return Ok(0);
+            };
+            let loan = page_store.aquire_page_loan(id).await?;
+            let size = loan.read().await?.header.size;
+            Ok(full + size)
+        })
+    }
+
+    /// The page a position is in and the offset within it
+    fn locate(&self) -> (usize, usize) {
+        let position = self.position as usize;
+        (position / PAGE_DATA_SIZE, position % PAGE_DATA_SIZE)
+    }
+
+    fn read_op(&self, max: usize) -> Op<'a, Vec<u8>> {
+        let page_store = self.page_store;
+        let (index, offset) = self.locate();
+        let id = self.pages.get(index).copied();
+        Box::pin(async move {
+            let Some(id) = id else {
+                return Ok(Vec::new());
+            };
+            let loan = page_store.aquire_page_loan(id).await?;
+            let page = loan.read().await?;
+            let data = page.active_data();
+            let end = data.len().min(offset + max);
+            Ok(data.get(offset..end).unwrap_or_default().to_vec())
+        })
+    }
+
+    fn write_op(&self, buf: &[u8]) -> Op<'a, (usize, Option<PageId>)> {
+        let page_store = self.page_store;
+        let tag = self.tag;
+        let (index, offset) = self.locate();
+        let id = self.pages.get(index).copied();
+        let data = buf[..buf.len().min(PAGE_DATA_SIZE - offset)].to_vec();
+        Box::pin(async move {
+            let (loan, allocated) = match id {
+                Some(id) => (page_store.aquire_page_loan(id).await?, None),
+                None => {
+                    let loan = page_store.new_page(tag).await?;
+                    let id = loan.id();
+                    (loan, Some(id))
+                }
+            };
+            let mut page = loan.write().await?;
+            if offset > page.header.size as usize {
+                return Err(error::CustomErrors::InvalidState(format!(
+                    "page {} has shrunk under its source",
+                    page.header.id
+                ))
+                .into());
+            }
+            let end = offset + data.len();
+            page.data[offset..end].copy_from_slice(&data);
+            page.header.size = page.header.size.max(end as u64);
+            page_store.log_write(&mut page, offset..end);
+            Ok((data.len(), allocated))
+        })
+    }
+
+    /// Finish a write left in flight, it may have already changed its page
+    fn poll_settle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Writing(op) = &mut self.state {
+            let result = ready!(op.as_mut().poll(cx));
+            self.state = State::Idle;
+            self.wrote(result.map_err(io_error)?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn wrote(&mut self, (written, allocated): (usize, Option<PageId>)) -> usize {
+        self.pages.extend(allocated);
+        self.position += written as u64;
+        written
+    }
 }
 
-impl<'a> AsyncRead for SourceReader<'a> {
+impl AsyncRead for SourceBlocks<'_> {
     fn poll_read(
-            self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
-            buf: &mut tokio::io::ReadBuf<'_>,
-        ) -> std::task::Poll<std::io::Result<()>> {
-        let pinned_page = Pin::new(&mut self.source.cur_page);
-        // AsyncRead::poll_read(pinned_page, cx, buf)
-        todo!()
-    }
-}
\ No newline at end of file
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_settle(cx))?;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if !matches!(this.state, State::Reading(_)) {
+            this.state = State::Reading(this.read_op(buf.remaining()));
+        }
+        let State::Reading(op) = &mut this.state else {
+            unreachable!()
+        };
+        let result = ready!(op.as_mut().poll(cx));
+        this.state = State::Idle;
+        let data = result.map_err(io_error)?;
+        // A read may have been started for a larger buffer
+        let data = &data[..data.len().min(buf.remaining())];
+        buf.put_slice(data);
+        this.position += data.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SourceBlocks<'_> {
+    /// Writes at most up to the end of the current page
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // A write left pending finishes with the data it was started with
+        if let State::Writing(op) = &mut this.state {
+            let result = ready!(op.as_mut().poll(cx));
+            this.state = State::Idle;
+            return Poll::Ready(Ok(this.wrote(result.map_err(io_error)?)));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut op = this.write_op(buf);
+        match op.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                this.state = State::Idle;
+                Poll::Ready(Ok(this.wrote(result.map_err(io_error)?)))
+            }
+            Poll::Pending => {
+                this.state = State::Writing(op);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Writes go straight to the page cache and the log, commit or flush the page manager to
+    /// make them durable
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_settle(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_settle(cx)
+    }
+}
+
+impl AsyncSeek for SourceBlocks<'_> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        if matches!(this.state, State::Writing(_) | State::Seeking(..)) {
+            return Err(io::Error::other("another operation is in progress"));
+        }
+        this.state = State::Seeking(position, this.size_op());
+        Ok(())
+    }
+
+    /// Seeking past the end of the source is an error, there is no way to leave a gap
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(this.poll_settle(cx))?;
+        let State::Seeking(position, op) = &mut this.state else {
+            return Poll::Ready(Ok(this.position));
+        };
+        let position = *position;
+        let result = ready!(op.as_mut().poll(cx));
+        this.state = State::Idle;
+        let size = result.map_err(io_error)?;
+        let (base, offset) = match position {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(offset) => (size, offset),
+            SeekFrom::Current(offset) => (this.position, offset),
+        };
+        match base
+            .checked_add_signed(offset)
+            .filter(|target| *target <= size)
+        {
+            Some(target) => {
+                this.position = target;
+                Poll::Ready(Ok(target))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't seek outside the {} bytes of the source", size),
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{page::PAGE_SIZE, Lfu, PageCache};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn read_all(source: &mut SourceBlocks<'_>) -> Vec<u8> {
+        source.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut data = Vec::new();
+        source.read_to_end(&mut data).await.unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn streams_across_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PageManager::open(dir.path(), 16 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let data = pattern(3 * PAGE_DATA_SIZE + 100);
+        let mut source = manager.get_source(1);
+        source.write_all(&data).await.unwrap();
+        source.flush().await.unwrap();
+        assert_eq!(source.pages().len(), 4);
+        assert_eq!(source.size().await.unwrap(), data.len() as u64);
+        assert!(manager.get_source(2).pages().is_empty());
+
+        let mut source = manager.get_source(1);
+        assert_eq!(read_all(&mut source).await, data);
+
+        // Appends fill the last page before allocating another
+        source.write_all(&pattern(PAGE_DATA_SIZE)).await.unwrap();
+        assert_eq!(source.pages().len(), 5);
+        let mut expected = data;
+        expected.extend(pattern(PAGE_DATA_SIZE));
+        assert_eq!(read_all(&mut manager.get_source(1)).await, expected);
+    }
+
+    #[tokio::test]
+    async fn seeks_and_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PageManager::open(dir.path(), 16 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let mut source = manager.get_source(1);
+        let mut expected = pattern(2 * PAGE_DATA_SIZE + 10);
+        source.write_all(&expected).await.unwrap();
+
+        // Across the boundary between the first two pages
+        let at = PAGE_DATA_SIZE as u64 - 3;
+        assert_eq!(source.seek(SeekFrom::Start(at)).await.unwrap(), at);
+        source.write_all(b"boundary").await.unwrap();
+        expected[at as usize..at as usize + 8].copy_from_slice(b"boundary");
+        source.seek(SeekFrom::Current(-8)).await.unwrap();
+        let mut read = [0; 8];
+        source.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"boundary");
+
+        source.seek(SeekFrom::End(-2)).await.unwrap();
+        source.write_all(b"end!").await.unwrap();
+        expected.truncate(expected.len() - 2);
+        expected.extend(b"end!");
+        assert_eq!(source.size().await.unwrap(), expected.len() as u64);
+        assert_eq!(read_all(&mut source).await, expected);
+
+        assert!(source.seek(SeekFrom::End(1)).await.is_err());
+        let before_start = -(expected.len() as i64) - 1;
+        assert!(source.seek(SeekFrom::Current(before_start)).await.is_err());
+        assert_eq!(
+            source.stream_position().await.unwrap(),
+            expected.len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_pages_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PageManager::open(dir.path(), 16 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+            .with_cache(PageCache::new(2, Box::<Lfu>::default()));
+        let data = pattern(6 * PAGE_DATA_SIZE);
+        manager.get_source(1).write_all(&data).await.unwrap();
+        assert!(manager.cache_stats().evictions >= 4);
+
+        let misses = manager.cache_stats().misses;
+        assert_eq!(read_all(&mut manager.get_source(1)).await, data);
+        assert!(manager.cache_stats().misses >= misses + 4);
+        manager.flush().await.unwrap();
+        drop(manager);
+
+        let manager = PageManager::open(dir.path(), 16 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut manager.get_source(1)).await, data);
+    }
+}
@@ -0,0 +1,876 @@
+use std::{collections::BTreeMap, mem};
+
+use intake::{Column, DataValue, PrimaryKey};
+
+use crate::{
+    btree::{check_key, BTree},
+    page::{PageId, PageManager, RawPage, PAGE_DATA_SIZE},
+    source::SourceTag,
+};
+use row::{Field, RowLayout};
+use slotted::{SlotKind, SlottedPage, SlottedView, MAX_ROW_SIZE};
+
+mod row;
+mod slotted;
+
+const ROWS_PAGE: u8 = 1;
+const OVERFLOW_PAGE: u8 = 2;
+/// Kind and next page of an overflow page
+const OVERFLOW_HEADER: usize = 9;
+/// Varlen values longer than this always go to overflow pages
+const OVERFLOW_THRESHOLD: usize = PAGE_DATA_SIZE / 4;
+/// Largest row kept in a page, leaving room for the row id a moved row is prefixed with
+const MAX_INLINE_ROW: usize = MAX_ROW_SIZE - 8;
+/// Rows are padded to the size of a forward so they can always be replaced by one
+const MIN_ROW: usize = 8;
+
+/// Where a row lives, its page in the high bits and slot in the low 16. A row keeps its id
+/// for as long as it exists, even when an update moves it to another page.
+pub type RowId = u64;
+
+fn row_id(page: PageId, slot: u16) -> RowId {
+    page << 16 | slot as u64
+}
+
+fn split_row_id(id: RowId) -> (PageId, u16) {
+    (id >> 16, id as u16)
+}
+
+fn read_row_id(bytes: &[u8]) -> RowId {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn corrupt(id: RowId) -> error::Error {
+    error::CustomErrors::InvalidState(format!("row {} forwards to a row that isn't it", id)).into()
+}
+
+fn encode_primary_key(value: Option<&DataValue>) -> error::Result<Vec<u8>> {
+    match value {
+        None | Some(DataValue::Null) => {
+            Err(error::CustomErrors::InvalidArguments("rows need a primary key".to_string()).into())
+        }
+        Some(value) => Ok(intake::encode_key(std::slice::from_ref(value))),
+    }
+}
+
+/// Where a row is stored
+struct Stored {
+    /// Where the row moved to, if an update moved it
+    moved_to: Option<RowId>,
+    bytes: Vec<u8>,
+}
+
+/// Rows of a table in slotted pages tagged `tag`, with a btree over the primary key in the
+/// pages tagged `index_tag`. Values too large for a page go to chains of overflow pages.
+/// Pages are logged as they change, and the log committed before a change returns.
+pub struct Table<'a> {
+    page_store: &'a PageManager,
+    tag: SourceTag,
+    layout: RowLayout,
+    key_column: usize,
+    primary_key: BTree<'a>,
+    /// Free bytes of each rows page, held by writes so they don't interleave
+    writing: tokio::sync::Mutex<BTreeMap<PageId, usize>>,
+}
+
+impl<'a> Table<'a> {
+    /// An empty table in sources without pages
+    pub async fn create(
+        page_store: &'a PageManager,
+        tag: SourceTag,
+        index_tag: SourceTag,
+        columns: Vec<Column>,
+        primary_key: &PrimaryKey,
+    ) -> error::Result<Self> {
+        if !page_store.list_pages(tag).is_empty() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "source {} already has pages",
+                tag
+            ))
+            .into());
+        }
+        if tag == index_tag {
+            return Err(error::CustomErrors::InvalidArguments(
+                "rows and their index need sources of their own".to_string(),
+            )
+            .into());
+        }
+        let key_column = Table::key_column(&columns, primary_key)?;
+        Ok(Table {
+            page_store,
+            tag,
+            layout: RowLayout::new(columns),
+            key_column,
+            primary_key: BTree::create(page_store, index_tag).await?,
+            writing: Default::default(),
+        })
+    }
+
+    /// The table stored in the given sources, with the columns it was created with
+    pub async fn open(
+        page_store: &'a PageManager,
+        tag: SourceTag,
+        index_tag: SourceTag,
+        columns: Vec<Column>,
+        primary_key: &PrimaryKey,
+    ) -> error::Result<Self> {
+        let key_column = Table::key_column(&columns, primary_key)?;
+        let mut free = BTreeMap::new();
+        for id in page_store.list_pages(tag) {
+            let loan = page_store.aquire_page_loan(id).await?;
+            let page = loan.read().await?;
+            if page.data[0] == ROWS_PAGE {
+                free.insert(id, SlottedView::new(&page).free_space());
+            }
+        }
+        Ok(Table {
+            page_store,
+            tag,
+            layout: RowLayout::new(columns),
+            key_column,
+            primary_key: BTree::open(page_store, index_tag).await?,
+            writing: tokio::sync::Mutex::new(free),
+        })
+    }
+
+    fn key_column(columns: &[Column], primary_key: &PrimaryKey) -> error::Result<usize> {
+        columns
+            .iter()
+            .position(|c| c.name == primary_key.name && c.data_type == primary_key.data_type)
+            .ok_or_else(|| {
+                error::CustomErrors::InvalidArguments(format!(
+                    "no {:?} column {} for the primary key",
+                    primary_key.data_type, primary_key.name
+                ))
+                .into()
+            })
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        self.layout.columns()
+    }
+
+    /// Primary key of a row, checked to fit in the index before anything is written
+    fn key(&self, row: &[DataValue]) -> error::Result<Vec<u8>> {
+        let key = encode_primary_key(row.get(self.key_column))?;
+        check_key(&key)?;
+        Ok(key)
+    }
+
+    fn duplicate(&self, row: &[DataValue]) -> error::Error {
+        error::CustomErrors::InvalidArguments(format!(
+            "a row with primary key {:?} already exists",
+            row[self.key_column]
+        ))
+        .into()
+    }
+
+    /// Add a row given in column order, returning its id
+    pub async fn insert(&self, row: &[DataValue]) -> error::Result<RowId> {
+        let key = self.key(row)?;
+        let mut free = self.writing.lock().await;
+        if self.primary_key.get(&key).await?.is_some() {
+            return Err(self.duplicate(row));
+        }
+        let bytes = self.encode(row).await?;
+        let id = match self.place(&mut free, SlotKind::Row, &bytes).await {
+            Ok(id) => id,
+            Err(e) => {
+                self.discard(&mut free, None, &bytes).await;
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.primary_key.insert(&key, id).await {
+            self.discard(&mut free, Some(id), &bytes).await;
+            return Err(e);
+        }
+        self.page_store.commit().await?;
+        Ok(id)
+    }
+
+    /// Undo a write that failed part way, freeing the slot and overflow pages it took as far
+    /// as it can
+    async fn discard(&self, free: &mut BTreeMap<PageId, usize>, id: Option<RowId>, bytes: &[u8]) {
+        if let Some(id) = id {
+            let _ = self.remove(free, id).await;
+        }
+        if let Ok(fields) = self.layout.unpack(bytes) {
+            let _ = self.free_overflow(&fields).await;
+        }
+    }
+
+    pub async fn get(&self, id: RowId) -> error::Result<Option<Vec<DataValue>>> {
+        let stored = match self.stored(id).await {
+            // An update moved the row while it was followed, look again once it's done
+            Err(_) => {
+                let _writing = self.writing.lock().await;
+                self.stored(id).await?
+            }
+            stored => stored?,
+        };
+        match stored {
+            Some(stored) => Ok(Some(self.decode(&stored.bytes).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The row with the given primary key and its id
+    pub async fn lookup(&self, key: &DataValue) -> error::Result<Option<(RowId, Vec<DataValue>)>> {
+        let key = intake::encode_key(std::slice::from_ref(key));
+        let Some(id) = self.primary_key.get(&key).await? else {
+            return Ok(None);
+        };
+        Ok(self.get(id).await?.map(|row| (id, row)))
+    }
+
+    /// Replace a row, keeping its id. Returns whether there was a row to replace.
+    pub async fn update(&self, id: RowId, row: &[DataValue]) -> error::Result<bool> {
+        let key = self.key(row)?;
+        let mut free = self.writing.lock().await;
+        let Some(stored) = self.stored(id).await? else {
+            return Ok(false);
+        };
+        let old = self.layout.unpack(&stored.bytes)?;
+        let old_key = self.stored_key(&old).await?;
+        let key_changed = key != old_key;
+        if key_changed && self.primary_key.get(&key).await?.is_some() {
+            return Err(self.duplicate(row));
+        }
+
+        let bytes = self.encode(row).await?;
+        // The new key goes in first, so the row is always found by one of them
+        if key_changed {
+            if let Err(e) = self.primary_key.insert(&key, id).await {
+                self.discard(&mut free, None, &bytes).await;
+                return Err(e);
+            }
+        }
+        let left = match self.rewrite(&mut free, id, stored.moved_to, &bytes).await {
+            Ok(left) => left,
+            Err(e) => {
+                if key_changed {
+                    let _ = self.primary_key.remove(&key).await;
+                }
+                self.discard(&mut free, None, &bytes).await;
+                return Err(e);
+            }
+        };
+        if let Some(left) = left {
+            self.remove(&mut free, left).await?;
+        }
+        self.free_overflow(&old).await?;
+        if key_changed {
+            self.primary_key.remove(&old_key).await?;
+        }
+        self.page_store.commit().await?;
+        Ok(true)
+    }
+
+    /// Store new bytes for a row, moving it if they don't fit where it is, and return the
+    /// slot it moved out of for the caller to remove. Leaves the row as it was if it fails.
+    async fn rewrite(
+        &self,
+        free: &mut BTreeMap<PageId, usize>,
+        id: RowId,
+        moved_to: Option<RowId>,
+        bytes: &[u8],
+    ) -> error::Result<Option<RowId>> {
+        match moved_to {
+            None => {
+                if !self.replace(free, id, SlotKind::Row, bytes).await? {
+                    self.move_row(free, id, bytes).await?;
+                }
+                Ok(None)
+            }
+            Some(moved_to) => {
+                let mut moved = id.to_le_bytes().to_vec();
+                moved.extend(bytes);
+                if self
+                    .replace(free, moved_to, SlotKind::Moved, &moved)
+                    .await?
+                {
+                    return Ok(None);
+                }
+                self.move_row(free, id, bytes).await?;
+                Ok(Some(moved_to))
+            }
+        }
+    }
+
+    /// Remove a row, returning whether there was one
+    pub async fn delete(&self, id: RowId) -> error::Result<bool> {
+        let mut free = self.writing.lock().await;
+        let Some(stored) = self.stored(id).await? else {
+            return Ok(false);
+        };
+        let fields = self.layout.unpack(&stored.bytes)?;
+        let key = self.stored_key(&fields).await?;
+        if let Some(moved_to) = stored.moved_to {
+            self.remove(&mut free, moved_to).await?;
+        }
+        self.remove(&mut free, id).await?;
+        self.free_overflow(&fields).await?;
+        self.primary_key.remove(&key).await?;
+        self.page_store.commit().await?;
+        Ok(true)
+    }
+
+    /// Every row and its id, as of one point in time
+    pub async fn scan(&self) -> error::Result<Vec<(RowId, Vec<DataValue>)>> {
+        let _writing = self.writing.lock().await;
+        let mut stored = Vec::new();
+        for page_id in self.page_store.list_pages(self.tag) {
+            let loan = self.page_store.aquire_page_loan(page_id).await?;
+            let page = loan.read().await?;
+            if page.data[0] != ROWS_PAGE {
+                continue;
+            }
+            let view = SlottedView::new(&page);
+            for slot in 0..view.slots() {
+                match view.get(slot) {
+                    Some((SlotKind::Row, bytes)) => {
+                        stored.push((row_id(page_id, slot), bytes.to_vec()))
+                    }
+                    Some((SlotKind::Moved, bytes)) => {
+                        stored.push((read_row_id(bytes), bytes[8..].to_vec()))
+                    }
+                    Some((SlotKind::Forward, _)) | None => {}
+                }
+            }
+        }
+        let mut rows = Vec::with_capacity(stored.len());
+        for (id, bytes) in stored {
+            rows.push((id, self.decode(&bytes).await?));
+        }
+        Ok(rows)
+    }
+
+    /// What the slot of a row id holds
+    async fn slot(&self, id: RowId) -> error::Result<Option<(SlotKind, Vec<u8>)>> {
+        let (page_id, slot) = split_row_id(id);
+        if !self.page_store.contains_page(page_id) {
+            return Ok(None);
+        }
+        let loan = self.page_store.aquire_page_loan(page_id).await?;
+        let page = loan.read().await?;
+        if page.data[0] != ROWS_PAGE || page.header.tag != self.tag {
+            return Ok(None);
+        }
+        let slot = SlottedView::new(&page).get(slot);
+        Ok(slot.map(|(kind, bytes)| (kind, bytes.to_vec())))
+    }
+
+    /// The row with an id, following it if it moved. Fails if it moves while followed.
+    async fn stored(&self, id: RowId) -> error::Result<Option<Stored>> {
+        match self.slot(id).await? {
+            Some((SlotKind::Row, bytes)) => Ok(Some(Stored {
+                moved_to: None,
+                bytes,
+            })),
+            Some((SlotKind::Forward, target)) => {
+                let moved_to = read_row_id(&target);
+                match self.slot(moved_to).await? {
+                    Some((SlotKind::Moved, bytes)) if read_row_id(&bytes) == id => {
+                        Ok(Some(Stored {
+                            moved_to: Some(moved_to),
+                            bytes: bytes[8..].to_vec(),
+                        }))
+                    }
+                    _ => Err(corrupt(id)),
+                }
+            }
+            // Where a row moved to isn't its id
+            Some((SlotKind::Moved, _)) | None => Ok(None),
+        }
+    }
+
+    /// Store bytes in a new slot, in the first page with room for them
+    async fn place(
+        &self,
+        free: &mut BTreeMap<PageId, usize>,
+        kind: SlotKind,
+        bytes: &[u8],
+    ) -> error::Result<RowId> {
+        let candidates: Vec<_> = free
+            .iter()
+            .filter(|(_, space)| **space >= bytes.len() + 4)
+            .map(|(id, _)| *id)
+            .collect();
+        for page_id in candidates {
+            let loan = self.page_store.aquire_page_loan(page_id).await?;
+            let mut page = loan.write().await?;
+            let mut slotted = SlottedPage::new(&mut page);
+            let slot = slotted.insert(kind, bytes);
+            free.insert(page_id, slotted.view().free_space());
+            if let Some(slot) = slot {
+                self.page_store.log_write(&mut page, 0..PAGE_DATA_SIZE);
+                return Ok(row_id(page_id, slot));
+            }
+        }
+
+        let loan = self.page_store.new_page(self.tag).await?;
+        let mut page = loan.write().await?;
+        let mut slotted = SlottedPage::init(&mut page, ROWS_PAGE);
+        let slot = slotted
+            .insert(kind, bytes)
+            .expect("rows fit in an empty page");
+        free.insert(loan.id(), slotted.view().free_space());
+        self.page_store.log_write(&mut page, 0..PAGE_DATA_SIZE);
+        Ok(row_id(loan.id(), slot))
+    }
+
+    /// Replace what the slot of a row id holds, returning whether the bytes fit in its page
+    async fn replace(
+        &self,
+        free: &mut BTreeMap<PageId, usize>,
+        id: RowId,
+        kind: SlotKind,
+        bytes: &[u8],
+    ) -> error::Result<bool> {
+        self.edit(free, id, |slotted, slot| slotted.replace(slot, kind, bytes))
+            .await
+    }
+
+    async fn remove(&self, free: &mut BTreeMap<PageId, usize>, id: RowId) -> error::Result<()> {
+        self.edit(free, id, |slotted, slot| slotted.remove(slot))
+            .await
+    }
+
+    async fn edit<T>(
+        &self,
+        free: &mut BTreeMap<PageId, usize>,
+        id: RowId,
+        f: impl FnOnce(&mut SlottedPage, u16) -> T,
+    ) -> error::Result<T> {
+        let (page_id, slot) = split_row_id(id);
+        let loan = self.page_store.aquire_page_loan(page_id).await?;
+        let mut page = loan.write().await?;
+        let mut slotted = SlottedPage::new(&mut page);
+        let result = f(&mut slotted, slot);
+        free.insert(page_id, slotted.view().free_space());
+        self.page_store.log_write(&mut page, 0..PAGE_DATA_SIZE);
+        Ok(result)
+    }
+
+    /// Move a row that outgrew its page, leaving a forward to it in its slot
+    async fn move_row(
+        &self,
+        free: &mut BTreeMap<PageId, usize>,
+        id: RowId,
+        bytes: &[u8],
+    ) -> error::Result<()> {
+        let mut moved = id.to_le_bytes().to_vec();
+        moved.extend(bytes);
+        let moved_to = self.place(free, SlotKind::Moved, &moved).await?;
+        let forward = moved_to.to_le_bytes();
+        match self.replace(free, id, SlotKind::Forward, &forward).await {
+            Ok(true) => Ok(()),
+            forwarded => {
+                let _ = self.remove(free, moved_to).await;
+                Err(forwarded.err().unwrap_or_else(|| corrupt(id)))
+            }
+        }
+    }
+
+    /// Row bytes of the values, moving the largest varlen values to overflow pages until the
+    /// row fits in a page
+    async fn encode(&self, row: &[DataValue]) -> error::Result<Vec<u8>> {
+        let mut fields = self.layout.fields(row)?;
+        loop {
+            let too_large = self.layout.packed_size(&fields) > MAX_INLINE_ROW;
+            let largest = fields
+                .iter()
+                .enumerate()
+                .filter_map(|(i, field)| match field {
+                    Field::Varlen(bytes) if bytes.len() > 8 => Some((bytes.len(), i)),
+                    _ => None,
+                })
+                .max();
+            match largest {
+                Some((len, i)) if too_large || len > OVERFLOW_THRESHOLD => {
+                    let Field::Varlen(bytes) = mem::replace(&mut fields[i], Field::Null) else {
+                        unreachable!()
+                    };
+                    let first = match self.write_overflow(&bytes).await {
+                        Ok(first) => first,
+                        Err(e) => {
+                            let _ = self.free_overflow(&fields).await;
+                            return Err(e);
+                        }
+                    };
+                    fields[i] = Field::Overflow {
+                        first,
+                        len: len as u32,
+                    };
+                }
+                _ if too_large => {
+                    self.free_overflow(&fields).await?;
+                    return Err(error::CustomErrors::InvalidArguments(format!(
+                        "rows are at most {} bytes without their varlen values",
+                        MAX_INLINE_ROW
+                    ))
+                    .into());
+                }
+                _ => break,
+            }
+        }
+        let mut bytes = self.layout.pack(&fields);
+        if bytes.len() < MIN_ROW {
+            bytes.resize(MIN_ROW, 0);
+        }
+        Ok(bytes)
+    }
+
+    async fn decode(&self, bytes: &[u8]) -> error::Result<Vec<DataValue>> {
+        let mut fields = self.layout.unpack(bytes)?;
+        for field in &mut fields {
+            if let Field::Overflow { first, len } = *field {
+                *field = Field::Varlen(self.read_overflow(first, len as usize).await?);
+            }
+        }
+        self.layout.values(fields)
+    }
+
+    /// Primary key of unpacked row fields, reading back only the key if it overflowed
+    async fn stored_key(&self, fields: &[Field]) -> error::Result<Vec<u8>> {
+        let field = match &fields[self.key_column] {
+            Field::Overflow { first, len } => {
+                Field::Varlen(self.read_overflow(*first, *len as usize).await?)
+            }
+            field => field.clone(),
+        };
+        encode_primary_key(Some(&self.layout.value(self.key_column, field)?))
+    }
+
+    /// Write a value to a chain of overflow pages, returning the first
+    async fn write_overflow(&self, bytes: &[u8]) -> error::Result<PageId> {
+        if bytes.len() >= 1 << 31 {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "values are under 2 GB, got {} bytes",
+                bytes.len()
+            ))
+            .into());
+        }
+        let mut written = Vec::new();
+        let first = self.write_chain(bytes, &mut written).await;
+        if first.is_err() {
+            // Pages of a chain that isn't whole are never reached, so give them back
+            for id in written {
+                let _ = self.page_store.delete_page(id).await;
+            }
+        }
+        first
+    }
+
+    /// Write the pages of an overflow chain, adding each page to `written` as it is made
+    async fn write_chain(&self, bytes: &[u8], written: &mut Vec<PageId>) -> error::Result<PageId> {
+        // Written from the end so each page knows the next
+        let mut next: PageId = 0;
+        for chunk in bytes.rchunks(PAGE_DATA_SIZE - OVERFLOW_HEADER) {
+            let loan = self.page_store.new_page(self.tag).await?;
+            written.push(loan.id());
+            let mut page = loan.write().await?;
+            page.data[0] = OVERFLOW_PAGE;
+            page.data[1..OVERFLOW_HEADER].copy_from_slice(&next.to_le_bytes());
+            page.data[OVERFLOW_HEADER..OVERFLOW_HEADER + chunk.len()].copy_from_slice(chunk);
+            page.header.size = (OVERFLOW_HEADER + chunk.len()) as u64;
+            self.page_store
+                .log_write(&mut page, 0..OVERFLOW_HEADER + chunk.len());
+            next = loan.id();
+        }
+        Ok(next)
+    }
+
+    /// Pages of an overflow chain, calling `f` with each
+    async fn overflow_pages(
+        &self,
+        first: PageId,
+        mut f: impl FnMut(&RawPage),
+    ) -> error::Result<()> {
+        let mut next = first;
+        while next != 0 {
+            let loan = self.page_store.aquire_page_loan(next).await?;
+            let page = loan.read().await?;
+            if page.data[0] != OVERFLOW_PAGE {
+                return Err(error::CustomErrors::InvalidState(format!(
+                    "page {} isn't an overflow page",
+                    next
+                ))
+                .into());
+            }
+            f(&page);
+            next = read_row_id(&page.data[1..OVERFLOW_HEADER]);
+        }
+        Ok(())
+    }
+
+    async fn read_overflow(&self, first: PageId, len: usize) -> error::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len);
+        self.overflow_pages(first, |page| {
+            bytes.extend(&page.active_data()[OVERFLOW_HEADER..])
+        })
+        .await?;
+        if bytes.len() != len {
+            return Err(error::CustomErrors::InvalidState(format!(
+                "overflow value of {} bytes has {}",
+                len,
+                bytes.len()
+            ))
+            .into());
+        }
+        Ok(bytes)
+    }
+
+    async fn free_overflow(&self, fields: &[Field]) -> error::Result<()> {
+        for field in fields {
+            if let Field::Overflow { first, .. } = field {
+                let mut pages = Vec::new();
+                self.overflow_pages(*first, |page| pages.push(page.header.id))
+                    .await?;
+                for id in pages {
+                    self.page_store.delete_page(id).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use intake::ColumnTypes;
+
+    use super::*;
+    use crate::{page::PAGE_SIZE, Lfu, PageCache, Wal, WalOptions};
+
+    async fn manager(dir: &tempfile::TempDir) -> PageManager {
+        PageManager::open(dir.path(), 1024 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+    }
+
+    fn columns() -> (Vec<Column>, PrimaryKey) {
+        let columns = vec![
+            Column::new("name", ColumnTypes::String),
+            Column::new("id", ColumnTypes::I64),
+            Column::new("data", ColumnTypes::Bytes),
+            Column::new("score", ColumnTypes::F64),
+        ];
+        let primary_key = PrimaryKey {
+            name: "id".to_string(),
+            data_type: ColumnTypes::I64,
+        };
+        (columns, primary_key)
+    }
+
+    fn row(i: i64, data: usize) -> Vec<DataValue> {
+        vec![
+            DataValue::String(format!("row {}", i)),
+            DataValue::I64(i),
+            DataValue::Bytes(vec![i as u8; data]),
+            if i % 3 == 0 {
+                DataValue::Null
+            } else {
+                DataValue::F64(i as f64 / 2.0)
+            },
+        ]
+    }
+
+    async fn table<'a>(manager: &'a PageManager) -> Table<'a> {
+        let (columns, primary_key) = columns();
+        Table::create(manager, 1, 2, columns, &primary_key)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn inserts_and_looks_up_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let table = table(&manager).await;
+        let mut ids = Vec::new();
+        for i in 0..500 {
+            ids.push(table.insert(&row(i, 20)).await.unwrap());
+        }
+        assert!(manager.list_pages(1).len() > 1);
+        for (i, id) in ids.iter().enumerate() {
+            let i = i as i64;
+            assert_eq!(table.get(*id).await.unwrap(), Some(row(i, 20)));
+            let found = table.lookup(&DataValue::I64(i)).await.unwrap();
+            assert_eq!(found, Some((*id, row(i, 20))));
+        }
+        assert_eq!(table.lookup(&DataValue::I64(500)).await.unwrap(), None);
+        assert!(table.insert(&row(7, 1)).await.is_err());
+        let mut no_key = row(600, 1);
+        no_key[1] = DataValue::Null;
+        assert!(table.insert(&no_key).await.is_err());
+        let mut wrong_type = row(600, 1);
+        wrong_type[3] = DataValue::I64(1);
+        assert!(table.insert(&wrong_type).await.is_err());
+
+        assert!(table.delete(ids[7]).await.unwrap());
+        assert!(!table.delete(ids[7]).await.unwrap());
+        assert_eq!(table.get(ids[7]).await.unwrap(), None);
+        assert_eq!(table.lookup(&DataValue::I64(7)).await.unwrap(), None);
+        // The slot isn't reused, the old id keeps finding nothing
+        let id = table.insert(&row(7, 20)).await.unwrap();
+        assert_ne!(id, ids[7]);
+        assert_eq!(table.get(ids[7]).await.unwrap(), None);
+
+        let scanned = table.scan().await.unwrap();
+        assert_eq!(scanned.len(), 500);
+        assert!(scanned.contains(&(id, row(7, 20))));
+        assert!(scanned.iter().all(|(found, _)| *found != ids[7]));
+    }
+
+    #[tokio::test]
+    async fn keeps_row_ids_when_rows_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let table = table(&manager).await;
+        let mut ids = Vec::new();
+        for i in 0..30 {
+            ids.push(table.insert(&row(i, 100)).await.unwrap());
+        }
+        let id = ids[3];
+
+        // Grows past the room left in its page, so it moves
+        table.update(id, &row(3, 900)).await.unwrap();
+        assert_eq!(table.get(id).await.unwrap(), Some(row(3, 900)));
+        let moved = table.stored(id).await.unwrap().unwrap().moved_to.unwrap();
+        assert_eq!(table.get(moved).await.unwrap(), None);
+        // Moves on again, then shrinks where it is
+        table.update(id, &row(3, 950)).await.unwrap();
+        table.update(id, &row(3, 10)).await.unwrap();
+        assert_eq!(table.get(id).await.unwrap(), Some(row(3, 10)));
+
+        // A new primary key moves the row in the index too
+        let mut renamed = row(3, 10);
+        renamed[1] = DataValue::I64(1000);
+        table.update(id, &renamed).await.unwrap();
+        assert_eq!(table.lookup(&DataValue::I64(3)).await.unwrap(), None);
+        let found = table.lookup(&DataValue::I64(1000)).await.unwrap();
+        assert_eq!(found, Some((id, renamed)));
+        assert!(table.update(id, &row(4, 10)).await.is_err());
+
+        let scanned = table.scan().await.unwrap();
+        assert_eq!(scanned.len(), 30);
+        assert_eq!(scanned.iter().filter(|(found, _)| *found == id).count(), 1);
+
+        assert!(table.delete(id).await.unwrap());
+        assert_eq!(table.scan().await.unwrap().len(), 29);
+        assert!(!table.update(id, &row(3, 10)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stores_large_values_in_overflow_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (columns, primary_key) = columns();
+        let big = row(1, 20_000);
+        let id = {
+            let manager = manager(&dir).await;
+            let table = table(&manager).await;
+            let id = table.insert(&big).await.unwrap();
+            assert!(manager.list_pages(1).len() > 5);
+            assert_eq!(table.get(id).await.unwrap(), Some(big.clone()));
+            manager.flush().await.unwrap();
+            id
+        };
+
+        let manager = manager(&dir).await;
+        let table = Table::open(&manager, 1, 2, columns, &primary_key)
+            .await
+            .unwrap();
+        assert_eq!(table.get(id).await.unwrap(), Some(big));
+        table.update(id, &row(1, 5)).await.unwrap();
+        assert_eq!(manager.list_pages(1).len(), 1);
+        assert_eq!(table.get(id).await.unwrap(), Some(row(1, 5)));
+
+        let many: Vec<_> = (0..2).map(|i| row(2 + i, 3000)).collect();
+        for row in &many {
+            let id = table.insert(row).await.unwrap();
+            assert_eq!(table.get(id).await.unwrap().as_ref(), Some(row));
+        }
+    }
+
+    #[tokio::test]
+    async fn frees_what_failed_inserts_wrote() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager(&dir).await;
+        let (columns, _) = columns();
+        let primary_key = PrimaryKey {
+            name: "name".to_string(),
+            data_type: ColumnTypes::String,
+        };
+        let table = Table::create(&manager, 1, 2, columns, &primary_key)
+            .await
+            .unwrap();
+        let id = table.insert(&row(1, 10)).await.unwrap();
+        let pages = manager.list_pages(1);
+
+        // Fits in a row but not in the primary key, so it is turned away before anything is
+        // written
+        let mut long_key = row(2, 20_000);
+        long_key[0] = DataValue::String("k".repeat(600));
+        assert!(table.insert(&long_key).await.is_err());
+        assert_eq!(manager.list_pages(1), pages);
+        let scanned = table.scan().await.unwrap();
+        assert_eq!(scanned, vec![(id, row(1, 10))]);
+
+        // Same for an update, which leaves the row and its key as they were
+        assert!(table.update(id, &long_key).await.is_err());
+        assert_eq!(manager.list_pages(1), pages);
+        let key = DataValue::String("row 1".to_string());
+        let found = table.lookup(&key).await.unwrap();
+        assert_eq!(found, Some((id, row(1, 10))));
+        assert_eq!(table.get(id).await.unwrap(), Some(row(1, 10)));
+    }
+
+    #[tokio::test]
+    async fn redoes_logged_changes_after_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || async {
+            let wal = Wal::open(dir.path().join("wal"), WalOptions::default())
+                .await
+                .unwrap();
+            manager(&dir)
+                .await
+                .with_cache(PageCache::new(8, Box::<Lfu>::default()))
+                .with_wal(wal)
+                .await
+                .unwrap()
+        };
+        let (columns, primary_key) = columns();
+        let manager = open().await;
+        let table = table(&manager).await;
+        let mut ids = Vec::new();
+        for i in 0..100 {
+            ids.push(table.insert(&row(i, 50)).await.unwrap());
+        }
+        manager.flush().await.unwrap();
+
+        // Changes since the flush are only in the log when it crashes
+        let large = table.insert(&row(100, 10_000)).await.unwrap();
+        table.update(ids[3], &row(3, 900)).await.unwrap();
+        table.delete(ids[5]).await.unwrap();
+        let mut expected = table.scan().await.unwrap();
+        drop(table);
+        drop(manager);
+
+        let manager = open().await;
+        let table = Table::open(&manager, 1, 2, columns, &primary_key)
+            .await
+            .unwrap();
+        let mut scanned = table.scan().await.unwrap();
+        scanned.sort_by_key(|(id, _)| *id);
+        expected.sort_by_key(|(id, _)| *id);
+        assert_eq!(scanned, expected);
+        assert_eq!(table.get(large).await.unwrap(), Some(row(100, 10_000)));
+        assert_eq!(table.get(ids[3]).await.unwrap(), Some(row(3, 900)));
+        assert_eq!(table.lookup(&DataValue::I64(5)).await.unwrap(), None);
+        assert_eq!(
+            table.lookup(&DataValue::I64(99)).await.unwrap(),
+            Some((ids[99], row(99, 50)))
+        );
+    }
+}
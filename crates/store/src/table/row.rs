@@ -0,0 +1,291 @@
+use chrono::{TimeZone, Utc};
+use intake::{Column, ColumnTypes, DataValue};
+use query_parser::Interval;
+
+use crate::page::PageId;
+
+/// Marks a varlen value stored in overflow pages, its bytes in the row are the first page
+const OVERFLOW: u32 = 1 << 31;
+/// Offset and length in the varlen section
+const VARLEN_SLOT: usize = 8;
+
+/// A column value between its row bytes and a `DataValue`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Field {
+    Null,
+    Fixed(Vec<u8>),
+    Varlen(Vec<u8>),
+    /// A varlen value of `len` bytes in the overflow pages starting at `first`
+    Overflow {
+        first: PageId,
+        len: u32,
+    },
+}
+
+/// Where the columns of a table's rows go. Rows start with a bitmap of the null columns,
+/// then every column's fixed width bytes, then the bytes of varlen values. A varlen column's
+/// fixed bytes hold the offset and length of its value. Null columns keep their fixed bytes.
+pub(crate) struct RowLayout {
+    columns: Vec<Column>,
+    /// Start of each column's fixed bytes
+    offsets: Vec<usize>,
+    /// End of the fixed section
+    fixed_end: usize,
+}
+
+/// Fixed width of a type, or `None` for varlen types
+fn fixed_width(data_type: ColumnTypes) -> Option<usize> {
+    match data_type {
+        ColumnTypes::I64 | ColumnTypes::F64 => Some(8),
+        ColumnTypes::Bool => Some(1),
+        // Seconds and nanoseconds
+        ColumnTypes::DateTime => Some(12),
+        ColumnTypes::UUID => Some(16),
+        // Months and microseconds
+        ColumnTypes::Interval => Some(12),
+        ColumnTypes::String | ColumnTypes::Bytes | ColumnTypes::Json => None,
+        ColumnTypes::BinaryVector(_) => None,
+    }
+}
+
+fn corrupt(message: &str) -> error::Error {
+    error::CustomErrors::InvalidState(format!("corrupt row: {}", message)).into()
+}
+
+impl RowLayout {
+    pub(crate) fn new(columns: Vec<Column>) -> Self {
+        let mut offset = columns.len().div_ceil(8);
+        let offsets = columns
+            .iter()
+            .map(|column| {
+                let start = offset;
+                offset += fixed_width(column.data_type).unwrap_or(VARLEN_SLOT);
+                start
+            })
+            .collect();
+        RowLayout {
+            columns,
+            offsets,
+            fixed_end: offset,
+        }
+    }
+
+    pub(crate) fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Fields of a row given in column order, checking each value has its column's type
+    pub(crate) fn fields(&self, row: &[DataValue]) -> error::Result<Vec<Field>> {
+        if row.len() != self.columns.len() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "rows have {} columns, got {} values",
+                self.columns.len(),
+                row.len()
+            ))
+            .into());
+        }
+        self.columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| {
+                let field = match (column.data_type, value) {
+                    (_, DataValue::Null) => Field::Null,
+                    (ColumnTypes::I64, DataValue::I64(i)) => Field::Fixed(i.to_le_bytes().into()),
+                    (ColumnTypes::F64, DataValue::F64(f)) => Field::Fixed(f.to_le_bytes().into()),
+                    (ColumnTypes::Bool, DataValue::Bool(b)) => Field::Fixed(vec![*b as u8]),
+                    (ColumnTypes::DateTime, DataValue::DateTime(dt)) => {
+                        let mut bytes = dt.timestamp().to_le_bytes().to_vec();
+                        bytes.extend(dt.timestamp_subsec_nanos().to_le_bytes());
+                        Field::Fixed(bytes)
+                    }
+                    (ColumnTypes::UUID, DataValue::UUID(uuid)) => {
+                        Field::Fixed(uuid.as_bytes().to_vec())
+                    }
+                    (ColumnTypes::Interval, DataValue::Interval(interval)) => {
+                        let mut bytes = interval.months.to_le_bytes().to_vec();
+                        bytes.extend(interval.micros.to_le_bytes());
+                        Field::Fixed(bytes)
+                    }
+                    (ColumnTypes::String, DataValue::String(s)) => Field::Varlen(s.clone().into()),
+                    (ColumnTypes::Bytes | ColumnTypes::BinaryVector(_), DataValue::Bytes(b)) => {
+                        Field::Varlen(b.clone())
+                    }
+                    (ColumnTypes::Json, DataValue::Json(json)) => {
+                        Field::Varlen(json.to_string().into())
+                    }
+                    (data_type, value) => {
+                        return Err(error::CustomErrors::InvalidArguments(format!(
+                            "column {} holds {:?}, got {:?}",
+                            column.name, data_type, value
+                        ))
+                        .into())
+                    }
+                };
+                Ok(field)
+            })
+            .collect()
+    }
+
+    /// Bytes `pack` makes of the fields
+    pub(crate) fn packed_size(&self, fields: &[Field]) -> usize {
+        let varlen: usize = fields
+            .iter()
+            .map(|field| match field {
+                Field::Varlen(bytes) => bytes.len(),
+                Field::Overflow { .. } => 8,
+                Field::Null | Field::Fixed(_) => 0,
+            })
+            .sum();
+        self.fixed_end + varlen
+    }
+
+    pub(crate) fn pack(&self, fields: &[Field]) -> Vec<u8> {
+        let mut row = vec![0; self.fixed_end];
+        for (i, field) in fields.iter().enumerate() {
+            let at = self.offsets[i];
+            let varlen = |row: &mut Vec<u8>, bytes: &[u8], len: u32| {
+                let offset = row.len() as u32;
+                row[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+                row[at + 4..at + 8].copy_from_slice(&len.to_le_bytes());
+                row.extend_from_slice(bytes);
+            };
+            match field {
+                Field::Null => row[i / 8] |= 1 << (i % 8),
+                Field::Fixed(bytes) => row[at..at + bytes.len()].copy_from_slice(bytes),
+                Field::Varlen(bytes) => varlen(&mut row, bytes, bytes.len() as u32),
+                Field::Overflow { first, len } => {
+                    varlen(&mut row, &first.to_le_bytes(), len | OVERFLOW)
+                }
+            }
+        }
+        row
+    }
+
+    pub(crate) fn unpack(&self, row: &[u8]) -> error::Result<Vec<Field>> {
+        if row.len() < self.fixed_end {
+            return Err(corrupt("shorter than its fixed section"));
+        }
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                if row[i / 8] & 1 << (i % 8) != 0 {
+                    return Ok(Field::Null);
+                }
+                let at = self.offsets[i];
+                if let Some(width) = fixed_width(column.data_type) {
+                    return Ok(Field::Fixed(row[at..at + width].to_vec()));
+                }
+                let u32_at = |i: usize| u32::from_le_bytes(row[i..i + 4].try_into().unwrap());
+                let (offset, len) = (u32_at(at) as usize, u32_at(at + 4));
+                let stored = if len & OVERFLOW != 0 { 8 } else { len as usize };
+                let bytes = row
+                    .get(offset..offset + stored)
+                    .ok_or_else(|| corrupt("varlen value past its end"))?;
+                Ok(if len & OVERFLOW != 0 {
+                    Field::Overflow {
+                        first: u64::from_le_bytes(bytes.try_into().unwrap()),
+                        len: len & !OVERFLOW,
+                    }
+                } else {
+                    Field::Varlen(bytes.to_vec())
+                })
+            })
+            .collect()
+    }
+
+    /// Values of unpacked fields, once overflowing values are read back in
+    pub(crate) fn values(&self, fields: Vec<Field>) -> error::Result<Vec<DataValue>> {
+        fields
+            .into_iter()
+            .enumerate()
+            .map(|(i, field)| self.value(i, field))
+            .collect()
+    }
+
+    /// Value of the unpacked field of a column
+    pub(crate) fn value(&self, column: usize, field: Field) -> error::Result<DataValue> {
+        let bytes = match field {
+            Field::Null => return Ok(DataValue::Null),
+            Field::Fixed(bytes) | Field::Varlen(bytes) => bytes,
+            Field::Overflow { .. } => return Err(corrupt("overflow value not read")),
+        };
+        let i64_at = |i: usize| i64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Ok(match self.columns[column].data_type {
+            ColumnTypes::I64 => DataValue::I64(i64_at(0)),
+            ColumnTypes::F64 => DataValue::F64(f64::from_bits(i64_at(0) as u64)),
+            ColumnTypes::Bool => DataValue::Bool(bytes[0] != 0),
+            ColumnTypes::DateTime => {
+                let nanos = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+                Utc.timestamp_opt(i64_at(0), nanos)
+                    .single()
+                    .map(DataValue::DateTime)
+                    .ok_or_else(|| corrupt("datetime out of range"))?
+            }
+            ColumnTypes::UUID => {
+                DataValue::UUID(uuid::Uuid::from_slice(&bytes).map_err(|_| corrupt("uuid"))?)
+            }
+            ColumnTypes::Interval => DataValue::Interval(Interval {
+                months: i32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                micros: i64_at(4),
+            }),
+            ColumnTypes::String => DataValue::String(
+                String::from_utf8(bytes).map_err(|_| corrupt("string isn't utf-8"))?,
+            ),
+            ColumnTypes::Bytes | ColumnTypes::BinaryVector(_) => DataValue::Bytes(bytes),
+            ColumnTypes::Json => {
+                DataValue::Json(serde_json::from_slice(&bytes).map_err(|_| corrupt("json"))?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_and_unpacks_rows() {
+        let layout = RowLayout::new(vec![
+            Column::new("id", ColumnTypes::I64),
+            Column::new("name", ColumnTypes::String),
+            Column::new("score", ColumnTypes::F64),
+            Column::new("blob", ColumnTypes::Bytes),
+            Column::new("at", ColumnTypes::DateTime),
+            Column::new("uuid", ColumnTypes::UUID),
+            Column::new("ok", ColumnTypes::Bool),
+            Column::new("wait", ColumnTypes::Interval),
+            Column::new("doc", ColumnTypes::Json),
+        ]);
+        let row = vec![
+            DataValue::I64(-7),
+            DataValue::String("héllo".into()),
+            DataValue::Null,
+            DataValue::Bytes(vec![0, 1, 2]),
+            DataValue::DateTime(Utc.timestamp_opt(-5, 123).unwrap()),
+            DataValue::UUID(uuid::Uuid::from_u128(99)),
+            DataValue::Bool(true),
+            DataValue::Interval(Interval {
+                months: 2,
+                micros: -3,
+            }),
+            DataValue::Json(serde_json::json!({"a": [1, null]})),
+        ];
+        let fields = layout.fields(&row).unwrap();
+        let packed = layout.pack(&fields);
+        assert_eq!(packed.len(), layout.packed_size(&fields));
+        assert_eq!(layout.unpack(&packed).unwrap(), fields);
+        assert_eq!(layout.values(fields).unwrap(), row);
+
+        let mut fields = layout.fields(&row).unwrap();
+        fields[3] = Field::Overflow { first: 9, len: 3 };
+        assert_eq!(layout.unpack(&layout.pack(&fields)).unwrap(), fields);
+        assert!(layout.values(fields).is_err());
+
+        let mut wrong = row.clone();
+        wrong[0] = DataValue::String("7".into());
+        assert!(layout.fields(&wrong).is_err());
+        assert!(layout.fields(&row[1..]).is_err());
+        assert!(layout.unpack(&packed[..10]).is_err());
+    }
+}
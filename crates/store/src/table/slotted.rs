@@ -0,0 +1,231 @@
+use crate::page::{RawPage, PAGE_DATA_SIZE};
+
+/// Kind, slot count and where the row bytes start
+const PAGE_HEADER: usize = 5;
+/// Offset and length of a row
+const SLOT_SIZE: usize = 4;
+const LEN_MASK: u16 = 0x3fff;
+
+/// Largest row a page with no other rows holds
+pub(crate) const MAX_ROW_SIZE: usize = PAGE_DATA_SIZE - PAGE_HEADER - SLOT_SIZE;
+
+/// What a slot holds besides a row
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SlotKind {
+    Row,
+    /// The row moved, the slot holds the row id it moved to
+    Forward,
+    /// A row moved here, prefixed with the row id it still goes by
+    Moved,
+}
+
+impl SlotKind {
+    fn flags(self) -> u16 {
+        match self {
+            SlotKind::Row => 0,
+            SlotKind::Forward => 0x8000,
+            SlotKind::Moved => 0x4000,
+        }
+    }
+
+    fn from_flags(len: u16) -> Self {
+        match len & !LEN_MASK {
+            0x8000 => SlotKind::Forward,
+            0x4000 => SlotKind::Moved,
+            _ => SlotKind::Row,
+        }
+    }
+}
+
+/// A page of rows. Slots grow from the front and row bytes from the back, so a row keeps its
+/// slot when the page is compacted. Slots of removed rows stay empty for good, a stale row id
+/// finds nothing rather than another row.
+pub(crate) struct SlottedPage<'p> {
+    page: &'p mut RawPage,
+}
+
+/// The `SlottedPage` accessors that only read
+pub(crate) struct SlottedView<'p> {
+    data: &'p [u8; PAGE_DATA_SIZE],
+}
+
+impl<'p> SlottedView<'p> {
+    pub(crate) fn new(page: &'p RawPage) -> Self {
+        SlottedView { data: &page.data }
+    }
+
+    fn u16_at(&self, i: usize) -> u16 {
+        u16::from_le_bytes(self.data[i..i + 2].try_into().unwrap())
+    }
+
+    pub(crate) fn slots(&self) -> u16 {
+        self.u16_at(1)
+    }
+
+    fn rows_start(&self) -> usize {
+        self.u16_at(3) as usize
+    }
+
+    /// Offset and flagged length of a slot
+    fn slot(&self, slot: u16) -> (usize, u16) {
+        let at = PAGE_HEADER + slot as usize * SLOT_SIZE;
+        (self.u16_at(at) as usize, self.u16_at(at + 2))
+    }
+
+    /// What a slot holds, or `None` once its row is removed
+    pub(crate) fn get(&self, slot: u16) -> Option<(SlotKind, &'p [u8])> {
+        if slot >= self.slots() {
+            return None;
+        }
+        let (offset, len) = self.slot(slot);
+        let data = self.data;
+        (offset != 0).then(|| {
+            let bytes = &data[offset..offset + (len & LEN_MASK) as usize];
+            (SlotKind::from_flags(len), bytes)
+        })
+    }
+
+    /// Bytes free for rows and their slots, once compacted
+    pub(crate) fn free_space(&self) -> usize {
+        let used: usize = (0..self.slots())
+            .filter_map(|slot| self.get(slot))
+            .map(|(_, bytes)| bytes.len())
+            .sum();
+        PAGE_DATA_SIZE - PAGE_HEADER - self.slots() as usize * SLOT_SIZE - used
+    }
+}
+
+impl<'p> SlottedPage<'p> {
+    /// Lay out an empty page of the given kind
+    pub(crate) fn init(page: &'p mut RawPage, kind: u8) -> Self {
+        page.data[0] = kind;
+        page.data[1..3].copy_from_slice(&0u16.to_le_bytes());
+        page.data[3..5].copy_from_slice(&(PAGE_DATA_SIZE as u16).to_le_bytes());
+        page.header.size = PAGE_DATA_SIZE as u64;
+        SlottedPage { page }
+    }
+
+    pub(crate) fn new(page: &'p mut RawPage) -> Self {
+        SlottedPage { page }
+    }
+
+    pub(crate) fn view(&self) -> SlottedView<'_> {
+        SlottedView::new(self.page)
+    }
+
+    fn set_u16(&mut self, i: usize, value: u16) {
+        self.page.data[i..i + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_slot(&mut self, slot: u16, offset: usize, len: u16) {
+        let at = PAGE_HEADER + slot as usize * SLOT_SIZE;
+        self.set_u16(at, offset as u16);
+        self.set_u16(at + 2, len);
+    }
+
+    /// Add a row in a new slot, or `None` when it doesn't fit
+    pub(crate) fn insert(&mut self, kind: SlotKind, bytes: &[u8]) -> Option<u16> {
+        let slot = self.view().slots();
+        if slot == u16::MAX || self.view().free_space() < bytes.len() + SLOT_SIZE {
+            return None;
+        }
+        // Room for the new slot has to be made before it's taken
+        self.make_room(slot + 1, bytes.len());
+        self.set_u16(1, slot + 1);
+        self.set_slot(slot, 0, 0);
+        self.place(slot, kind, bytes);
+        Some(slot)
+    }
+
+    /// Replace what a slot holds, leaving it as it was when the bytes don't fit
+    pub(crate) fn replace(&mut self, slot: u16, kind: SlotKind, bytes: &[u8]) -> bool {
+        let view = self.view();
+        let old = view.get(slot).map_or(0, |(_, old)| old.len());
+        if slot >= view.slots() || view.free_space() + old < bytes.len() {
+            return false;
+        }
+        self.set_slot(slot, 0, 0);
+        self.place(slot, kind, bytes);
+        true
+    }
+
+    pub(crate) fn remove(&mut self, slot: u16) {
+        if slot < self.view().slots() {
+            self.set_slot(slot, 0, 0);
+        }
+    }
+
+    /// Compact the page if `len` bytes don't fit between `slots` slots and the rows as is
+    fn make_room(&mut self, slots: u16, len: usize) {
+        let slots_end = PAGE_HEADER + slots as usize * SLOT_SIZE;
+        if self.view().rows_start() < slots_end + len {
+            self.compact();
+        }
+    }
+
+    /// Write bytes that fit into an empty slot
+    fn place(&mut self, slot: u16, kind: SlotKind, bytes: &[u8]) {
+        self.make_room(self.view().slots(), bytes.len());
+        let start = self.view().rows_start() - bytes.len();
+        self.page.data[start..start + bytes.len()].copy_from_slice(bytes);
+        self.set_u16(3, start as u16);
+        self.set_slot(slot, start, bytes.len() as u16 | kind.flags());
+    }
+
+    /// Move every row to the back of the page, leaving the free space in one piece
+    fn compact(&mut self) {
+        let view = self.view();
+        let rows: Vec<_> = (0..view.slots())
+            .filter_map(|slot| {
+                view.get(slot)
+                    .map(|(kind, bytes)| (slot, kind, bytes.to_vec()))
+            })
+            .collect();
+        self.set_u16(3, PAGE_DATA_SIZE as u16);
+        for (slot, kind, bytes) in rows {
+            self.place(slot, kind, &bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_slots_across_compaction() {
+        let mut page = RawPage::new(1, 1);
+        let mut slotted = SlottedPage::init(&mut page, 1);
+        let big = vec![7; MAX_ROW_SIZE / 3];
+        let a = slotted.insert(SlotKind::Row, &big).unwrap();
+        let b = slotted.insert(SlotKind::Row, b"b").unwrap();
+        let c = slotted.insert(SlotKind::Moved, &big).unwrap();
+        assert_eq!((a, b, c), (0, 1, 2));
+        assert!(slotted.insert(SlotKind::Row, &big).is_none());
+
+        slotted.remove(a);
+        assert_eq!(slotted.view().get(a), None);
+        // Only fits once the space `a` left is compacted with the rest
+        let d = slotted
+            .insert(SlotKind::Row, &vec![9; MAX_ROW_SIZE / 3 + 16])
+            .unwrap();
+        assert_eq!(d, 3);
+        assert_eq!(slotted.view().get(b), Some((SlotKind::Row, &b"b"[..])));
+        assert_eq!(slotted.view().get(c).unwrap().0, SlotKind::Moved);
+        assert_eq!(slotted.view().get(c).unwrap().1, &big[..]);
+
+        assert!(slotted.replace(b, SlotKind::Forward, &[1; 8]));
+        assert!(!slotted.replace(b, SlotKind::Row, &[0; MAX_ROW_SIZE]));
+        assert_eq!(
+            slotted.view().get(b),
+            Some((SlotKind::Forward, &[1; 8][..]))
+        );
+        assert!(slotted.replace(b, SlotKind::Row, b""));
+        assert_eq!(slotted.view().get(b), Some((SlotKind::Row, &b""[..])));
+        assert_eq!(slotted.view().get(4), None);
+
+        let mut page = RawPage::new(2, 1);
+        let mut slotted = SlottedPage::init(&mut page, 1);
+        assert_eq!(slotted.insert(SlotKind::Row, &[0; MAX_ROW_SIZE]), Some(0));
+    }
+}
@@ -0,0 +1,859 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io, mem,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{blocking, page::PAGE_DATA_SIZE, PageId, PageManager, SourceTag};
+
+/// Position of a record in the log, counting up from 1
+pub type Lsn = u64;
+
+/// Bytes before each record: its length, a checksum of the rest and its lsn
+const FRAME_HEADER_SIZE: usize = 16;
+
+/// 16 MB
+const SEGMENT_SIZE: u64 = 16_000_000;
+
+const WAL_DIRECTORY: &str = "wal";
+
+/// A change to pages, redone after a crash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalRecord {
+    NewPage {
+        id: PageId,
+        tag: SourceTag,
+    },
+    /// `data` written into a page at `offset`, leaving `size` bytes of it in use
+    Write {
+        id: PageId,
+        offset: u32,
+        size: u64,
+        data: Vec<u8>,
+    },
+    DeletePage {
+        id: PageId,
+    },
+    /// The pages of a source were made read only
+    SealSource {
+        tag: SourceTag,
+    },
+}
+
+impl WalRecord {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            WalRecord::NewPage { id, tag } => {
+                bytes.push(0);
+                bytes.extend(id.to_le_bytes());
+                bytes.extend(tag.to_le_bytes());
+            }
+            WalRecord::Write {
+                id,
+                offset,
+                size,
+                data,
+            } => {
+                bytes.push(1);
+                bytes.extend(id.to_le_bytes());
+                bytes.extend(offset.to_le_bytes());
+                bytes.extend(size.to_le_bytes());
+                bytes.extend(data);
+            }
+            WalRecord::DeletePage { id } => {
+                bytes.push(2);
+                bytes.extend(id.to_le_bytes());
+            }
+            WalRecord::SealSource { tag } => {
+                bytes.push(3);
+                bytes.extend(tag.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (kind, rest) = bytes.split_first()?;
+        let u64_at = |i: usize| Some(u64::from_le_bytes(rest.get(i..i + 8)?.try_into().ok()?));
+        Some(match kind {
+            0 if rest.len() == 16 => WalRecord::NewPage {
+                id: u64_at(0)?,
+                tag: u64_at(8)?,
+            },
+            1 if rest.len() >= 20 => WalRecord::Write {
+                id: u64_at(0)?,
+                offset: u32::from_le_bytes(rest[8..12].try_into().ok()?),
+                size: u64_at(12)?,
+                data: rest[20..].to_vec(),
+            },
+            2 if rest.len() == 8 => WalRecord::DeletePage { id: u64_at(0)? },
+            3 if rest.len() == 8 => WalRecord::SealSource { tag: u64_at(0)? },
+            _ => return None,
+        })
+    }
+
+    /// Apply the change logged at `lsn` again. Records are replayed in order over pages that
+    /// may already have some of them, writes a page's lsn shows it has are skipped.
+    async fn redo(&self, lsn: Lsn, manager: &PageManager) -> error::Result<()> {
+        match self {
+            // the page was deleted and the deletion flushed before the crash
+            WalRecord::Write { id, .. } | WalRecord::DeletePage { id }
+                if !manager.contains_page(*id) =>
+            {
+                Ok(())
+            }
+            WalRecord::Write { id, .. } => {
+                let loan = manager.aquire_page_loan(*id).await?;
+                if loan.read().await?.header.lsn >= lsn {
+                    return Ok(());
+                }
+                self.apply(lsn, manager).await
+            }
+            _ => self.apply(lsn, manager).await,
+        }
+    }
+
+    /// Apply the change logged at `lsn` to its page, stamping the page with the lsn
+    pub async fn apply(&self, lsn: Lsn, manager: &PageManager) -> error::Result<()> {
+        match self {
+            WalRecord::NewPage { id, tag } => manager.restore_page(*id, *tag).await,
+            WalRecord::Write {
+                id,
+                offset,
+                size,
+                data,
+            } => {
+                let start = *offset as usize;
+                if start + data.len() > PAGE_DATA_SIZE {
+                    return Err(error::CustomErrors::InvalidState(format!(
+                        "logged write past the end of page {}",
+                        id
+                    ))
+                    .into());
+                }
+                let loan = manager.aquire_page_loan(*id).await?;
+                let mut page = loan.write().await?;
+                page.data[start..start + data.len()].copy_from_slice(data);
+                page.header.size = *size;
+                page.header.lsn = page.header.lsn.max(lsn);
+                Ok(())
+            }
+            WalRecord::DeletePage { id } => manager.forget_page(*id).await,
+            WalRecord::SealSource { tag } => {
+                manager.seal_pages(*tag);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A record framed for its segment, its lsn still to be filled in
+fn frame(record: &WalRecord) -> Vec<u8> {
+    let mut bytes = vec![0; FRAME_HEADER_SIZE];
+    record.encode(&mut bytes);
+    let len = (bytes.len() - FRAME_HEADER_SIZE) as u32;
+    bytes[..4].copy_from_slice(&len.to_le_bytes());
+    bytes
+}
+
+fn seal(bytes: &mut [u8], lsn: Lsn) {
+    bytes[8..16].copy_from_slice(&lsn.to_le_bytes());
+    let checksum = crc32c::crc32c(&bytes[8..]);
+    bytes[4..8].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Records framed in `bytes` from `first` on, and where the valid ones end. Reading stops at
+/// the first frame that is cut short, fails its checksum or is out of order, which is where a
+/// crash tore the last write.
+fn read_frames(bytes: &[u8], first: Lsn) -> (Vec<(Lsn, WalRecord)>, usize) {
+    let mut records = Vec::new();
+    let mut at = 0;
+    while let Some(header) = bytes.get(at..at + FRAME_HEADER_SIZE) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let Some(body) = bytes.get(at + 8..at + FRAME_HEADER_SIZE + len) else {
+            break;
+        };
+        let lsn = u64::from_le_bytes(body[..8].try_into().unwrap());
+        if crc32c::crc32c(body) != checksum || lsn != first + records.len() as u64 {
+            break;
+        }
+        let Some(record) = WalRecord::decode(&body[8..]) else {
+            break;
+        };
+        records.push((lsn, record));
+        at += FRAME_HEADER_SIZE + len;
+    }
+    (records, at)
+}
+
+fn segment_path(dir: &Path, first: Lsn) -> PathBuf {
+    dir.join(format!("wal_{:020}.log", first))
+}
+
+/// First lsns of the segments in `dir`, oldest first
+fn list_segments(dir: &Path) -> io::Result<Vec<Lsn>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let first = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("wal_")?.strip_suffix(".log"))
+            .and_then(|first| first.parse::<Lsn>().ok());
+        segments.extend(first);
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn open_segment(dir: &Path, first: Lsn) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(segment_path(dir, first))
+}
+
+/// When commits wait for the log to reach the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Every commit waits for an fsync, shared with the commits waiting alongside it
+    Always,
+    /// Commits wait for the write and fsync at most once per interval, so a crash can lose
+    /// the commits since the last one. The interval is only checked as commits come in, a
+    /// log that goes quiet stays unsynced until the next commit or checkpoint.
+    Interval(Duration),
+    /// Leave syncing to the OS
+    Never,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WalOptions {
+    /// Segments are rolled over once they would grow past this many bytes
+    pub segment_size: u64,
+    pub sync: SyncPolicy,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        WalOptions {
+            segment_size: SEGMENT_SIZE,
+            sync: SyncPolicy::Always,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WalStats {
+    pub records: u64,
+    /// Batches of records written by a commit
+    pub writes: u64,
+    pub syncs: u64,
+}
+
+/// Records appended but not yet written
+struct Pending {
+    next_lsn: Lsn,
+    frames: Vec<(Lsn, Vec<u8>)>,
+}
+
+struct Writer {
+    /// First lsns of the segments on disk, the last one being written to
+    segments: Vec<Lsn>,
+    file: Arc<File>,
+    len: u64,
+    last_sync: Instant,
+}
+
+/// Write-ahead log of page changes, kept in segment files named by their first lsn. Changes
+/// are appended, then made durable by a commit, then applied to their pages, which carry the
+/// lsn of the last one. After a crash they are redone into the page manager, and a checkpoint
+/// drops the segments holding only changes the page manager has flushed.
+pub struct Wal {
+    dir: PathBuf,
+    options: WalOptions,
+    pending: Mutex<Pending>,
+    /// Held by the commit writing for everyone
+    writer: tokio::sync::Mutex<Writer>,
+    written: AtomicU64,
+    synced: AtomicU64,
+    /// Where commits are done waiting, the records written or synced as the policy asks
+    durable: AtomicU64,
+    writes: AtomicU64,
+    syncs: AtomicU64,
+}
+
+impl Wal {
+    /// Open the log in the configured data directory
+    pub async fn new() -> error::Result<Self> {
+        let config = config::get_config().await;
+        let dir = Path::new(&config.data_directory).join(WAL_DIRECTORY);
+        Wal::open(dir, WalOptions::default()).await
+    }
+
+    /// Open the log in `dir`, creating it if needed, and cut off any write torn by a crash
+    pub async fn open(dir: impl AsRef<Path>, options: WalOptions) -> error::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        let (segments, file, len, last) = blocking({
+            let dir = dir.clone();
+            move || {
+                let mut segments = list_segments(&dir)?;
+                let mut last = 0;
+                let mut len = 0;
+                for (i, first) in segments.iter().enumerate() {
+                    let bytes = fs::read(segment_path(&dir, *first))?;
+                    let (records, valid) = read_frames(&bytes, *first);
+                    last = records.last().map_or(first - 1, |(lsn, _)| *lsn);
+                    len = valid as u64;
+                    if valid < bytes.len() {
+                        // Nothing after a torn write made it to disk whole
+                        open_segment(&dir, *first)?.set_len(len)?;
+                        for later in segments.drain(i + 1..) {
+                            fs::remove_file(segment_path(&dir, later))?;
+                        }
+                        File::open(&dir)?.sync_all()?;
+                        break;
+                    }
+                }
+                if segments.is_empty() {
+                    segments.push(1);
+                }
+                let file = open_segment(&dir, *segments.last().unwrap())?;
+                file.sync_all()?;
+                Ok((segments, file, len, last))
+            }
+        })
+        .await?;
+
+        Ok(Wal {
+            dir,
+            options,
+            pending: Mutex::new(Pending {
+                next_lsn: last + 1,
+                frames: Vec::new(),
+            }),
+            writer: tokio::sync::Mutex::new(Writer {
+                segments,
+                file: Arc::new(file),
+                len,
+                last_sync: Instant::now(),
+            }),
+            written: AtomicU64::new(last),
+            synced: AtomicU64::new(last),
+            durable: AtomicU64::new(last),
+            writes: AtomicU64::new(0),
+            syncs: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            records: self.last_lsn(),
+            writes: self.writes.load(Ordering::Relaxed),
+            syncs: self.syncs.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The lsn of the last record appended
+    pub fn last_lsn(&self) -> Lsn {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .next_lsn
+            - 1
+    }
+
+    /// Add a record to the log. It is durable once a commit covers its lsn.
+    pub fn append(&self, record: &WalRecord) -> Lsn {
+        let mut bytes = frame(record);
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let lsn = pending.next_lsn;
+        pending.next_lsn += 1;
+        seal(&mut bytes, lsn);
+        pending.frames.push((lsn, bytes));
+        lsn
+    }
+
+    /// The lsn of the last record a commit has made durable
+    pub fn durable_lsn(&self) -> Lsn {
+        self.durable.load(Ordering::Acquire)
+    }
+
+    fn committed(&self, lsn: Lsn) -> bool {
+        self.durable_lsn() >= lsn
+    }
+
+    /// Move the durable lsn up to what was last written or synced
+    fn advance(&self) {
+        let durable = match self.options.sync {
+            SyncPolicy::Always => self.synced.load(Ordering::Acquire),
+            _ => self.written.load(Ordering::Acquire),
+        };
+        self.durable.fetch_max(durable, Ordering::AcqRel);
+    }
+
+    /// Append a record, commit it and apply it to its page, returning its lsn
+    pub async fn apply(&self, record: &WalRecord, manager: &PageManager) -> error::Result<Lsn> {
+        let lsn = self.append(record);
+        self.commit(lsn).await?;
+        record.apply(lsn, manager).await?;
+        Ok(lsn)
+    }
+
+    /// Wait until the records up to `lsn` are as durable as the sync policy makes them. The
+    /// first commit to get the writer writes and syncs every record appended so far, so the
+    /// commits waiting behind it usually find their records already done.
+    pub async fn commit(&self, lsn: Lsn) -> error::Result<()> {
+        if lsn > self.last_lsn() {
+            return Err(error::CustomErrors::InvalidArguments(format!(
+                "record {} hasn't been appended",
+                lsn
+            ))
+            .into());
+        }
+        if self.committed(lsn) {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().await;
+        if self.committed(lsn) {
+            return Ok(());
+        }
+
+        let frames = mem::take(
+            &mut self
+                .pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .frames,
+        );
+        if let Err(e) = self.write(&mut writer, &frames).await {
+            // Put back what didn't make it so that the next commit tries again
+            let written = self.written.load(Ordering::Acquire);
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            let appended = mem::take(&mut pending.frames);
+            pending.frames = frames
+                .into_iter()
+                .filter(|(lsn, _)| *lsn > written)
+                .chain(appended)
+                .collect();
+            return Err(e);
+        }
+
+        let sync = match self.options.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => writer.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            let file = writer.file.clone();
+            blocking(move || file.sync_data()).await?;
+            writer.last_sync = Instant::now();
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            self.synced
+                .fetch_max(self.written.load(Ordering::Acquire), Ordering::AcqRel);
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Write frames to the end of the log, rolling over to new segments as they fill up
+    async fn write(&self, writer: &mut Writer, frames: &[(Lsn, Vec<u8>)]) -> error::Result<()> {
+        let mut batch: Vec<u8> = Vec::new();
+        let mut last = None;
+        for (lsn, bytes) in frames {
+            let len = writer.len + (batch.len() + bytes.len()) as u64;
+            if writer.len + batch.len() as u64 > 0 && len > self.options.segment_size {
+                self.write_batch(writer, mem::take(&mut batch), last)
+                    .await?;
+                self.roll(writer, *lsn).await?;
+            }
+            batch.extend(bytes);
+            last = Some(*lsn);
+        }
+        self.write_batch(writer, batch, last).await
+    }
+
+    async fn write_batch(
+        &self,
+        writer: &mut Writer,
+        batch: Vec<u8>,
+        last: Option<Lsn>,
+    ) -> error::Result<()> {
+        let Some(last) = last.filter(|_| !batch.is_empty()) else {
+            return Ok(());
+        };
+        let file = writer.file.clone();
+        let position = writer.len;
+        let len = batch.len() as u64;
+        // Writes go to the end of what's been written, so a failed one is written over
+        blocking(move || file.write_all_at(&batch, position)).await?;
+        writer.len += len;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.written.fetch_max(last, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Start a new segment at `first`, syncing the one before it
+    async fn roll(&self, writer: &mut Writer, first: Lsn) -> error::Result<()> {
+        let old = writer.file.clone();
+        let dir = self.dir.clone();
+        let file = blocking(move || {
+            old.sync_data()?;
+            let file = open_segment(&dir, first)?;
+            file.set_len(0)?;
+            File::open(&dir)?.sync_all()?;
+            Ok(file)
+        })
+        .await?;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.synced.fetch_max(first - 1, Ordering::AcqRel);
+        self.advance();
+        writer.segments.push(first);
+        writer.file = Arc::new(file);
+        writer.len = 0;
+        Ok(())
+    }
+
+    /// Flush `manager` and drop the segments holding only the records it has made durable.
+    /// Every change logged before the checkpoint must already be applied to its page.
+    pub async fn checkpoint(&self, manager: &PageManager) -> error::Result<()> {
+        let lsn = self.last_lsn();
+        self.commit(lsn).await?;
+        // A page held back can have older changes on it too, so their records have to stay
+        if manager.flush_pages().await?.is_empty() {
+            self.truncate(lsn).await?;
+        }
+        Ok(())
+    }
+
+    /// Drop the segments holding only records up to `lsn`
+    async fn truncate(&self, lsn: Lsn) -> error::Result<()> {
+        let mut writer = self.writer.lock().await;
+        if writer.len > 0 && self.written.load(Ordering::Acquire) == lsn {
+            self.roll(&mut writer, lsn + 1).await?;
+        }
+        // A segment is covered once the segment after it starts within the checkpoint
+        let covered = writer
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1] <= lsn + 1)
+            .count();
+        let obsolete: Vec<_> = writer.segments.drain(..covered).collect();
+        let dir = self.dir.clone();
+        blocking(move || {
+            for first in obsolete {
+                fs::remove_file(segment_path(&dir, first))?;
+            }
+            File::open(&dir)?.sync_all()
+        })
+        .await
+    }
+
+    /// Every record still in the log, oldest first
+    async fn read_log(&self) -> error::Result<Vec<(Lsn, WalRecord)>> {
+        let segments = self.writer.lock().await.segments.clone();
+        let dir = self.dir.clone();
+        blocking(move || {
+            let mut records = Vec::new();
+            for first in segments {
+                let bytes = fs::read(segment_path(&dir, first))?;
+                records.extend(read_frames(&bytes, first).0);
+            }
+            Ok(records)
+        })
+        .await
+    }
+
+    /// Redo the logged changes into `manager` after a crash, then checkpoint so the log
+    /// doesn't have to be replayed again. Returns how many records were redone.
+    pub async fn recover(&self, manager: &PageManager) -> error::Result<usize> {
+        let records = self.read_log().await?;
+        for (lsn, record) in &records {
+            record.redo(*lsn, manager).await?;
+        }
+        self.checkpoint(manager).await?;
+        Ok(records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PAGE_SIZE;
+
+    fn write(id: PageId, data: &[u8]) -> WalRecord {
+        WalRecord::Write {
+            id,
+            offset: 0,
+            size: data.len() as u64,
+            data: data.to_vec(),
+        }
+    }
+
+    async fn open(dir: &Path, sync: SyncPolicy) -> Wal {
+        let options = WalOptions {
+            segment_size: 256,
+            sync,
+        };
+        Wal::open(dir, options).await.unwrap()
+    }
+
+    fn segment_count(dir: &Path) -> usize {
+        list_segments(dir).unwrap().len()
+    }
+
+    #[test]
+    fn reads_frames_up_to_a_torn_write() {
+        let records = vec![
+            WalRecord::NewPage { id: 1, tag: 2 },
+            write(1, b"hello"),
+            WalRecord::DeletePage { id: 1 },
+        ];
+        let mut bytes = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            let mut framed = frame(record);
+            seal(&mut framed, 5 + i as u64);
+            bytes.extend(framed);
+        }
+        let (read, valid) = read_frames(&bytes, 5);
+        assert_eq!(valid, bytes.len());
+        assert_eq!(
+            read.into_iter().map(|(_, r)| r).collect::<Vec<_>>(),
+            records
+        );
+
+        let (read, valid) = read_frames(&bytes[..bytes.len() - 1], 5);
+        assert_eq!(read.len(), 2);
+        // A flipped bit in the second record
+        bytes[valid - 1] ^= 1;
+        assert_eq!(read_frames(&bytes, 5).0.len(), 1);
+        assert!(read_frames(&bytes, 4).0.is_empty());
+
+        let record = WalRecord::SealSource { tag: 3 };
+        let mut framed = frame(&record);
+        seal(&mut framed, 1);
+        assert_eq!(read_frames(&framed, 1).0, vec![(1, record)]);
+    }
+
+    #[tokio::test]
+    async fn redoes_changes_lost_in_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let pages = dir.path().join("pages");
+        let manager = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let wal = open(&dir.path().join(WAL_DIRECTORY), SyncPolicy::Always).await;
+        let kept = manager.new_page(1).await.unwrap().id();
+        let deleted = manager.new_page(1).await.unwrap().id();
+        wal.append(&WalRecord::NewPage { id: kept, tag: 1 });
+        wal.append(&WalRecord::NewPage {
+            id: deleted,
+            tag: 1,
+        });
+        wal.checkpoint(&manager).await.unwrap();
+
+        // Log then apply, and crash before the pages are flushed
+        let page = manager.new_page(2).await.unwrap().id();
+        for record in [
+            WalRecord::NewPage { id: page, tag: 2 },
+            write(page, b"new"),
+            write(kept, b"kept"),
+            WalRecord::DeletePage { id: deleted },
+        ] {
+            let lsn = wal.append(&record);
+            wal.commit(lsn).await.unwrap();
+            record.redo(lsn, &manager).await.unwrap();
+        }
+        drop((manager, wal));
+
+        let manager = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        assert!(!manager.contains_page(page));
+        assert!(manager.contains_page(deleted));
+        let wal = open(&dir.path().join(WAL_DIRECTORY), SyncPolicy::Always).await;
+        assert_eq!(wal.recover(&manager).await.unwrap(), 4);
+        drop((manager, wal));
+
+        let manager = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let read = |id| {
+            let manager = &manager;
+            async move {
+                let page = manager.get_page(id).await.unwrap().unwrap();
+                page.active_data().to_vec()
+            }
+        };
+        assert_eq!(read(page).await, b"new");
+        assert_eq!(read(kept).await, b"kept");
+        assert_eq!(manager.get_page(kept).await.unwrap().unwrap().header.lsn, 5);
+        // Writes older than a page's lsn are already in it
+        write(kept, b"old").redo(4, &manager).await.unwrap();
+        assert_eq!(read(kept).await, b"kept");
+        assert!(!manager.contains_page(deleted));
+        assert_eq!(manager.list_pages(2), vec![page]);
+        let wal = open(&dir.path().join(WAL_DIRECTORY), SyncPolicy::Always).await;
+        assert!(wal.read_log().await.unwrap().is_empty());
+        assert_eq!(wal.last_lsn(), 6);
+    }
+
+    #[tokio::test]
+    async fn stamps_pages_and_holds_back_changes_not_yet_durable() {
+        let dir = tempfile::tempdir().unwrap();
+        let pages = dir.path().join("pages");
+        let wal = open(&dir.path().join(WAL_DIRECTORY), SyncPolicy::Always).await;
+        let manager = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap()
+            .with_wal(wal)
+            .await
+            .unwrap();
+        let wal = manager.wal().unwrap();
+        let (held, other) = (
+            manager.new_page(1).await.unwrap().id(),
+            manager.new_page(1).await.unwrap().id(),
+        );
+        let lsn = wal.apply(&write(held, b"first"), &manager).await.unwrap();
+        assert_eq!(wal.durable_lsn(), lsn);
+        manager.flush().await.unwrap();
+
+        // Applied ahead of its record, so the page can't go to disk while the rest can
+        wal.apply(&write(other, b"other"), &manager).await.unwrap();
+        let record = write(held, b"second");
+        let next = wal.last_lsn() + 1;
+        record.apply(next, &manager).await.unwrap();
+        manager.flush().await.unwrap();
+        let on_disk = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let stored = on_disk.get_page(held).await.unwrap().unwrap();
+        assert_eq!(
+            (stored.active_data(), stored.header.lsn),
+            (&b"first"[..], lsn)
+        );
+        let stored = on_disk.get_page(other).await.unwrap().unwrap();
+        assert_eq!(stored.active_data(), b"other");
+        drop(on_disk);
+        // The held page could have older changes on it too, so the log keeps them
+        assert!(!wal.read_log().await.unwrap().is_empty());
+
+        // Still dirty, so once the log catches up the next flush writes it
+        assert_eq!(wal.append(&record), next);
+        manager.flush().await.unwrap();
+        assert!(wal.read_log().await.unwrap().is_empty());
+        drop(manager);
+        let manager = PageManager::open(&pages, 4 * PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let stored = manager.get_page(held).await.unwrap().unwrap();
+        assert_eq!(stored.active_data(), b"second");
+        assert_eq!(stored.header.lsn, next);
+    }
+
+    #[tokio::test]
+    async fn cuts_off_torn_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = open(dir.path(), SyncPolicy::Always).await;
+        for i in 0..3 {
+            wal.append(&write(1, &[i]));
+        }
+        wal.commit(3).await.unwrap();
+        let lost = wal.append(&write(1, b"lost"));
+        drop(wal);
+        assert_eq!(lost, 4);
+
+        // Half a record made it to disk before the crash
+        let path = segment_path(dir.path(), 1);
+        let len = fs::metadata(&path).unwrap().len();
+        let mut torn = frame(&write(1, b"torn"));
+        seal(&mut torn, 4);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&torn[..torn.len() / 2], len).unwrap();
+
+        let wal = open(dir.path(), SyncPolicy::Always).await;
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(wal.last_lsn(), 3);
+        let lsn = wal.append(&write(1, b"after"));
+        wal.commit(lsn).await.unwrap();
+        let records = wal.read_log().await.unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3], (4, write(1, b"after")));
+    }
+
+    #[tokio::test]
+    async fn rolls_over_and_truncates_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = PageManager::open(dir.path().join("pages"), PAGE_SIZE as u64)
+            .await
+            .unwrap();
+        let wal = open(dir.path(), SyncPolicy::Never).await;
+        // Each frame is 16 + 21 + 50 bytes, two to a segment
+        for _ in 0..7 {
+            let lsn = wal.append(&write(1, &[0; 50]));
+            wal.commit(lsn).await.unwrap();
+        }
+        assert_eq!(segment_count(dir.path()), 4);
+        assert_eq!(wal.read_log().await.unwrap().len(), 7);
+
+        wal.checkpoint(&manager).await.unwrap();
+        assert_eq!(segment_count(dir.path()), 1);
+        assert!(wal.read_log().await.unwrap().is_empty());
+        let lsn = wal.append(&write(1, b"a"));
+        assert_eq!(lsn, 8);
+        wal.commit(lsn).await.unwrap();
+        drop(wal);
+
+        let wal = open(dir.path(), SyncPolicy::Never).await;
+        assert_eq!(wal.read_log().await.unwrap(), vec![(8, write(1, b"a"))]);
+        assert_eq!(wal.append(&write(1, b"b")), 9);
+    }
+
+    #[tokio::test]
+    async fn groups_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = open(dir.path(), SyncPolicy::Always).await;
+        let lsns: Vec<_> = (0..5).map(|i| wal.append(&write(1, &[i]))).collect();
+        wal.commit(lsns[4]).await.unwrap();
+        for lsn in lsns {
+            wal.commit(lsn).await.unwrap();
+        }
+        assert_eq!(wal.stats().syncs, 1);
+        assert_eq!(wal.stats().writes, 1);
+        assert!(wal.commit(6).await.is_err());
+
+        let wal = Arc::new(wal);
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let wal = wal.clone();
+                tokio::spawn(async move {
+                    let lsn = wal.append(&write(2, &[i]));
+                    wal.commit(lsn).await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let stats = wal.stats();
+        assert_eq!(stats.records, 21);
+        assert!(stats.syncs <= 17);
+        assert_eq!(wal.read_log().await.unwrap().len(), 21);
+
+        let dir = tempfile::tempdir().unwrap();
+        let wal = open(dir.path(), SyncPolicy::Interval(Duration::from_secs(60))).await;
+        for i in 0..3 {
+            let lsn = wal.append(&write(1, &[i]));
+            wal.commit(lsn).await.unwrap();
+        }
+        assert_eq!(wal.stats().writes, 3);
+        assert_eq!(wal.stats().syncs, 0);
+    }
+}